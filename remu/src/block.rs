@@ -0,0 +1,369 @@
+// Basic-block engine. Instead of looking up every instruction like
+// `Machine::step`, it decodes the run of instructions up to the next control
+// transfer once, keeps it as a block found by the virtual address it starts
+// at, and links every block to the blocks it was seen to continue with, so
+// most transitions skip the lookup. Devices are advanced and interrupts are
+// taken between blocks.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::common::{Decoded, Machine, VmExit};
+#[cfg(feature = "jit")]
+use crate::jit::{Backend, NativeCode};
+use crate::mmu::{Access, VirtAddr, PAGE_SIZE};
+use crate::riscv::register::Register;
+
+/// Longest block. Devices only advance and interrupts are only taken
+/// between blocks, also between linked ones, so a timer interrupt or a
+/// CLINT/PLIC update is seen up to this many instructions later than with
+/// `Machine::step`. Software does not notice, as interrupts are
+/// asynchronous anyway, but `mtime` moves in steps of up to this size.
+const MAX_BLOCK_LEN: usize = 64;
+
+/// How a `Machine` is run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    /// One instruction at a time with `Machine::step`, printing a trace
+    Step,
+    /// Block by block with `Machine::run_blocks`
+    Block,
+    /// Block by block, running blocks compiled to x86-64 code
+    #[cfg(feature = "x86-jit")]
+    Jit,
+    /// Block by block, running blocks compiled with Cranelift
+    #[cfg(feature = "cranelift")]
+    Cranelift,
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "step" => Ok(Engine::Step),
+            "block" => Ok(Engine::Block),
+            #[cfg(feature = "x86-jit")]
+            "jit" => Ok(Engine::Jit),
+            #[cfg(feature = "cranelift")]
+            "cranelift" => Ok(Engine::Cranelift),
+            _ => Err(format!("unknown engine '{}'", s)),
+        }
+    }
+}
+
+struct Block {
+    insts: Vec<Decoded>,
+    /// Blocks that followed this one, with their start address
+    links: [Option<(u64, usize)>; 2],
+    /// Compiled code for the instructions from the start of the block
+    #[cfg(feature = "jit")]
+    native: Option<NativeCode>,
+}
+
+pub struct BlockCache {
+    blocks: Vec<Block>,
+    /// Block indices by the virtual address of their first instruction
+    by_pc: HashMap<u64, usize>,
+    /// Bumped by every flush, which invalidates the block indices
+    generation: u64,
+    /// Engine the blocks are made for
+    #[cfg(feature = "jit")]
+    engine: Engine,
+    /// Compiler of new blocks, for the JIT engines
+    #[cfg(feature = "jit")]
+    backend: Option<Backend>,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        BlockCache {
+            blocks: Vec::new(),
+            by_pc: HashMap::new(),
+            generation: 0,
+            #[cfg(feature = "jit")]
+            engine: Engine::Block,
+            #[cfg(feature = "jit")]
+            backend: None,
+        }
+    }
+
+    /// Drops every block, for when code was written or instruction fetches
+    /// are translated differently
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.by_pc.clear();
+        self.generation = self.generation.wrapping_add(1);
+        #[cfg(feature = "jit")]
+        if let Some(backend) = &mut self.backend {
+            backend.clear();
+        }
+    }
+
+    /// Makes new blocks for `engine`, dropping those made for another one.
+    /// Blocks are interpreted if Cranelift does not support the host.
+    #[cfg(feature = "jit")]
+    fn set_engine(&mut self, engine: Engine) {
+        if self.engine == engine {
+            return;
+        }
+        self.flush();
+        self.engine = engine;
+        self.backend = match engine {
+            #[cfg(feature = "x86-jit")]
+            Engine::Jit => Some(Backend::X86(Box::new(crate::jit::x86::CodeArena::new()))),
+            #[cfg(feature = "cranelift")]
+            Engine::Cranelift => crate::jit::cranelift::Compiler::new().map(|c| Backend::Cranelift(Box::new(c))),
+            _ => None,
+        };
+    }
+
+    fn insert(&mut self, pc: u64, block: Block) -> usize {
+        self.blocks.push(block);
+        self.by_pc.insert(pc, self.blocks.len() - 1);
+        self.blocks.len() - 1
+    }
+
+    /// Block that followed block `from` at `pc` before
+    fn linked(&self, from: usize, pc: u64) -> Option<usize> {
+        self.blocks[from].links.iter().flatten()
+            .find(|&&(start, _)| start == pc)
+            .map(|&(_, to)| to)
+    }
+
+    /// Links block `from` to block `to` starting at `pc`, replacing the
+    /// second link once both are taken
+    fn link(&mut self, from: usize, pc: u64, to: usize) {
+        let links = &mut self.blocks[from].links;
+        let slot = if links[0].is_none() { 0 } else { 1 };
+        links[slot] = Some((pc, to));
+    }
+}
+
+impl Machine {
+    /// Runs until the machine stops, returning why
+    pub fn run(&mut self, engine: Engine) -> VmExit {
+        match engine {
+            Engine::Step => loop {
+                if let Err(e) = self.step() {
+                    return e;
+                }
+            },
+            _ => {
+                #[cfg(feature = "jit")]
+                self.blocks.set_engine(engine);
+                self.run_blocks()
+            },
+        }
+    }
+
+    /// Runs block by block until the machine stops. This has the same
+    /// effect as calling `step` until it fails, except that devices see
+    /// the steps of a block at once, after it ran.
+    pub fn run_blocks(&mut self) -> VmExit {
+        let mut steps = 1;
+        // Block that ran last, as long as the cache was not flushed since
+        let mut prev: Option<(u64, usize)> = None;
+        loop {
+            match self.poll_interrupts(steps) {
+                Ok(false) => {},
+                Ok(true) => {
+                    steps = 1;
+                    prev = None;
+                    continue;
+                },
+                Err(e) => return e,
+            }
+            self.drop_written_code();
+
+            let generation = self.blocks.generation;
+            let pc = self.get_r(Register::Pc);
+            let prev_index = prev.filter(|&(g, _)| g == generation).map(|(_, i)| i);
+            let index = match prev_index.and_then(|p| self.blocks.linked(p, pc)) {
+                Some(i) => i,
+                None => {
+                    let i = match self.blocks.by_pc.get(&pc) {
+                        Some(&i) => i,
+                        None => match self.translate_block(pc) {
+                            Ok(i) => i,
+                            Err(e) => {
+                                if let Err(e) = self.raise(e, 0) {
+                                    return e;
+                                }
+                                steps = 1;
+                                prev = None;
+                                continue;
+                            },
+                        },
+                    };
+                    if let Some(p) = prev_index {
+                        self.blocks.link(p, pc, i);
+                    }
+                    i
+                },
+            };
+
+            steps = match self.run_block(index) {
+                Ok(n) => n,
+                Err(e) => return e,
+            };
+            prev = Some((generation, index));
+        }
+    }
+
+    /// Decodes the block starting at `pc`. Only a fault fetching its first
+    /// instruction is returned, later ones end the block and are raised
+    /// once execution gets there.
+    fn translate_block(&mut self, pc: u64) -> Result<usize, VmExit> {
+        let mut insts = Vec::new();
+        let mut addr = VirtAddr(pc as usize);
+        while insts.len() < MAX_BLOCK_LEN {
+            let d = match self.fetch(addr) {
+                Ok(d) => d,
+                Err(e) if insts.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            // Writes to any page the instruction was fetched from drop it
+            for &byte in &[addr.0, addr.0 + d.len - 1] {
+                let paddr = self.mmu.translate(self.vaddr(byte as u64), Access::Execute)?;
+                self.mmu.watch_code_page(paddr.0 / PAGE_SIZE);
+            }
+            insts.push(d);
+            if d.inst.ends_block() {
+                break;
+            }
+            addr = self.vaddr((addr.0 + d.len) as u64);
+        }
+        let block = Block {
+            #[cfg(feature = "jit")]
+            native: match &mut self.blocks.backend {
+                Some(backend) => backend.compile(&insts, pc, &self.isa),
+                None => None,
+            },
+            insts,
+            links: [None; 2],
+        };
+        Ok(self.blocks.insert(pc, block))
+    }
+
+    /// Runs block `index` from its start at the PC, returning the number of
+    /// steps taken. It is left early when an instruction does not continue
+    /// with the next one or invalidates the block.
+    fn run_block(&mut self, index: usize) -> Result<u64, VmExit> {
+        #[cfg(feature = "jit")]
+        if let Some(native) = &self.blocks.blocks[index].native {
+            let (done, bail) = self.run_native(native.entry())?;
+            if !bail {
+                return Ok(done);
+            }
+            // Faults and instructions without native code are left to the
+            // interpreter, which runs the instruction the code stopped at
+            let d = self.blocks.blocks[index].insts[done as usize];
+            self.execute(self.get_pc(), &d)?;
+            return Ok(done + 1);
+        }
+
+        let generation = self.blocks.generation;
+        let len = self.blocks.blocks[index].insts.len();
+        let mut pc = self.get_pc();
+        for n in 0..len {
+            let d = self.blocks.blocks[index].insts[n];
+            self.execute(pc, &d)?;
+
+            let next = self.vaddr((pc.0 + d.len) as u64);
+            if self.get_pc() != next || self.blocks.generation != generation || self.mmu.code_written() {
+                return Ok(n as u64 + 1);
+            }
+            pc = next;
+        }
+        Ok(len as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::riscv::csr;
+    use crate::riscv::register::Register::*;
+    use crate::test_util::{machine, ENGINES};
+
+    #[test]
+    fn test_engines_agree() {
+        let prog = [
+            0x00a00513, // li a0, 10
+            0x00000593, // li a1, 0
+            0x00a585b3, // add a1, a1, a0
+            0xfff50513, // addi a0, a0, -1
+            0xfe051ce3, // bnez a0, -8
+            0x00058513, // mv a0, a1
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+        ];
+        let mut step = machine(&prog);
+        let mut block = machine(&prog);
+
+        assert_eq!(step.run(Engine::Step), VmExit::Exit(55));
+        assert_eq!(block.run(Engine::Block), VmExit::Exit(55));
+        assert_eq!(block.get_r(A1), 55);
+        assert_eq!(block.get_pc(), step.get_pc());
+        assert_eq!(block.read_csr(csr::MINSTRET), step.read_csr(csr::MINSTRET));
+    }
+
+    #[test]
+    fn test_code_writes() {
+        // The store replaces the nop after it, in the same block
+        for &engine in ENGINES {
+            let mut m = machine(&[
+                0x00100513, // li a0, 1
+                0x00b02623, // sw a1, 12(zero)
+                0x00000013, // nop
+                0x00000013, // nop, replaced by li a7, 93
+                0x00000073, // ecall
+            ]);
+            m.set_r(A1, 0x05d00893).unwrap();
+            assert_eq!(m.run(engine), VmExit::Exit(1), "{:?}", engine);
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        // Increments a counter in memory and exits with it, so every run
+        // from the snapshot exits with 1
+        let mut m = machine(&[
+            0x40003503, // ld a0, 1024(zero)
+            0x00150513, // addi a0, a0, 1
+            0x40a03023, // sd a0, 1024(zero)
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+        ]);
+        m.snapshot();
+        for &engine in ENGINES {
+            assert_eq!(m.run(engine), VmExit::Exit(1));
+            assert_eq!(m.read_csr(csr::MINSTRET), Ok(4));
+            assert!(m.reset());
+            assert_eq!(m.get_pc(), VirtAddr(0));
+        }
+        assert_eq!(m.get_r(A0), 0);
+    }
+
+    #[test]
+    fn test_fork() {
+        // Same program as in test_reset, run by forks on several threads
+        let mut m = machine(&[
+            0x40003503, // ld a0, 1024(zero)
+            0x00150513, // addi a0, a0, 1
+            0x40a03023, // sd a0, 1024(zero)
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+        ]);
+        let forks: Vec<Machine> = (0..4).map(|_| m.fork()).collect();
+        let workers: Vec<_> = forks.into_iter()
+            .map(|mut f| std::thread::spawn(move || f.run(Engine::Block)))
+            .collect();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), VmExit::Exit(1));
+        }
+        assert_eq!(m.mmu.read_u64(VirtAddr(1024)), Ok(0));
+        assert_eq!(m.run(Engine::Block), VmExit::Exit(1));
+    }
+}
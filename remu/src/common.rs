@@ -1,30 +1,218 @@
-use crate::mmu::{Mmu, VirtAddr};
-use crate::riscv::instruction::parse_instruction;
-use crate::riscv::register::Register;
+use std::collections::HashMap;
+
+use crate::mmu::{Access, Mmu, Paging, PagingMode, PhysAddr, VirtAddr, PAGE_SIZE};
+use crate::riscv::instruction::{parse_instruction, Inst};
+use crate::riscv::compressed::{expand_compressed, instruction_length};
+use crate::riscv::register::{Register, FRegister, Xlen};
+use crate::riscv::float::RoundingMode;
+use crate::riscv::vector::{VectorRegisters, DEFAULT_VLEN, VTYPE_VILL};
+use crate::riscv::csr::{self, CsrFile, Privilege};
+use crate::riscv::isa::{Extension, Isa};
+use crate::riscv::trap::Exception;
+use crate::syscall::handle_syscall;
+use crate::block::BlockCache;
+use crate::devices::clint::{Clint, CLINT_BASE, CLINT_SIZE};
+use crate::devices::plic::{Plic, PLIC_BASE, PLIC_SIZE};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum VmExit {
     InvalidOpcode(u32),
-    NotImpl,
+    IllegalInstruction,
     Syscall,
+    Exit(i64),
+    /// Exception raised by an instruction, with the value reported in mtval
+    Exception(Exception, u64),
+    ReadFault(VirtAddr),
+    WriteFault(VirtAddr),
+    ExecFault(VirtAddr),
+    /// Read of memory that was allocated but never written
+    UninitFault(VirtAddr),
+}
+
+/// A decoded instruction together with its encoding as fetched
+#[derive(Clone, Copy)]
+pub(crate) struct Decoded {
+    pub inst: Inst,
+    pub bits: u32,
+    pub len: usize,
 }
 
+/// Decoded instructions of one physical page, by halfword offset
+type CodePage = Box<[Option<Decoded>]>;
+
 pub struct Machine {
     pub mmu : Mmu,
+    pub(crate) isa: Isa,
+    /// Integer registers, sign-extended from XLEN bits, and the PC,
+    /// zero-extended
+    registers: [u64; 33],
+    /// Address of the instruction following the one being executed
+    next_pc: u64,
+    fregisters: [u64; 32],
+    fcsr: u32,
+    pub vregs: VectorRegisters,
+    pub csrs: CsrFile,
+    privilege: Privilege,
+    /// Interrupt lines raised by devices, which read as set in mip on top
+    /// of the bits software writes
+    irq_lines: u64,
+    cycle: u64,
+    instret: u64,
+    /// Decoded instructions by physical page number. Pages are dropped
+    /// when they are written.
+    icache: HashMap<usize, CodePage>,
+    /// Basic blocks of the block engine
+    pub(crate) blocks: BlockCache,
+    /// Reservation set registered by the last LR, as an aligned granule.
+    /// Only the stores of this hart invalidate it.
+    reservation: Option<VirtAddr>,
+    /// Handle ECALL as a host syscall and hand exceptions back to the caller
+    /// of `step` instead of trapping into the guest
+    emulate_syscalls: bool,
+    /// Hart state restored by `reset`
+    snapshot: Option<Box<HartState>>,
+}
+
+/// Architectural state of a `Machine` outside of its `Mmu`
+#[derive(Clone)]
+struct HartState {
     registers: [u64; 33],
+    next_pc: u64,
+    fregisters: [u64; 32],
+    fcsr: u32,
+    vregs: VectorRegisters,
+    csrs: CsrFile,
+    privilege: Privilege,
+    irq_lines: u64,
+    cycle: u64,
+    instret: u64,
+    reservation: Option<VirtAddr>,
 }
 
+/// Size of the naturally aligned block covered by a LR reservation
+const RESERVATION_GRANULE: usize = 8;
+
+
 impl Machine {
+    /// Creates a machine with the XLEN of the loaded ELF file, RV64 by
+    /// default, and every implemented extension
     pub fn new(mmu : Mmu) -> Self {
+        let xlen = mmu.xlen.unwrap_or(Xlen::Rv64);
+        Machine::new_with_xlen(mmu, xlen)
+    }
+
+    pub fn new_with_xlen(mmu : Mmu, xlen : Xlen) -> Self {
+        Machine::new_with_isa(mmu, Isa::full(xlen))
+    }
+
+    /// Creates a machine implementing only the extensions in `isa`
+    pub fn new_with_isa(mut mmu : Mmu, isa : Isa) -> Self {
         let entry_point = mmu.entry_point.unwrap();
+        mmu.map_device(CLINT_BASE, CLINT_SIZE, Box::new(Clint::new()));
+        mmu.map_device(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new()));
+
         let mut r = Machine {
-            mmu : mmu,
+            mmu,
+            isa,
             registers : [0; 33],
+            next_pc : 0,
+            fregisters : [0; 32],
+            fcsr : 0,
+            vregs : VectorRegisters::new(DEFAULT_VLEN),
+            csrs : CsrFile::new(&isa),
+            privilege : Privilege::Machine,
+            irq_lines : 0,
+            cycle : 0,
+            instret : 0,
+            icache : HashMap::new(),
+            blocks : BlockCache::new(),
+            reservation : None,
+            emulate_syscalls : true,
+            snapshot : None,
         };
         r.registers[Register::Pc as usize] = entry_point.0 as u64;
+        // Vector state starts out enabled, as a kernel hands it to user
+        // programs. Firmware can switch it off through mstatus.VS.
+        if isa.has(Extension::V) {
+            let status = r.csrs.get(csr::MSTATUS).unwrap();
+            r.csrs.set(csr::MSTATUS, status | csr::MSTATUS_VS_INITIAL);
+        }
         r
     }
 
+    /// Saves the state of the machine and its memory, to be returned to by
+    /// `reset`. This is meant to be done once after loading and setting up
+    /// a program, so it can be run many times from the same start.
+    pub fn snapshot(&mut self) {
+        self.snapshot = Some(Box::new(HartState {
+            registers: self.registers,
+            next_pc: self.next_pc,
+            fregisters: self.fregisters,
+            fcsr: self.fcsr,
+            vregs: self.vregs.clone(),
+            csrs: self.csrs.clone(),
+            privilege: self.privilege,
+            irq_lines: self.irq_lines,
+            cycle: self.cycle,
+            instret: self.instret,
+            reservation: self.reservation,
+        }));
+        self.mmu.snapshot();
+    }
+
+    /// Returns to the state saved by `snapshot`. Only the memory written
+    /// since is copied back. Returns false without a snapshot.
+    pub fn reset(&mut self) -> bool {
+        let s = match &self.snapshot {
+            Some(s) => s.as_ref().clone(),
+            None => return false,
+        };
+        self.registers = s.registers;
+        self.next_pc = s.next_pc;
+        self.fregisters = s.fregisters;
+        self.fcsr = s.fcsr;
+        self.vregs = s.vregs;
+        self.csrs = s.csrs;
+        self.privilege = s.privilege;
+        self.irq_lines = s.irq_lines;
+        self.cycle = s.cycle;
+        self.instret = s.instret;
+        self.reservation = s.reservation;
+        let fetch_space = self.mmu.paging().fetch_space();
+        self.mmu.reset();
+        if self.mmu.paging().fetch_space() != fetch_space {
+            self.blocks.flush();
+        }
+        self.update_paging();
+        true
+    }
+
+    /// Copy of the machine, for running the same program on another
+    /// thread. Memory is shared with `self` and only copied page by page as
+    /// either of them writes it. The copy has no snapshot and decodes its
+    /// code again.
+    pub fn fork(&self) -> Machine {
+        Machine {
+            mmu: self.mmu.fork(),
+            isa: self.isa,
+            registers: self.registers,
+            next_pc: self.next_pc,
+            fregisters: self.fregisters,
+            fcsr: self.fcsr,
+            vregs: self.vregs.clone(),
+            csrs: self.csrs.clone(),
+            privilege: self.privilege,
+            irq_lines: self.irq_lines,
+            cycle: self.cycle,
+            instret: self.instret,
+            icache: HashMap::new(),
+            blocks: BlockCache::new(),
+            reservation: self.reservation,
+            emulate_syscalls: self.emulate_syscalls,
+            snapshot: None,
+        }
+    }
+
     pub fn print_state(&self){
         print!("|");
         for i in 1..33 {
@@ -32,36 +220,699 @@ impl Machine {
             print!(" {:?}: {:} |", r, self.registers[i as usize] as i64);
 
             if i % 16 == 0 {
-                println!("");
+                println!();
             }
         }
     }
 
+    /// Integer registers followed by the PC, for native code
+    #[cfg(feature = "jit")]
+    pub(crate) fn registers_mut(&mut self) -> &mut [u64; 33] {
+        &mut self.registers
+    }
+
+    pub fn xlen(&self) -> Xlen {
+        self.isa.xlen
+    }
+
     pub fn get_r(&self, reg : Register) -> u64 {
         self.registers[reg as usize]
     }
 
+    /// Unsigned view of a register, its low XLEN bits
+    pub fn get_ru(&self, reg : Register) -> u64 {
+        self.isa.xlen.zext(self.get_r(reg))
+    }
+
+    /// Writes the low XLEN bits of `value` to a register
     pub fn set_r(&mut self, reg : Register, value : u64) -> Result<(), VmExit> {
-        if reg != Register::Zero {
-            self.registers[reg as usize] = value;
+        match reg {
+            Register::Zero => {},
+            Register::Pc => self.registers[reg as usize] = self.isa.xlen.zext(value),
+            _ => self.registers[reg as usize] = self.isa.xlen.sext(value),
         }
         Ok(())
     }
 
+    /// Virtual address an address computed in a register refers to
+    pub fn vaddr(&self, addr : u64) -> VirtAddr {
+        VirtAddr(self.isa.xlen.zext(addr) as usize)
+    }
+
+    /// Raw bits of a floating-point register
+    pub fn get_f(&self, reg : FRegister) -> u64 {
+        self.fregisters[reg as usize]
+    }
+
+    pub fn set_f(&mut self, reg : FRegister, value : u64) -> Result<(), VmExit> {
+        self.fregisters[reg as usize] = value;
+        Ok(())
+    }
+
+    /// Reads a single-precision value, treating anything that is not a
+    /// properly NaN-boxed value as the canonical NaN.
+    pub fn get_f32(&self, reg : FRegister) -> f32 {
+        let bits = self.get_f(reg);
+        if bits >> 32 == 0xffff_ffff {
+            f32::from_bits(bits as u32)
+        } else {
+            f32::from_bits(0x7fc0_0000)
+        }
+    }
+
+    pub fn set_f32(&mut self, reg : FRegister, value : f32) -> Result<(), VmExit> {
+        self.set_f(reg, 0xffff_ffff_0000_0000 | value.to_bits() as u64)
+    }
+
+    pub fn get_f64(&self, reg : FRegister) -> f64 {
+        f64::from_bits(self.get_f(reg))
+    }
+
+    pub fn set_f64(&mut self, reg : FRegister, value : f64) -> Result<(), VmExit> {
+        self.set_f(reg, value.to_bits())
+    }
+
+    pub fn get_fcsr(&self) -> u32 {
+        self.fcsr
+    }
+
+    pub fn set_fcsr(&mut self, value : u32) {
+        self.fcsr = value & 0xff;
+    }
+
+    /// Accrues floating-point exception flags into fflags
+    pub fn set_fflags(&mut self, flags : u32) {
+        self.fcsr |= flags & 0x1f;
+    }
+
+    /// Resolves the rm field of an instruction, where 0b111 selects the
+    /// dynamic rounding mode in frm.
+    pub fn rounding_mode(&self, rm : u32) -> Result<RoundingMode, VmExit> {
+        let rm = if rm == 0b111 { self.fcsr >> 5 } else { rm };
+        RoundingMode::from_bits(rm).ok_or(VmExit::IllegalInstruction)
+    }
+
+    pub fn get_privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    #[allow(dead_code)]
+    pub fn set_privilege(&mut self, privilege : Privilege) {
+        self.privilege = privilege;
+        self.update_paging();
+    }
+
+    /// Hands the translation state selected by satp, mstatus and the
+    /// privilege level to the MMU. Called whenever one of them changes.
+    fn update_paging(&mut self) {
+        let satp = self.csrs.get(csr::SATP).unwrap();
+        let status = self.csrs.get(csr::MSTATUS).unwrap();
+
+        // With MPRV, M-mode loads and stores use the privilege level in MPP
+        let data_privilege = if self.privilege == Privilege::Machine && status & csr::MSTATUS_MPRV != 0 {
+            Privilege::from_bits((status & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT).unwrap()
+        } else {
+            self.privilege
+        };
+
+        let (root, asid) = match self.isa.xlen {
+            Xlen::Rv32 => (satp & ((1 << 22) - 1), (satp >> 22) & 0x1ff),
+            Xlen::Rv64 => (satp & ((1 << 44) - 1), (satp >> 44) & 0xffff),
+        };
+        let paging = Paging {
+            mode: PagingMode::from_satp(satp, self.isa.xlen).unwrap(),
+            root: PhysAddr((root as usize) << 12),
+            asid: asid as u16,
+            fetch_privilege: self.privilege,
+            data_privilege,
+            sum: status & csr::MSTATUS_SUM != 0,
+            mxr: status & csr::MSTATUS_MXR != 0,
+        };
+        // Blocks are found by virtual address, and stay valid as long as
+        // instruction fetches are translated the same way
+        if paging.fetch_space() != self.mmu.paging().fetch_space() {
+            self.blocks.flush();
+        }
+        self.mmu.set_paging(paging);
+    }
+
+    /// Selects between running a program on top of emulated host syscalls
+    /// (the default) and running bare-metal code that handles its own traps
+    pub fn set_emulate_syscalls(&mut self, emulate : bool) {
+        self.emulate_syscalls = emulate;
+    }
+
+    /// Replaces the vector registers with ones of `vlen` bits
+    pub fn set_vlen(&mut self, vlen : usize) {
+        self.vregs = VectorRegisters::new(vlen);
+    }
+
+    /// Value of the time CSR, which mirrors mtime in the CLINT
+    pub fn get_time(&self) -> u64 {
+        self.mmu.device::<Clint>().map_or(0, |c| c.mtime())
+    }
+
+    /// Checks whether `csr` is accessible from the current privilege level
+    fn check_csr(&self, csr : u16, write : bool) -> Result<(), VmExit> {
+        if csr::csr_privilege(csr) > self.privilege as u64 ||
+            (write && csr::csr_read_only(csr)) {
+            return Err(VmExit::IllegalInstruction);
+        }
+
+        // The floating-point and vector CSRs only exist with their extension
+        let ext = match csr {
+            csr::FFLAGS..=csr::FCSR => Some(Extension::F),
+            csr::VSTART..=csr::VCSR | csr::VL..=csr::VLENB => Some(Extension::V),
+            _ => None,
+        };
+        if ext.is_some_and(|e| !self.isa.has(e)) {
+            return Err(VmExit::IllegalInstruction);
+        }
+        if ext == Some(Extension::V) {
+            self.check_vector_enabled()?;
+        }
+
+        // The upper halves of the counters only exist in RV32
+        let high = (csr::CYCLEH..=csr::HPMCOUNTER31H).contains(&csr) ||
+            csr == csr::MCYCLEH || csr == csr::MINSTRETH;
+        if high && self.isa.xlen != Xlen::Rv32 {
+            return Err(VmExit::IllegalInstruction);
+        }
+
+        // Lower privilege levels need the counter enabled in mcounteren, and
+        // in scounteren for U-mode
+        let counter = if (csr::CYCLE..=csr::HPMCOUNTER31).contains(&csr) {
+            Some(csr - csr::CYCLE)
+        } else if (csr::CYCLEH..=csr::HPMCOUNTER31H).contains(&csr) {
+            Some(csr - csr::CYCLEH)
+        } else {
+            None
+        };
+        if let Some(n) = counter.filter(|_| self.privilege < Privilege::Machine) {
+            let bit = 1 << n;
+            let mut enabled = self.csrs.get(csr::MCOUNTEREN).unwrap();
+            if self.privilege == Privilege::User {
+                enabled &= self.csrs.get(csr::SCOUNTEREN).unwrap();
+            }
+            if enabled & bit == 0 {
+                return Err(VmExit::IllegalInstruction);
+            }
+        }
+
+        // TVM traps S-mode accesses to satp
+        if csr == csr::SATP && self.privilege == Privilege::Supervisor &&
+            self.csrs.get(csr::MSTATUS).unwrap() & csr::MSTATUS_TVM != 0 {
+            return Err(VmExit::IllegalInstruction);
+        }
+        Ok(())
+    }
+
+    /// Pending interrupts, from software and from devices
+    fn get_mip(&self) -> u64 {
+        self.csrs.get(csr::MIP).unwrap() | self.irq_lines
+    }
+
+    pub fn read_csr(&self, csr : u16) -> Result<u64, VmExit> {
+        self.check_csr(csr, false)?;
+
+        match csr {
+            csr::FFLAGS => Ok((self.get_fcsr() & 0x1f) as u64),
+            csr::FRM => Ok((self.get_fcsr() >> 5) as u64),
+            csr::FCSR => Ok(self.get_fcsr() as u64),
+            csr::VSTART => Ok(self.vregs.vstart as u64),
+            csr::VXSAT => Ok(self.vregs.vxsat as u64),
+            csr::VXRM => Ok(self.vregs.vxrm),
+            csr::VCSR => Ok((self.vregs.vxrm << 1) | self.vregs.vxsat as u64),
+            csr::VL => Ok(self.vregs.vl as u64),
+            // vill is bit XLEN-1 of vtype
+            csr::VTYPE if self.vregs.vtype == VTYPE_VILL => Ok(self.isa.xlen.sext(1 << (self.isa.xlen.bits() - 1))),
+            csr::VTYPE => Ok(self.vregs.vtype),
+            csr::VLENB => Ok(self.vregs.vlenb() as u64),
+            csr::CYCLE | csr::MCYCLE => Ok(self.cycle),
+            csr::TIME => Ok(self.get_time()),
+            csr::INSTRET | csr::MINSTRET => Ok(self.instret),
+            csr::HPMCOUNTER3..=csr::HPMCOUNTER31 => Ok(0),
+            csr::CYCLEH | csr::MCYCLEH => Ok(self.cycle >> 32),
+            csr::TIMEH => Ok(self.get_time() >> 32),
+            csr::INSTRETH | csr::MINSTRETH => Ok(self.instret >> 32),
+            csr::HPMCOUNTER3H..=csr::HPMCOUNTER31H => Ok(0),
+            // Restricted views of the machine-level registers
+            csr::SSTATUS => Ok(self.csrs.get(csr::MSTATUS).unwrap() & csr::SSTATUS_MASK),
+            csr::SIE => Ok(self.csrs.get(csr::MIE).unwrap() & self.csrs.get(csr::MIDELEG).unwrap()),
+            csr::MIP => Ok(self.get_mip()),
+            csr::SIP => Ok(self.get_mip() & self.csrs.get(csr::MIDELEG).unwrap()),
+            _ => self.csrs.get(csr).ok_or(VmExit::IllegalInstruction),
+        }
+    }
+
+    pub fn write_csr(&mut self, csr : u16, value : u64) -> Result<(), VmExit> {
+        self.check_csr(csr, true)?;
+
+        match csr {
+            csr::FFLAGS => self.set_fcsr((self.fcsr & !0x1f) | (value as u32 & 0x1f)),
+            csr::FRM => self.set_fcsr((self.fcsr & 0x1f) | ((value as u32 & 0b111) << 5)),
+            csr::FCSR => self.set_fcsr(value as u32),
+            csr::VSTART => self.vregs.vstart = value as usize & (self.vregs.vlen() - 1),
+            csr::VXSAT => self.vregs.vxsat = value & 1 != 0,
+            csr::VXRM => self.vregs.vxrm = value & 0b11,
+            csr::VCSR => {
+                self.vregs.vxsat = value & 1 != 0;
+                self.vregs.vxrm = (value >> 1) & 0b11;
+            },
+            csr::MCYCLE => self.cycle = self.write_low(self.cycle, value),
+            csr::MINSTRET => self.instret = self.write_low(self.instret, value),
+            csr::MCYCLEH => self.cycle = (self.cycle as u32 as u64) | (value << 32),
+            csr::MINSTRETH => self.instret = (self.instret as u32 as u64) | (value << 32),
+            csr::MSTATUS => self.write_mstatus(value, u64::MAX),
+            csr::SSTATUS => self.write_mstatus(value, csr::SSTATUS_MASK),
+            csr::SIE => self.write_masked(csr::MIE, value, self.csrs.get(csr::MIDELEG).unwrap()),
+            csr::SIP => self.write_masked(csr::MIP, value, self.csrs.get(csr::MIDELEG).unwrap() & csr::MIP_SSIP),
+            csr::SATP => {
+                // Writes selecting an unsupported mode have no effect
+                if PagingMode::from_satp(value, self.isa.xlen).is_some() {
+                    self.csrs.set(csr::SATP, value);
+                }
+            },
+            _ => {
+                if !self.csrs.set(csr, value) {
+                    return Err(VmExit::IllegalInstruction);
+                }
+            },
+        }
+        if matches!(csr, csr::SATP | csr::MSTATUS | csr::SSTATUS) {
+            self.update_paging();
+        }
+        if (csr::VSTART..=csr::VCSR).contains(&csr) {
+            self.mark_vector_dirty();
+        }
+        Ok(())
+    }
+
+    /// Vector instructions and CSRs are illegal while mstatus.VS is Off
+    pub(crate) fn check_vector_enabled(&self) -> Result<(), VmExit> {
+        if self.csrs.get(csr::MSTATUS).unwrap() & csr::MSTATUS_VS == 0 {
+            return Err(VmExit::IllegalInstruction);
+        }
+        Ok(())
+    }
+
+    /// Sets mstatus.VS to Dirty once the vector state may have changed
+    pub(crate) fn mark_vector_dirty(&mut self) {
+        let status = self.csrs.get(csr::MSTATUS).unwrap();
+        if status & csr::MSTATUS_VS != csr::MSTATUS_VS_DIRTY {
+            self.csrs.set(csr::MSTATUS, status | csr::MSTATUS_VS_DIRTY);
+        }
+    }
+
+    /// Replaces the low XLEN bits of a 64-bit counter, RV32 writes the upper
+    /// half through the xH CSR
+    fn write_low(&self, counter : u64, value : u64) -> u64 {
+        match self.isa.xlen {
+            Xlen::Rv32 => (counter & !0xffff_ffff) | (value as u32 as u64),
+            Xlen::Rv64 => value,
+        }
+    }
+
+    /// Writes the bits of `value` selected by `mask` into a CSR
+    fn write_masked(&mut self, csr : u16, value : u64, mask : u64) {
+        let old = self.csrs.get(csr).unwrap();
+        self.csrs.set(csr, (old & !mask) | (value & mask));
+    }
+
+    fn write_mstatus(&mut self, value : u64, mask : u64) {
+        // MPP is WARL, writing the reserved mode keeps the old one
+        let old = self.csrs.get(csr::MSTATUS).unwrap();
+        let mut value = (old & !mask) | (value & mask);
+        if Privilege::from_bits((value & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT).is_none() {
+            value = (value & !csr::MSTATUS_MPP) | (old & csr::MSTATUS_MPP);
+        }
+        self.csrs.set(csr::MSTATUS, value);
+    }
+
     pub fn get_pc(&self) -> VirtAddr {
         VirtAddr(self.get_r(Register::Pc) as usize)
     }
 
+    /// Registers a reservation on the granule containing `addr`, replacing
+    /// any previously held reservation.
+    pub fn reserve(&mut self, addr: VirtAddr) {
+        self.reservation = Some(VirtAddr(addr.0 & !(RESERVATION_GRANULE - 1)));
+    }
+
+    /// Consumes the reservation, returning whether it was still valid for
+    /// `addr`. A store conditional always clears the reservation, whether it
+    /// succeeds or not.
+    pub fn take_reservation(&mut self, addr: VirtAddr) -> bool {
+        let granule = VirtAddr(addr.0 & !(RESERVATION_GRANULE - 1));
+        self.reservation.take() == Some(granule)
+    }
+
+    /// Invalidates the reservation if a store of `size` bytes at `addr`
+    /// overlaps it. Every store of this hart goes through here. A machine
+    /// has a single hart and forks do not share memory, so no other hart
+    /// can break the reservation; harts sharing memory would need the
+    /// reservations kept with the memory instead.
+    pub fn invalidate_reservation(&mut self, addr: VirtAddr, size: usize) {
+        if let Some(r) = self.reservation {
+            if addr.0 < r.0 + RESERVATION_GRANULE && r.0 < addr.0 + size {
+                self.reservation = None;
+            }
+        }
+    }
+
+    /// Address the PC advances to after the current instruction, unless it
+    /// jumps. This is also the link address for JAL and JALR.
+    pub fn get_next_pc(&self) -> u64 {
+        self.next_pc
+    }
+
+    /// Transfers control to `target` once the current instruction retires
+    pub fn jump(&mut self, target : u64) -> Result<(), VmExit> {
+        let target = self.isa.xlen.zext(target);
+        // Instructions are 2-byte aligned with the C extension, 4 otherwise
+        let ialign = if self.isa.has(Extension::C) { 2 } else { 4 };
+        if !target.is_multiple_of(ialign) {
+            return Err(VmExit::Exception(Exception::InstructionAddressMisaligned, target));
+        }
+        self.next_pc = target;
+        Ok(())
+    }
+
+    /// Enters the trap handler for `cause`. Traps from S-mode and U-mode
+    /// that are delegated in medeleg/mideleg go to the S-mode handler at
+    /// stvec, everything else to M-mode at mtvec. The trapping instruction is
+    /// saved in xepc and the previous privilege and interrupt enable in
+    /// mstatus, for xRET.
+    pub fn take_trap(&mut self, cause : u64, tval : u64) -> Result<(), VmExit> {
+        let interrupt = cause & csr::MCAUSE_INTERRUPT != 0;
+        let code = cause & !csr::MCAUSE_INTERRUPT;
+        let deleg = self.csrs.get(if interrupt { csr::MIDELEG } else { csr::MEDELEG }).unwrap();
+        let pc = self.get_r(Register::Pc);
+        // The interrupt bit is the top bit of xcause
+        let cause = if interrupt {
+            self.isa.xlen.sext((1 << (self.isa.xlen.bits() - 1)) | code)
+        } else {
+            code
+        };
+
+        let mut status = self.csrs.get(csr::MSTATUS).unwrap();
+        let tvec = if self.privilege <= Privilege::Supervisor && (deleg >> code) & 1 != 0 {
+            status &= !(csr::MSTATUS_SPIE | csr::MSTATUS_SPP);
+            if status & csr::MSTATUS_SIE != 0 {
+                status |= csr::MSTATUS_SPIE;
+            }
+            if self.privilege == Privilege::Supervisor {
+                status |= csr::MSTATUS_SPP;
+            }
+            status &= !csr::MSTATUS_SIE;
+
+            self.csrs.set(csr::SEPC, pc);
+            self.csrs.set(csr::SCAUSE, cause);
+            self.csrs.set(csr::STVAL, tval);
+            self.privilege = Privilege::Supervisor;
+            self.csrs.get(csr::STVEC).unwrap()
+        } else {
+            status &= !(csr::MSTATUS_MPIE | csr::MSTATUS_MPP);
+            if status & csr::MSTATUS_MIE != 0 {
+                status |= csr::MSTATUS_MPIE;
+            }
+            status &= !csr::MSTATUS_MIE;
+            status |= (self.privilege as u64) << csr::MSTATUS_MPP_SHIFT;
+
+            self.csrs.set(csr::MEPC, pc);
+            self.csrs.set(csr::MCAUSE, cause);
+            self.csrs.set(csr::MTVAL, tval);
+            self.privilege = Privilege::Machine;
+            self.csrs.get(csr::MTVEC).unwrap()
+        };
+        self.csrs.set(csr::MSTATUS, status);
+        self.update_paging();
+
+        // Vectored mode only applies to interrupts
+        let base = tvec & !0b11;
+        let target = if tvec & 0b11 == 1 && interrupt {
+            base.wrapping_add(4 * code)
+        } else {
+            base
+        };
+        self.set_r(Register::Pc, target)
+    }
+
+    /// Returns from an M-mode trap handler to mepc, restoring the privilege
+    /// level and interrupt enable saved by `take_trap`
+    pub fn mret(&mut self) -> Result<(), VmExit> {
+        if self.privilege != Privilege::Machine {
+            return Err(VmExit::IllegalInstruction);
+        }
+
+        let mut status = self.csrs.get(csr::MSTATUS).unwrap();
+        let mpp = Privilege::from_bits((status & csr::MSTATUS_MPP) >> csr::MSTATUS_MPP_SHIFT).unwrap();
+        status &= !(csr::MSTATUS_MIE | csr::MSTATUS_MPP);
+        if status & csr::MSTATUS_MPIE != 0 {
+            status |= csr::MSTATUS_MIE;
+        }
+        status |= csr::MSTATUS_MPIE;
+        if mpp != Privilege::Machine {
+            status &= !csr::MSTATUS_MPRV;
+        }
+
+        self.csrs.set(csr::MSTATUS, status);
+        self.privilege = mpp;
+        self.update_paging();
+        self.jump(self.csrs.get(csr::MEPC).unwrap())
+    }
+
+    /// Returns from an S-mode trap handler to sepc. TSR makes this illegal
+    /// in S-mode so M-mode can emulate it.
+    pub fn sret(&mut self) -> Result<(), VmExit> {
+        let mut status = self.csrs.get(csr::MSTATUS).unwrap();
+        if self.privilege < Privilege::Supervisor ||
+            (self.privilege == Privilege::Supervisor && status & csr::MSTATUS_TSR != 0) {
+            return Err(VmExit::IllegalInstruction);
+        }
+
+        let spp = if status & csr::MSTATUS_SPP != 0 { Privilege::Supervisor } else { Privilege::User };
+        status &= !(csr::MSTATUS_SIE | csr::MSTATUS_SPP | csr::MSTATUS_MPRV);
+        if status & csr::MSTATUS_SPIE != 0 {
+            status |= csr::MSTATUS_SIE;
+        }
+        status |= csr::MSTATUS_SPIE;
+
+        self.csrs.set(csr::MSTATUS, status);
+        self.privilege = spp;
+        self.update_paging();
+        self.jump(self.csrs.get(csr::SEPC).unwrap())
+    }
+
+    /// Flushes cached translations for the virtual address in `addr` and the
+    /// address space in `asid`, where `Zero` stands for all of them
+    pub fn sfence_vma(&mut self, addr : Register, asid : Register) -> Result<(), VmExit> {
+        let status = self.csrs.get(csr::MSTATUS).unwrap();
+        if self.privilege == Privilege::User ||
+            (self.privilege == Privilege::Supervisor && status & csr::MSTATUS_TVM != 0) {
+            return Err(VmExit::IllegalInstruction);
+        }
+
+        let addr = (addr != Register::Zero).then(|| self.vaddr(self.get_r(addr)));
+        let asid = (asid != Register::Zero).then(|| self.get_r(asid) as u16);
+        self.mmu.flush_tlb(addr, asid);
+        self.blocks.flush();
+        Ok(())
+    }
+
+    /// Highest priority interrupt that is pending, enabled in mie and not
+    /// masked at the current privilege level. Interrupts are enabled below
+    /// the privilege level they are taken in, and at the same level with
+    /// mstatus.xIE.
+    fn pending_interrupt(&self) -> Option<u64> {
+        let pending = self.get_mip() & self.csrs.get(csr::MIE).unwrap();
+        if pending == 0 {
+            return None;
+        }
+
+        let status = self.csrs.get(csr::MSTATUS).unwrap();
+        let deleg = self.csrs.get(csr::MIDELEG).unwrap();
+        let m_enabled = self.privilege < Privilege::Machine || status & csr::MSTATUS_MIE != 0;
+        let s_enabled = self.privilege < Privilege::Supervisor ||
+            (self.privilege == Privilege::Supervisor && status & csr::MSTATUS_SIE != 0);
+
+        let mut enabled = 0;
+        if m_enabled {
+            enabled |= pending & !deleg;
+        }
+        if s_enabled {
+            enabled |= pending & deleg;
+        }
+
+        // Interrupts taken in M-mode go first, then by external, software
+        // and timer interrupts
+        if enabled & !deleg != 0 {
+            enabled &= !deleg;
+        }
+        [11, 3, 7, 9, 1, 5].iter().copied().find(|&code| enabled & (1 << code) != 0)
+    }
+
+    /// Turns the error an instruction stopped with into a trap into the guest.
+    /// `bits` is the encoding of the instruction, reported for illegal
+    /// instructions. Errors the guest cannot handle, and everything when
+    /// syscalls are emulated, are passed on to the caller of `step`.
+    ///
+    /// The instruction does not retire, ECALL and EBREAK included: taking
+    /// the trap takes a cycle but leaves instret alone. Every engine
+    /// raises through here, so they count traps the same way. An ECALL
+    /// handled as a host syscall completes instead, and retires.
+    pub(crate) fn raise(&mut self, e : VmExit, bits : u32) -> Result<(), VmExit> {
+        if self.emulate_syscalls {
+            return Err(e);
+        }
+
+        let (exception, tval) = match e {
+            VmExit::InvalidOpcode(bits) => (Exception::IllegalInstruction, bits as u64),
+            VmExit::IllegalInstruction => (Exception::IllegalInstruction, bits as u64),
+            VmExit::Syscall => (Exception::ecall_from(self.privilege), 0),
+            VmExit::Exception(exception, tval) => (exception, tval),
+            VmExit::ReadFault(addr) | VmExit::UninitFault(addr) => (Exception::LoadAccessFault, addr.0 as u64),
+            VmExit::WriteFault(addr) => (Exception::StoreAccessFault, addr.0 as u64),
+            VmExit::ExecFault(addr) => (Exception::InstructionAccessFault, addr.0 as u64),
+            VmExit::Exit(_) => return Err(e),
+        };
+        self.take_trap(exception as u64, tval)?;
+        self.cycle = self.cycle.wrapping_add(1);
+        Ok(())
+    }
+
+    /// Drops all cached decoded instructions, so code written to memory since
+    /// is fetched again. This is what FENCE.I does.
+    pub fn flush_icache(&mut self) {
+        self.icache.clear();
+        self.blocks.flush();
+    }
+
+    /// Drops the decoded instructions and blocks of the pages written since
+    /// the last call, so modified code is decoded again
+    pub(crate) fn drop_written_code(&mut self) {
+        let pages = self.mmu.take_written_code();
+        if pages.is_empty() {
+            return;
+        }
+        for page in pages {
+            self.icache.remove(&page);
+        }
+        self.blocks.flush();
+    }
+
+    /// Fetches and decodes the instruction at `pc`, which may be compressed
+    pub(crate) fn fetch(&mut self, pc : VirtAddr) -> Result<Decoded, VmExit> {
+        let parcel = self.mmu.fetch_u16(pc)?;
+
+        let len = instruction_length(parcel);
+        let bits = if len == 2 {
+            parcel as u32
+        } else {
+            self.mmu.fetch_u32(pc)?
+        };
+        // Encodings that do not decode report the fetched bits, which is
+        // what mtval holds for them, also for compressed instructions
+        let invalid = move |e| match e {
+            VmExit::IllegalInstruction | VmExit::InvalidOpcode(_) => VmExit::InvalidOpcode(bits),
+            e => e,
+        };
+        let inst_u32 = if len == 2 {
+            if !self.isa.has(Extension::C) {
+                return Err(VmExit::InvalidOpcode(bits));
+            }
+            expand_compressed(parcel, self.isa.xlen).map_err(invalid)?
+        } else {
+            bits
+        };
+
+        Ok(Decoded {
+            inst: parse_instruction(inst_u32, &self.isa).map_err(invalid)?,
+            bits,
+            len,
+        })
+    }
+
+    /// Advances the devices by `steps` steps and takes the highest priority
+    /// pending interrupt, returning whether there was one
+    pub(crate) fn poll_interrupts(&mut self, steps : u64) -> Result<bool, VmExit> {
+        self.irq_lines = self.mmu.tick_devices(steps);
+        match self.pending_interrupt() {
+            Some(code) => {
+                self.take_trap(csr::MCAUSE_INTERRUPT | code, 0)?;
+                self.cycle = self.cycle.wrapping_add(1);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Executes `d`, fetched from `pc`, and moves on to the next
+    /// instruction, or into the trap handler if it raised an exception
+    pub(crate) fn execute(&mut self, pc : VirtAddr, d : &Decoded) -> Result<(), VmExit> {
+        self.next_pc = (pc.0 + d.len) as u64;
+
+        match d.inst.emulate(self) {
+            Ok(()) => {},
+            Err(VmExit::Syscall) if self.emulate_syscalls => handle_syscall(self)?,
+            Err(e) => return self.raise(e, d.bits),
+        }
+
+        self.set_r(Register::Pc, self.next_pc)?;
+        self.retire(1);
+        Ok(())
+    }
+
+    /// Counts `n` instructions as executed
+    pub(crate) fn retire(&mut self, n: u64) {
+        self.cycle = self.cycle.wrapping_add(n);
+        self.instret = self.instret.wrapping_add(n);
+    }
+
     pub fn step(&mut self) -> Result<(), VmExit> {
+        // Interrupts are taken between instructions
+        if self.poll_interrupts(1)? {
+            return Ok(());
+        }
+
         let pc = self.get_pc();
-        let inst_u32 = self.mmu.read_u32(pc);
 
-        let inst = parse_instruction(inst_u32)?;
-        println!("\t{:x}: {:08x}\t\t{:}", pc.0, inst_u32, inst.disassemble());
+        let paddr = match self.mmu.translate(pc, Access::Execute) {
+            Ok(p) => p,
+            Err(e) => return self.raise(e, 0),
+        };
+
+        self.drop_written_code();
 
-        inst.emulate(self)?;
+        let page = paddr.0 / PAGE_SIZE;
+        let slot = paddr.0 % PAGE_SIZE / 2;
+        let cached = self.icache.get(&page).and_then(|p| p[slot]);
+        let d = match cached {
+            Some(d) => d,
+            None => {
+                let d = match self.fetch(pc) {
+                    Ok(d) => d,
+                    Err(e) => return self.raise(e, 0),
+                };
+                // Instructions crossing a page are never cached, as writes
+                // to their second page would not drop them
+                if pc.0 % PAGE_SIZE + d.len <= PAGE_SIZE {
+                    if !self.icache.contains_key(&page) {
+                        self.mmu.watch_code_page(page);
+                    }
+                    self.icache.entry(page)
+                        .or_insert_with(|| vec![None; PAGE_SIZE / 2].into_boxed_slice())[slot] = Some(d);
+                }
+                d
+            },
+        };
 
-        self.set_r(Register::Pc, self.get_r(Register::Pc).wrapping_add(4))?;
+        if d.len == 2 {
+            println!("\t{:x}: {:04x}    \t\t{:}", pc.0, d.bits, d.inst.disassemble());
+        } else {
+            println!("\t{:x}: {:08x}\t\t{:}", pc.0, d.bits, d.inst.disassemble());
+        }
+        self.execute(pc, &d)?;
         self.print_state();
         Ok(())
     }
@@ -75,15 +926,13 @@ pub trait Emulate {
     fn emulate(&self, m : &mut Machine) -> Result<(), VmExit>;
 }
 
-pub trait Instruction : Disassemble + Emulate {}
-impl<T: Disassemble + Emulate> Instruction for T {}
-
 
 #[macro_export]
 macro_rules! instr {
     ($n:ident, $t:ident, $i:ident, $m:ident, $ev:expr) => {
-        struct $n {
-            i: $t,
+        #[derive(Clone, Copy)]
+        pub struct $n {
+            pub(crate) i: $t,
         }
 
         impl $n {
@@ -95,7 +944,7 @@ macro_rules! instr {
         impl Disassemble for $n {
             fn disassemble(&self) -> String {
                 let t = std::any::type_name::<$n>();
-                let t = t.split("::").last().unwrap().to_lowercase();
+                let t = t.split("::").last().unwrap().to_lowercase().replace('_', ".");
                 format!("{:} {:}", t, self.i.disassemble())
             }
         }
@@ -109,3 +958,42 @@ macro_rules! instr {
 
     };
 }
+
+/// Declares an enum with a variant for each instruction defined with
+/// `instr!`, plus variants wrapping other such enums, so decoded
+/// instructions are stored inline and dispatched without a vtable
+#[macro_export]
+macro_rules! instr_enum {
+    ($name:ident { $($n:ident),* $(,)? } $(+ $v:ident($t:ty))*) => {
+        #[derive(Clone, Copy)]
+        pub enum $name {
+            $($n($n),)*
+            $($v($t),)*
+        }
+
+        $(impl From<$n> for $name {
+            fn from(inst: $n) -> Self {
+                $name::$n(inst)
+            }
+        })*
+
+        impl Disassemble for $name {
+            fn disassemble(&self) -> String {
+                match self {
+                    $($name::$n(inst) => inst.disassemble(),)*
+                    $($name::$v(inst) => inst.disassemble(),)*
+                }
+            }
+        }
+
+        impl Emulate for $name {
+            #[inline]
+            fn emulate(&self, m : &mut Machine) -> Result<(), VmExit> {
+                match self {
+                    $($name::$n(inst) => inst.emulate(m),)*
+                    $($name::$v(inst) => inst.emulate(m),)*
+                }
+            }
+        }
+    };
+}
@@ -0,0 +1,120 @@
+// Core-local interruptor, in the layout used by SiFive and QEMU's virt
+// machine, for a single hart: the software interrupt pending bit msip and
+// the machine timer mtime with its compare register mtimecmp.
+
+use crate::devices::{Device, field};
+use crate::mmu::PhysAddr;
+use crate::riscv::csr;
+
+pub const CLINT_BASE: PhysAddr = PhysAddr(0x0200_0000);
+pub const CLINT_SIZE: usize = 0x10000;
+
+const MSIP: usize = 0x0000;
+const MTIMECMP: usize = 0x4000;
+const MTIME: usize = 0xbff8;
+
+#[derive(Clone)]
+pub struct Clint {
+    msip: u32,
+    mtimecmp: u64,
+    mtime: u64,
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        Clint {
+            msip: 0,
+            // No timer interrupt until software programs one
+            mtimecmp: u64::MAX,
+            mtime: 0,
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Register containing `offset`, with its width and the offset into it
+    fn register(&mut self, offset: usize, size: usize) -> Option<(&mut u64, usize)> {
+        // Registers are accessed with naturally aligned 32 or 64-bit accesses
+        if (size != 4 && size != 8) || !offset.is_multiple_of(size) {
+            return None;
+        }
+        match offset & !0b111 {
+            MTIMECMP => Some((&mut self.mtimecmp, offset - MTIMECMP)),
+            MTIME => Some((&mut self.mtime, offset - MTIME)),
+            _ => None,
+        }
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        if offset == MSIP && size == 4 {
+            return Some(self.msip as u64);
+        }
+        let (reg, off) = self.register(offset, size)?;
+        let (mask, shift) = field(off, size);
+        Some((*reg >> shift) & mask)
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        if offset == MSIP && size == 4 {
+            self.msip = value as u32 & 1;
+            return true;
+        }
+        match self.register(offset, size) {
+            Some((reg, off)) => {
+                let (mask, shift) = field(off, size);
+                *reg = (*reg & !(mask << shift)) | ((value & mask) << shift);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn tick(&mut self, steps: u64) {
+        self.mtime = self.mtime.wrapping_add(steps);
+    }
+
+    fn interrupts(&self) -> u64 {
+        let mut lines = 0;
+        if self.msip != 0 {
+            lines |= csr::MIP_MSIP;
+        }
+        if self.mtime >= self.mtimecmp {
+            lines |= csr::MIP_MTIP;
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clint() {
+        let mut c = Clint::new();
+        assert_eq!(c.interrupts(), 0);
+
+        assert!(c.write(MTIMECMP, 4, 2));
+        assert!(c.write(MTIMECMP + 4, 4, 0));
+        assert_eq!(c.read(MTIMECMP, 8), Some(2));
+        assert!(!c.write(MTIMECMP + 2, 4, 0));
+        assert_eq!(c.read(0x100, 4), None);
+
+        c.tick(1);
+        assert_eq!(c.interrupts(), 0);
+        c.tick(1);
+        assert_eq!(c.interrupts(), csr::MIP_MTIP);
+        assert_eq!(c.read(MTIME, 4), Some(2));
+
+        assert!(c.write(MTIME + 4, 4, 1));
+        assert_eq!(c.mtime(), (1 << 32) | 2);
+
+        assert!(c.write(MSIP, 4, 0xff));
+        assert_eq!(c.read(MSIP, 4), Some(1));
+        assert_eq!(c.interrupts(), csr::MIP_MSIP | csr::MIP_MTIP);
+    }
+}
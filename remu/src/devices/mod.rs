@@ -0,0 +1,65 @@
+// Memory-mapped devices. Devices are mapped into the physical address space
+// by the `Mmu`, which routes the loads and stores that hit them here, and
+// are ticked by `Machine` once per step to drive their interrupt lines.
+
+use std::any::Any;
+
+pub mod clint;
+pub mod plic;
+
+/// A device, which is `Send` so machines can be run on other threads, and
+/// `Clone` so they can be snapshotted and forked
+pub trait Device: Any + Send + DeviceClone {
+    /// Reads a register of `size` bytes at `offset`, or returns None if there
+    /// is none, in which case the access faults
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64>;
+
+    /// Writes a register of `size` bytes at `offset`, returning false if
+    /// there is none
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool;
+
+    /// Advances the device by `steps` steps of the hart
+    fn tick(&mut self, _steps: u64) {}
+
+    /// Interrupt lines currently raised by the device, as mip bits
+    fn interrupts(&self) -> u64 {
+        0
+    }
+
+    /// Level of the line the device drives into the interrupt controller,
+    /// for devices mapped with an interrupt source
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Sets the level of interrupt source `source`, for interrupt controllers
+    fn set_source(&mut self, _source: u32, _level: bool) {}
+}
+
+/// Copies of a device, implemented for every `Device` that is `Clone`
+pub trait DeviceClone {
+    /// Copy of the device in its current state, for snapshots and forks
+    fn clone_box(&self) -> Box<dyn Device>;
+
+    /// Returns to the state of `from`, a copy of this device, in place
+    fn restore(&mut self, from: &dyn Device);
+}
+
+impl<T: Device + Clone> DeviceClone for T {
+    fn clone_box(&self) -> Box<dyn Device> {
+        Box::new(self.clone())
+    }
+
+    fn restore(&mut self, from: &dyn Device) {
+        let from = (from as &dyn Any).downcast_ref::<T>()
+            .expect("device restored from another type of device");
+        self.clone_from(from);
+    }
+}
+
+/// Bits of a little-endian register touched by an access of `size` bytes at
+/// byte `offset` into it, as a mask and a shift
+pub fn field(offset: usize, size: usize) -> (u64, u32) {
+    let mask = if size >= 8 { u64::MAX } else { (1 << (size * 8)) - 1 };
+    (mask, offset as u32 * 8)
+}
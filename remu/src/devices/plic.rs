@@ -0,0 +1,230 @@
+// Platform-level interrupt controller, in the layout used by SiFive and
+// QEMU's virt machine. Context 0 is M-mode and context 1 S-mode of the only
+// hart. Sources are level triggered: a source is pending while its line is
+// high, until it is claimed, and can become pending again once the claim is
+// completed.
+
+use crate::devices::Device;
+use crate::mmu::PhysAddr;
+use crate::riscv::csr;
+
+pub const PLIC_BASE: PhysAddr = PhysAddr(0x0c00_0000);
+pub const PLIC_SIZE: usize = 0x400_0000;
+
+/// Number of interrupt sources, source 0 does not exist
+const SOURCES: usize = 1024;
+const WORDS: usize = SOURCES / 32;
+const CONTEXTS: usize = 2;
+
+/// Implemented priority bits, priorities range from 0 (never) to 7
+const PRIORITY_MASK: u32 = 0x7;
+
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+#[derive(Clone)]
+pub struct Plic {
+    priority: [u32; SOURCES],
+    level: [u32; WORDS],
+    pending: [u32; WORDS],
+    /// Claimed sources that have not been completed yet
+    claimed: [u32; WORDS],
+    enable: [[u32; WORDS]; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+}
+
+fn bit(bits: &[u32; WORDS], source: usize) -> bool {
+    bits[source / 32] & (1 << (source % 32)) != 0
+}
+
+fn set_bit(bits: &mut [u32; WORDS], source: usize, value: bool) {
+    if value {
+        bits[source / 32] |= 1 << (source % 32);
+    } else {
+        bits[source / 32] &= !(1 << (source % 32));
+    }
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Plic {
+            priority: [0; SOURCES],
+            level: [0; WORDS],
+            pending: [0; WORDS],
+            claimed: [0; WORDS],
+            enable: [[0; WORDS]; CONTEXTS],
+            threshold: [0; CONTEXTS],
+        }
+    }
+
+    /// Re-evaluates the pending bit of `source` after its line or claim
+    /// changed
+    fn update(&mut self, source: usize) {
+        let pending = bit(&self.level, source) && !bit(&self.claimed, source);
+        set_bit(&mut self.pending, source, pending);
+    }
+
+    /// Pending and enabled source of `context` with the highest priority
+    /// above the threshold, the lowest numbered one among equals
+    fn best(&self, context: usize) -> Option<usize> {
+        let mut best = None;
+        let mut max = self.threshold[context];
+        for word in 0..WORDS {
+            let mut bits = self.pending[word] & self.enable[context][word];
+            while bits != 0 {
+                let source = word * 32 + bits.trailing_zeros() as usize;
+                if self.priority[source] > max {
+                    max = self.priority[source];
+                    best = Some(source);
+                }
+                bits &= bits - 1;
+            }
+        }
+        best
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best(context) {
+            Some(source) => {
+                set_bit(&mut self.claimed, source, true);
+                self.update(source);
+                source as u32
+            },
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: usize) {
+        // Completions for sources the context cannot claim are ignored
+        if source < SOURCES && bit(&self.enable[context], source) {
+            set_bit(&mut self.claimed, source, false);
+            self.update(source);
+        }
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: usize, size: usize) -> Option<u64> {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return None;
+        }
+
+        let value = match offset {
+            0..PENDING => self.priority[offset / 4],
+            PENDING..ENABLE => *self.pending.get((offset - PENDING) / 4).unwrap_or(&0),
+            ENABLE..CONTEXT => {
+                let context = (offset - ENABLE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE) % ENABLE_STRIDE / 4;
+                self.enable.get(context).map_or(0, |e| e[word])
+            },
+            _ => {
+                let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+                match (context < CONTEXTS, (offset - CONTEXT) % CONTEXT_STRIDE) {
+                    (true, 0) => self.threshold[context],
+                    (true, 4) => self.claim(context),
+                    _ => 0,
+                }
+            },
+        };
+        Some(value as u64)
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u64) -> bool {
+        if size != 4 || !offset.is_multiple_of(4) {
+            return false;
+        }
+
+        let value = value as u32;
+        match offset {
+            // Source 0 does not exist
+            4..PENDING => self.priority[offset / 4] = value & PRIORITY_MASK,
+            ENABLE..CONTEXT => {
+                let context = (offset - ENABLE) / ENABLE_STRIDE;
+                let word = (offset - ENABLE) % ENABLE_STRIDE / 4;
+                if let Some(e) = self.enable.get_mut(context) {
+                    e[word] = if word == 0 { value & !1 } else { value };
+                }
+            },
+            CONTEXT.. => {
+                let context = (offset - CONTEXT) / CONTEXT_STRIDE;
+                match (context < CONTEXTS, (offset - CONTEXT) % CONTEXT_STRIDE) {
+                    (true, 0) => self.threshold[context] = value & PRIORITY_MASK,
+                    (true, 4) => self.complete(context, value as usize),
+                    _ => {},
+                }
+            },
+            // The pending bits are read-only
+            _ => {},
+        }
+        true
+    }
+
+    fn set_source(&mut self, source: u32, level: bool) {
+        let source = source as usize;
+        if source > 0 && source < SOURCES {
+            set_bit(&mut self.level, source, level);
+            self.update(source);
+        }
+    }
+
+    fn interrupts(&self) -> u64 {
+        let mut lines = 0;
+        if self.best(0).is_some() {
+            lines |= csr::MIP_MEIP;
+        }
+        if self.best(1).is_some() {
+            lines |= csr::MIP_SEIP;
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLAIM: usize = CONTEXT + 4;
+
+    #[test]
+    fn test_plic() {
+        let mut p = Plic::new();
+        p.set_source(3, true);
+        p.set_source(5, true);
+        assert_eq!(p.read(PENDING, 4), Some(0b101000));
+        assert_eq!(p.interrupts(), 0);
+
+        // Enabled for M-mode, but priority 0 never interrupts
+        assert!(p.write(ENABLE, 4, 0b101000));
+        assert_eq!(p.interrupts(), 0);
+        assert!(p.write(3 * 4, 4, 1));
+        assert!(p.write(5 * 4, 4, 0xff));
+        assert_eq!(p.read(5 * 4, 4), Some(7));
+        assert_eq!(p.interrupts(), csr::MIP_MEIP);
+
+        // Highest priority first, and claimed sources stay quiet until
+        // they are completed
+        assert_eq!(p.read(CLAIM, 4), Some(5));
+        assert_eq!(p.read(CLAIM, 4), Some(3));
+        assert_eq!(p.read(CLAIM, 4), Some(0));
+        assert_eq!(p.interrupts(), 0);
+
+        p.set_source(3, false);
+        assert!(p.write(CLAIM, 4, 3));
+        assert!(p.write(CLAIM, 4, 5));
+        assert_eq!(p.read(PENDING, 4), Some(0b100000));
+        assert_eq!(p.interrupts(), csr::MIP_MEIP);
+
+        // Threshold, and the S-mode context
+        assert!(p.write(CONTEXT, 4, 7));
+        assert_eq!(p.interrupts(), 0);
+        assert!(p.write(ENABLE + ENABLE_STRIDE, 4, 0b100000));
+        assert_eq!(p.interrupts(), csr::MIP_SEIP);
+        assert_eq!(p.read(CLAIM + CONTEXT_STRIDE, 4), Some(5));
+        assert_eq!(p.interrupts(), 0);
+
+        assert_eq!(p.read(CLAIM, 8), None);
+    }
+}
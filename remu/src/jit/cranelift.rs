@@ -0,0 +1,456 @@
+// Cranelift backend, for every host Cranelift generates code for. Guest
+// registers become Cranelift variables, loaded from the register file the
+// first time a block reads them and stored back at every exit, so values
+// stay in host registers within a block.
+
+use std::mem::offset_of;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlags, SigRef, Signature, Type, Value};
+use cranelift_codegen::isa::{CallConv, OwnedTargetIsa};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use crate::common::Decoded;
+use crate::jit::{self, JitContext, NativeFn, STORE_FAILED, STORE_OK};
+use crate::riscv::instruction::Inst;
+use crate::riscv::instruction_types::{Btype, Itype, ItypeOp, ItypeShift, Stype};
+use crate::riscv::isa::{Extension, Isa};
+use crate::riscv::register::{Register, Xlen};
+
+const CTX_REGS: i32 = offset_of!(JitContext, regs) as i32;
+const CTX_PC: i32 = offset_of!(JitContext, pc) as i32;
+const CTX_BAIL: i32 = offset_of!(JitContext, bail) as i32;
+const CTX_VALUE: i32 = offset_of!(JitContext, value) as i32;
+
+/// Compiles blocks into a `JITModule`, which owns the code of all of them
+pub struct Compiler {
+    isa: OwnedTargetIsa,
+    module: JITModule,
+    ctx: Context,
+    builder_ctx: FunctionBuilderContext,
+}
+
+fn new_module(isa: &OwnedTargetIsa) -> JITModule {
+    JITModule::new(JITBuilder::with_isa(isa.clone(), default_libcall_names()))
+}
+
+impl Compiler {
+    /// Compiler for the host, if Cranelift supports it
+    pub fn new() -> Option<Self> {
+        let mut flags = settings::builder();
+        flags.set("opt_level", "speed").ok()?;
+        flags.set("use_colocated_libcalls", "false").ok()?;
+        flags.set("is_pic", "false").ok()?;
+        let isa = cranelift_native::builder().ok()?
+            .finish(settings::Flags::new(flags)).ok()?;
+        let module = new_module(&isa);
+        Some(Compiler {
+            ctx: module.make_context(),
+            isa,
+            module,
+            builder_ctx: FunctionBuilderContext::new(),
+        })
+    }
+
+    /// Frees the code of every block compiled so far
+    pub fn clear(&mut self) {
+        let module = std::mem::replace(&mut self.module, new_module(&self.isa));
+        // The blocks holding the code were dropped by the flush
+        unsafe { module.free_memory() };
+    }
+
+    /// Compiles the block of `insts` starting at `pc`. Compilation stops at
+    /// the first instruction that is not supported, which is left to the
+    /// interpreter, and None is returned if that is the first one.
+    pub fn compile(&mut self, insts: &[Decoded], pc: u64, isa: &Isa) -> Option<NativeFn> {
+        // Results are not truncated to 32 bits
+        if isa.xlen != Xlen::Rv64 {
+            return None;
+        }
+
+        let ptr = self.module.target_config().pointer_type();
+        self.ctx.func.signature = self.module.make_signature();
+        self.ctx.func.signature.params.push(AbiParam::new(ptr));
+        self.ctx.func.signature.returns.push(AbiParam::new(types::I64));
+        let compiled = build(&mut self.ctx, &mut self.builder_ctx, ptr, insts, pc, isa);
+        let code = compiled.and_then(|()| {
+            let id = self.module.declare_anonymous_function(&self.ctx.func.signature).ok()?;
+            self.module.define_function(id, &mut self.ctx).ok()?;
+            self.module.finalize_definitions().ok()?;
+            Some(self.module.get_finalized_function(id))
+        });
+        self.module.clear_context(&mut self.ctx);
+        code.map(|code| unsafe { std::mem::transmute::<*const u8, NativeFn>(code) })
+    }
+}
+
+/// Builds the function of a block into `ctx`, returning None if its first
+/// instruction is not supported
+fn build(ctx: &mut Context, builder_ctx: &mut FunctionBuilderContext, ptr: Type,
+         insts: &[Decoded], pc: u64, isa: &Isa) -> Option<()> {
+    let mut b = FunctionBuilder::new(&mut ctx.func, builder_ctx);
+    let entry = b.create_block();
+    b.append_block_params_for_function_params(entry);
+    b.switch_to_block(entry);
+    b.seal_block(entry);
+    let context = b.block_params(entry)[0];
+    let regs = b.ins().load(ptr, MemFlags::trusted(), context, CTX_REGS);
+    for r in 1..32 {
+        b.declare_var(Variable::from_u32(r), types::I64);
+    }
+
+    let call_conv = b.func.signature.call_conv;
+    let load_sig = b.func.import_signature(helper_signature(call_conv, ptr, 1));
+    let store_sig = b.func.import_signature(helper_signature(call_conv, ptr, 2));
+    let mut l = Lower {
+        b,
+        ptr,
+        ctx: context,
+        regs,
+        load_sig,
+        store_sig,
+        loaded: 0,
+        written: 0,
+        ialign: if isa.has(Extension::C) { 2 } else { 4 },
+    };
+
+    let mut pc = pc;
+    for (k, d) in insts.iter().enumerate() {
+        let k = k as i64;
+        let next = pc.wrapping_add(d.len as u64);
+        if !l.lower(&d.inst, pc, next, k) {
+            if k == 0 {
+                return None;
+            }
+            l.exit(pc, k, true);
+            break;
+        }
+        if d.inst.ends_block() {
+            break;
+        }
+        pc = next;
+        if k as usize == insts.len() - 1 {
+            // Blocks cut short go on with the next instruction
+            l.exit(pc, k + 1, false);
+        }
+    }
+    l.b.finalize();
+    Some(())
+}
+
+/// Signature of the load helpers with one argument after the context, or
+/// the store helpers with two
+fn helper_signature(call_conv: CallConv, ptr: Type, args: usize) -> Signature {
+    let mut sig = Signature::new(call_conv);
+    sig.params.push(AbiParam::new(ptr));
+    for _ in 0..args {
+        sig.params.push(AbiParam::new(types::I64));
+    }
+    sig.returns.push(AbiParam::new(types::I64));
+    sig
+}
+
+struct Lower<'a> {
+    b: FunctionBuilder<'a>,
+    ptr: Type,
+    ctx: Value,
+    regs: Value,
+    load_sig: SigRef,
+    store_sig: SigRef,
+    /// Registers whose variable is defined
+    loaded: u32,
+    /// Registers to store back on exit
+    written: u32,
+    ialign: u64,
+}
+
+impl Lower<'_> {
+    fn get(&mut self, r: Register) -> Value {
+        let n = r as u32;
+        if r == Register::Zero {
+            return self.b.ins().iconst(types::I64, 0);
+        }
+        if self.loaded & (1 << n) == 0 {
+            // Loaded on the path every later instruction is on
+            let v = self.b.ins().load(types::I64, MemFlags::trusted(), self.regs, n as i32 * 8);
+            self.b.def_var(Variable::from_u32(n), v);
+            self.loaded |= 1 << n;
+        }
+        self.b.use_var(Variable::from_u32(n))
+    }
+
+    fn set(&mut self, r: Register, v: Value) {
+        let n = r as u32;
+        if r != Register::Zero {
+            self.b.def_var(Variable::from_u32(n), v);
+            self.loaded |= 1 << n;
+            self.written |= 1 << n;
+        }
+    }
+
+    fn imm(&mut self, imm: u64) -> Value {
+        self.b.ins().iconst(types::I64, imm as i64)
+    }
+
+    /// Stores the registers back and returns `done` completed instructions,
+    /// going on at `pc`
+    fn exit_to(&mut self, pc: Value, done: i64, bail: bool) {
+        for n in 1..32 {
+            if self.written & (1 << n) != 0 {
+                let v = self.b.use_var(Variable::from_u32(n));
+                self.b.ins().store(MemFlags::trusted(), v, self.regs, n as i32 * 8);
+            }
+        }
+        self.b.ins().store(MemFlags::trusted(), pc, self.ctx, CTX_PC);
+        if bail {
+            let one = self.imm(1);
+            self.b.ins().store(MemFlags::trusted(), one, self.ctx, CTX_BAIL);
+        }
+        let done = self.imm(done as u64);
+        self.b.ins().return_(&[done]);
+    }
+
+    fn exit(&mut self, pc: u64, done: i64, bail: bool) {
+        let pc = self.imm(pc);
+        self.exit_to(pc, done, bail);
+    }
+
+    /// Leaves the block through `exit` if `cond` is set, going on with the
+    /// code emitted after
+    fn exit_if(&mut self, cond: Value, exit: impl FnOnce(&mut Self)) {
+        let taken = self.b.create_block();
+        let rest = self.b.create_block();
+        self.b.ins().brif(cond, taken, &[], rest, &[]);
+        self.b.seal_block(taken);
+        self.b.seal_block(rest);
+        self.b.switch_to_block(taken);
+        exit(self);
+        self.b.switch_to_block(rest);
+    }
+
+    /// Runs `f` on the low 32 bits and sign-extends the result
+    fn word(&mut self, v: Value, f: impl FnOnce(&mut Self, Value) -> Value) -> Value {
+        let w = self.b.ins().ireduce(types::I32, v);
+        let w = f(self, w);
+        self.b.ins().sextend(types::I64, w)
+    }
+
+    fn flag(&mut self, cc: IntCC, a: Value, b: Value) -> Value {
+        let c = self.b.ins().icmp(cc, a, b);
+        self.b.ins().uextend(types::I64, c)
+    }
+
+    fn op_imm(&mut self, i: Itype, f: impl FnOnce(&mut Self, Value, i64) -> Value) {
+        let a = self.get(i.rs1);
+        let v = f(self, a, i.imm as i64);
+        self.set(i.rd, v);
+    }
+
+    fn op_shift(&mut self, i: ItypeShift, f: impl FnOnce(&mut Self, Value, i64) -> Value) {
+        let a = self.get(i.rs1);
+        let v = f(self, a, i.shamt as i64);
+        self.set(i.rd, v);
+    }
+
+    fn op(&mut self, i: ItypeOp, f: impl FnOnce(&mut Self, Value, Value) -> Value) {
+        let a = self.get(i.rs1);
+        let b = self.get(i.rs2);
+        let v = f(self, a, b);
+        self.set(i.rd, v);
+    }
+
+    /// Division with the RISC-V results for a zero divisor and overflow,
+    /// where Cranelift would trap
+    fn div(&mut self, i: ItypeOp, signed: bool, rem: bool) {
+        self.op(i, |l, a, b| {
+            let zero = l.b.ins().icmp_imm(IntCC::Equal, b, 0);
+            let mut unsafe_divisor = zero;
+            if signed {
+                let min = l.b.ins().icmp_imm(IntCC::Equal, a, i64::MIN);
+                let minus_one = l.b.ins().icmp_imm(IntCC::Equal, b, -1);
+                let overflow = l.b.ins().band(min, minus_one);
+                unsafe_divisor = l.b.ins().bor(zero, overflow);
+            }
+            let one = l.imm(1);
+            let divisor = l.b.ins().select(unsafe_divisor, one, b);
+            let v = match (signed, rem) {
+                (false, false) => l.b.ins().udiv(a, divisor),
+                (false, true) => l.b.ins().urem(a, divisor),
+                (true, false) => l.b.ins().sdiv(a, divisor),
+                (true, true) => l.b.ins().srem(a, divisor),
+            };
+            // x / 0 is all ones and x % 0 is x
+            let by_zero = if rem { a } else { l.imm(u64::MAX) };
+            l.b.ins().select(zero, by_zero, v)
+        });
+    }
+
+    fn call(&mut self, sig: SigRef, f: usize, args: &[Value]) -> Value {
+        let callee = self.b.ins().iconst(self.ptr, f as i64);
+        let mut all = vec![self.ctx];
+        all.extend_from_slice(args);
+        let call = self.b.ins().call_indirect(sig, callee, &all);
+        self.b.inst_results(call)[0]
+    }
+
+    fn address(&mut self, rs1: Register, imm: i32) -> Value {
+        let base = self.get(rs1);
+        self.b.ins().iadd_imm(base, imm as i64)
+    }
+
+    fn load(&mut self, helper: jit::LoadFn, i: Itype, pc: u64, k: i64) {
+        let addr = self.address(i.rs1, i.imm);
+        let status = self.call(self.load_sig, helper as usize, &[addr]);
+        self.exit_if(status, |l| l.exit(pc, k, true));
+        let v = self.b.ins().load(types::I64, MemFlags::trusted(), self.ctx, CTX_VALUE);
+        self.set(i.rd, v);
+    }
+
+    fn store(&mut self, helper: jit::StoreFn, i: Stype, pc: u64, next: u64, k: i64) {
+        let addr = self.address(i.rs1, i.imm);
+        let value = self.get(i.rs2);
+        let status = self.call(self.store_sig, helper as usize, &[addr, value]);
+        let failed = self.b.ins().icmp_imm(IntCC::Equal, status, STORE_FAILED as i64);
+        self.exit_if(failed, |l| l.exit(pc, k, true));
+        // The store changed decoded code, which may be in this block
+        let written = self.b.ins().icmp_imm(IntCC::NotEqual, status, STORE_OK as i64);
+        self.exit_if(written, |l| l.exit(next, k + 1, false));
+    }
+
+    fn branch(&mut self, cc: IntCC, i: Btype, pc: u64, next: u64, k: i64) -> bool {
+        let target = pc.wrapping_add(i.imm as i64 as u64);
+        if !target.is_multiple_of(self.ialign) {
+            return false;
+        }
+        let a = self.get(i.rs1);
+        let b = self.get(i.rs2);
+        let taken = self.b.ins().icmp(cc, a, b);
+        let target = self.imm(target);
+        let next = self.imm(next);
+        let pc = self.b.ins().select(taken, target, next);
+        self.exit_to(pc, k + 1, false);
+        true
+    }
+
+    /// Lowers the `k`th instruction of the block, at `pc`, returning false
+    /// if it is not supported
+    fn lower(&mut self, inst: &Inst, pc: u64, next: u64, k: i64) -> bool {
+        use types::I32;
+
+        match *inst {
+            Inst::LUI(x) => {
+                let v = self.imm(x.i.imm as i64 as u64);
+                self.set(x.i.rd, v);
+            },
+            Inst::AUIPC(x) => {
+                let v = self.imm(pc.wrapping_add(x.i.imm as i64 as u64));
+                self.set(x.i.rd, v);
+            },
+            Inst::JAL(x) => {
+                let target = pc.wrapping_add(x.i.imm as i64 as u64);
+                if !target.is_multiple_of(self.ialign) {
+                    return false;
+                }
+                let link = self.imm(next);
+                self.set(x.i.rd, link);
+                self.exit(target, k + 1, false);
+            },
+            Inst::JALR(x) => {
+                let target = self.address(x.i.rs1, x.i.imm);
+                let target = self.b.ins().band_imm(target, -2);
+                if self.ialign == 4 {
+                    let misaligned = self.b.ins().band_imm(target, 2);
+                    self.exit_if(misaligned, |l| l.exit(pc, k, true));
+                }
+                let link = self.imm(next);
+                self.set(x.i.rd, link);
+                self.exit_to(target, k + 1, false);
+            },
+            Inst::BEQ(x) => return self.branch(IntCC::Equal, x.i, pc, next, k),
+            Inst::BNE(x) => return self.branch(IntCC::NotEqual, x.i, pc, next, k),
+            Inst::BLT(x) => return self.branch(IntCC::SignedLessThan, x.i, pc, next, k),
+            Inst::BGE(x) => return self.branch(IntCC::SignedGreaterThanOrEqual, x.i, pc, next, k),
+            Inst::BLTU(x) => return self.branch(IntCC::UnsignedLessThan, x.i, pc, next, k),
+            Inst::BGEU(x) => return self.branch(IntCC::UnsignedGreaterThanOrEqual, x.i, pc, next, k),
+            Inst::LB(x) => self.load(jit::load_i8, x.i, pc, k),
+            Inst::LH(x) => self.load(jit::load_i16, x.i, pc, k),
+            Inst::LW(x) => self.load(jit::load_i32, x.i, pc, k),
+            Inst::LBU(x) => self.load(jit::load_u8, x.i, pc, k),
+            Inst::LHU(x) => self.load(jit::load_u16, x.i, pc, k),
+            Inst::LWU(x) => self.load(jit::load_u32, x.i, pc, k),
+            Inst::LD(x) => self.load(jit::load_u64, x.i, pc, k),
+            Inst::SB(x) => self.store(jit::store_u8, x.i, pc, next, k),
+            Inst::SH(x) => self.store(jit::store_u16, x.i, pc, next, k),
+            Inst::SW(x) => self.store(jit::store_u32, x.i, pc, next, k),
+            Inst::SD(x) => self.store(jit::store_u64, x.i, pc, next, k),
+            Inst::ADDI(x) => self.op_imm(x.i, |l, a, imm| l.b.ins().iadd_imm(a, imm)),
+            Inst::XORI(x) => self.op_imm(x.i, |l, a, imm| l.b.ins().bxor_imm(a, imm)),
+            Inst::ORI(x) => self.op_imm(x.i, |l, a, imm| l.b.ins().bor_imm(a, imm)),
+            Inst::ANDI(x) => self.op_imm(x.i, |l, a, imm| l.b.ins().band_imm(a, imm)),
+            Inst::SLTI(x) => self.op_imm(x.i, |l, a, imm| {
+                let c = l.b.ins().icmp_imm(IntCC::SignedLessThan, a, imm);
+                l.b.ins().uextend(types::I64, c)
+            }),
+            Inst::SLTIU(x) => self.op_imm(x.i, |l, a, imm| {
+                let c = l.b.ins().icmp_imm(IntCC::UnsignedLessThan, a, imm);
+                l.b.ins().uextend(types::I64, c)
+            }),
+            Inst::ADDIW(x) => self.op_imm(x.i, |l, a, imm| l.word(a, |l, a| l.b.ins().iadd_imm(a, imm))),
+            Inst::SLLI(x) => self.op_shift(x.i, |l, a, s| l.b.ins().ishl_imm(a, s)),
+            Inst::SRLI(x) => self.op_shift(x.i, |l, a, s| l.b.ins().ushr_imm(a, s)),
+            Inst::SRAI(x) => self.op_shift(x.i, |l, a, s| l.b.ins().sshr_imm(a, s)),
+            Inst::SLLIW(x) => self.op_shift(x.i, |l, a, s| l.word(a, |l, a| l.b.ins().ishl_imm(a, s))),
+            Inst::SRLIW(x) => self.op_shift(x.i, |l, a, s| l.word(a, |l, a| l.b.ins().ushr_imm(a, s))),
+            Inst::SRAIW(x) => self.op_shift(x.i, |l, a, s| l.word(a, |l, a| l.b.ins().sshr_imm(a, s))),
+            Inst::ADD(x) => self.op(x.i, |l, a, b| l.b.ins().iadd(a, b)),
+            Inst::SUB(x) => self.op(x.i, |l, a, b| l.b.ins().isub(a, b)),
+            Inst::XOR(x) => self.op(x.i, |l, a, b| l.b.ins().bxor(a, b)),
+            Inst::OR(x) => self.op(x.i, |l, a, b| l.b.ins().bor(a, b)),
+            Inst::AND(x) => self.op(x.i, |l, a, b| l.b.ins().band(a, b)),
+            Inst::SLT(x) => self.op(x.i, |l, a, b| l.flag(IntCC::SignedLessThan, a, b)),
+            Inst::SLTU(x) => self.op(x.i, |l, a, b| l.flag(IntCC::UnsignedLessThan, a, b)),
+            // Cranelift takes shift amounts modulo the width like RISC-V
+            Inst::SLL(x) => self.op(x.i, |l, a, b| l.b.ins().ishl(a, b)),
+            Inst::SRL(x) => self.op(x.i, |l, a, b| l.b.ins().ushr(a, b)),
+            Inst::SRA(x) => self.op(x.i, |l, a, b| l.b.ins().sshr(a, b)),
+            Inst::ADDW(x) => self.op(x.i, |l, a, b| {
+                let v = l.b.ins().iadd(a, b);
+                l.word(v, |_, v| v)
+            }),
+            Inst::SUBW(x) => self.op(x.i, |l, a, b| {
+                let v = l.b.ins().isub(a, b);
+                l.word(v, |_, v| v)
+            }),
+            Inst::SLLW(x) => self.op(x.i, |l, a, b| {
+                let b = l.b.ins().ireduce(I32, b);
+                l.word(a, |l, a| l.b.ins().ishl(a, b))
+            }),
+            Inst::SRLW(x) => self.op(x.i, |l, a, b| {
+                let b = l.b.ins().ireduce(I32, b);
+                l.word(a, |l, a| l.b.ins().ushr(a, b))
+            }),
+            Inst::SRAW(x) => self.op(x.i, |l, a, b| {
+                let b = l.b.ins().ireduce(I32, b);
+                l.word(a, |l, a| l.b.ins().sshr(a, b))
+            }),
+            Inst::MUL(x) => self.op(x.i, |l, a, b| l.b.ins().imul(a, b)),
+            Inst::MULH(x) => self.op(x.i, |l, a, b| l.b.ins().smulhi(a, b)),
+            Inst::MULHU(x) => self.op(x.i, |l, a, b| l.b.ins().umulhi(a, b)),
+            Inst::MULW(x) => self.op(x.i, |l, a, b| {
+                let v = l.b.ins().imul(a, b);
+                l.word(v, |_, v| v)
+            }),
+            Inst::DIV(x) => self.div(x.i, true, false),
+            Inst::DIVU(x) => self.div(x.i, false, false),
+            Inst::REM(x) => self.div(x.i, true, true),
+            Inst::REMU(x) => self.div(x.i, false, true),
+            Inst::FENCE(_) => {},
+            _ => return false,
+        }
+        true
+    }
+}
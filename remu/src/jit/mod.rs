@@ -0,0 +1,285 @@
+// Native code for the blocks of the block engine. Compiled blocks work on
+// the registers of the `Machine` through a `JitContext` and call back into
+// the `Mmu` for every load and store, so permission checks and faults are
+// exactly those of the interpreter. An instruction a backend does not
+// compile, or whose memory access fails, is handed back to the interpreter.
+
+#[cfg(feature = "x86-jit")]
+pub mod x86;
+#[cfg(feature = "cranelift")]
+pub mod cranelift;
+
+#[cfg(all(feature = "x86-jit", not(target_arch = "x86_64")))]
+compile_error!("the x86-jit feature needs an x86-64 host, use the cranelift feature instead");
+
+use crate::common::{Decoded, Machine, VmExit};
+use crate::riscv::isa::Isa;
+use crate::riscv::register::Register;
+
+/// State shared between a `Machine` and the native code of a block
+#[repr(C)]
+pub struct JitContext {
+    /// Integer registers, x0 to x31 followed by the PC
+    pub regs: *mut u64,
+    pub machine: *mut Machine,
+    /// Address execution continues at when the native code returns
+    pub pc: u64,
+    /// Set when the instruction at `pc` has to be run by the interpreter
+    pub bail: u64,
+    /// Value read by the load helpers
+    pub value: u64,
+}
+
+/// Entry point of a compiled block, returning the number of instructions
+/// it completed
+pub type NativeFn = unsafe extern "C" fn(*mut JitContext) -> u64;
+
+/// Helpers the native code calls for loads and stores
+pub type LoadFn = unsafe extern "C" fn(*mut JitContext, u64) -> u64;
+pub type StoreFn = unsafe extern "C" fn(*mut JitContext, u64, u64) -> u64;
+
+/// Compiles blocks to native code
+pub enum Backend {
+    #[cfg(feature = "x86-jit")]
+    X86(Box<x86::CodeArena>),
+    #[cfg(feature = "cranelift")]
+    Cranelift(Box<cranelift::Compiler>),
+}
+
+/// Native code of a block, made by a `Backend`
+pub enum NativeCode {
+    /// Code in the `x86::CodeArena`, valid until it is cleared
+    #[cfg(feature = "x86-jit")]
+    X86(NativeFn),
+    /// Code owned by the `cranelift::Compiler`, valid until it is cleared
+    #[cfg(feature = "cranelift")]
+    Cranelift(NativeFn),
+}
+
+impl Backend {
+    /// Compiles the block of `insts` starting at `pc`, if the backend
+    /// supports its first instruction
+    pub fn compile(&mut self, insts: &[Decoded], pc: u64, isa: &Isa) -> Option<NativeCode> {
+        match self {
+            #[cfg(feature = "x86-jit")]
+            Backend::X86(arena) => x86::compile(arena, insts, pc, isa).map(NativeCode::X86),
+            #[cfg(feature = "cranelift")]
+            Backend::Cranelift(c) => c.compile(insts, pc, isa).map(NativeCode::Cranelift),
+        }
+    }
+
+    /// Frees the code of all blocks, which must have been dropped
+    pub fn clear(&mut self) {
+        match self {
+            #[cfg(feature = "x86-jit")]
+            Backend::X86(arena) => arena.clear(),
+            #[cfg(feature = "cranelift")]
+            Backend::Cranelift(c) => c.clear(),
+        }
+    }
+}
+
+impl NativeCode {
+    pub fn entry(&self) -> NativeFn {
+        match self {
+            #[cfg(feature = "x86-jit")]
+            NativeCode::X86(f) => *f,
+            #[cfg(feature = "cranelift")]
+            NativeCode::Cranelift(f) => *f,
+        }
+    }
+}
+
+/// Store helper result: the store was done and the block can go on
+pub const STORE_OK: u64 = 0;
+/// Store helper result: the store failed and has to be retried by the
+/// interpreter to raise the fault
+pub const STORE_FAILED: u64 = 1;
+/// Store helper result: the store was done but hit decoded code, so the
+/// block has to be left
+pub const STORE_CODE_WRITTEN: u64 = 2;
+
+// Load helpers, returning 0 and the loaded value, sign- or zero-extended
+// like the load instruction does, in `JitContext::value`, or 1 on a fault.
+macro_rules! load_helper {
+    ($name:ident, $read:ident, $t:ty) => {
+        /// # Safety
+        /// `ctx` has to point to the context of the running block
+        pub unsafe extern "C" fn $name(ctx: *mut JitContext, addr: u64) -> u64 {
+            let ctx = &mut *ctx;
+            let m = &mut *ctx.machine;
+            match m.mmu.$read(m.vaddr(addr)) {
+                Ok(v) => {
+                    ctx.value = v as $t as i64 as u64;
+                    0
+                },
+                Err(_) => 1,
+            }
+        }
+    };
+}
+
+load_helper!(load_i8, read_u8, i8);
+load_helper!(load_i16, read_u16, i16);
+load_helper!(load_i32, read_u32, i32);
+load_helper!(load_u8, read_u8, u8);
+load_helper!(load_u16, read_u16, u16);
+load_helper!(load_u32, read_u32, u32);
+load_helper!(load_u64, read_u64, u64);
+
+macro_rules! store_helper {
+    ($name:ident, $write:ident, $t:ty) => {
+        /// # Safety
+        /// `ctx` has to point to the context of the running block
+        pub unsafe extern "C" fn $name(ctx: *mut JitContext, addr: u64, value: u64) -> u64 {
+            let m = &mut *(*ctx).machine;
+            let addr = m.vaddr(addr);
+            m.invalidate_reservation(addr, std::mem::size_of::<$t>());
+            match m.mmu.$write(addr, value as $t) {
+                Ok(()) if m.mmu.code_written() => STORE_CODE_WRITTEN,
+                Ok(()) => STORE_OK,
+                Err(_) => STORE_FAILED,
+            }
+        }
+    };
+}
+
+store_helper!(store_u8, write_u8, u8);
+store_helper!(store_u16, write_u16, u16);
+store_helper!(store_u32, write_u32, u32);
+store_helper!(store_u64, write_u64, u64);
+
+impl Machine {
+    /// Runs the native code `f` of the block at the PC, returning the number
+    /// of instructions it completed and whether the next one has to be run
+    /// by the interpreter
+    pub(crate) fn run_native(&mut self, f: NativeFn) -> Result<(u64, bool), VmExit> {
+        // Both pointers are derived from one, so the native code and the
+        // helpers it calls can use either
+        let m: *mut Machine = self;
+        let mut ctx = JitContext {
+            regs: unsafe { (*m).registers_mut().as_mut_ptr() },
+            machine: m,
+            pc: 0,
+            bail: 0,
+            value: 0,
+        };
+        let done = unsafe { f(&mut ctx) };
+
+        self.retire(done);
+        self.set_r(Register::Pc, ctx.pc)?;
+        Ok((done, ctx.bail != 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::block::Engine;
+    use crate::common::{Machine, VmExit};
+    use crate::mmu::{Mmu, VirtAddr};
+    use crate::riscv::csr;
+    use crate::riscv::register::Register::{self, *};
+    use crate::test_util::{machine, ENGINES};
+
+    /// Runs the machines made by `new` with the interpreter and every other
+    /// engine, which have to end up in the same state
+    fn compare(new: impl Fn() -> Machine) -> (VmExit, Machine) {
+        let mut step = new();
+        let exit = step.run(Engine::Step);
+        for &engine in ENGINES.iter().filter(|&&e| e != Engine::Step) {
+            let mut jit = new();
+            assert_eq!(jit.run(engine), exit, "{:?}", engine);
+            for r in 0..32 {
+                let r = Register::from(r);
+                assert_eq!(jit.get_r(r), step.get_r(r), "{:?} {:?}", engine, r);
+            }
+            assert_eq!(jit.get_pc(), step.get_pc(), "{:?}", engine);
+            assert_eq!(jit.read_csr(csr::MINSTRET), step.read_csr(csr::MINSTRET), "{:?}", engine);
+        }
+        (exit, step)
+    }
+
+    #[test]
+    fn test_against_interpreter() {
+        let (exit, m) = compare(|| machine(&[
+            0xffb00513, // li a0, -5
+            0x00300593, // li a1, 3
+            0x12345637, // lui a2, 0x12345
+            0x6786061b, // addiw a2, a2, 0x678
+            0x01461693, // slli a3, a2, 20
+            0x4076d713, // srai a4, a3, 7
+            0x0096d793, // srli a5, a3, 9
+            0x4036581b, // sraiw a6, a2, 3
+            0x40b508b3, // sub a7, a0, a1
+            0x02c602bb, // mulw t0, a2, a2
+            0x02a68333, // mul t1, a3, a0
+            0x00b523b3, // slt t2, a0, a1
+            0x00b53433, // sltu s0, a0, a1
+            0xfff5b493, // sltiu s1, a1, -1
+            0x05554913, // xori s2, a0, 85
+            0x00b619bb, // sllw s3, a2, a1
+            0x40b55a3b, // sraw s4, a0, a1
+            0x00b55ab3, // srl s5, a0, a1
+            0x20a03023, // sd a0, 512(zero)
+            0x20002b03, // lw s6, 512(zero)
+            0x20004b83, // lbu s7, 512(zero)
+            0x20201c03, // lh s8, 514(zero)
+            0x02a64e33, // div t3, a2, a0
+            0x02056eb3, // rem t4, a0, zero
+            0x02a69f33, // mulh t5, a3, a0
+            0x02055fb3, // divu t6, a0, zero
+            0x02a6bd33, // mulhu s10, a3, a0
+            0xfff58593, // addi a1, a1, -1
+            0xf8059ce3, // bnez a1, -104
+            0x00001c97, // auipc s9, 1
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+        ]));
+        assert_eq!(exit, VmExit::Exit(-5));
+        assert_eq!(m.get_r(S7), 0xfb);
+        assert_eq!(m.get_r(T4), -5i64 as u64);
+        assert_eq!(m.get_r(T6), u64::MAX);
+    }
+
+    #[test]
+    fn test_faults() {
+        // The load is run again by the interpreter, which raises the fault
+        let (exit, m) = compare(|| machine(&[
+            0x00100513, // li a0, 1
+            0x100005b7, // lui a1, 0x10000
+            0x0085b503, // ld a0, 8(a1)
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+        ]));
+        assert!(matches!(exit, VmExit::ReadFault(_)));
+        assert_eq!(m.get_pc(), VirtAddr(8));
+        assert_eq!(m.get_r(A0), 1);
+    }
+
+    /// Loads an example binary like main does
+    fn load(path: &PathBuf) -> Machine {
+        let mut mmu = Mmu::new(1024 * 1024);
+        mmu.load_elf(path).unwrap();
+        let stack = mmu.alloc(64 * 1024).unwrap();
+        let sp = stack.0 + 64 * 1024 - 32;
+        mmu.write_from(VirtAddr(sp), &[0; 32]).unwrap();
+        let mut m = Machine::new(mmu);
+        m.set_r(Sp, sp as u64).unwrap();
+        m
+    }
+
+    #[test]
+    fn test_examples() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../riscv_examples");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if fs::read(&path).unwrap().starts_with(b"\x7fELF") {
+                let (exit, _) = compare(|| load(&path));
+                assert_eq!(exit, VmExit::Exit(0), "{}", path.display());
+            }
+        }
+    }
+}
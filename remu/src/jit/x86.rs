@@ -0,0 +1,494 @@
+// x86-64 backend. Every guest instruction is lowered on its own: its source
+// registers are loaded from the register file into rax and rcx, the result
+// is computed there and stored back, so nothing is tracked across
+// instructions. rbx holds the register file and r12 the `JitContext` for
+// the whole block.
+
+use std::convert::TryFrom;
+use std::mem::offset_of;
+use std::ptr;
+
+use crate::common::Decoded;
+use crate::jit::{self, JitContext, NativeFn, STORE_FAILED};
+use crate::riscv::instruction::Inst;
+use crate::riscv::instruction_types::{Btype, Itype, ItypeOp, ItypeShift, Stype};
+use crate::riscv::isa::{Extension, Isa};
+use crate::riscv::register::{Register, Xlen};
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R12: u8 = 12;
+
+// Condition codes, the low nibble of Jcc, SETcc and CMOVcc
+const CC_B: u8 = 0x2;
+const CC_AE: u8 = 0x3;
+const CC_E: u8 = 0x4;
+const CC_NE: u8 = 0x5;
+const CC_L: u8 = 0xc;
+const CC_GE: u8 = 0xd;
+
+// Opcode extensions of the group 1 (81 /n) and group 2 (C1 /n, D3 /n)
+// instructions
+const ALU_ADD: u8 = 0;
+const ALU_OR: u8 = 1;
+const ALU_AND: u8 = 4;
+const ALU_XOR: u8 = 6;
+const ALU_CMP: u8 = 7;
+const SHIFT_SHL: u8 = 4;
+const SHIFT_SHR: u8 = 5;
+const SHIFT_SAR: u8 = 7;
+
+const CTX_REGS: i32 = offset_of!(JitContext, regs) as i32;
+const CTX_PC: i32 = offset_of!(JitContext, pc) as i32;
+const CTX_BAIL: i32 = offset_of!(JitContext, bail) as i32;
+const CTX_VALUE: i32 = offset_of!(JitContext, value) as i32;
+
+/// Size of the mappings code is allocated from
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Executable memory the code of blocks is bump allocated from, so making a
+/// block does not map memory. The code stays valid until the arena is
+/// cleared or dropped.
+pub struct CodeArena {
+    /// Mappings with their size, the last one being filled
+    chunks: Vec<(*mut u8, usize)>,
+    /// Bytes used of the last mapping
+    used: usize,
+}
+
+impl CodeArena {
+    pub fn new() -> Self {
+        CodeArena {
+            chunks: Vec::new(),
+            used: 0,
+        }
+    }
+
+    /// Copies `code` into the arena, returning its address
+    fn alloc(&mut self, code: &[u8]) -> Option<NativeFn> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        if !matches!(self.chunks.last(), Some(&(_, size)) if self.used + code.len() <= size) {
+            let size = code.len().max(CHUNK_SIZE).next_multiple_of(page);
+            let ptr = unsafe {
+                libc::mmap(ptr::null_mut(), size, libc::PROT_READ | libc::PROT_EXEC,
+                           libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+            };
+            if ptr == libc::MAP_FAILED {
+                return None;
+            }
+            self.chunks.push((ptr as *mut u8, size));
+            self.used = 0;
+        }
+
+        // Only the pages the code goes to are writable, and only while it
+        // is copied
+        let (chunk, _) = *self.chunks.last().unwrap();
+        let first = self.used / page * page;
+        let end = self.used + code.len();
+        unsafe {
+            let pages = chunk.add(first) as *mut libc::c_void;
+            if libc::mprotect(pages, end - first, libc::PROT_READ | libc::PROT_WRITE) != 0 {
+                return None;
+            }
+            ptr::copy_nonoverlapping(code.as_ptr(), chunk.add(self.used), code.len());
+            if libc::mprotect(pages, end - first, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                return None;
+            }
+            let entry = chunk.add(self.used);
+            // Entry points are aligned like a compiler aligns functions
+            self.used = end.next_multiple_of(16);
+            Some(std::mem::transmute::<*mut u8, NativeFn>(entry))
+        }
+    }
+
+    /// Frees the code of all blocks, keeping the first mapping for new ones
+    pub fn clear(&mut self) {
+        for &(ptr, size) in self.chunks.iter().skip(1) {
+            unsafe {
+                libc::munmap(ptr as *mut libc::c_void, size);
+            }
+        }
+        self.chunks.truncate(1);
+        self.used = 0;
+    }
+}
+
+// The mappings are owned by the arena, and only written by it
+unsafe impl Send for CodeArena {}
+
+impl Drop for CodeArena {
+    fn drop(&mut self) {
+        for &(ptr, size) in &self.chunks {
+            unsafe {
+                libc::munmap(ptr as *mut libc::c_void, size);
+            }
+        }
+    }
+}
+
+struct Asm {
+    code: Vec<u8>,
+    /// Alignment of jump targets, as checked by `Machine::jump`
+    ialign: u64,
+}
+
+impl Asm {
+    fn bytes(&mut self, b: &[u8]) {
+        self.code.extend_from_slice(b);
+    }
+
+    fn rex(&mut self, w: bool, reg: u8, rm: u8) {
+        let rex = 0x40 | (w as u8) << 3 | (reg >> 3) << 2 | (rm >> 3);
+        if rex != 0x40 {
+            self.code.push(rex);
+        }
+    }
+
+    /// `op` on the registers `reg` and `rm`, where `reg` may also be an
+    /// opcode extension
+    fn rr(&mut self, w: bool, op: &[u8], reg: u8, rm: u8) {
+        self.rex(w, reg, rm);
+        self.bytes(op);
+        self.code.push(0xc0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    /// `op` on the register `reg` and the memory at `base` + `disp`
+    fn rm(&mut self, w: bool, op: &[u8], reg: u8, base: u8, disp: i32) {
+        self.rex(w, reg, base);
+        self.bytes(op);
+        self.code.push(0x80 | (reg & 7) << 3 | (base & 7));
+        if base & 7 == 4 {
+            self.code.push(0x24);
+        }
+        self.bytes(&disp.to_le_bytes());
+    }
+
+    fn mov_imm(&mut self, dst: u8, imm: u64) {
+        self.rex(true, 0, dst);
+        self.code.push(0xb8 | (dst & 7));
+        self.bytes(&imm.to_le_bytes());
+    }
+
+    fn mov(&mut self, dst: u8, src: u8) {
+        self.rr(true, &[0x89], src, dst);
+    }
+
+    fn load_reg(&mut self, dst: u8, r: Register) {
+        if r == Register::Zero {
+            self.rr(false, &[0x31], dst, dst);
+        } else {
+            self.rm(true, &[0x8b], dst, RBX, r as i32 * 8);
+        }
+    }
+
+    fn store_reg(&mut self, r: Register, src: u8) {
+        if r != Register::Zero {
+            self.rm(true, &[0x89], src, RBX, r as i32 * 8);
+        }
+    }
+
+    /// Sign-extends eax into rax, after the 32-bit operations of RV64
+    fn sext_w(&mut self) {
+        self.rr(true, &[0x63], RAX, RAX);
+    }
+
+    fn set_pc(&mut self, pc: u64) {
+        self.mov_imm(RAX, pc);
+        self.rm(true, &[0x89], RAX, R12, CTX_PC);
+    }
+
+    /// Calls a helper with the context as its first argument
+    fn call(&mut self, f: usize) {
+        self.mov(RDI, R12);
+        self.mov_imm(RAX, f as u64);
+        self.rr(false, &[0xff], 2, RAX);
+    }
+
+    fn prologue(&mut self) {
+        // push rbx, r12 and rbp, which also aligns the stack for calls
+        self.bytes(&[0x53, 0x41, 0x54, 0x55]);
+        self.mov(R12, RDI);
+        self.rm(true, &[0x8b], RBX, RDI, CTX_REGS);
+    }
+
+    /// Returns `done` completed instructions
+    fn ret(&mut self, done: u32) {
+        self.code.push(0xb8);
+        self.bytes(&done.to_le_bytes());
+        self.bytes(&[0x5d, 0x41, 0x5c, 0x5b, 0xc3]);
+    }
+
+    /// Returns to have the instruction at `pc`, the `done`th of the block,
+    /// run by the interpreter
+    fn bail(&mut self, pc: u64, done: u32) {
+        self.set_pc(pc);
+        self.rm(true, &[0xc7], 0, R12, CTX_BAIL);
+        self.bytes(&1u32.to_le_bytes());
+        self.ret(done);
+    }
+
+    /// Short forward jump on `cc`, returning where to `patch` it
+    fn jcc(&mut self, cc: u8) -> usize {
+        self.bytes(&[0x70 | cc, 0]);
+        self.code.len()
+    }
+
+    /// Points the jump emitted at `at` here
+    fn patch(&mut self, at: usize) {
+        self.code[at - 1] = u8::try_from(self.code.len() - at).unwrap();
+    }
+
+    fn alu_imm(&mut self, w: bool, ext: u8, i: Itype) {
+        self.load_reg(RAX, i.rs1);
+        self.rr(w, &[0x81], ext, RAX);
+        self.bytes(&i.imm.to_le_bytes());
+        if !w {
+            self.sext_w();
+        }
+        self.store_reg(i.rd, RAX);
+    }
+
+    fn alu(&mut self, w: bool, op: &[u8], i: ItypeOp) {
+        self.load_reg(RAX, i.rs1);
+        self.load_reg(RCX, i.rs2);
+        if op == [0x0f, 0xaf] {
+            // imul has the destination in reg
+            self.rr(w, op, RAX, RCX);
+        } else {
+            self.rr(w, op, RCX, RAX);
+        }
+        if !w {
+            self.sext_w();
+        }
+        self.store_reg(i.rd, RAX);
+    }
+
+    /// Sets `rd` to whether the last comparison met `cc`
+    fn set_cc(&mut self, cc: u8, rd: Register) {
+        self.rr(false, &[0x0f, 0x90 | cc], 0, RAX);
+        self.rr(false, &[0x0f, 0xb6], RAX, RAX);
+        self.store_reg(rd, RAX);
+    }
+
+    fn slt_imm(&mut self, cc: u8, i: Itype) {
+        self.load_reg(RAX, i.rs1);
+        self.rr(true, &[0x81], ALU_CMP, RAX);
+        self.bytes(&i.imm.to_le_bytes());
+        self.set_cc(cc, i.rd);
+    }
+
+    fn slt(&mut self, cc: u8, i: ItypeOp) {
+        self.load_reg(RAX, i.rs1);
+        self.load_reg(RCX, i.rs2);
+        self.rr(true, &[0x39], RCX, RAX);
+        self.set_cc(cc, i.rd);
+    }
+
+    fn shift_imm(&mut self, w: bool, ext: u8, i: ItypeShift) {
+        self.load_reg(RAX, i.rs1);
+        self.rr(w, &[0xc1], ext, RAX);
+        self.code.push(i.shamt as u8);
+        if !w {
+            self.sext_w();
+        }
+        self.store_reg(i.rd, RAX);
+    }
+
+    /// Shift by rs2, which x86 masks to the low 5 or 6 bits like RISC-V
+    fn shift(&mut self, w: bool, ext: u8, i: ItypeOp) {
+        self.load_reg(RAX, i.rs1);
+        self.load_reg(RCX, i.rs2);
+        self.rr(w, &[0xd3], ext, RAX);
+        if !w {
+            self.sext_w();
+        }
+        self.store_reg(i.rd, RAX);
+    }
+
+    /// rs1 + imm in rax
+    fn address(&mut self, rs1: Register, imm: i32) {
+        self.load_reg(RAX, rs1);
+        if imm != 0 {
+            self.rr(true, &[0x81], ALU_ADD, RAX);
+            self.bytes(&imm.to_le_bytes());
+        }
+    }
+
+    fn load(&mut self, helper: jit::LoadFn, i: Itype, pc: u64, k: u32) {
+        self.address(i.rs1, i.imm);
+        self.mov(RSI, RAX);
+        self.call(helper as usize);
+        self.rr(true, &[0x85], RAX, RAX);
+        let ok = self.jcc(CC_E);
+        self.bail(pc, k);
+        self.patch(ok);
+        self.rm(true, &[0x8b], RAX, R12, CTX_VALUE);
+        self.store_reg(i.rd, RAX);
+    }
+
+    fn store(&mut self, helper: jit::StoreFn, i: Stype, pc: u64, next: u64, k: u32) {
+        self.address(i.rs1, i.imm);
+        self.mov(RSI, RAX);
+        self.load_reg(RDX, i.rs2);
+        self.call(helper as usize);
+        self.rr(true, &[0x85], RAX, RAX);
+        let ok = self.jcc(CC_E);
+        self.rr(true, &[0x83], ALU_CMP, RAX);
+        self.code.push(STORE_FAILED as u8);
+        let written = self.jcc(CC_NE);
+        self.bail(pc, k);
+        // The store changed decoded code, which may be in this block
+        self.patch(written);
+        self.set_pc(next);
+        self.ret(k + 1);
+        self.patch(ok);
+    }
+
+    fn branch(&mut self, cc: u8, i: Btype, pc: u64, next: u64, k: u32) -> bool {
+        let target = pc.wrapping_add(i.imm as i64 as u64);
+        if !target.is_multiple_of(self.ialign) {
+            return false;
+        }
+        self.load_reg(RAX, i.rs1);
+        self.load_reg(RCX, i.rs2);
+        self.rr(true, &[0x39], RCX, RAX);
+        self.mov_imm(RDX, next);
+        self.mov_imm(RCX, target);
+        self.rr(true, &[0x0f, 0x40 | cc], RDX, RCX);
+        self.rm(true, &[0x89], RDX, R12, CTX_PC);
+        self.ret(k + 1);
+        true
+    }
+
+    /// Lowers the `k`th instruction of the block, at `pc`, returning false
+    /// if it is not supported
+    fn lower(&mut self, inst: &Inst, pc: u64, next: u64, k: u32) -> bool {
+        match *inst {
+            Inst::LUI(x) => {
+                self.mov_imm(RAX, x.i.imm as i64 as u64);
+                self.store_reg(x.i.rd, RAX);
+            },
+            Inst::AUIPC(x) => {
+                self.mov_imm(RAX, pc.wrapping_add(x.i.imm as i64 as u64));
+                self.store_reg(x.i.rd, RAX);
+            },
+            Inst::JAL(x) => {
+                let target = pc.wrapping_add(x.i.imm as i64 as u64);
+                if !target.is_multiple_of(self.ialign) {
+                    return false;
+                }
+                self.mov_imm(RAX, next);
+                self.store_reg(x.i.rd, RAX);
+                self.set_pc(target);
+                self.ret(k + 1);
+            },
+            Inst::JALR(x) => {
+                self.address(x.i.rs1, x.i.imm);
+                self.rr(true, &[0x83], ALU_AND, RAX);
+                self.code.push(0xfe);
+                if self.ialign == 4 {
+                    // test al, 2
+                    self.bytes(&[0xa8, 0x02]);
+                    let ok = self.jcc(CC_E);
+                    self.bail(pc, k);
+                    self.patch(ok);
+                }
+                self.rm(true, &[0x89], RAX, R12, CTX_PC);
+                self.mov_imm(RCX, next);
+                self.store_reg(x.i.rd, RCX);
+                self.ret(k + 1);
+            },
+            Inst::BEQ(x) => return self.branch(CC_E, x.i, pc, next, k),
+            Inst::BNE(x) => return self.branch(CC_NE, x.i, pc, next, k),
+            Inst::BLT(x) => return self.branch(CC_L, x.i, pc, next, k),
+            Inst::BGE(x) => return self.branch(CC_GE, x.i, pc, next, k),
+            Inst::BLTU(x) => return self.branch(CC_B, x.i, pc, next, k),
+            Inst::BGEU(x) => return self.branch(CC_AE, x.i, pc, next, k),
+            Inst::LB(x) => self.load(jit::load_i8, x.i, pc, k),
+            Inst::LH(x) => self.load(jit::load_i16, x.i, pc, k),
+            Inst::LW(x) => self.load(jit::load_i32, x.i, pc, k),
+            Inst::LBU(x) => self.load(jit::load_u8, x.i, pc, k),
+            Inst::LHU(x) => self.load(jit::load_u16, x.i, pc, k),
+            Inst::LWU(x) => self.load(jit::load_u32, x.i, pc, k),
+            Inst::LD(x) => self.load(jit::load_u64, x.i, pc, k),
+            Inst::SB(x) => self.store(jit::store_u8, x.i, pc, next, k),
+            Inst::SH(x) => self.store(jit::store_u16, x.i, pc, next, k),
+            Inst::SW(x) => self.store(jit::store_u32, x.i, pc, next, k),
+            Inst::SD(x) => self.store(jit::store_u64, x.i, pc, next, k),
+            Inst::ADDI(x) => self.alu_imm(true, ALU_ADD, x.i),
+            Inst::XORI(x) => self.alu_imm(true, ALU_XOR, x.i),
+            Inst::ORI(x) => self.alu_imm(true, ALU_OR, x.i),
+            Inst::ANDI(x) => self.alu_imm(true, ALU_AND, x.i),
+            Inst::ADDIW(x) => self.alu_imm(false, ALU_ADD, x.i),
+            Inst::SLTI(x) => self.slt_imm(CC_L, x.i),
+            Inst::SLTIU(x) => self.slt_imm(CC_B, x.i),
+            Inst::SLLI(x) => self.shift_imm(true, SHIFT_SHL, x.i),
+            Inst::SRLI(x) => self.shift_imm(true, SHIFT_SHR, x.i),
+            Inst::SRAI(x) => self.shift_imm(true, SHIFT_SAR, x.i),
+            Inst::SLLIW(x) => self.shift_imm(false, SHIFT_SHL, x.i),
+            Inst::SRLIW(x) => self.shift_imm(false, SHIFT_SHR, x.i),
+            Inst::SRAIW(x) => self.shift_imm(false, SHIFT_SAR, x.i),
+            Inst::ADD(x) => self.alu(true, &[0x01], x.i),
+            Inst::SUB(x) => self.alu(true, &[0x29], x.i),
+            Inst::XOR(x) => self.alu(true, &[0x31], x.i),
+            Inst::OR(x) => self.alu(true, &[0x09], x.i),
+            Inst::AND(x) => self.alu(true, &[0x21], x.i),
+            Inst::MUL(x) => self.alu(true, &[0x0f, 0xaf], x.i),
+            Inst::ADDW(x) => self.alu(false, &[0x01], x.i),
+            Inst::SUBW(x) => self.alu(false, &[0x29], x.i),
+            Inst::MULW(x) => self.alu(false, &[0x0f, 0xaf], x.i),
+            Inst::SLT(x) => self.slt(CC_L, x.i),
+            Inst::SLTU(x) => self.slt(CC_B, x.i),
+            Inst::SLL(x) => self.shift(true, SHIFT_SHL, x.i),
+            Inst::SRL(x) => self.shift(true, SHIFT_SHR, x.i),
+            Inst::SRA(x) => self.shift(true, SHIFT_SAR, x.i),
+            Inst::SLLW(x) => self.shift(false, SHIFT_SHL, x.i),
+            Inst::SRLW(x) => self.shift(false, SHIFT_SHR, x.i),
+            Inst::SRAW(x) => self.shift(false, SHIFT_SAR, x.i),
+            Inst::FENCE(_) => {},
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Compiles the block of `insts` starting at `pc` into `arena`. Compilation
+/// stops at the first instruction that is not supported, which is left to
+/// the interpreter, and None is returned if that is the first one.
+pub fn compile(arena: &mut CodeArena, insts: &[Decoded], pc: u64, isa: &Isa) -> Option<NativeFn> {
+    // Results are not truncated to 32 bits
+    if isa.xlen != Xlen::Rv64 {
+        return None;
+    }
+
+    let mut a = Asm {
+        code: Vec::new(),
+        ialign: if isa.has(Extension::C) { 2 } else { 4 },
+    };
+    a.prologue();
+    let mut pc = pc;
+    for (k, d) in insts.iter().enumerate() {
+        let k = k as u32;
+        let next = pc.wrapping_add(d.len as u64);
+        if !a.lower(&d.inst, pc, next, k) {
+            if k == 0 {
+                return None;
+            }
+            a.bail(pc, k);
+            return arena.alloc(&a.code);
+        }
+        if d.inst.ends_block() {
+            return arena.alloc(&a.code);
+        }
+        pc = next;
+    }
+    // Blocks cut short go on with the next instruction
+    a.set_pc(pc);
+    a.ret(insts.len() as u32);
+    arena.alloc(&a.code)
+}
+
@@ -1,32 +1,207 @@
 mod mmu;
 
 mod common;
+mod block;
 mod riscv;
+mod syscall;
+mod devices;
+#[cfg(feature = "jit")]
+mod jit;
+#[cfg(test)]
+mod test_util;
 
 use std::path::PathBuf;
 use clap::{Arg, App};
-use crate::mmu::Mmu;
-use crate::common::Machine;
-
+use crate::mmu::{Mmu, PhysAddr, Region, VirtAddr, PERM_EXEC, PERM_READ, PERM_WRITE};
+use crate::common::{Machine, VmExit};
+use crate::block::Engine;
+use crate::riscv::isa::Isa;
+use crate::riscv::trap::Exception;
+use crate::riscv::register::{Register, Xlen};
 
+const STACK_SIZE: usize = 64 * 1024;
 
 fn main() {
     let matches = App::new("Emulator")
         .arg(Arg::with_name("input")
              .long("input").
              takes_value(true))
+        .arg(Arg::with_name("bare-metal")
+             .long("bare-metal")
+             .help("Run firmware that handles its own traps instead of emulating syscalls"))
+        .arg(Arg::with_name("vlen")
+             .long("vlen")
+             .takes_value(true)
+             .help("Width of the vector registers in bits, a power of two from 64 to 65536"))
+        .arg(Arg::with_name("engine")
+             .long("engine")
+             .takes_value(true)
+             .possible_values(&[
+                 "step",
+                 "block",
+                 #[cfg(feature = "x86-jit")]
+                 "jit",
+                 #[cfg(feature = "cranelift")]
+                 "cranelift",
+             ])
+             .default_value("step")
+             .help("Run one instruction at a time with a trace, block by block, or block by block as native code"))
+        .arg(Arg::with_name("memory")
+             .long("memory")
+             .takes_value(true)
+             .default_value("ram:0:1M")
+             .help("Comma separated memory regions as kind:base:size, with kind ram or rom, such as ram:0x80000000:16M"))
+        .arg(Arg::with_name("check-uninit")
+             .long("check-uninit")
+             .help("Stop at reads of the stack or heap before they are written"))
+        .arg(Arg::with_name("runs")
+             .long("runs")
+             .takes_value(true)
+             .help("Run the program this many times, each from the state it was loaded in, until a run does not exit with 0"))
+        .arg(Arg::with_name("threads")
+             .long("threads")
+             .takes_value(true)
+             .help("Run the program on this many threads at once, sharing the memory it was loaded in"))
+        .arg(Arg::with_name("isa")
+             .long("isa")
+             .takes_value(true)
+             .help("ISA string such as rv64imac_zicsr_zba, every extension by default"))
         .get_matches();
 
     let myfile = matches.value_of("input").unwrap();
     let path = PathBuf::from(myfile);
 
-    let mut mmu = Mmu::new(1024 * 1024);
-    mmu.load_elf(&path);
+    let mut mmu = Mmu::empty();
+    for region in matches.value_of("memory").unwrap().split(',') {
+        if let Err(e) = region.parse::<Region>().and_then(|r| mmu.map_region(r)) {
+            eprintln!("invalid --memory: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = mmu.load_elf(&path) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    mmu.set_check_uninit(matches.is_present("check-uninit"));
+    // Bare-metal code sets up its own stack
+    let bare_metal = matches.is_present("bare-metal");
+    let stack = if bare_metal {
+        // Firmware manages the memory after its image itself
+        let free = mmu.alloc_next().0;
+        mmu.set_perms(PhysAddr(free), mmu.alloc_limit() - free, PERM_READ | PERM_WRITE | PERM_EXEC);
+        None
+    } else {
+        match mmu.alloc(STACK_SIZE) {
+            Some(stack) => Some(stack),
+            None => {
+                eprintln!("no RAM for the stack");
+                std::process::exit(1);
+            },
+        }
+    };
 
-    let mut machine = Machine::new(mmu);
+    let xlen = mmu.xlen.unwrap_or(Xlen::Rv64);
+    let mut machine = match matches.value_of("isa").map(str::parse::<Isa>) {
+        None => Machine::new(mmu),
+        Some(Ok(isa)) if isa.xlen == xlen => Machine::new_with_isa(mmu, isa),
+        Some(Ok(isa)) => {
+            eprintln!("--isa {} does not match the RV{} input", isa, xlen.bits());
+            std::process::exit(1);
+        },
+        Some(Err(e)) => {
+            eprintln!("invalid --isa: {}", e);
+            std::process::exit(1);
+        },
+    };
+    machine.set_emulate_syscalls(!bare_metal);
+    if let Some(vlen) = matches.value_of("vlen") {
+        match vlen.parse::<usize>() {
+            Ok(v) if v.is_power_of_two() && (64..=65536).contains(&v) => machine.set_vlen(v),
+            _ => {
+                eprintln!("invalid --vlen {}", vlen);
+                std::process::exit(1);
+            },
+        }
+    }
+    if let Some(stack) = stack {
+        // The startup code reads argc, the argv and envp terminators and the
+        // end of the auxiliary vector from the top of the stack
+        let sp = stack.0 + STACK_SIZE - 32;
+        machine.mmu.write_from(VirtAddr(sp), &[0; 32]).unwrap();
+        machine.set_r(Register::Sp, sp as u64).unwrap();
+    }
     machine.print_state();
 
-    loop {
-        machine.step().unwrap();
+    let runs = match matches.value_of("runs").map(str::parse::<u64>) {
+        None => 1,
+        Some(Ok(n)) if n > 0 => n,
+        _ => {
+            eprintln!("invalid --runs {}", matches.value_of("runs").unwrap());
+            std::process::exit(1);
+        },
+    };
+    let threads = match matches.value_of("threads").map(str::parse::<usize>) {
+        None => 1,
+        Some(Ok(n)) if n > 0 => n,
+        _ => {
+            eprintln!("invalid --threads {}", matches.value_of("threads").unwrap());
+            std::process::exit(1);
+        },
+    };
+
+    let engine = matches.value_of("engine").unwrap().parse::<Engine>().unwrap();
+    if threads > 1 && engine == Engine::Step {
+        // The traces of the threads would be interleaved
+        eprintln!("--threads needs an engine other than step");
+        std::process::exit(1);
+    }
+    let run = move |m: &mut Machine| {
+        if runs > 1 {
+            m.snapshot();
+        }
+        // The first run that does not exit with 0 is the one reported
+        let mut exit = m.run(engine);
+        for _ in 1..runs {
+            if exit != VmExit::Exit(0) {
+                break;
+            }
+            m.reset();
+            exit = m.run(engine);
+        }
+        exit
+    };
+    // Every thread but this one runs a fork
+    let forks: Vec<Machine> = (1..threads).map(|_| machine.fork()).collect();
+    let exits = std::thread::scope(|s| {
+        let workers: Vec<_> = forks.into_iter()
+            .map(|mut m| s.spawn(move || (run(&mut m), m.get_pc())))
+            .collect();
+        let mut exits = vec![(run(&mut machine), machine.get_pc())];
+        exits.extend(workers.into_iter().map(|worker| worker.join().unwrap()));
+        exits
+    });
+    if threads > 1 {
+        for (n, (e, pc)) in exits.iter().enumerate() {
+            eprintln!("thread {} stopped at {:#x}: {:?}", n, pc.0, e);
+        }
+    }
+    // The first thread that did not exit with 0 is the one reported
+    let (exit, pc) = *exits.iter()
+        .find(|(e, _)| *e != VmExit::Exit(0))
+        .unwrap_or(&exits[0]);
+    match exit {
+        VmExit::Exit(code) => std::process::exit(code as i32),
+        VmExit::UninitFault(addr) => {
+            eprintln!("uninitialized read of {:#x} at {:#x}", addr.0, pc.0);
+            std::process::exit(1);
+        },
+        VmExit::Exception(Exception::Breakpoint, pc) => {
+            eprintln!("breakpoint at {:#x}", pc);
+            std::process::exit(1);
+        },
+        e => {
+            eprintln!("stopped at {:#x}: {:?}", pc.0, e);
+            std::process::exit(1);
+        },
     }
 }
@@ -1,74 +1,1080 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
-use std::convert::TryInto;
+use std::str::FromStr;
+use std::sync::Arc;
 
+use crate::common::VmExit;
+use crate::devices::Device;
+use crate::riscv::csr::Privilege;
+use crate::riscv::register::Xlen;
+use crate::riscv::trap::Exception;
 
+
+/// Address as seen by the guest, translated through the page tables when
+/// paging is enabled
 #[repr(transparent)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct VirtAddr(pub usize);
 
+/// Guest physical address, in memory regions or device registers
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PhysAddr(pub usize);
+
+pub const PAGE_SIZE: usize = 4096;
+
+// Permission bits of a byte of memory
+pub const PERM_READ: u8 = 1 << 0;
+pub const PERM_WRITE: u8 = 1 << 1;
+pub const PERM_EXEC: u8 = 1 << 2;
+/// Becomes readable once written, for memory that starts out uninitialized
+pub const PERM_RAW: u8 = 1 << 3;
+
+/// Kind of memory access, which selects the permission that is checked and
+/// the fault reported when it fails
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    /// Permission the access needs on every byte it touches
+    fn perm(self) -> u8 {
+        match self {
+            Access::Read => PERM_READ,
+            Access::Write => PERM_WRITE,
+            Access::Execute => PERM_EXEC,
+        }
+    }
+
+    fn access_fault(self, addr: VirtAddr) -> VmExit {
+        match self {
+            Access::Read => VmExit::ReadFault(addr),
+            Access::Write => VmExit::WriteFault(addr),
+            Access::Execute => VmExit::ExecFault(addr),
+        }
+    }
+
+    fn page_fault(self, addr: VirtAddr) -> VmExit {
+        let e = match self {
+            Access::Read => Exception::LoadPageFault,
+            Access::Write => Exception::StorePageFault,
+            Access::Execute => Exception::InstructionPageFault,
+        };
+        VmExit::Exception(e, addr.0 as u64)
+    }
+}
+
+/// Translation scheme selected by the MODE field of satp
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PagingMode {
+    Bare,
+    Sv32,
+    Sv39,
+    Sv48,
+}
+
+impl PagingMode {
+    /// Decodes MODE, which is the top bit of satp in RV32 and the top four
+    /// bits in RV64
+    pub fn from_satp(satp: u64, xlen: Xlen) -> Option<Self> {
+        match xlen {
+            Xlen::Rv32 if (satp >> 31) & 1 == 0 => Some(PagingMode::Bare),
+            Xlen::Rv32 => Some(PagingMode::Sv32),
+            Xlen::Rv64 => match satp >> 60 {
+                0 => Some(PagingMode::Bare),
+                8 => Some(PagingMode::Sv39),
+                9 => Some(PagingMode::Sv48),
+                _ => None,
+            },
+        }
+    }
+
+    fn levels(self) -> usize {
+        match self {
+            PagingMode::Bare => 0,
+            PagingMode::Sv32 => 2,
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+        }
+    }
+
+    /// Bits of the virtual page number translated per level, which is also
+    /// log2 of the number of PTEs in a page table
+    fn index_bits(self) -> usize {
+        match self {
+            PagingMode::Sv32 => 10,
+            _ => 9,
+        }
+    }
+
+    fn pte_size(self) -> usize {
+        match self {
+            PagingMode::Sv32 => 4,
+            _ => 8,
+        }
+    }
+}
+
+/// Address translation state, derived by `Machine` from satp, mstatus and
+/// the current privilege level
+#[derive(Clone, Copy, Debug)]
+pub struct Paging {
+    pub mode: PagingMode,
+    /// Root page table
+    pub root: PhysAddr,
+    pub asid: u16,
+    /// Privilege of instruction fetches
+    pub fetch_privilege: Privilege,
+    /// Privilege of loads and stores, which differs from the fetch privilege
+    /// with mstatus.MPRV
+    pub data_privilege: Privilege,
+    /// Supervisor access to user pages (mstatus.SUM)
+    pub sum: bool,
+    /// Loads from executable pages (mstatus.MXR)
+    pub mxr: bool,
+}
+
+impl Paging {
+    /// No translation, as after reset
+    pub fn bare() -> Self {
+        Paging {
+            mode: PagingMode::Bare,
+            root: PhysAddr(0),
+            asid: 0,
+            fetch_privilege: Privilege::Machine,
+            data_privilege: Privilege::Machine,
+            sum: false,
+            mxr: false,
+        }
+    }
+
+    /// Page table, address space and privilege instruction fetches are
+    /// translated and checked with, or None if they are not translated
+    pub fn fetch_space(&self) -> Option<(PagingMode, PhysAddr, u16, Privilege)> {
+        if self.mode == PagingMode::Bare || self.fetch_privilege == Privilege::Machine {
+            None
+        } else {
+            Some((self.mode, self.root, self.asid, self.fetch_privilege))
+        }
+    }
+}
+
+// Page table entry bits
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_G: u64 = 1 << 5;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+/// Cached translation of a 4 KiB virtual page. Superpages get one entry
+/// per 4 KiB page that is used.
+#[derive(Clone, Copy, Debug)]
+struct TlbEntry {
+    page: PhysAddr,
+    /// Low bits of the leaf PTE, as updated with the A and D bits
+    flags: u64,
+    /// Mask of the virtual page number bits within the leaf, nonzero for
+    /// superpages, which have an entry for every page used
+    span: usize,
+}
+
+/// Address space and virtual page number of a `TlbEntry`, with no address
+/// space for global mappings
+type TlbKey = (Option<u16>, usize);
+
+/// A device occupying `size` bytes of the physical address space at `base`
+struct Mapping {
+    base: PhysAddr,
+    size: usize,
+    device: Box<dyn Device>,
+    /// Interrupt controller source the device's interrupt line is wired to
+    irq: Option<u32>,
+}
+
+/// Kind of memory backing a `Region`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    Ram,
+    /// Memory filled when the ELF file is loaded, which the guest can read
+    /// and execute but not write
+    Rom,
+}
+
+/// Range of the physical address space backed by memory. Addresses outside
+/// of every region and device fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub base: PhysAddr,
+    pub size: usize,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn ram(base: PhysAddr, size: usize) -> Self {
+        Region { base, size, kind: RegionKind::Ram }
+    }
+
+    pub fn rom(base: PhysAddr, size: usize) -> Self {
+        Region { base, size, kind: RegionKind::Rom }
+    }
+
+    pub fn end(&self) -> usize {
+        self.base.0 + self.size
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.base.0 && addr < self.end()
+    }
+
+    /// Permissions of the memory before the ELF file is loaded
+    fn perms(&self) -> u8 {
+        match self.kind {
+            RegionKind::Ram => PERM_READ | PERM_WRITE | PERM_EXEC,
+            RegionKind::Rom => PERM_READ | PERM_EXEC,
+        }
+    }
+}
+
+/// Parses a size or address, in hex with a 0x prefix or in decimal with an
+/// optional K, M or G suffix
+fn parse_size(s: &str) -> Option<usize> {
+    if let Some(hex) = s.strip_prefix("0x") {
+        return usize::from_str_radix(hex, 16).ok();
+    }
+    let (digits, shift) = match s.as_bytes().last()? {
+        b'K' | b'k' => (&s[..s.len() - 1], 10),
+        b'M' | b'm' => (&s[..s.len() - 1], 20),
+        b'G' | b'g' => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+impl FromStr for Region {
+    type Err = String;
+
+    /// Parses a region such as `ram:0x80000000:16M` or `rom:0x1000:64K`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let (kind, base, size) = match parts[..] {
+            [kind, base, size] => (kind, base, size),
+            _ => return Err(format!("memory region '{}' has to be kind:base:size", s)),
+        };
+        let base = parse_size(base).ok_or_else(|| format!("invalid base in '{}'", s))?;
+        let size = parse_size(size)
+            .filter(|&size| size > 0)
+            .ok_or_else(|| format!("invalid size in '{}'", s))?;
+        match kind {
+            "ram" => Ok(Region::ram(PhysAddr(base), size)),
+            "rom" => Ok(Region::rom(PhysAddr(base), size)),
+            _ => Err(format!("unknown memory kind '{}' in '{}'", kind, s)),
+        }
+    }
+}
+
+/// A page of memory and the permissions of its bytes. Pages are shared
+/// between snapshots and forks, and copied when one of them writes.
+#[derive(Clone)]
+struct Page {
+    data: [u8; PAGE_SIZE],
+    perms: [u8; PAGE_SIZE],
+}
+
+/// Data of pages that were never written
+static ZERO_PAGE: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+/// A page of a region
+#[derive(Clone)]
+enum Frame {
+    /// Zeroed page that was never written, with the same permissions for
+    /// every byte
+    Zero(u8),
+    Page(Arc<Page>),
+}
+
+/// A region and its pages, which are allocated as they are written
+#[derive(Clone)]
+struct Memory {
+    region: Region,
+    frames: Vec<Frame>,
+}
+
+/// State of an `Mmu` that `Mmu::reset` returns to
+struct Snapshot {
+    /// Pages of every region
+    frames: Vec<Vec<Frame>>,
+    devices: Vec<Box<dyn Device>>,
+    cur_alloc: VirtAddr,
+    paging: Paging,
+}
+
 pub struct Mmu {
-    memory: Vec<u8>,
+    /// Regions of memory, which do not overlap
+    memory: Vec<Memory>,
+    /// Region of the last address looked up, which the next one is most
+    /// likely in as well
+    last_region: Cell<usize>,
+    /// Whether allocated memory is readable only once written
+    check_uninit: bool,
+    devices: Vec<Mapping>,
+    /// Levels of the device interrupt lines, by source
+    irq_levels: Vec<(u32, bool)>,
     cur_alloc: VirtAddr,
     pub entry_point : Option<VirtAddr>,
+    /// Register width the loaded ELF file was built for, from its class
+    pub xlen : Option<Xlen>,
+    paging: Paging,
+    /// Translations by address space and virtual page number, so switching
+    /// address spaces keeps the translations of each
+    tlb: HashMap<TlbKey, TlbEntry>,
+    /// Physical pages holding decoded instructions, whose writes are
+    /// recorded in `written_code`
+    code_pages: HashSet<usize>,
+    written_code: Vec<usize>,
+    snapshot: Option<Box<Snapshot>>,
+    /// Pages written since the snapshot as region and page index, with one
+    /// bit per page of each region in `dirty_bits` to list each only once
+    dirty: Vec<(usize, usize)>,
+    dirty_bits: Vec<Vec<u64>>,
 }
 
 #[allow(dead_code)]
 impl Mmu {
+    /// Creates an MMU with `size` bytes of RAM at physical address 0
     pub fn new(size: usize) -> Self {
+        let mut mmu = Mmu::empty();
+        mmu.map_region(Region::ram(PhysAddr(0), size)).unwrap();
+        mmu
+    }
+
+    /// Creates an MMU without memory, to be mapped with `map_region`
+    pub fn empty() -> Self {
         Mmu {
-            memory: vec![0; size],
+            memory: Vec::new(),
+            last_region: Cell::new(0),
+            check_uninit: false,
+            devices: Vec::new(),
+            irq_levels: Vec::new(),
             cur_alloc: VirtAddr(0),
             entry_point: None,
+            xlen: None,
+            paging: Paging::bare(),
+            tlb: HashMap::new(),
+            code_pages: HashSet::new(),
+            written_code: Vec::new(),
+            snapshot: None,
+            dirty: Vec::new(),
+            dirty_bits: Vec::new(),
+        }
+    }
+
+    /// Maps memory for `region`, whose pages are only allocated once they
+    /// are written. Regions have to start at a page boundary and must not
+    /// overlap. `alloc` hands out memory from the first RAM region until
+    /// a program is loaded.
+    pub fn map_region(&mut self, region: Region) -> Result<(), String> {
+        if !region.base.0.is_multiple_of(PAGE_SIZE) {
+            return Err(format!("memory region at {:#x} is not page aligned", region.base.0));
+        }
+        if region.size == 0 {
+            return Err(format!("memory region at {:#x} is empty", region.base.0));
+        }
+        if region.base.0.checked_add(region.size).is_none() {
+            return Err(format!("memory region at {:#x} does not fit in the address space", region.base.0));
+        }
+        if let Some(m) = self.memory.iter().find(|m| m.region.base.0 < region.end() && region.base.0 < m.region.end()) {
+            return Err(format!("memory region at {:#x} overlaps the one at {:#x}", region.base.0, m.region.base.0));
+        }
+        if region.kind == RegionKind::Ram && self.regions().all(|r| r.kind != RegionKind::Ram) {
+            self.cur_alloc = VirtAddr(region.base.0);
+        }
+        self.memory.push(Memory {
+            region,
+            frames: vec![Frame::Zero(region.perms()); region.size.div_ceil(PAGE_SIZE)],
+        });
+        Ok(())
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = Region> + '_ {
+        self.memory.iter().map(|m| m.region)
+    }
+
+    /// Copy of the memory, devices and translation state, sharing the pages
+    /// of memory with `self` until either of them writes to one. The copy
+    /// has no snapshot and no watched code pages.
+    pub fn fork(&self) -> Mmu {
+        Mmu {
+            memory: self.memory.clone(),
+            last_region: self.last_region.clone(),
+            check_uninit: self.check_uninit,
+            devices: self.devices.iter().map(|m| Mapping {
+                base: m.base,
+                size: m.size,
+                device: m.device.clone_box(),
+                irq: m.irq,
+            }).collect(),
+            irq_levels: self.irq_levels.clone(),
+            cur_alloc: self.cur_alloc,
+            entry_point: self.entry_point,
+            xlen: self.xlen,
+            paging: self.paging,
+            tlb: self.tlb.clone(),
+            code_pages: HashSet::new(),
+            written_code: Vec::new(),
+            snapshot: None,
+            dirty: Vec::new(),
+            dirty_bits: Vec::new(),
+        }
+    }
+
+    /// Region and page holding the physical address `addr`, and the offset
+    /// into the page, if `size` bytes from there are memory within that page
+    fn frame_of(&self, addr: PhysAddr, size: usize) -> Option<(usize, usize, usize)> {
+        let end = addr.0.checked_add(size)?;
+        let last = self.last_region.get();
+        let r = match self.memory.get(last) {
+            Some(m) if m.region.contains(addr.0) => last,
+            _ => {
+                let r = self.memory.iter().position(|m| m.region.contains(addr.0))?;
+                self.last_region.set(r);
+                r
+            },
+        };
+        let region = self.memory[r].region;
+        let off = addr.0 % PAGE_SIZE;
+        if end > region.end() || off + size > PAGE_SIZE {
+            return None;
+        }
+        Some((r, (addr.0 - region.base.0) / PAGE_SIZE, off))
+    }
+
+    /// Page `page` of region `r` for writing, allocated or copied first if
+    /// it is not yet owned by `self` alone
+    fn page_mut(&mut self, r: usize, page: usize) -> &mut Page {
+        let frame = &mut self.memory[r].frames[page];
+        if let Frame::Zero(perms) = *frame {
+            *frame = Frame::Page(Arc::new(Page { data: [0; PAGE_SIZE], perms: [perms; PAGE_SIZE] }));
+        }
+        match frame {
+            Frame::Page(p) => Arc::make_mut(p),
+            Frame::Zero(_) => unreachable!(),
         }
     }
 
-    pub fn alloc(&mut self, size: usize) -> VirtAddr {
+    /// End of the RAM region `alloc` hands out memory from
+    pub fn alloc_limit(&self) -> usize {
+        let cur = self.cur_alloc.0;
+        self.regions()
+            .find(|r| r.kind == RegionKind::Ram && cur >= r.base.0 && cur <= r.end())
+            .map_or(cur, |r| r.end())
+    }
+
+    /// Address the next allocation starts at
+    pub fn alloc_next(&self) -> VirtAddr {
+        self.cur_alloc
+    }
+
+    /// Allocates `size` bytes after the loaded program, which become
+    /// readable and writable. With `set_check_uninit` they only become
+    /// readable once they are written. Returns None if they do not fit in
+    /// the RAM region before `alloc_limit`.
+    pub fn alloc(&mut self, size: usize) -> Option<VirtAddr> {
         let r = self.cur_alloc;
-        self.cur_alloc = VirtAddr(self.cur_alloc.0 + size);
-        r
+        let end = r.0.checked_add(size).filter(|&end| end <= self.alloc_limit())?;
+        self.cur_alloc = VirtAddr(end);
+        let perms = if self.check_uninit { PERM_WRITE | PERM_RAW } else { PERM_READ | PERM_WRITE };
+        self.set_perms(PhysAddr(r.0), size, perms);
+        Some(r)
+    }
+
+    /// Makes reads of allocated memory that was not written yet fail with
+    /// `VmExit::UninitFault`, for memory allocated from now on
+    pub fn set_check_uninit(&mut self, check: bool) {
+        self.check_uninit = check;
+    }
+
+    /// Sets the permissions of the memory from `addr` to `addr + size`.
+    /// Decoded instructions there are dropped, in case execution is no
+    /// longer permitted.
+    pub fn set_perms(&mut self, addr: PhysAddr, size: usize, perms: u8) {
+        let end = addr.0.saturating_add(size);
+        for r in 0..self.memory.len() {
+            let region = self.memory[r].region;
+            let (start, stop) = (std::cmp::max(addr.0, region.base.0), std::cmp::min(end, region.end()));
+            let mut a = start;
+            while a < stop {
+                let (page, off) = ((a - region.base.0) / PAGE_SIZE, a % PAGE_SIZE);
+                let len = std::cmp::min(stop - a, PAGE_SIZE - off);
+                let whole = off == 0 && len == std::cmp::min(PAGE_SIZE, region.end() - a);
+                // Pages that already have the permissions stay shared, and
+                // unwritten ones stay unallocated if they are set as a whole
+                let frame = &mut self.memory[r].frames[page];
+                match frame {
+                    Frame::Zero(p) if *p == perms => {},
+                    Frame::Zero(_) if whole => *frame = Frame::Zero(perms),
+                    Frame::Page(p) if p.perms[off..off + len].iter().all(|&p| p == perms) => {},
+                    _ => self.page_mut(r, page).perms[off..off + len].fill(perms),
+                }
+                a += len;
+            }
+            if start < stop {
+                self.code_modified(start, stop);
+                self.mark_dirty(r, start, stop);
+            }
+        }
+    }
+
+    /// Permissions of the byte of memory at `addr`
+    pub fn perms(&self, addr: PhysAddr) -> Option<u8> {
+        let (r, page, off) = self.frame_of(addr, 1)?;
+        match &self.memory[r].frames[page] {
+            Frame::Zero(p) => Some(*p),
+            Frame::Page(p) => Some(p.perms[off]),
+        }
+    }
+
+    /// Checks that `size` bytes of memory at `pa`, mapped at `va`, permit
+    /// `access`, faulting at the first byte that does not. Devices have no
+    /// permissions.
+    fn check_perms(&self, va: VirtAddr, pa: PhysAddr, size: usize, access: Access) -> Result<(), VmExit> {
+        let (r, page, off) = match self.frame_of(pa, size) {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        if access == Access::Write && self.memory[r].region.kind == RegionKind::Rom {
+            return Err(access.access_fault(va));
+        }
+        let perms = match &self.memory[r].frames[page] {
+            Frame::Zero(p) => std::slice::from_ref(p),
+            Frame::Page(p) => &p.perms[off..off + size],
+        };
+        match perms.iter().position(|p| p & access.perm() == 0) {
+            Some(off) => {
+                let addr = VirtAddr(va.0.wrapping_add(off));
+                if access == Access::Read && perms[off] & PERM_RAW != 0 {
+                    Err(VmExit::UninitFault(addr))
+                } else {
+                    Err(access.access_fault(addr))
+                }
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Makes written bytes with `PERM_RAW` readable
+    fn mark_written(&mut self, addr: PhysAddr, size: usize) {
+        if let Some((r, page, off)) = self.frame_of(addr, size) {
+            let raw = match &self.memory[r].frames[page] {
+                Frame::Zero(p) => p & PERM_RAW != 0,
+                Frame::Page(p) => p.perms[off..off + size].iter().any(|p| p & PERM_RAW != 0),
+            };
+            if raw {
+                for p in &mut self.page_mut(r, page).perms[off..off + size] {
+                    if *p & PERM_RAW != 0 {
+                        *p |= PERM_READ;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Maps `device` at physical address `base`. Memory takes precedence
+    /// over devices that overlap it.
+    pub fn map_device(&mut self, base: PhysAddr, size: usize, device: Box<dyn Device>) {
+        self.devices.push(Mapping { base, size, device, irq: None });
+    }
+
+    /// Maps a device whose interrupt line is wired to source `irq` of the
+    /// interrupt controllers
+    pub fn map_device_irq(&mut self, base: PhysAddr, size: usize, device: Box<dyn Device>, irq: u32) {
+        self.devices.push(Mapping { base, size, device, irq: Some(irq) });
+    }
+
+    /// First mapped device of type `T`
+    pub fn device<T: Device>(&self) -> Option<&T> {
+        self.devices.iter()
+            .find_map(|m| (m.device.as_ref() as &dyn std::any::Any).downcast_ref::<T>())
+    }
+
+    /// Ticks every device and routes the device interrupt lines to the
+    /// interrupt controllers, returning the lines raised into mip
+    pub fn tick_devices(&mut self, steps: u64) -> u64 {
+        self.irq_levels.clear();
+        for m in &mut self.devices {
+            m.device.tick(steps);
+            if let Some(irq) = m.irq {
+                self.irq_levels.push((irq, m.device.irq()));
+            }
+        }
+
+        let mut lines = 0;
+        for m in &mut self.devices {
+            for &(irq, level) in &self.irq_levels {
+                m.device.set_source(irq, level);
+            }
+            lines |= m.device.interrupts();
+        }
+        lines
+    }
+
+    /// Device register access of `size` bytes at `addr`, with the offset
+    /// into the device
+    fn device_at(&mut self, addr: PhysAddr, size: usize) -> Option<(&mut dyn Device, usize)> {
+        if size > 8 {
+            return None;
+        }
+        self.devices.iter_mut()
+            .find(|m| addr.0 >= m.base.0 && addr.0 - m.base.0 + size <= m.size)
+            .map(|m| (m.device.as_mut(), addr.0 - m.base.0))
+    }
+
+    /// Reads physical memory or a device register, returning false if
+    /// nothing is mapped at `addr`
+    fn read_phys(&mut self, addr: PhysAddr, buf: &mut [u8], access: Access) -> bool {
+        if let Some(src) = self.phys(addr, buf.len()) {
+            buf.copy_from_slice(src);
+            return true;
+        }
+        if access == Access::Execute {
+            return false;
+        }
+        let size = buf.len();
+        match self.device_at(addr, size).and_then(|(d, off)| d.read(off, size)) {
+            Some(v) => {
+                buf.copy_from_slice(&v.to_le_bytes()[..size]);
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn write_phys(&mut self, addr: PhysAddr, buf: &[u8]) -> bool {
+        if let Some(dst) = self.phys_mut(addr, buf.len()) {
+            dst.copy_from_slice(buf);
+            return true;
+        }
+        let size = buf.len();
+        match self.device_at(addr, size) {
+            Some((d, off)) => {
+                let mut b = [0; 8];
+                b[..size].copy_from_slice(buf);
+                d.write(off, size, u64::from_le_bytes(b))
+            },
+            None => false,
+        }
+    }
+
+    pub fn paging(&self) -> Paging {
+        self.paging
+    }
+
+    pub fn set_paging(&mut self, paging: Paging) {
+        self.paging = paging;
+    }
+
+    /// Drops cached translations, as SFENCE.VMA does. `addr` restricts the
+    /// flush to one page and `asid` to one address space, in which case
+    /// global mappings are kept.
+    pub fn flush_tlb(&mut self, addr: Option<VirtAddr>, asid: Option<u16>) {
+        // An address flushes every page of the leaf it is in
+        self.tlb.retain(|&(space, vpn), e| {
+            let page = addr.is_none_or(|a| (a.0 / PAGE_SIZE) & !e.span == vpn & !e.span);
+            let space = asid.is_none_or(|id| space == Some(id));
+            !(page && space)
+        });
+    }
+
+    /// Watches the physical page `page`, so its next write is reported by
+    /// `take_written_code`
+    pub fn watch_code_page(&mut self, page: usize) {
+        self.code_pages.insert(page);
+    }
+
+    /// Watched code pages written since the last call, which are no longer
+    /// watched
+    pub fn take_written_code(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.written_code)
+    }
+
+    /// Whether a watched code page was written since `take_written_code`
+    /// was last called
+    pub fn code_written(&self) -> bool {
+        !self.written_code.is_empty()
     }
 
-    pub fn read(&self, addr: VirtAddr, size: usize) -> &[u8] {
-        &self.memory[addr.0..addr.0 + size]
+    /// Bounds checked physical memory, within one page
+    pub fn phys(&self, addr: PhysAddr, size: usize) -> Option<&[u8]> {
+        let (r, page, off) = self.frame_of(addr, size)?;
+        match &self.memory[r].frames[page] {
+            Frame::Zero(_) => Some(&ZERO_PAGE[off..off + size]),
+            Frame::Page(p) => Some(&p.data[off..off + size]),
+        }
     }
 
-    pub fn read_u32(&self, addr: VirtAddr) -> u32 {
-        let bts : [u8; 4] = self.memory[addr.0..addr.0 + 4].try_into().unwrap();
-        u32::from_le_bytes(bts)
+    pub fn phys_mut(&mut self, addr: PhysAddr, size: usize) -> Option<&mut [u8]> {
+        let (r, page, off) = self.frame_of(addr, size)?;
+        self.code_modified(addr.0, addr.0 + size);
+        self.mark_dirty(r, addr.0, addr.0 + size);
+        Some(&mut self.page_mut(r, page).data[off..off + size])
     }
 
-    pub fn read_into(&self, addr: VirtAddr, buf: &mut [u8]) {
-        buf.copy_from_slice(&self.memory[addr.0..addr.0 + buf.len()]);
+    /// Records the pages of region `r` between the physical addresses
+    /// `start` and `end` as written, once there is a snapshot
+    fn mark_dirty(&mut self, r: usize, start: usize, end: usize) {
+        let bits = match self.dirty_bits.get_mut(r) {
+            Some(b) => b,
+            None => return,
+        };
+        let base = self.memory[r].region.base.0;
+        for page in (start - base) / PAGE_SIZE..(end - base).div_ceil(PAGE_SIZE) {
+            let (word, bit) = (page / 64, 1 << (page % 64));
+            if bits[word] & bit == 0 {
+                bits[word] |= bit;
+                self.dirty.push((r, page));
+            }
+        }
     }
 
-    pub fn write_from(&mut self, addr: VirtAddr, buf: &[u8]) {
-        self.memory[addr.0..addr.0 + buf.len()].copy_from_slice(buf);
+    /// Saves the memory, permissions and devices, to be restored by `reset`.
+    /// The pages of memory are shared with the snapshot until written.
+    pub fn snapshot(&mut self) {
+        self.snapshot = Some(Box::new(Snapshot {
+            frames: self.memory.iter().map(|m| m.frames.clone()).collect(),
+            devices: self.devices.iter().map(|m| m.device.clone_box()).collect(),
+            cur_alloc: self.cur_alloc,
+            paging: self.paging,
+        }));
+        self.dirty.clear();
+        self.dirty_bits = self.memory.iter().map(|m| vec![0; m.frames.len().div_ceil(64)]).collect();
     }
 
-    pub fn load_section(&mut self, section: &elf::Section) {
+    /// Returns to the state saved by `snapshot`, restoring only the pages
+    /// of memory written since. Returns false without a snapshot.
+    pub fn reset(&mut self) -> bool {
+        let snapshot = match self.snapshot.take() {
+            Some(s) => s,
+            None => return false,
+        };
+        for (r, page) in std::mem::take(&mut self.dirty) {
+            self.memory[r].frames[page] = snapshot.frames[r][page].clone();
+            let start = self.memory[r].region.base.0 + page * PAGE_SIZE;
+            self.code_modified(start, start + PAGE_SIZE);
+            self.dirty_bits[r][page / 64] = 0;
+        }
+        for (m, device) in self.devices.iter_mut().zip(&snapshot.devices) {
+            m.device.restore(device.as_ref());
+        }
+        self.cur_alloc = snapshot.cur_alloc;
+        self.paging = snapshot.paging;
+        self.tlb.clear();
+        self.snapshot = Some(snapshot);
+        true
+    }
+
+    /// Records the watched code pages between the physical addresses
+    /// `start` and `end` as written
+    fn code_modified(&mut self, start: usize, end: usize) {
+        if !self.code_pages.is_empty() {
+            for page in start / PAGE_SIZE..end.div_ceil(PAGE_SIZE) {
+                if self.code_pages.remove(&page) {
+                    self.written_code.push(page);
+                }
+            }
+        }
+    }
+
+    /// Checks the permissions of a leaf PTE for an access at `privilege`
+    fn permitted(&self, pte: u64, access: Access, privilege: Privilege) -> bool {
+        let user = pte & PTE_U != 0;
+        let allowed = match privilege {
+            Privilege::User => user,
+            // Supervisor code never runs from user pages
+            _ => !user || (self.paging.sum && access != Access::Execute),
+        };
+        allowed && match access {
+            Access::Read => pte & PTE_R != 0 || (self.paging.mxr && pte & PTE_X != 0),
+            Access::Write => pte & PTE_W != 0,
+            Access::Execute => pte & PTE_X != 0,
+        }
+    }
+
+    /// Walks the page tables for `addr`, setting the A and D bits of the
+    /// leaf PTE once the access is known to be permitted
+    fn walk(&mut self, addr: VirtAddr, access: Access, privilege: Privilege) -> Result<TlbEntry, VmExit> {
+        let mode = self.paging.mode;
+        let (levels, bits, pte_size) = (mode.levels(), mode.index_bits(), mode.pte_size());
+        let va = addr.0 as u64;
+
+        // Sv39 and Sv48 addresses have to be sign extended from the top
+        // translated bit, Sv32 translates all 32 bits
+        let unused = 64 - (12 + bits * levels);
+        if mode != PagingMode::Sv32 && ((va << unused) as i64 >> unused) as u64 != va {
+            return Err(access.page_fault(addr));
+        }
+
+        let mut table = self.paging.root;
+        for level in (0..levels).rev() {
+            let index = ((va >> (12 + bits * level)) & ((1 << bits) - 1)) as usize;
+            let pte_addr = PhysAddr(table.0 + index * pte_size);
+            let pte = match self.phys(pte_addr, pte_size) {
+                Some(b) => {
+                    let mut pte = [0; 8];
+                    pte[..pte_size].copy_from_slice(b);
+                    u64::from_le_bytes(pte)
+                },
+                None => return Err(access.access_fault(addr)),
+            };
+
+            // Reserved bits and write-only pages are invalid
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte >> 54 != 0 {
+                return Err(access.page_fault(addr));
+            }
+
+            let ppn = (pte >> 10) as usize;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // A, D and U are reserved in pointers to the next level
+                if pte & (PTE_A | PTE_D | PTE_U) != 0 {
+                    return Err(access.page_fault(addr));
+                }
+                table = PhysAddr(ppn * PAGE_SIZE);
+                continue;
+            }
+
+            // Superpages have to be aligned to their size
+            let span = (1 << (bits * level)) - 1;
+            if ppn & span != 0 || !self.permitted(pte, access, privilege) {
+                return Err(access.page_fault(addr));
+            }
+
+            let mut flags = pte | PTE_A;
+            if access == Access::Write {
+                flags |= PTE_D;
+            }
+            if flags != pte {
+                match self.phys_mut(pte_addr, pte_size) {
+                    Some(b) => b.copy_from_slice(&flags.to_le_bytes()[..pte_size]),
+                    None => return Err(access.access_fault(addr)),
+                }
+            }
+
+            let page = (ppn | ((addr.0 / PAGE_SIZE) & span)) * PAGE_SIZE;
+            return Ok(TlbEntry { page: PhysAddr(page), flags: flags & 0x3ff, span });
+        }
+
+        // Ran out of levels on a pointer
+        Err(access.page_fault(addr))
+    }
+
+    /// Translates `addr` for an access of type `access`
+    pub fn translate(&mut self, addr: VirtAddr, access: Access) -> Result<PhysAddr, VmExit> {
+        let privilege = if access == Access::Execute {
+            self.paging.fetch_privilege
+        } else {
+            self.paging.data_privilege
+        };
+        if self.paging.mode == PagingMode::Bare || privilege == Privilege::Machine {
+            return Ok(PhysAddr(addr.0));
+        }
+
+        let vpn = addr.0 / PAGE_SIZE;
+        let cached = self.tlb.get(&(Some(self.paging.asid), vpn))
+            .or_else(|| self.tlb.get(&(None, vpn)))
+            .copied()
+            // The first store to a clean page has to set its D bit
+            .filter(|e| access != Access::Write || e.flags & PTE_D != 0);
+        let entry = match cached {
+            Some(e) if self.permitted(e.flags, access, privilege) => e,
+            Some(_) => return Err(access.page_fault(addr)),
+            None => {
+                let e = self.walk(addr, access, privilege)?;
+                let space = if e.flags & PTE_G != 0 { None } else { Some(self.paging.asid) };
+                self.tlb.insert((space, vpn), e);
+                e
+            },
+        };
+        Ok(PhysAddr(entry.page.0 + addr.0 % PAGE_SIZE))
+    }
+
+    /// Translates every page touched by an access of `size` bytes, calling
+    /// `f` with the physical address and offset of each part
+    fn for_each_page<F>(&mut self, addr: VirtAddr, size: usize, access: Access, mut f: F) -> Result<(), VmExit>
+        where F: FnMut(&mut Self, VirtAddr, PhysAddr, usize, usize) -> Result<(), VmExit> {
+        let mut done = 0;
+        while done < size {
+            let va = VirtAddr(addr.0.wrapping_add(done));
+            let len = std::cmp::min(size - done, PAGE_SIZE - va.0 % PAGE_SIZE);
+            let pa = self.translate(va, access)?;
+            f(self, va, pa, done, len)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, addr: VirtAddr, buf: &mut [u8], access: Access) -> Result<(), VmExit> {
+        self.for_each_page(addr, buf.len(), access, |mmu, va, pa, off, len| {
+            mmu.check_perms(va, pa, len, access)?;
+            if mmu.read_phys(pa, &mut buf[off..off + len], access) {
+                Ok(())
+            } else {
+                Err(access.access_fault(va))
+            }
+        })
+    }
+
+    pub fn read(&mut self, addr: VirtAddr, size: usize) -> Result<Vec<u8>, VmExit> {
+        let mut buf = vec![0; size];
+        self.read_into(addr, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn read_u8(&mut self, addr: VirtAddr) -> Result<u8, VmExit> {
+        let mut b = [0; 1];
+        self.read_into(addr, &mut b)?;
+        Ok(b[0])
+    }
+
+    pub fn read_u16(&mut self, addr: VirtAddr) -> Result<u16, VmExit> {
+        let mut b = [0; 2];
+        self.read_into(addr, &mut b)?;
+        Ok(u16::from_le_bytes(b))
+    }
+
+    pub fn read_u32(&mut self, addr: VirtAddr) -> Result<u32, VmExit> {
+        let mut b = [0; 4];
+        self.read_into(addr, &mut b)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub fn read_u64(&mut self, addr: VirtAddr) -> Result<u64, VmExit> {
+        let mut b = [0; 8];
+        self.read_into(addr, &mut b)?;
+        Ok(u64::from_le_bytes(b))
+    }
+
+    /// Instruction fetch, checked for execute permission
+    pub fn fetch_u16(&mut self, addr: VirtAddr) -> Result<u16, VmExit> {
+        let mut b = [0; 2];
+        self.load(addr, &mut b, Access::Execute)?;
+        Ok(u16::from_le_bytes(b))
+    }
+
+    /// Instruction fetch, checked for execute permission
+    pub fn fetch_u32(&mut self, addr: VirtAddr) -> Result<u32, VmExit> {
+        let mut b = [0; 4];
+        self.load(addr, &mut b, Access::Execute)?;
+        Ok(u32::from_le_bytes(b))
+    }
+
+    pub fn write_u8(&mut self, addr: VirtAddr, val: u8) -> Result<(), VmExit> {
+        self.write_from(addr, &val.to_le_bytes())
+    }
+
+    pub fn write_u16(&mut self, addr: VirtAddr, val: u16) -> Result<(), VmExit> {
+        self.write_from(addr, &val.to_le_bytes())
+    }
+
+    pub fn write_u32(&mut self, addr: VirtAddr, val: u32) -> Result<(), VmExit> {
+        self.write_from(addr, &val.to_le_bytes())
+    }
+
+    pub fn write_u64(&mut self, addr: VirtAddr, val: u64) -> Result<(), VmExit> {
+        self.write_from(addr, &val.to_le_bytes())
+    }
+
+    pub fn read_into(&mut self, addr: VirtAddr, buf: &mut [u8]) -> Result<(), VmExit> {
+        self.load(addr, buf, Access::Read)
+    }
+
+    pub fn write_from(&mut self, addr: VirtAddr, buf: &[u8]) -> Result<(), VmExit> {
+        // Check all pages first, so a fault leaves memory untouched
+        self.for_each_page(addr, buf.len(), Access::Write, |mmu, va, pa, _, len| {
+            mmu.check_perms(va, pa, len, Access::Write)
+        })?;
+        self.for_each_page(addr, buf.len(), Access::Write, |mmu, va, pa, off, len| {
+            if mmu.write_phys(pa, &buf[off..off + len]) {
+                mmu.mark_written(pa, len);
+                Ok(())
+            } else {
+                Err(VmExit::WriteFault(va))
+            }
+        })
+    }
+
+    /// Copies `section` to its address, failing if it is not all memory
+    pub fn load_section(&mut self, section: &elf::Section) -> Result<(), String> {
         let header = &section.shdr;
         let addr = header.addr as usize;
         let size = header.size as usize;
 
-        self.write_from(VirtAddr(addr), &section.data);
-        self.cur_alloc = VirtAddr(std::cmp::max(self.cur_alloc.0, addr + size));
+        let mut done = 0;
+        while done < section.data.len() {
+            let pa = addr + done;
+            let len = std::cmp::min(section.data.len() - done, PAGE_SIZE - pa % PAGE_SIZE);
+            self.phys_mut(PhysAddr(pa), len)
+                .ok_or_else(|| format!("section {} at {:#x} is outside --memory", header.name, addr))?
+                .copy_from_slice(&section.data[done..done + len]);
+            done += len;
+        }
+        // Memory is allocated after the part of the program in RAM
+        if self.regions().any(|r| r.kind == RegionKind::Ram && r.contains(addr)) {
+            self.cur_alloc = VirtAddr(std::cmp::max(self.cur_alloc.0, addr + size));
+        }
+        Ok(())
     }
 
-    pub fn load_elf(&mut self, path : &PathBuf) {
-        let file = elf::File::open_path(&path).unwrap();
+    /// Loads the ELF file at `path`. Sections are copied to their address,
+    /// which is virtual, as physical address, so segments have to be linked
+    /// to load where they run, with the same virtual and physical address.
+    pub fn load_elf(&mut self, path : &PathBuf) -> Result<(), String> {
+        let file = elf::File::open_path(path)
+            .map_err(|e| format!("cannot load {}: {:?}", path.display(), e))?;
         self.entry_point = Some(VirtAddr(file.ehdr.entry as usize));
+        self.xlen = Some(if file.ehdr.class == elf::types::ELFCLASS32 { Xlen::Rv32 } else { Xlen::Rv64 });
+
+        // Only the segments of the program are accessible, with their flags
+        for region in self.regions().collect::<Vec<_>>() {
+            self.set_perms(region.base, region.size, 0);
+        }
+        for phdr in file.phdrs.iter().filter(|p| p.progtype == elf::types::PT_LOAD) {
+            if phdr.paddr != phdr.vaddr {
+                return Err(format!("segment at {:#x} is loaded at {:#x}, which is not supported", phdr.vaddr, phdr.paddr));
+            }
+            let flags = phdr.flags.0;
+            let mut perms = 0;
+            if flags & elf::types::PF_R.0 != 0 {
+                perms |= PERM_READ;
+            }
+            if flags & elf::types::PF_W.0 != 0 {
+                perms |= PERM_WRITE;
+            }
+            if flags & elf::types::PF_X.0 != 0 {
+                perms |= PERM_EXEC;
+            }
+            self.set_perms(PhysAddr(phdr.paddr as usize), phdr.memsz as usize, perms);
+        }
 
         for section in &file.sections {
             if section.shdr.addr > 0 {
-                self.load_section(&section);
+                self.load_section(section)?;
             }
         }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::convert::TryInto;
+    use crate::devices::plic::{Plic, PLIC_BASE, PLIC_SIZE};
+    use crate::riscv::csr;
 
     #[test]
     fn test_read_write() {
@@ -76,12 +1082,367 @@ mod tests {
         let addr = VirtAddr(0);
         let mut mmu = Mmu::new(128);
 
-        mmu.write_from(addr, &dat);
-        assert_eq!(dat, mmu.read(addr, dat.len()));
+        mmu.write_from(addr, &dat).unwrap();
+        assert_eq!(dat, mmu.read(addr, dat.len()).unwrap());
 
         let mut dat2 = vec![0; 4];
-        mmu.read_into(addr, &mut dat2);
+        mmu.read_into(addr, &mut dat2).unwrap();
         assert_eq!(dat, dat2);
 
     }
+
+    #[test]
+    fn test_out_of_bounds() {
+        let mut mmu = Mmu::new(128);
+
+        assert_eq!(mmu.read_u32(VirtAddr(126)), Err(VmExit::ReadFault(VirtAddr(126))));
+        assert_eq!(mmu.write_u8(VirtAddr(128), 0), Err(VmExit::WriteFault(VirtAddr(128))));
+        assert_eq!(mmu.fetch_u16(VirtAddr(usize::MAX)), Err(VmExit::ExecFault(VirtAddr(usize::MAX))));
+    }
+
+    #[test]
+    fn test_permissions() {
+        let mut mmu = Mmu::new(0x3000);
+        mmu.set_perms(PhysAddr(0), 0x1000, PERM_READ | PERM_EXEC);
+        mmu.set_perms(PhysAddr(0x1000), 0x1000, PERM_READ | PERM_WRITE);
+        mmu.set_perms(PhysAddr(0x2000), 0x1000, 0);
+
+        assert_eq!(mmu.fetch_u32(VirtAddr(0x10)), Ok(0));
+        assert_eq!(mmu.write_u8(VirtAddr(0x10), 1), Err(VmExit::WriteFault(VirtAddr(0x10))));
+        assert_eq!(mmu.fetch_u16(VirtAddr(0xffe)), Ok(0));
+        assert_eq!(mmu.fetch_u32(VirtAddr(0xffe)), Err(VmExit::ExecFault(VirtAddr(0x1000))));
+
+        // Faults are reported at the first byte without permission and
+        // leave memory untouched
+        assert_eq!(mmu.write_u32(VirtAddr(0x1ffe), !0), Err(VmExit::WriteFault(VirtAddr(0x2000))));
+        assert_eq!(mmu.read_u16(VirtAddr(0x1ffe)), Ok(0));
+        assert_eq!(mmu.read_u64(VirtAddr(0x1ffc)), Err(VmExit::ReadFault(VirtAddr(0x2000))));
+
+        assert_eq!(mmu.perms(PhysAddr(0x3000)), None);
+    }
+
+    #[test]
+    fn test_uninitialized() {
+        let mut mmu = Mmu::new(0x1000);
+        let zeroed = mmu.alloc(0x10).unwrap();
+        assert_eq!(mmu.read_u8(zeroed), Ok(0));
+        mmu.set_check_uninit(true);
+        let heap = mmu.alloc(0x100).unwrap().0;
+        assert_eq!(mmu.read_u8(VirtAddr(heap)), Err(VmExit::UninitFault(VirtAddr(heap))));
+
+        mmu.write_u16(VirtAddr(heap + 2), 0x1234).unwrap();
+        assert_eq!(mmu.read_u16(VirtAddr(heap + 2)), Ok(0x1234));
+        assert_eq!(mmu.read_u32(VirtAddr(heap + 2)), Err(VmExit::UninitFault(VirtAddr(heap + 4))));
+        assert_eq!(mmu.fetch_u16(VirtAddr(heap + 2)), Err(VmExit::ExecFault(VirtAddr(heap + 2))));
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut mmu = Mmu::new(4 * PAGE_SIZE);
+        mmu.write_u32(VirtAddr(0x10), 0x1234).unwrap();
+        mmu.set_check_uninit(true);
+        mmu.snapshot();
+
+        let heap = mmu.alloc(0x10).unwrap();
+        mmu.write_u32(heap, 1).unwrap();
+        mmu.write_u32(VirtAddr(0x10), 0x5678).unwrap();
+        mmu.write_u16(VirtAddr(2 * PAGE_SIZE - 1), !0).unwrap();
+        mmu.set_perms(PhysAddr(0x10), 4, PERM_READ);
+        assert!(mmu.reset());
+
+        assert_eq!(mmu.read_u32(VirtAddr(0x10)), Ok(0x1234));
+        assert_eq!(mmu.read_u16(VirtAddr(2 * PAGE_SIZE - 1)), Ok(0));
+        assert_eq!(mmu.perms(PhysAddr(0x10)), Some(PERM_READ | PERM_WRITE | PERM_EXEC));
+        // The allocation is undone, so the same memory is handed out again
+        // and is uninitialized once more
+        assert_eq!(mmu.alloc(0x10), Some(heap));
+        assert_eq!(mmu.read_u8(heap), Err(VmExit::UninitFault(heap)));
+
+        // Blocks are tracked again after a reset
+        mmu.write_u32(VirtAddr(0x10), 0x5678).unwrap();
+        assert!(mmu.reset());
+        assert_eq!(mmu.read_u32(VirtAddr(0x10)), Ok(0x1234));
+    }
+
+    /// Whether page `page` of the first region is the same for both
+    fn shared(a: &Mmu, b: &Mmu, page: usize) -> bool {
+        match (&a.memory[0].frames[page], &b.memory[0].frames[page]) {
+            (Frame::Zero(p), Frame::Zero(q)) => p == q,
+            (Frame::Page(p), Frame::Page(q)) => Arc::ptr_eq(p, q),
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_fork() {
+        let mut mmu = Mmu::new(4 * PAGE_SIZE);
+        mmu.write_u32(VirtAddr(0x10), 0x1234).unwrap();
+        let mut child = mmu.fork();
+        assert!(shared(&mmu, &child, 0));
+
+        // Writes copy the page, for the parent as well as the child
+        child.write_u32(VirtAddr(0x10), 0x5678).unwrap();
+        assert!(!shared(&mmu, &child, 0));
+        mmu.write_u8(VirtAddr(PAGE_SIZE), 1).unwrap();
+        assert!(!shared(&mmu, &child, 1));
+        assert!(shared(&mmu, &child, 2));
+
+        assert_eq!(mmu.read_u32(VirtAddr(0x10)), Ok(0x1234));
+        assert_eq!(child.read_u32(VirtAddr(0x10)), Ok(0x5678));
+        assert_eq!(child.read_u8(VirtAddr(PAGE_SIZE)), Ok(0));
+    }
+
+    #[test]
+    fn test_regions() {
+        let mut mmu = Mmu::empty();
+        mmu.map_region("rom:0x1000:4K".parse().unwrap()).unwrap();
+        mmu.map_region("ram:0x80000000:1M".parse().unwrap()).unwrap();
+        assert!(mmu.map_region(Region::ram(PhysAddr(0x80080000), 0x1000)).is_err());
+        assert!(mmu.map_region(Region::ram(PhysAddr(0x3001), 0x1000)).is_err());
+        assert_eq!("ram:0:64k".parse(), Ok(Region::ram(PhysAddr(0), 0x10000)));
+        assert!("flash:0:1M".parse::<Region>().is_err());
+
+        // Memory is handed out from the RAM region
+        assert_eq!(mmu.alloc(0x10), Some(VirtAddr(0x80000000)));
+        assert_eq!(mmu.alloc_limit(), 0x80100000);
+        assert_eq!(mmu.alloc(0x100000), None);
+        assert_eq!(mmu.alloc_next(), VirtAddr(0x80000010));
+
+        // Empty regions are rejected, and without RAM nothing is allocated
+        assert!(mmu.map_region(Region::ram(PhysAddr(0x4000), 0)).is_err());
+        assert!("ram:0x4000:0".parse::<Region>().is_err());
+        let mut rom = Mmu::empty();
+        rom.map_region("rom:0:1M".parse().unwrap()).unwrap();
+        assert_eq!(rom.alloc(1), None);
+
+        // Holes fault and the ROM can only be written by the loader
+        assert_eq!(mmu.read_u8(VirtAddr(0x3000)), Err(VmExit::ReadFault(VirtAddr(0x3000))));
+        assert_eq!(mmu.write_u8(VirtAddr(0x80100000), 1), Err(VmExit::WriteFault(VirtAddr(0x80100000))));
+        mmu.phys_mut(PhysAddr(0x1000), 4).unwrap().copy_from_slice(&[0x13, 0, 0, 0]);
+        assert_eq!(mmu.fetch_u32(VirtAddr(0x1000)), Ok(0x13));
+        assert_eq!(mmu.write_u32(VirtAddr(0x1000), 0), Err(VmExit::WriteFault(VirtAddr(0x1000))));
+
+        // Pages are only allocated once written, permissions set on whole
+        // pages leave them unallocated. The first page got the permissions
+        // of the allocation.
+        mmu.set_perms(PhysAddr(0x80000000), 0x100000, PERM_READ | PERM_WRITE);
+        assert_eq!(mmu.read_u64(VirtAddr(0x80042000)), Ok(0));
+        assert_eq!(mmu.perms(PhysAddr(0x80042000)), Some(PERM_READ | PERM_WRITE));
+        assert!(mmu.memory[1].frames[1..].iter().all(|f| matches!(f, Frame::Zero(_))));
+        mmu.write_u64(VirtAddr(0x80042000), 1).unwrap();
+        let allocated = mmu.memory[1].frames.iter().filter(|f| matches!(f, Frame::Page(_))).count();
+        assert_eq!(allocated, 2);
+    }
+
+    fn section(name: &str, addr: u64, data: &[u8]) -> elf::Section {
+        elf::Section {
+            shdr: elf::types::SectionHeader {
+                name: name.to_string(),
+                shtype: elf::types::SHT_PROGBITS,
+                flags: elf::types::SHF_ALLOC,
+                addr,
+                offset: 0,
+                size: data.len() as u64,
+                link: 0,
+                info: 0,
+                addralign: 1,
+                entsize: 0,
+            },
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_load_section() {
+        let mut mmu = Mmu::empty();
+        mmu.map_region("rom:0x1000:4K".parse().unwrap()).unwrap();
+        mmu.map_region("ram:0x80000000:1M".parse().unwrap()).unwrap();
+
+        mmu.load_section(&section(".text", 0x1ffe, &[1, 2])).unwrap();
+        mmu.load_section(&section(".data", 0x80000ffe, &[3, 4, 5, 6])).unwrap();
+        assert_eq!(mmu.phys(PhysAddr(0x1ffe), 2), Some(&[1, 2][..]));
+        assert_eq!(mmu.phys(PhysAddr(0x80001000), 2), Some(&[5, 6][..]));
+        assert_eq!(mmu.alloc_next(), VirtAddr(0x80001002));
+
+        // Sections in a hole or running past the end of a region fail
+        assert_eq!(mmu.load_section(&section(".bss", 0x3000, &[0])),
+                   Err("section .bss at 0x3000 is outside --memory".to_string()));
+        assert_eq!(mmu.load_section(&section(".rodata", 0x1fff, &[1, 2])),
+                   Err("section .rodata at 0x1fff is outside --memory".to_string()));
+    }
+
+    /// Device with one register that sets its interrupt line
+    #[derive(Clone)]
+    struct Irq(bool);
+
+    impl Device for Irq {
+        fn read(&mut self, _offset: usize, _size: usize) -> Option<u64> {
+            Some(self.0 as u64)
+        }
+
+        fn write(&mut self, _offset: usize, _size: usize, value: u64) -> bool {
+            self.0 = value != 0;
+            true
+        }
+
+        fn irq(&self) -> bool {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_devices() {
+        let mut mmu = Mmu::new(128);
+        mmu.map_device(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new()));
+        mmu.map_device_irq(PhysAddr(0x1000), 8, Box::new(Irq(false)), 10);
+
+        let plic = PLIC_BASE.0;
+        mmu.write_u32(VirtAddr(plic + 10 * 4), 1).unwrap();
+        mmu.write_u32(VirtAddr(plic + 0x2000), 1 << 10).unwrap();
+        assert_eq!(mmu.tick_devices(1), 0);
+        mmu.snapshot();
+
+        mmu.write_u8(VirtAddr(0x1000), 1).unwrap();
+        assert_eq!(mmu.read_u8(VirtAddr(0x1000)), Ok(1));
+        assert_eq!(mmu.tick_devices(1), csr::MIP_MEIP);
+        assert_eq!(mmu.read_u32(VirtAddr(plic + 0x20_0004)), Ok(10));
+        assert_eq!(mmu.tick_devices(1), 0);
+
+        assert_eq!(mmu.read_u64(VirtAddr(0x1004)), Err(VmExit::ReadFault(VirtAddr(0x1004))));
+        assert_eq!(mmu.fetch_u16(VirtAddr(0x1000)), Err(VmExit::ExecFault(VirtAddr(0x1000))));
+
+        // Devices are restored with the memory
+        mmu.write_u8(VirtAddr(0x1000), 1).unwrap();
+        assert!(mmu.reset());
+        assert_eq!(mmu.read_u8(VirtAddr(0x1000)), Ok(0));
+        assert_eq!(mmu.tick_devices(1), 0);
+    }
+
+    fn set_pte(mmu: &mut Mmu, addr: usize, pte: u64) {
+        mmu.phys_mut(PhysAddr(addr), 8).unwrap().copy_from_slice(&pte.to_le_bytes());
+    }
+
+    fn get_pte(mmu: &Mmu, addr: usize) -> u64 {
+        u64::from_le_bytes(mmu.phys(PhysAddr(addr), 8).unwrap().try_into().unwrap())
+    }
+
+    #[test]
+    fn test_sv39() {
+        let mut mmu = Mmu::new(0x10000);
+        let mut paging = Paging {
+            mode: PagingMode::Sv39,
+            root: PhysAddr(0x1000),
+            asid: 1,
+            fetch_privilege: Privilege::Supervisor,
+            data_privilege: Privilege::Supervisor,
+            sum: false,
+            mxr: false,
+        };
+        mmu.set_paging(paging);
+
+        // 0x4000_0000: 1 GiB read-only user superpage at physical 0
+        set_pte(&mut mmu, 0x1008, PTE_V | PTE_R | PTE_U);
+        // 0x0: pointers to a level 0 table at 0x3000
+        set_pte(&mut mmu, 0x1000, ((0x2000 >> 12) << 10) | PTE_V);
+        set_pte(&mut mmu, 0x2000, ((0x3000 >> 12) << 10) | PTE_V);
+        // Two writable pages and a global execute-only one
+        set_pte(&mut mmu, 0x3000, ((0x8000 >> 12) << 10) | PTE_V | PTE_R | PTE_W);
+        set_pte(&mut mmu, 0x3008, ((0x9000 >> 12) << 10) | PTE_V | PTE_R | PTE_W);
+        set_pte(&mut mmu, 0x3010, ((0xa000 >> 12) << 10) | PTE_V | PTE_X | PTE_G);
+        mmu.phys_mut(PhysAddr(0xa000), 2).unwrap().copy_from_slice(&[0x01, 0x00]);
+
+        assert_eq!(mmu.translate(VirtAddr(0x10), Access::Read), Ok(PhysAddr(0x8010)));
+        assert_eq!(get_pte(&mmu, 0x3000) & (PTE_A | PTE_D), PTE_A);
+        mmu.write_u32(VirtAddr(0xffe), 0x1234_5678).unwrap();
+        assert_eq!(get_pte(&mmu, 0x3000) & (PTE_A | PTE_D), PTE_A | PTE_D);
+        assert_eq!(mmu.phys(PhysAddr(0x8ffe), 2), Some(&[0x78, 0x56][..]));
+        assert_eq!(mmu.phys(PhysAddr(0x9000), 2), Some(&[0x34, 0x12][..]));
+
+        // Permissions
+        let fault = |e, a: usize| VmExit::Exception(e, a as u64);
+        assert_eq!(mmu.read_u8(VirtAddr(0x2000)), Err(fault(Exception::LoadPageFault, 0x2000)));
+        assert_eq!(mmu.write_u8(VirtAddr(0x2000), 0), Err(fault(Exception::StorePageFault, 0x2000)));
+        assert_eq!(mmu.fetch_u16(VirtAddr(0x2000)), Ok(1));
+        assert_eq!(mmu.fetch_u16(VirtAddr(0x1000)), Err(fault(Exception::InstructionPageFault, 0x1000)));
+        assert_eq!(mmu.read_u8(VirtAddr(0x4000_0010)), Err(fault(Exception::LoadPageFault, 0x4000_0010)));
+        assert_eq!(mmu.read_u8(VirtAddr(1 << 40)), Err(fault(Exception::LoadPageFault, 1 << 40)));
+
+        paging.sum = true;
+        paging.mxr = true;
+        mmu.set_paging(paging);
+        assert_eq!(mmu.translate(VirtAddr(0x4000_3010), Access::Read), Ok(PhysAddr(0x3010)));
+        assert_eq!(mmu.read_u16(VirtAddr(0x2000)), Ok(1));
+
+        // Flushing one address of a superpage flushes all of its pages
+        assert_eq!(mmu.translate(VirtAddr(0x4020_0010), Access::Read), Ok(PhysAddr(0x20_0010)));
+        set_pte(&mut mmu, 0x1008, ((0x4000_0000 >> 12) << 10) | PTE_V | PTE_R | PTE_U | PTE_A);
+        mmu.flush_tlb(Some(VirtAddr(0x4000_3000)), Some(1));
+        assert_eq!(mmu.translate(VirtAddr(0x4020_0010), Access::Read), Ok(PhysAddr(0x4020_0010)));
+        assert_eq!(mmu.translate(VirtAddr(0x4000_3010), Access::Read), Ok(PhysAddr(0x4000_3010)));
+
+        // Stale until flushed
+        set_pte(&mut mmu, 0x3000, ((0xb000 >> 12) << 10) | PTE_V | PTE_R | PTE_A);
+        assert_eq!(mmu.translate(VirtAddr(0), Access::Read), Ok(PhysAddr(0x8000)));
+        mmu.flush_tlb(Some(VirtAddr(0)), Some(1));
+        assert_eq!(mmu.translate(VirtAddr(0), Access::Read), Ok(PhysAddr(0xb000)));
+
+        // Global mappings survive an ASID flush
+        paging.asid = 2;
+        mmu.set_paging(paging);
+        set_pte(&mut mmu, 0x3010, ((0xc000 >> 12) << 10) | PTE_V | PTE_X | PTE_G);
+        mmu.flush_tlb(None, Some(2));
+        assert_eq!(mmu.translate(VirtAddr(0x2000), Access::Execute), Ok(PhysAddr(0xa000)));
+        mmu.flush_tlb(None, None);
+        assert_eq!(mmu.translate(VirtAddr(0x2000), Access::Execute), Ok(PhysAddr(0xc000)));
+
+        // Every address space keeps its own translations
+        assert_eq!(mmu.translate(VirtAddr(0), Access::Read), Ok(PhysAddr(0xb000)));
+        set_pte(&mut mmu, 0x3000, ((0xd000 >> 12) << 10) | PTE_V | PTE_R | PTE_A);
+        paging.asid = 3;
+        mmu.set_paging(paging);
+        assert_eq!(mmu.translate(VirtAddr(0), Access::Read), Ok(PhysAddr(0xd000)));
+        paging.asid = 2;
+        mmu.set_paging(paging);
+        assert_eq!(mmu.translate(VirtAddr(0), Access::Read), Ok(PhysAddr(0xb000)));
+
+        // Pointers to the next level must not have A, D or U set
+        for bit in [PTE_A, PTE_D, PTE_U] {
+            set_pte(&mut mmu, 0x1010, ((0x2000 >> 12) << 10) | PTE_V | bit);
+            assert_eq!(mmu.read_u8(VirtAddr(0x8000_0000)), Err(fault(Exception::LoadPageFault, 0x8000_0000)));
+        }
+        set_pte(&mut mmu, 0x1010, ((0x2000 >> 12) << 10) | PTE_V);
+        assert_eq!(mmu.translate(VirtAddr(0x8000_0000), Access::Read), Ok(PhysAddr(0xd000)));
+    }
+
+    #[test]
+    fn test_sv32() {
+        let mut mmu = Mmu::new(0x10000);
+        mmu.set_paging(Paging {
+            mode: PagingMode::Sv32,
+            root: PhysAddr(0x1000),
+            asid: 0,
+            fetch_privilege: Privilege::Supervisor,
+            data_privilege: Privilege::Supervisor,
+            sum: false,
+            mxr: false,
+        });
+
+        // 0x40_0000: 4 MiB megapage at physical 0, 0x0: pointer to a level
+        // 0 table at 0x2000 with a page at 0x8000
+        let set = |mmu: &mut Mmu, addr: usize, pte: u32| {
+            mmu.phys_mut(PhysAddr(addr), 4).unwrap().copy_from_slice(&pte.to_le_bytes());
+        };
+        set(&mut mmu, 0x1004, (PTE_V | PTE_R | PTE_W) as u32);
+        set(&mut mmu, 0x1000, (((0x2000 >> 12) << 10) | PTE_V) as u32);
+        set(&mut mmu, 0x2008, (((0x8000 >> 12) << 10) | PTE_V | PTE_R) as u32);
+
+        assert_eq!(mmu.translate(VirtAddr(0x2010), Access::Read), Ok(PhysAddr(0x8010)));
+        assert_eq!(mmu.translate(VirtAddr(0x40_1234), Access::Write), Ok(PhysAddr(0x1234)));
+        assert_eq!(mmu.phys(PhysAddr(0x1004), 4), Some(&[0xc7, 0, 0, 0][..]));
+        assert_eq!(mmu.phys(PhysAddr(0x1008), 4), Some(&[0, 0, 0, 0][..]));
+
+        // All 32 bits are translated, there is no sign extension
+        let fault = VmExit::Exception(Exception::LoadPageFault, 0x8000_2010);
+        assert_eq!(mmu.translate(VirtAddr(0x8000_2010), Access::Read), Err(fault));
+    }
 }
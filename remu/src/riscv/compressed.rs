@@ -0,0 +1,303 @@
+// Expansion of RVC instructions into their 32-bit base equivalents, so the
+// regular decoder and instruction implementations can be reused.
+
+use crate::common::VmExit;
+use crate::riscv::register::Xlen;
+
+/// Returns the length in bytes of the instruction starting with `parcel`
+pub fn instruction_length(parcel: u16) -> usize {
+    if parcel & 0b11 == 0b11 { 4 } else { 2 }
+}
+
+fn bits(i: u32, hi: u32, lo: u32) -> u32 {
+    (i >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Sign extends the lowest `width` bits of `v`
+fn sext(v: u32, width: u32) -> u32 {
+    (((v << (32 - width)) as i32) >> (32 - width)) as u32
+}
+
+fn rtype(funct7: u32, rs2: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn itype(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm & 0xfff) << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | opcode
+}
+
+fn stype(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    (bits(imm, 11, 5) << 25) | (rs2 << 20) | (rs1 << 15) | (funct3 << 12) |
+        (bits(imm, 4, 0) << 7) | opcode
+}
+
+fn btype(imm: u32, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    (bits(imm, 12, 12) << 31) | (bits(imm, 10, 5) << 25) | (rs2 << 20) |
+        (rs1 << 15) | (funct3 << 12) | (bits(imm, 4, 1) << 8) |
+        (bits(imm, 11, 11) << 7) | 0b1100011
+}
+
+fn jtype(imm: u32, rd: u32) -> u32 {
+    (bits(imm, 20, 20) << 31) | (bits(imm, 10, 1) << 21) |
+        (bits(imm, 11, 11) << 20) | (bits(imm, 19, 12) << 12) | (rd << 7) |
+        0b1101111
+}
+
+const OP_LOAD:     u32 = 0b0000011;
+const OP_LOAD_FP:  u32 = 0b0000111;
+const OP_STORE:    u32 = 0b0100011;
+const OP_STORE_FP: u32 = 0b0100111;
+const OP_IMM:      u32 = 0b0010011;
+const OP_IMM_32:   u32 = 0b0011011;
+const OP:          u32 = 0b0110011;
+const OP_32:       u32 = 0b0111011;
+const OP_LUI:      u32 = 0b0110111;
+const OP_JALR:     u32 = 0b1100111;
+const OP_SYSTEM:   u32 = 0b1110011;
+
+const SP: u32 = 2;
+const RA: u32 = 1;
+
+/// Expands `c` into the equivalent 32-bit instruction. Some encodings differ
+/// between RV32C and RV64C, which replaces the single-precision loads and
+/// stores with doubleword ones and C.JAL with C.ADDIW.
+pub fn expand_compressed(c: u16, xlen: Xlen) -> Result<u32, VmExit> {
+    let i = c as u32;
+    let invalid = Err(VmExit::InvalidOpcode(i));
+    let rv32 = xlen == Xlen::Rv32;
+
+    // Register fields, the primed variants address x8-x15
+    let rd = bits(i, 11, 7);
+    let rs2 = bits(i, 6, 2);
+    let rdp = bits(i, 4, 2) + 8;
+    let rs1p = bits(i, 9, 7) + 8;
+
+    // Common immediate layouts
+    let imm6 = sext((bits(i, 12, 12) << 5) | bits(i, 6, 2), 6);
+    let shamt = (bits(i, 12, 12) << 5) | bits(i, 6, 2);
+    // Shift amounts of 32 and up are reserved in RV32C
+    let shamt_ok = !rv32 || bits(i, 12, 12) == 0;
+    let uimm_w = (bits(i, 12, 10) << 3) | (bits(i, 6, 6) << 2) | (bits(i, 5, 5) << 6);
+    let uimm_d = (bits(i, 12, 10) << 3) | (bits(i, 6, 5) << 6);
+    let cj_imm = sext((bits(i, 12, 12) << 11) | (bits(i, 11, 11) << 4) |
+        (bits(i, 10, 9) << 8) | (bits(i, 8, 8) << 10) | (bits(i, 7, 7) << 6) |
+        (bits(i, 6, 6) << 7) | (bits(i, 5, 3) << 1) | (bits(i, 2, 2) << 5), 12);
+
+    let inst = match (i & 0b11, bits(i, 15, 13)) {
+        // Quadrant 0
+        (0b00, 0b000) => {
+            let imm = (bits(i, 12, 11) << 4) | (bits(i, 10, 7) << 6) |
+                (bits(i, 6, 6) << 2) | (bits(i, 5, 5) << 3);
+            if imm == 0 { return invalid; }
+            itype(imm, SP, 0b000, rdp, OP_IMM)
+        },
+        (0b00, 0b001) => itype(uimm_d, rs1p, 0b011, rdp, OP_LOAD_FP),
+        (0b00, 0b010) => itype(uimm_w, rs1p, 0b010, rdp, OP_LOAD),
+        (0b00, 0b011) if rv32 => itype(uimm_w, rs1p, 0b010, rdp, OP_LOAD_FP),
+        (0b00, 0b011) => itype(uimm_d, rs1p, 0b011, rdp, OP_LOAD),
+        (0b00, 0b101) => stype(uimm_d, rdp, rs1p, 0b011, OP_STORE_FP),
+        (0b00, 0b110) => stype(uimm_w, rdp, rs1p, 0b010, OP_STORE),
+        (0b00, 0b111) if rv32 => stype(uimm_w, rdp, rs1p, 0b010, OP_STORE_FP),
+        (0b00, 0b111) => stype(uimm_d, rdp, rs1p, 0b011, OP_STORE),
+
+        // Quadrant 1
+        (0b01, 0b000) => itype(imm6, rd, 0b000, rd, OP_IMM),
+        (0b01, 0b001) if rv32 => jtype(cj_imm, RA),
+        (0b01, 0b001) => {
+            if rd == 0 { return invalid; }
+            itype(imm6, rd, 0b000, rd, OP_IMM_32)
+        },
+        (0b01, 0b010) => itype(imm6, 0, 0b000, rd, OP_IMM),
+        (0b01, 0b011) if rd == SP => {
+            let imm = (bits(i, 12, 12) << 9) | (bits(i, 6, 6) << 4) |
+                (bits(i, 5, 5) << 6) | (bits(i, 4, 3) << 7) | (bits(i, 2, 2) << 5);
+            if imm == 0 { return invalid; }
+            itype(sext(imm, 10), SP, 0b000, SP, OP_IMM)
+        },
+        (0b01, 0b011) => {
+            if imm6 == 0 { return invalid; }
+            (imm6 << 12) | (rd << 7) | OP_LUI
+        },
+        (0b01, 0b100) => {
+            match (bits(i, 11, 10), bits(i, 12, 12), bits(i, 6, 5)) {
+                (0b00, _, _) if shamt_ok => itype(shamt, rs1p, 0b101, rs1p, OP_IMM),
+                (0b01, _, _) if shamt_ok => itype(shamt | 0x400, rs1p, 0b101, rs1p, OP_IMM),
+                (0b10, _, _) => itype(imm6, rs1p, 0b111, rs1p, OP_IMM),
+                (0b11, 0, 0b00) => rtype(0b0100000, rdp, rs1p, 0b000, rs1p, OP),
+                (0b11, 0, 0b01) => rtype(0b0000000, rdp, rs1p, 0b100, rs1p, OP),
+                (0b11, 0, 0b10) => rtype(0b0000000, rdp, rs1p, 0b110, rs1p, OP),
+                (0b11, 0, 0b11) => rtype(0b0000000, rdp, rs1p, 0b111, rs1p, OP),
+                (0b11, 1, 0b00) if !rv32 => rtype(0b0100000, rdp, rs1p, 0b000, rs1p, OP_32),
+                (0b11, 1, 0b01) if !rv32 => rtype(0b0000000, rdp, rs1p, 0b000, rs1p, OP_32),
+                _ => return invalid,
+            }
+        },
+        (0b01, 0b101) => jtype(cj_imm, 0),
+        (0b01, 0b110) | (0b01, 0b111) => {
+            let imm = (bits(i, 12, 12) << 8) | (bits(i, 11, 10) << 3) |
+                (bits(i, 6, 5) << 6) | (bits(i, 4, 3) << 1) | (bits(i, 2, 2) << 5);
+            btype(sext(imm, 9), 0, rs1p, bits(i, 13, 13))
+        },
+
+        // Quadrant 2
+        (0b10, 0b000) if shamt_ok => itype(shamt, rd, 0b001, rd, OP_IMM),
+        (0b10, 0b001) => {
+            let imm = (bits(i, 12, 12) << 5) | (bits(i, 6, 5) << 3) | (bits(i, 4, 2) << 6);
+            itype(imm, SP, 0b011, rd, OP_LOAD_FP)
+        },
+        (0b10, 0b010) => {
+            if rd == 0 { return invalid; }
+            let imm = (bits(i, 12, 12) << 5) | (bits(i, 6, 4) << 2) | (bits(i, 3, 2) << 6);
+            itype(imm, SP, 0b010, rd, OP_LOAD)
+        },
+        (0b10, 0b011) if rv32 => {
+            let imm = (bits(i, 12, 12) << 5) | (bits(i, 6, 4) << 2) | (bits(i, 3, 2) << 6);
+            itype(imm, SP, 0b010, rd, OP_LOAD_FP)
+        },
+        (0b10, 0b011) => {
+            if rd == 0 { return invalid; }
+            let imm = (bits(i, 12, 12) << 5) | (bits(i, 6, 5) << 3) | (bits(i, 4, 2) << 6);
+            itype(imm, SP, 0b011, rd, OP_LOAD)
+        },
+        (0b10, 0b100) => {
+            match (bits(i, 12, 12), rd, rs2) {
+                (0, 0, 0) => return invalid,
+                (0, _, 0) => itype(0, rd, 0b000, 0, OP_JALR),
+                (0, _, _) => rtype(0, rs2, 0, 0b000, rd, OP),
+                (1, 0, 0) => itype(1, 0, 0b000, 0, OP_SYSTEM),
+                (1, _, 0) => itype(0, rd, 0b000, RA, OP_JALR),
+                (_, _, _) => rtype(0, rs2, rd, 0b000, rd, OP),
+            }
+        },
+        (0b10, 0b101) => {
+            let imm = (bits(i, 12, 10) << 3) | (bits(i, 9, 7) << 6);
+            stype(imm, rs2, SP, 0b011, OP_STORE_FP)
+        },
+        (0b10, 0b110) => {
+            let imm = (bits(i, 12, 9) << 2) | (bits(i, 8, 7) << 6);
+            stype(imm, rs2, SP, 0b010, OP_STORE)
+        },
+        (0b10, 0b111) if rv32 => {
+            let imm = (bits(i, 12, 9) << 2) | (bits(i, 8, 7) << 6);
+            stype(imm, rs2, SP, 0b010, OP_STORE_FP)
+        },
+        (0b10, 0b111) => {
+            let imm = (bits(i, 12, 10) << 3) | (bits(i, 9, 7) << 6);
+            stype(imm, rs2, SP, 0b011, OP_STORE)
+        },
+        _ => return invalid,
+    };
+
+    Ok(inst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Machine;
+    use crate::mmu::{Mmu, VirtAddr};
+    use crate::riscv::register::Register::{*};
+
+    #[test]
+    fn test_expand() {
+        let cases: &[(u16, u32)] = &[
+            (0x1fe0, 0x3fc10413), // addi s0, sp, 1020
+            (0x3fe0, 0x0f87b407), // fld fs0, 248(a5)
+            (0x5de8, 0x07c5a503), // lw a0, 124(a1)
+            (0x7ef0, 0x0f86b603), // ld a2, 248(a3)
+            (0xa404, 0x00943427), // fsd fs1, 8(s0)
+            (0xc3b8, 0x04e7a023), // sw a4, 64(a5)
+            (0xe044, 0x08943023), // sd s1, 128(s0)
+            (0x1281, 0xfe028293), // addi t0, t0, -32
+            (0x257d, 0x01f5051b), // addiw a0, a0, 31
+            (0x57fd, 0xfff00793), // li a5, -1
+            (0x7101, 0xe0010113), // addi sp, sp, -512
+            (0x7685, 0xfffe16b7), // lui a3, 1048545
+            (0x90fd, 0x03f4d493), // srli s1, s1, 63
+            (0x8505, 0x40155513), // srai a0, a0, 1
+            (0x99c1, 0xff05f593), // andi a1, a1, -16
+            (0x8c05, 0x40940433), // sub s0, s0, s1
+            (0x8e35, 0x00d64633), // xor a2, a2, a3
+            (0x8f5d, 0x00f76733), // or a4, a4, a5
+            (0x8d6d, 0x00b57533), // and a0, a0, a1
+            (0x9e15, 0x40d6063b), // subw a2, a2, a3
+            (0x9f3d, 0x00f7073b), // addw a4, a4, a5
+            (0xb001, 0x801ff06f), // j -2048
+            (0xd101, 0xf00500e3), // beqz a0, -256
+            (0xecfd, 0x0e049f63), // bnez s1, 254
+            (0x1306, 0x02131313), // slli t1, t1, 33
+            (0x307e, 0x1f813007), // fld ft0, 504(sp)
+            (0x53fe, 0x0fc12383), // lw t2, 252(sp)
+            (0x70fe, 0x1f813083), // ld ra, 504(sp)
+            (0x8302, 0x00030067), // jr t1
+            (0x854a, 0x01200533), // add a0, zero, s2
+            (0x9002, 0x00100073), // ebreak
+            (0x9582, 0x000580e7), // jalr a1
+            (0x9546, 0x01150533), // add a0, a0, a7
+            (0xbf86, 0x1e113c27), // fsd ft1, 504(sp)
+            (0xdff2, 0x0fc12e23), // sw t3, 252(sp)
+            (0xff86, 0x1e113c23), // sd ra, 504(sp)
+        ];
+
+        for (c, expected) in cases {
+            assert_eq!(expand_compressed(*c, Xlen::Rv64), Ok(*expected), "{:04x}", c);
+        }
+    }
+
+    #[test]
+    fn test_expand_rv32() {
+        let cases: &[(u16, u32)] = &[
+            (0x2095, 0x064000ef), // jal 100
+            (0x6588, 0x0085a507), // flw fa0, 8(a1)
+            (0xfde8, 0x06a5ae27), // fsw fa0, 124(a1)
+            (0x75fe, 0x0fc12587), // flw fa1, 252(sp)
+            (0xffae, 0x0eb12e27), // fsw fa1, 252(sp)
+            (0x817d, 0x01f55513), // srli a0, a0, 31
+        ];
+
+        for (c, expected) in cases {
+            assert_eq!(expand_compressed(*c, Xlen::Rv32), Ok(*expected), "{:04x}", c);
+        }
+
+        // Shifts by 32 or more and the W operations are RV64 only
+        for c in &[0x90fd, 0x1306, 0x9d2d] {
+            assert_eq!(expand_compressed(*c, Xlen::Rv32), Err(VmExit::InvalidOpcode(*c as u32)));
+        }
+    }
+
+    #[test]
+    fn test_reserved() {
+        // All zeroes, c.addiw with rd=0 and c.lui with a zero immediate
+        for c in &[0x0000, 0x2001, 0x6181] {
+            assert_eq!(expand_compressed(*c, Xlen::Rv64), Err(VmExit::InvalidOpcode(*c as u32)));
+        }
+        assert_eq!(instruction_length(0x0001), 2);
+        assert_eq!(instruction_length(0x0013), 4);
+    }
+
+    #[test]
+    fn test_mixed_length_execution() {
+        let mut mmu = Mmu::new(4096);
+        mmu.write_from(VirtAddr(0), &[
+            0x15, 0x45,             // c.li a0, 5
+            0x7d, 0x15,             // c.addi a0, -1
+            0x7d, 0xfd,             // c.bnez a0, -2
+            0xef, 0x00, 0x80, 0x00, // jal ra, 8
+            0x85, 0x45,             // c.li a1, 1
+            0x01, 0x00,             // c.nop
+            0x06, 0x86,             // c.mv a2, ra
+        ]).unwrap();
+        mmu.entry_point = Some(VirtAddr(0));
+
+        let mut m = Machine::new(mmu);
+        for _ in 0..13 {
+            m.step().unwrap();
+        }
+
+        assert_eq!(m.get_r(A0), 0);
+        assert_eq!(m.get_r(A1), 0);
+        assert_eq!(m.get_r(Ra), 10);
+        assert_eq!(m.get_r(A2), 10);
+        assert_eq!(m.get_r(Pc), 16);
+    }
+}
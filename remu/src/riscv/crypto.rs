@@ -0,0 +1,227 @@
+// Helpers for the scalar cryptography extensions. The AES instructions work
+// on half of the 128-bit state at a time: the state is held column by column
+// in rs1 (columns 0 and 1) and rs2 (columns 2 and 3), and each instruction
+// produces the two low columns of the result of a round.
+
+/// AES forward S-box
+const AES_SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+/// AES inverse S-box
+const AES_INV_SBOX: [u8; 256] = [
+    0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+    0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+    0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+    0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+    0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+    0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+    0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+    0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+    0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+    0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+    0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+    0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+    0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+    0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+    0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+    0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+];
+
+/// SM4 S-box
+const SM4_SBOX: [u8; 256] = [
+    0xd6, 0x90, 0xe9, 0xfe, 0xcc, 0xe1, 0x3d, 0xb7, 0x16, 0xb6, 0x14, 0xc2, 0x28, 0xfb, 0x2c, 0x05,
+    0x2b, 0x67, 0x9a, 0x76, 0x2a, 0xbe, 0x04, 0xc3, 0xaa, 0x44, 0x13, 0x26, 0x49, 0x86, 0x06, 0x99,
+    0x9c, 0x42, 0x50, 0xf4, 0x91, 0xef, 0x98, 0x7a, 0x33, 0x54, 0x0b, 0x43, 0xed, 0xcf, 0xac, 0x62,
+    0xe4, 0xb3, 0x1c, 0xa9, 0xc9, 0x08, 0xe8, 0x95, 0x80, 0xdf, 0x94, 0xfa, 0x75, 0x8f, 0x3f, 0xa6,
+    0x47, 0x07, 0xa7, 0xfc, 0xf3, 0x73, 0x17, 0xba, 0x83, 0x59, 0x3c, 0x19, 0xe6, 0x85, 0x4f, 0xa8,
+    0x68, 0x6b, 0x81, 0xb2, 0x71, 0x64, 0xda, 0x8b, 0xf8, 0xeb, 0x0f, 0x4b, 0x70, 0x56, 0x9d, 0x35,
+    0x1e, 0x24, 0x0e, 0x5e, 0x63, 0x58, 0xd1, 0xa2, 0x25, 0x22, 0x7c, 0x3b, 0x01, 0x21, 0x78, 0x87,
+    0xd4, 0x00, 0x46, 0x57, 0x9f, 0xd3, 0x27, 0x52, 0x4c, 0x36, 0x02, 0xe7, 0xa0, 0xc4, 0xc8, 0x9e,
+    0xea, 0xbf, 0x8a, 0xd2, 0x40, 0xc7, 0x38, 0xb5, 0xa3, 0xf7, 0xf2, 0xce, 0xf9, 0x61, 0x15, 0xa1,
+    0xe0, 0xae, 0x5d, 0xa4, 0x9b, 0x34, 0x1a, 0x55, 0xad, 0x93, 0x32, 0x30, 0xf5, 0x8c, 0xb1, 0xe3,
+    0x1d, 0xf6, 0xe2, 0x2e, 0x82, 0x66, 0xca, 0x60, 0xc0, 0x29, 0x23, 0xab, 0x0d, 0x53, 0x4e, 0x6f,
+    0xd5, 0xdb, 0x37, 0x45, 0xde, 0xfd, 0x8e, 0x2f, 0x03, 0xff, 0x6a, 0x72, 0x6d, 0x6c, 0x5b, 0x51,
+    0x8d, 0x1b, 0xaf, 0x92, 0xbb, 0xdd, 0xbc, 0x7f, 0x11, 0xd9, 0x5c, 0x41, 0x1f, 0x10, 0x5a, 0xd8,
+    0x0a, 0xc1, 0x31, 0x88, 0xa5, 0xcd, 0x7b, 0xbd, 0x2d, 0x74, 0xd0, 0x12, 0xb8, 0xe5, 0xb4, 0xb0,
+    0x89, 0x69, 0x97, 0x4a, 0x0c, 0x96, 0x77, 0x7e, 0x65, 0xb9, 0xf1, 0x09, 0xc5, 0x6e, 0xc6, 0x84,
+    0x18, 0xf0, 0x7d, 0xec, 0x3a, 0xdc, 0x4d, 0x20, 0x79, 0xee, 0x5f, 0x3e, 0xd7, 0xcb, 0x39, 0x48,
+];
+
+/// AES round constants, by round number
+const AES_RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+/// Multiplication in GF(2^8) modulo the AES polynomial
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut r = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            r ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1b } else { 0 };
+        b >>= 1;
+    }
+    r
+}
+
+/// Applies `sbox` to each byte of `x`
+fn sub_bytes(x: u64, sbox: &[u8; 256]) -> u64 {
+    u64::from_le_bytes(x.to_le_bytes().map(|b| sbox[b as usize]))
+}
+
+/// Multiplies a column by the fixed MixColumns matrix given by its first row
+fn mix_column(col: u32, row: [u8; 4]) -> u32 {
+    let b = col.to_le_bytes();
+    let mut out = [0; 4];
+    for (r, o) in out.iter_mut().enumerate() {
+        *o = (0..4).fold(0, |acc, c| acc ^ gf_mul(b[c], row[(c + 4 - r) % 4]));
+    }
+    u32::from_le_bytes(out)
+}
+
+/// Applies a MixColumns matrix to both columns in `x`
+fn mix_columns(x: u64, row: [u8; 4]) -> u64 {
+    (mix_column(x as u32, row) as u64) | ((mix_column((x >> 32) as u32, row) as u64) << 32)
+}
+
+/// The two low columns of the state after ShiftRows, or InvShiftRows with
+/// `inverse`
+fn shift_rows(rs1: u64, rs2: u64, inverse: bool) -> u64 {
+    let state = (rs1 as u128) | ((rs2 as u128) << 64);
+    (0..8).fold(0, |acc, k| {
+        let (c, r) = (k / 4, k % 4);
+        let src = if inverse { (c + 4 - r) % 4 } else { (c + r) % 4 };
+        let byte = (state >> (8 * (4 * src + r))) as u8;
+        acc | ((byte as u64) << (8 * k))
+    })
+}
+
+/// aes64es and aes64esm: ShiftRows, SubBytes and, for a middle round,
+/// MixColumns
+pub fn aes64_encrypt(rs1: u64, rs2: u64, mix: bool) -> u64 {
+    let x = sub_bytes(shift_rows(rs1, rs2, false), &AES_SBOX);
+    if mix { mix_columns(x, [2, 3, 1, 1]) } else { x }
+}
+
+/// aes64ds and aes64dsm: InvShiftRows, InvSubBytes and, for a middle round,
+/// InvMixColumns
+pub fn aes64_decrypt(rs1: u64, rs2: u64, mix: bool) -> u64 {
+    let x = sub_bytes(shift_rows(rs1, rs2, true), &AES_INV_SBOX);
+    if mix { aes64_im(x) } else { x }
+}
+
+/// aes64im: InvMixColumns, to turn encryption round keys into ones for the
+/// equivalent inverse cipher
+pub fn aes64_im(x: u64) -> u64 {
+    mix_columns(x, [14, 11, 13, 9])
+}
+
+/// aes64ks1i: the substituted and rotated word of the key schedule for
+/// round `rnum`. Round 10 only substitutes, for AES-256.
+pub fn aes64_ks1i(rs1: u64, rnum: u32) -> u64 {
+    let mut w = (rs1 >> 32) as u32;
+    let mut rcon = 0;
+    if rnum != 0xa {
+        w = w.rotate_right(8);
+        rcon = AES_RCON[rnum as usize];
+    }
+    let w = sub_bytes(w as u64, &AES_SBOX) as u32 ^ rcon as u32;
+    ((w as u64) << 32) | w as u64
+}
+
+/// aes64ks2: the XOR chain producing the next two key schedule words
+pub fn aes64_ks2(rs1: u64, rs2: u64) -> u64 {
+    let w0 = (rs1 >> 32) as u32 ^ rs2 as u32;
+    let w1 = w0 ^ (rs2 >> 32) as u32;
+    ((w1 as u64) << 32) | w0 as u64
+}
+
+/// aes32esi, aes32esmi, aes32dsi and aes32dsmi: substitutes byte `bs` of
+/// rs2 with the forward or inverse S-box, applies the matching MixColumns
+/// column with `mix`, and XORs the result rotated into place into rs1
+pub fn aes32(rs1: u64, rs2: u64, bs: u32, decrypt: bool, mix: bool) -> u64 {
+    let shamt = bs * 8;
+    let si = ((rs2 >> shamt) & 0xff) as usize;
+    let (so, row) = if decrypt {
+        (AES_INV_SBOX[si], [14, 11, 13, 9])
+    } else {
+        (AES_SBOX[si], [2, 3, 1, 1])
+    };
+    let y = if mix { mix_column(so as u32, row) } else { so as u32 };
+    (y.rotate_left(shamt) ^ rs1 as u32) as i32 as i64 as u64
+}
+
+/// sm4ed and sm4ks: substitutes byte `bs` of rs2, applies the linear
+/// transform of the data path or the key schedule, and XORs the result
+/// rotated back into place into rs1
+pub fn sm4(rs1: u64, rs2: u64, bs: u32, key: bool) -> u64 {
+    let shamt = bs * 8;
+    let x = SM4_SBOX[((rs2 >> shamt) & 0xff) as usize] as u32;
+    let y = if key {
+        x ^ ((x & 0x07) << 29) ^ ((x & 0xfe) << 7) ^ ((x & 0x01) << 23) ^ ((x & 0xf8) << 13)
+    } else {
+        x ^ (x << 8) ^ (x << 2) ^ (x << 18) ^ ((x & 0x3f) << 26) ^ ((x & 0xc0) << 10)
+    };
+    (y.rotate_left(shamt) ^ rs1 as u32) as i32 as i64 as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_aes_round() {
+        // First round of the FIPS-197 AES-128 example, starting from the
+        // state after the initial AddRoundKey
+        let state: u128 = 0x193de3be_a0f4e22b_9ac68d2a_e9f84808_u128.swap_bytes();
+        let (lo, hi) = (state as u64, (state >> 64) as u64);
+        let after = ((aes64_encrypt(hi, lo, true) as u128) << 64) | aes64_encrypt(lo, hi, true) as u128;
+        assert_eq!(after.swap_bytes(), 0x046681e5_e0cb199a_48f8d37a_2806264c);
+
+        // Decryption undoes it
+        let (lo, hi) = (after as u64, (after >> 64) as u64);
+        let (lo, hi) = (aes64_im(lo), aes64_im(hi));
+        let back = ((aes64_decrypt(hi, lo, false) as u128) << 64) | aes64_decrypt(lo, hi, false) as u128;
+        assert_eq!(back, state);
+    }
+
+    #[test]
+    fn test_aes32() {
+        // The RV32 instructions build the same round one column at a time
+        let state: u128 = 0x193de3be_a0f4e22b_9ac68d2a_e9f84808_u128.swap_bytes();
+        let (lo, hi) = (state as u64, (state >> 64) as u64);
+        let cols = [lo, lo >> 32, hi, hi >> 32];
+        let col0 = (0..4).fold(0, |acc, r| aes32(acc, cols[r as usize], r, false, true));
+        assert_eq!(col0 as u32, aes64_encrypt(lo, hi, true) as u32);
+
+        let col0 = (0..4).fold(0, |acc, r| aes32(acc, cols[((4 - r) % 4) as usize], r, true, false));
+        assert_eq!(col0 as u32, aes64_decrypt(lo, hi, false) as u32);
+    }
+
+    #[test]
+    fn test_aes_key_schedule() {
+        // First round key of the FIPS-197 AES-128 example
+        let key: u128 = 0x2b7e1516_28aed2a6_abf71588_09cf4f3c_u128.swap_bytes();
+        let (k0, k1) = (key as u64, (key >> 64) as u64);
+        let t = aes64_ks1i(k1, 0);
+        let n0 = aes64_ks2(t, k0);
+        let n1 = aes64_ks2(n0, k1);
+        let next = ((n1 as u128) << 64) | n0 as u128;
+        assert_eq!(next.swap_bytes(), 0xa0fafe17_88542cb1_23a33939_2a6c7605);
+    }
+}
@@ -0,0 +1,286 @@
+// Control and status registers. Registers with side effects on the machine
+// state (counters, fcsr) are handled by `Machine` itself, everything else is
+// plain storage in a `CsrFile` table that higher privilege levels and devices
+// register their CSRs in.
+
+use std::collections::HashMap;
+
+use crate::riscv::isa::{Extension, Isa};
+use crate::riscv::register::Xlen;
+
+// User floating-point CSRs
+pub const FFLAGS:     u16 = 0x001;
+pub const FRM:        u16 = 0x002;
+pub const FCSR:       u16 = 0x003;
+
+// User vector CSRs
+pub const VSTART:     u16 = 0x008;
+pub const VXSAT:      u16 = 0x009;
+pub const VXRM:       u16 = 0x00a;
+pub const VCSR:       u16 = 0x00f;
+pub const VL:         u16 = 0xc20;
+pub const VTYPE:      u16 = 0xc21;
+pub const VLENB:      u16 = 0xc22;
+
+// User counters
+pub const CYCLE:      u16 = 0xc00;
+pub const TIME:       u16 = 0xc01;
+pub const INSTRET:    u16 = 0xc02;
+pub const HPMCOUNTER3:  u16 = 0xc03;
+pub const HPMCOUNTER31: u16 = 0xc1f;
+
+// Upper halves of the user counters, RV32 only
+pub const CYCLEH:     u16 = 0xc80;
+pub const TIMEH:      u16 = 0xc81;
+pub const INSTRETH:   u16 = 0xc82;
+pub const HPMCOUNTER3H:  u16 = 0xc83;
+pub const HPMCOUNTER31H: u16 = 0xc9f;
+
+// Supervisor trap setup and handling
+pub const SSTATUS:    u16 = 0x100;
+pub const SIE:        u16 = 0x104;
+pub const STVEC:      u16 = 0x105;
+pub const SCOUNTEREN: u16 = 0x106;
+pub const SSCRATCH:   u16 = 0x140;
+pub const SEPC:       u16 = 0x141;
+pub const SCAUSE:     u16 = 0x142;
+pub const STVAL:      u16 = 0x143;
+pub const SIP:        u16 = 0x144;
+
+// Supervisor protection and translation
+pub const SATP:       u16 = 0x180;
+
+// Machine information registers
+pub const MVENDORID:  u16 = 0xf11;
+pub const MARCHID:    u16 = 0xf12;
+pub const MIMPID:     u16 = 0xf13;
+pub const MHARTID:    u16 = 0xf14;
+
+// Machine trap setup and handling
+pub const MSTATUS:    u16 = 0x300;
+pub const MSTATUSH:   u16 = 0x310;
+pub const MISA:       u16 = 0x301;
+pub const MEDELEG:    u16 = 0x302;
+pub const MIDELEG:    u16 = 0x303;
+pub const MIE:        u16 = 0x304;
+pub const MTVEC:      u16 = 0x305;
+pub const MCOUNTEREN: u16 = 0x306;
+pub const MSCRATCH:   u16 = 0x340;
+pub const MEPC:       u16 = 0x341;
+pub const MCAUSE:     u16 = 0x342;
+pub const MTVAL:      u16 = 0x343;
+pub const MIP:        u16 = 0x344;
+
+// Machine counters
+pub const MCYCLE:     u16 = 0xb00;
+pub const MINSTRET:   u16 = 0xb02;
+pub const MHPMCOUNTER3:  u16 = 0xb03;
+pub const MCYCLEH:    u16 = 0xb80;
+pub const MINSTRETH:  u16 = 0xb82;
+pub const MHPMCOUNTER3H: u16 = 0xb83;
+pub const MHPMEVENT3:    u16 = 0x323;
+pub const MCOUNTINHIBIT: u16 = 0x320;
+
+// mstatus fields
+pub const MSTATUS_SIE:  u64 = 1 << 1;
+pub const MSTATUS_MIE:  u64 = 1 << 3;
+pub const MSTATUS_SPIE: u64 = 1 << 5;
+pub const MSTATUS_MPIE: u64 = 1 << 7;
+pub const MSTATUS_SPP:  u64 = 1 << 8;
+pub const MSTATUS_MPP_SHIFT: u64 = 11;
+pub const MSTATUS_MPP:  u64 = 0b11 << MSTATUS_MPP_SHIFT;
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+pub const MSTATUS_SUM:  u64 = 1 << 18;
+pub const MSTATUS_MXR:  u64 = 1 << 19;
+pub const MSTATUS_TVM:  u64 = 1 << 20;
+pub const MSTATUS_TW:   u64 = 1 << 21;
+pub const MSTATUS_TSR:  u64 = 1 << 22;
+
+/// Fields of mstatus visible through sstatus: SIE, SPIE, SPP, VS, FS, SUM,
+/// MXR, UXL and SD
+pub const SSTATUS_MASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | (0b11 << 9) | (0b11 << 13) |
+    MSTATUS_SUM | MSTATUS_MXR | (0b11 << 32) | (1 << 63);
+
+// Interrupt pending and enable bits, by interrupt code
+pub const MIP_SSIP: u64 = 1 << 1;
+pub const MIP_MSIP: u64 = 1 << 3;
+pub const MIP_STIP: u64 = 1 << 5;
+pub const MIP_MTIP: u64 = 1 << 7;
+pub const MIP_SEIP: u64 = 1 << 9;
+pub const MIP_MEIP: u64 = 1 << 11;
+
+/// Bits of mip software can write, the others are driven by devices
+pub const MIP_WRITABLE: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// Interrupt bit of mcause, set when the trap is an interrupt. This is the
+/// RV64 position, the bit moves to bit 31 in RV32.
+pub const MCAUSE_INTERRUPT: u64 = 1 << 63;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl Privilege {
+    /// Decodes a privilege level as stored in the xPP fields of mstatus
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0 => Some(Privilege::User),
+            1 => Some(Privilege::Supervisor),
+            3 => Some(Privilege::Machine),
+            _ => None,
+        }
+    }
+}
+
+/// Lowest privilege level that can access `csr`, encoded in bits 9:8
+pub fn csr_privilege(csr: u16) -> u64 {
+    ((csr >> 8) & 0b11) as u64
+}
+
+/// CSRs with bits 11:10 set are read-only
+pub fn csr_read_only(csr: u16) -> bool {
+    (csr >> 10) & 0b11 == 0b11
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Csr {
+    pub value: u64,
+    /// Writable bits, the others keep their value on writes (WARL)
+    pub mask: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct CsrFile {
+    csrs: HashMap<u16, Csr>,
+}
+
+/// Writable mstatus fields: SIE, MIE, SPIE, MPIE, SPP, VS, MPP, FS, MPRV,
+/// SUM, MXR, TVM, TW and TSR
+const MSTATUS_MASK: u64 = 0x7e_7faa;
+
+/// UXL and SXL are fixed to 64 bits in RV64 and do not exist in RV32
+const MSTATUS_RESET_RV64: u64 = (2 << 32) | (2 << 34);
+
+/// mstatus.VS and mstatus.FS, which are read-only zero without V and F
+pub const MSTATUS_VS: u64 = 0b11 << 9;
+const MSTATUS_FS: u64 = 0b11 << 13;
+
+// Values of mstatus.VS: Off, Initial, Clean and Dirty
+pub const MSTATUS_VS_INITIAL: u64 = 0b01 << 9;
+pub const MSTATUS_VS_DIRTY: u64 = 0b11 << 9;
+
+/// Exceptions that can be delegated to S-mode, all but ecall from M-mode
+const MEDELEG_MASK: u64 = 0xb3ff;
+
+impl CsrFile {
+    /// Creates the table with the machine-level CSRs every hart implements
+    pub fn new(isa: &Isa) -> Self {
+        let mut f = CsrFile { csrs: HashMap::new() };
+
+        f.register(MVENDORID, 0, 0);
+        f.register(MARCHID, 0, 0);
+        f.register(MIMPID, 0, 0);
+        f.register(MHARTID, 0, 0);
+
+        let mut mstatus_mask = MSTATUS_MASK;
+        if !isa.has(Extension::F) {
+            mstatus_mask &= !MSTATUS_FS;
+        }
+        if !isa.has(Extension::V) {
+            mstatus_mask &= !MSTATUS_VS;
+        }
+        match isa.xlen {
+            Xlen::Rv32 => {
+                f.register(MSTATUS, 0, mstatus_mask);
+                f.register(MSTATUSH, 0, 0);
+            },
+            Xlen::Rv64 => f.register(MSTATUS, MSTATUS_RESET_RV64, mstatus_mask),
+        }
+        f.register(MISA, isa.misa(), 0);
+        f.register(MIE, 0, 0xaaa);
+        f.register(MTVEC, 0, !0b10);
+        f.register(MCOUNTEREN, 0, 0xffff_ffff);
+        f.register(MCOUNTINHIBIT, 0, 0);
+        f.register(MSCRATCH, 0, u64::MAX);
+        f.register(MEPC, 0, !1);
+        f.register(MCAUSE, 0, u64::MAX);
+        f.register(MTVAL, 0, u64::MAX);
+        f.register(MIP, 0, MIP_WRITABLE);
+        f.register(MEDELEG, 0, MEDELEG_MASK);
+        f.register(MIDELEG, 0, MIP_WRITABLE);
+
+        f.register(STVEC, 0, !0b10);
+        f.register(SCOUNTEREN, 0, 0xffff_ffff);
+        f.register(SSCRATCH, 0, u64::MAX);
+        f.register(SEPC, 0, !1);
+        f.register(SCAUSE, 0, u64::MAX);
+        f.register(STVAL, 0, u64::MAX);
+        f.register(SATP, 0, u64::MAX);
+
+        // Hardwired to zero, there are no hardware performance monitors
+        for n in 0..29 {
+            f.register(MHPMCOUNTER3 + n, 0, 0);
+            f.register(MHPMEVENT3 + n, 0, 0);
+            if isa.xlen == Xlen::Rv32 {
+                f.register(MHPMCOUNTER3H + n, 0, 0);
+            }
+        }
+        f
+    }
+
+    /// Adds (or replaces) a CSR with reset value `value` and writable bits `mask`
+    pub fn register(&mut self, csr: u16, value: u64, mask: u64) {
+        self.csrs.insert(csr, Csr { value, mask });
+    }
+
+    pub fn get(&self, csr: u16) -> Option<u64> {
+        self.csrs.get(&csr).map(|c| c.value)
+    }
+
+    /// Writes the writable bits of `csr`, returning false if it does not exist
+    pub fn set(&mut self, csr: u16, value: u64) -> bool {
+        match self.csrs.get_mut(&csr) {
+            Some(c) => {
+                c.value = (c.value & !c.mask) | (value & c.mask);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_csr_file() {
+        let mut f = CsrFile::new(&Isa::full(Xlen::Rv64));
+        assert_eq!(f.get(MISA).unwrap() >> 62, 2);
+        assert_eq!(f.get(MSTATUSH), None);
+
+        assert!(f.set(MTVEC, 0x8000_0003));
+        assert_eq!(f.get(MTVEC), Some(0x8000_0001));
+
+        assert!(f.set(MISA, 0));
+        assert_eq!(f.get(MISA), Some(Isa::full(Xlen::Rv64).misa()));
+
+        assert!(!f.set(0x7ff, 1));
+        assert_eq!(f.get(0x7ff), None);
+
+        assert_eq!(csr_privilege(MSTATUS), Privilege::Machine as u64);
+        assert!(csr_read_only(CYCLE));
+        assert!(!csr_read_only(MSCRATCH));
+
+        let mut f = CsrFile::new(&"rv32imac".parse().unwrap());
+        assert_eq!(f.get(MISA).unwrap() >> 30, 1);
+        assert_eq!(f.get(MSTATUS), Some(0));
+        assert_eq!(f.get(MSTATUSH), Some(0));
+        assert!(f.set(MSTATUS, MSTATUS_FS | MSTATUS_VS | MSTATUS_MIE));
+        assert_eq!(f.get(MSTATUS), Some(MSTATUS_MIE));
+    }
+}
@@ -0,0 +1,445 @@
+// IEEE 754 helpers for the F and D extensions. Host arithmetic always rounds
+// to nearest-even, so every operation computes that result together with the
+// error to the exact value, and `round` then picks the correctly rounded
+// neighbour for the requested rounding mode and accumulates fflags.
+
+use std::ops::{Add, Sub, Mul, Div, Neg};
+
+pub const FFLAG_NX: u32 = 1 << 0;
+pub const FFLAG_UF: u32 = 1 << 1;
+pub const FFLAG_OF: u32 = 1 << 2;
+pub const FFLAG_DZ: u32 = 1 << 3;
+pub const FFLAG_NV: u32 = 1 << 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoundingMode {
+    Rne,
+    Rtz,
+    Rdn,
+    Rup,
+    Rmm,
+}
+
+impl RoundingMode {
+    pub fn from_bits(rm: u32) -> Option<Self> {
+        match rm {
+            0b000 => Some(RoundingMode::Rne),
+            0b001 => Some(RoundingMode::Rtz),
+            0b010 => Some(RoundingMode::Rdn),
+            0b011 => Some(RoundingMode::Rup),
+            0b100 => Some(RoundingMode::Rmm),
+            _ => None,
+        }
+    }
+}
+
+pub trait Float: Copy + PartialEq + PartialOrd + Add<Output = Self> +
+    Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self> +
+    Neg<Output = Self>
+{
+    const ZERO: Self;
+    const MIN_POSITIVE: Self;
+    const CANONICAL_NAN: Self;
+
+    fn is_nan(self) -> bool;
+    fn is_snan(self) -> bool;
+    fn is_infinite(self) -> bool;
+    fn is_subnormal(self) -> bool;
+    fn is_sign_negative(self) -> bool;
+    fn abs(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn mul_add(self, a: Self, b: Self) -> Self;
+    fn next_up(self) -> Self;
+    fn next_down(self) -> Self;
+    fn to_f64(self) -> f64;
+    fn from_i128(v: i128) -> Self;
+    fn to_i128(self) -> i128;
+}
+
+macro_rules! impl_float {
+    ($t:ident, $nan:expr, $quiet:expr) => {
+        impl Float for $t {
+            const ZERO: Self = 0.0;
+            const MIN_POSITIVE: Self = $t::MIN_POSITIVE;
+            const CANONICAL_NAN: Self = $t::from_bits($nan);
+
+            fn is_nan(self) -> bool { $t::is_nan(self) }
+            fn is_snan(self) -> bool { self.is_nan() && self.to_bits() & $quiet == 0 }
+            fn is_infinite(self) -> bool { $t::is_infinite(self) }
+            fn is_subnormal(self) -> bool { $t::is_subnormal(self) }
+            fn is_sign_negative(self) -> bool { $t::is_sign_negative(self) }
+            fn abs(self) -> Self { $t::abs(self) }
+            fn sqrt(self) -> Self { $t::sqrt(self) }
+            fn mul_add(self, a: Self, b: Self) -> Self { $t::mul_add(self, a, b) }
+            fn next_up(self) -> Self { $t::next_up(self) }
+            fn next_down(self) -> Self { $t::next_down(self) }
+            fn to_f64(self) -> f64 { self as f64 }
+            fn from_i128(v: i128) -> Self { v as $t }
+            fn to_i128(self) -> i128 { self as i128 }
+        }
+    };
+}
+
+impl_float!(f32, 0x7fc0_0000, 1 << 22);
+impl_float!(f64, 0x7ff8_0000_0000_0000, 1 << 51);
+
+/// Picks between the round-to-nearest-even result `r` and its neighbour
+/// `other` on the other side of the exact value.
+fn select<F: Float>(r: F, other: F, tie: bool, rm: RoundingMode) -> F {
+    let (lo, hi) = if other > r { (r, other) } else { (other, r) };
+    let smaller = if r.abs() < other.abs() { r } else { other };
+    let larger = if r.abs() < other.abs() { other } else { r };
+
+    match rm {
+        RoundingMode::Rne => r,
+        RoundingMode::Rtz => smaller,
+        RoundingMode::Rdn => lo,
+        RoundingMode::Rup => hi,
+        RoundingMode::Rmm => if tie { larger } else { r },
+    }
+}
+
+/// Rounds `r` according to `rm`, where `err` is the (signed) difference
+/// between the exact result and `r`. An infinite `r` with non-zero `err`
+/// denotes an overflow.
+///
+/// Underflow is flagged for inexact results that are below the smallest
+/// normal number once rounded to the destination format. RISC-V detects
+/// tininess after rounding with an unbounded exponent instead, which also
+/// flags some inexact results that round up to exactly the smallest normal
+/// number: those that would stay below it with one more bit of precision.
+/// These do not flag underflow here, as `err` cannot represent differences
+/// that small for f64 results.
+fn round<F: Float>(r: F, err: f64, rm: RoundingMode, flags: &mut u32) -> F {
+    if err == 0.0 || err.is_nan() {
+        return r;
+    }
+
+    let other = if err > 0.0 { r.next_up() } else { r.next_down() };
+    let tie = other.to_f64() - r.to_f64() == err + err;
+    let res = select(r, other, tie, rm);
+
+    *flags |= FFLAG_NX;
+    if r.is_infinite() || res.is_infinite() {
+        *flags |= FFLAG_OF;
+    }
+    if res.abs() < F::MIN_POSITIVE {
+        *flags |= FFLAG_UF;
+    }
+    res
+}
+
+fn overflow_err<F: Float>(r: F) -> f64 {
+    -r.to_f64()
+}
+
+fn two_sum<F: Float>(a: F, b: F, s: F) -> F {
+    let bb = s - a;
+    (a - (s - bb)) + (b - bb)
+}
+
+pub fn add<F: Float>(a: F, b: F, rm: RoundingMode, flags: &mut u32) -> F {
+    let r = a + b;
+    if r.is_nan() {
+        if a.is_snan() || b.is_snan() || (!a.is_nan() && !b.is_nan()) {
+            *flags |= FFLAG_NV;
+        }
+        return F::CANONICAL_NAN;
+    }
+    if r.is_infinite() {
+        if a.is_infinite() || b.is_infinite() {
+            return r;
+        }
+        return round(r, overflow_err(r), rm, flags);
+    }
+
+    let err = two_sum(a, b, r);
+    if r == F::ZERO && err == F::ZERO && rm == RoundingMode::Rdn &&
+        (a.is_sign_negative() || b.is_sign_negative()) {
+        return -F::ZERO;
+    }
+    round(r, err.to_f64(), rm, flags)
+}
+
+pub fn sub<F: Float>(a: F, b: F, rm: RoundingMode, flags: &mut u32) -> F {
+    if b.is_nan() {
+        return add(a, b, rm, flags);
+    }
+    add(a, -b, rm, flags)
+}
+
+pub fn mul<F: Float>(a: F, b: F, rm: RoundingMode, flags: &mut u32) -> F {
+    let r = a * b;
+    if r.is_nan() {
+        if a.is_snan() || b.is_snan() || (!a.is_nan() && !b.is_nan()) {
+            *flags |= FFLAG_NV;
+        }
+        return F::CANONICAL_NAN;
+    }
+    if r.is_infinite() {
+        if a.is_infinite() || b.is_infinite() {
+            return r;
+        }
+        return round(r, overflow_err(r), rm, flags);
+    }
+
+    let err = a.mul_add(b, -r);
+    round(r, err.to_f64(), rm, flags)
+}
+
+pub fn div<F: Float>(a: F, b: F, rm: RoundingMode, flags: &mut u32) -> F {
+    let r = a / b;
+    if r.is_nan() {
+        if a.is_snan() || b.is_snan() || (!a.is_nan() && !b.is_nan()) {
+            *flags |= FFLAG_NV;
+        }
+        return F::CANONICAL_NAN;
+    }
+    if r.is_infinite() {
+        if b == F::ZERO && !a.is_infinite() {
+            *flags |= FFLAG_DZ;
+            return r;
+        }
+        if a.is_infinite() {
+            return r;
+        }
+        return round(r, overflow_err(r), rm, flags);
+    }
+    if b.is_infinite() {
+        return r;
+    }
+
+    let rem = (-r).mul_add(b, a);
+    round(r, rem.to_f64() / b.to_f64(), rm, flags)
+}
+
+pub fn sqrt<F: Float>(a: F, rm: RoundingMode, flags: &mut u32) -> F {
+    let r = a.sqrt();
+    if r.is_nan() {
+        if a.is_snan() || !a.is_nan() {
+            *flags |= FFLAG_NV;
+        }
+        return F::CANONICAL_NAN;
+    }
+    if r.is_infinite() || r == F::ZERO {
+        return r;
+    }
+
+    let rem = (-r).mul_add(r, a);
+    round(r, rem.to_f64() / (2.0 * r.to_f64()), rm, flags)
+}
+
+/// Fused `a * b + c`. The error of the fused result cannot be recovered
+/// exactly with host arithmetic, so it is approximated from the exact error
+/// terms of the product and the sum; its sign is what matters for rounding.
+pub fn fma<F: Float>(a: F, b: F, c: F, rm: RoundingMode, flags: &mut u32) -> F {
+    let r = a.mul_add(b, c);
+    if r.is_nan() {
+        let inf_zero = (a.is_infinite() && b == F::ZERO) ||
+            (a == F::ZERO && b.is_infinite());
+        if a.is_snan() || b.is_snan() || c.is_snan() || inf_zero ||
+            (!a.is_nan() && !b.is_nan() && !c.is_nan()) {
+            *flags |= FFLAG_NV;
+        }
+        return F::CANONICAL_NAN;
+    }
+    if r.is_infinite() {
+        if a.is_infinite() || b.is_infinite() || c.is_infinite() {
+            return r;
+        }
+        return round(r, overflow_err(r), rm, flags);
+    }
+
+    let p = a * b;
+    let pe = a.mul_add(b, -p);
+    let e1 = two_sum(p, c, p + c);
+    let err = (((p + c) - r) + e1).to_f64() + pe.to_f64();
+    if r == F::ZERO && err == 0.0 && rm == RoundingMode::Rdn &&
+        ((a.is_sign_negative() != b.is_sign_negative()) || c.is_sign_negative()) {
+        return -F::ZERO;
+    }
+    round(r, err, rm, flags)
+}
+
+pub fn min<F: Float>(a: F, b: F, flags: &mut u32) -> F {
+    if a.is_snan() || b.is_snan() {
+        *flags |= FFLAG_NV;
+    }
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => F::CANONICAL_NAN,
+        (true, false) => b,
+        (false, true) => a,
+        _ if a == b => if a.is_sign_negative() { a } else { b },
+        _ => if a < b { a } else { b },
+    }
+}
+
+pub fn max<F: Float>(a: F, b: F, flags: &mut u32) -> F {
+    if a.is_snan() || b.is_snan() {
+        *flags |= FFLAG_NV;
+    }
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => F::CANONICAL_NAN,
+        (true, false) => b,
+        (false, true) => a,
+        _ if a == b => if a.is_sign_negative() { b } else { a },
+        _ => if a > b { a } else { b },
+    }
+}
+
+/// Quiet comparison, only signaling NaNs raise the invalid flag
+pub fn eq<F: Float>(a: F, b: F, flags: &mut u32) -> bool {
+    if a.is_snan() || b.is_snan() {
+        *flags |= FFLAG_NV;
+    }
+    a == b
+}
+
+/// Signaling comparison, any NaN operand raises the invalid flag
+pub fn lt<F: Float>(a: F, b: F, flags: &mut u32) -> bool {
+    if a.is_nan() || b.is_nan() {
+        *flags |= FFLAG_NV;
+    }
+    a < b
+}
+
+/// Signaling comparison, any NaN operand raises the invalid flag
+pub fn le<F: Float>(a: F, b: F, flags: &mut u32) -> bool {
+    if a.is_nan() || b.is_nan() {
+        *flags |= FFLAG_NV;
+    }
+    a <= b
+}
+
+/// Returns the FCLASS bit mask for `a`
+pub fn classify<F: Float>(a: F) -> u64 {
+    let neg = a.is_sign_negative();
+    let bit = if a.is_nan() {
+        if a.is_snan() { 8 } else { 9 }
+    } else if a.is_infinite() {
+        if neg { 0 } else { 7 }
+    } else if a == F::ZERO {
+        if neg { 3 } else { 4 }
+    } else if a.is_subnormal() {
+        if neg { 2 } else { 5 }
+    } else if neg { 1 } else { 6 };
+    1 << bit
+}
+
+/// Converts to an integer in `[min, max]`, saturating out of range values
+/// and NaNs as the spec requires.
+pub fn to_int<F: Float>(a: F, min: i128, max: i128, rm: RoundingMode,
+                        flags: &mut u32) -> i128 {
+    if a.is_nan() {
+        *flags |= FFLAG_NV;
+        return max;
+    }
+
+    let a = a.to_f64();
+    let r = match rm {
+        RoundingMode::Rne => a.round_ties_even(),
+        RoundingMode::Rtz => a.trunc(),
+        RoundingMode::Rdn => a.floor(),
+        RoundingMode::Rup => a.ceil(),
+        RoundingMode::Rmm => a.round(),
+    };
+
+    let v = r as i128;
+    if v < min || v > max {
+        *flags |= FFLAG_NV;
+        return if a < 0.0 { min } else { max };
+    }
+    if r != a {
+        *flags |= FFLAG_NX;
+    }
+    v
+}
+
+pub fn from_int<F: Float>(v: i128, rm: RoundingMode, flags: &mut u32) -> F {
+    let r = F::from_i128(v);
+    let err = v - r.to_i128();
+    if err == 0 {
+        return r;
+    }
+
+    let other = if err > 0 { r.next_up() } else { r.next_down() };
+    let tie = other.to_i128() - r.to_i128() == 2 * err;
+    *flags |= FFLAG_NX;
+    select(r, other, tie, rm)
+}
+
+pub fn f64_to_f32(a: f64, rm: RoundingMode, flags: &mut u32) -> f32 {
+    if a.is_nan() {
+        if Float::is_snan(a) {
+            *flags |= FFLAG_NV;
+        }
+        return f32::CANONICAL_NAN;
+    }
+
+    let r = a as f32;
+    if r.is_infinite() && !a.is_infinite() {
+        return round(r, overflow_err(r), rm, flags);
+    }
+    round(r, a - r as f64, rm, flags)
+}
+
+pub fn f32_to_f64(a: f32, flags: &mut u32) -> f64 {
+    if a.is_nan() {
+        if Float::is_snan(a) {
+            *flags |= FFLAG_NV;
+        }
+        return f64::CANONICAL_NAN;
+    }
+    a as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directed_rounding() {
+        let mut flags = 0;
+        let third = |rm, flags: &mut u32| div(1.0f32, 3.0, rm, flags);
+
+        let rne = third(RoundingMode::Rne, &mut flags);
+        assert_eq!(flags, FFLAG_NX);
+        // 1/3 rounds up to nearest in single precision
+        assert_eq!(third(RoundingMode::Rdn, &mut flags), rne.next_down());
+        assert_eq!(third(RoundingMode::Rtz, &mut flags), rne.next_down());
+        assert_eq!(third(RoundingMode::Rup, &mut flags), rne);
+
+        assert_eq!(add(f64::MAX, f64::MAX, RoundingMode::Rtz, &mut flags), f64::MAX);
+        assert_eq!(add(f64::MAX, f64::MAX, RoundingMode::Rne, &mut flags), f64::INFINITY);
+        assert_eq!(add(1.0f64, -1.0, RoundingMode::Rdn, &mut flags).to_bits(), (-0.0f64).to_bits());
+
+        // 2^24 + 1 is a tie between 2^24 and 2^24 + 2 in single precision
+        let v = (1 << 24) + 1;
+        assert_eq!(from_int::<f32>(v, RoundingMode::Rne, &mut flags), 16777216.0);
+        assert_eq!(from_int::<f32>(v, RoundingMode::Rmm, &mut flags), 16777218.0);
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let mut flags = 0;
+        assert!(div(1.0f64, 0.0, RoundingMode::Rne, &mut flags).is_infinite());
+        assert_eq!(flags, FFLAG_DZ);
+
+        flags = 0;
+        let nan = sqrt(-1.0f32, RoundingMode::Rne, &mut flags);
+        assert_eq!(nan.to_bits(), 0x7fc0_0000);
+        assert_eq!(flags, FFLAG_NV);
+
+        flags = 0;
+        assert_eq!(to_int(-0.5f64, 0, u32::MAX as i128, RoundingMode::Rtz, &mut flags), 0);
+        assert_eq!(flags, FFLAG_NX);
+        assert_eq!(to_int(f32::NAN, i32::MIN as i128, i32::MAX as i128, RoundingMode::Rne, &mut flags),
+                   i32::MAX as i128);
+        assert_eq!(flags, FFLAG_NX | FFLAG_NV);
+
+        flags = 0;
+        assert_eq!(min(-0.0f32, 0.0, &mut flags).to_bits(), (-0.0f32).to_bits());
+        assert_eq!(max(f32::NAN, 1.0, &mut flags), 1.0);
+        assert_eq!(flags, 0);
+    }
+}
@@ -1,130 +1,766 @@
+#![allow(clippy::upper_case_acronyms, non_camel_case_types)]
+
 use super::instruction_types::{*};
+use super::float::{self, Float, RoundingMode};
+use super::vector;
+use super::crypto;
+use crate::riscv::register::{Register, FRegister, Xlen};
 use crate::riscv::register::Register::{*};
-use crate::common::{Emulate, Disassemble, Instruction, Machine, VmExit};
-use crate::instr;
+use crate::riscv::isa::Isa;
+use crate::riscv::isa::Extension::{*};
+use crate::common::{Emulate, Disassemble, Machine, VmExit};
+use crate::riscv::csr::{self, Privilege};
+use crate::riscv::trap::Exception;
+use crate::mmu::{Access, VirtAddr};
+use crate::{instr, instr_enum};
+
 
+/// Shift amount in the low log2(XLEN) bits of `reg`
+fn shamt(m: &Machine, reg: Register) -> u32 {
+    (m.get_r(reg) & (m.xlen().bits() as u64 - 1)) as u32
+}
 
-instr!(LUI,    Utype,      _i, _m, Err(VmExit::NotImpl));
+instr!(LUI,    Utype,      i, m, m.set_r(i.rd, i.imm as i64 as u64));
 instr!(AUIPC,  Utype,      i, m, m.set_r(i.rd, (i.imm as i64 as u64).wrapping_add(m.get_r(Pc))));
 instr!(JAL,    Jtype,      i, m, {
-    let pc = m.get_r(Pc);
-    m.set_r(i.rd, pc.wrapping_add(4))?;
-    m.set_r(Pc, pc.wrapping_add(i.imm as i64 as u64).wrapping_sub(4))});
+    let target = m.get_r(Pc).wrapping_add(i.imm as i64 as u64);
+    let link = m.get_next_pc();
+    m.jump(target)?;
+    m.set_r(i.rd, link)});
 instr!(JALR,   Itype,      i, m, {
-    let pc = m.get_r(Pc);
-    let target = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64).wrapping_sub(4);
-    m.set_r(i.rd, pc.wrapping_add(4))?;
-    m.set_r(Pc, target)});
+    let target = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64) & !1;
+    let link = m.get_next_pc();
+    m.jump(target)?;
+    m.set_r(i.rd, link)});
 instr!(BEQ,    Btype,      i, m, {
-    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64).wrapping_sub(4);
-    if m.get_r(i.rs1) == m.get_r(i.rs1) { m.set_r(Pc, t) } else { Ok(())}});
+    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64);
+    if m.get_r(i.rs1) == m.get_r(i.rs2) { m.jump(t) } else { Ok(())}});
 instr!(BNE,    Btype,      i, m, {
-    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64).wrapping_sub(4);
-    if m.get_r(i.rs1) != m.get_r(i.rs1) { m.set_r(Pc, t) } else { Ok(())}});
+    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64);
+    if m.get_r(i.rs1) != m.get_r(i.rs2) { m.jump(t) } else { Ok(())}});
 instr!(BLT,    Btype,      i, m, {
-    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64).wrapping_sub(4);
-    if (m.get_r(i.rs1) as i64) < (m.get_r(i.rs1) as i64) { m.set_r(Pc, t) } else { Ok(())}});
+    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64);
+    if (m.get_r(i.rs1) as i64) < (m.get_r(i.rs2) as i64) { m.jump(t) } else { Ok(())}});
 instr!(BGE,    Btype,      i, m, {
-    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64).wrapping_sub(4);
-    if (m.get_r(i.rs1) as i64) >= (m.get_r(i.rs1) as i64) { m.set_r(Pc, t) } else { Ok(())}});
+    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64);
+    if (m.get_r(i.rs1) as i64) >= (m.get_r(i.rs2) as i64) { m.jump(t) } else { Ok(())}});
 instr!(BLTU,   Btype,      i, m, {
-    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64).wrapping_sub(4);
-    if m.get_r(i.rs1) < m.get_r(i.rs1) { m.set_r(Pc, t) } else { Ok(())}});
+    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64);
+    if m.get_r(i.rs1) < m.get_r(i.rs2) { m.jump(t) } else { Ok(())}});
 instr!(BGEU,   Btype,      i, m, {
-    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64).wrapping_sub(4);
-    if m.get_r(i.rs1) >= m.get_r(i.rs1) { m.set_r(Pc, t) } else { Ok(())}});
-instr!(LB,     Itype,      _i, _m, Err(VmExit::NotImpl));
-instr!(LH,     Itype,      _i, _m, Err(VmExit::NotImpl));
-instr!(LW,     Itype,      _i, _m, Err(VmExit::NotImpl));
-instr!(LBU,    Itype,      _i, _m, Err(VmExit::NotImpl));
-instr!(LHU,    Itype,      _i, _m, Err(VmExit::NotImpl));
-instr!(SB,     Stype,      _i, _m, Err(VmExit::NotImpl));
-instr!(SH,     Stype,      _i, _m, Err(VmExit::NotImpl));
-instr!(SW,     Stype,      _i, _m, Err(VmExit::NotImpl));
+    let t = m.get_r(Pc).wrapping_add(i.imm as i64 as u64);
+    if m.get_r(i.rs1) >= m.get_r(i.rs2) { m.jump(t) } else { Ok(())}});
+instr!(LB,     Itype,      i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    let v = m.mmu.read_u8(m.vaddr(addr))?;
+    m.set_r(i.rd, v as i8 as i64 as u64)});
+instr!(LH,     Itype,      i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    let v = m.mmu.read_u16(m.vaddr(addr))?;
+    m.set_r(i.rd, v as i16 as i64 as u64)});
+instr!(LW,     Itype,      i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    let v = m.mmu.read_u32(m.vaddr(addr))?;
+    m.set_r(i.rd, v as i32 as i64 as u64)});
+instr!(LBU,    Itype,      i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    let v = m.mmu.read_u8(m.vaddr(addr))?;
+    m.set_r(i.rd, v as u64)});
+instr!(LHU,    Itype,      i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    let v = m.mmu.read_u16(m.vaddr(addr))?;
+    m.set_r(i.rd, v as u64)});
+instr!(SB,     Stype,      i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    m.invalidate_reservation(m.vaddr(addr), 1);
+    m.mmu.write_u8(m.vaddr(addr), m.get_r(i.rs2) as u8)?;
+    Ok(())});
+instr!(SH,     Stype,      i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    m.invalidate_reservation(m.vaddr(addr), 2);
+    m.mmu.write_u16(m.vaddr(addr), m.get_r(i.rs2) as u16)?;
+    Ok(())});
+instr!(SW,     Stype,      i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    m.invalidate_reservation(m.vaddr(addr), 4);
+    m.mmu.write_u32(m.vaddr(addr), m.get_r(i.rs2) as u32)?;
+    Ok(())});
 instr!(ADDI,   Itype,      i, m, m.set_r(i.rd, m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64)));
-instr!(SLTI,   Itype,      _i, _m, Err(VmExit::NotImpl));
-instr!(SLTIU,  Itype,      _i, _m, Err(VmExit::NotImpl));
-instr!(XORI,   Itype,      _i, _m, Err(VmExit::NotImpl));
-instr!(ORI,    Itype,      _i, _m, Err(VmExit::NotImpl));
-instr!(ANDI,   Itype,      _i, _m, Err(VmExit::NotImpl));
+instr!(SLTI,   Itype,      i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as i64) < (i.imm as i64)) as u64));
+instr!(SLTIU,  Itype,      i, m, m.set_r(i.rd, (m.get_r(i.rs1) < (i.imm as i64 as u64)) as u64));
+instr!(XORI,   Itype,      i, m, m.set_r(i.rd, m.get_r(i.rs1) ^ (i.imm as i64 as u64)));
+instr!(ORI,    Itype,      i, m, m.set_r(i.rd, m.get_r(i.rs1) | (i.imm as i64 as u64)));
+instr!(ANDI,   Itype,      i, m, m.set_r(i.rd, m.get_r(i.rs1) & (i.imm as i64 as u64)));
 instr!(SLLI,   ItypeShift, i, m, m.set_r(i.rd, m.get_r(i.rs1) << i.shamt));
-instr!(SRLI,   ItypeShift, i, m, m.set_r(i.rd, m.get_r(i.rs1) >> i.shamt));
+instr!(SRLI,   ItypeShift, i, m, m.set_r(i.rd, m.get_ru(i.rs1) >> i.shamt));
 instr!(SRAI,   ItypeShift, i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as i64) >> i.shamt) as u64));
 instr!(ADD,    ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1).wrapping_add(m.get_r(i.rs2))));
 instr!(SUB,    ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1).wrapping_sub(m.get_r(i.rs2))));
-instr!(SLL,    ItypeOp,    _i, _m, Err(VmExit::NotImpl));
-instr!(SLT,    ItypeOp,    _i, _m, Err(VmExit::NotImpl));
-instr!(SLTU,   ItypeOp,    _i, _m, Err(VmExit::NotImpl));
-instr!(XOR,    ItypeOp,    _i, _m, Err(VmExit::NotImpl));
-instr!(SRL,    ItypeOp,    _i, _m, Err(VmExit::NotImpl));
-instr!(SRA,    ItypeOp,    _i, _m, Err(VmExit::NotImpl));
-instr!(OR,     ItypeOp,    _i, _m, Err(VmExit::NotImpl));
-instr!(AND,    ItypeOp,    _i, _m, Err(VmExit::NotImpl));
-// TODO: FENCE
+instr!(SLL,    ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1) << shamt(m, i.rs2)));
+instr!(SLT,    ItypeOp,    i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as i64) < (m.get_r(i.rs2) as i64)) as u64));
+instr!(SLTU,   ItypeOp,    i, m, m.set_r(i.rd, (m.get_r(i.rs1) < m.get_r(i.rs2)) as u64));
+instr!(XOR,    ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1) ^ m.get_r(i.rs2)));
+instr!(SRL,    ItypeOp,    i, m, m.set_r(i.rd, m.get_ru(i.rs1) >> shamt(m, i.rs2)));
+instr!(SRA,    ItypeOp,    i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as i64) >> shamt(m, i.rs2)) as u64));
+instr!(OR,     ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1) | m.get_r(i.rs2)));
+instr!(AND,    ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1) & m.get_r(i.rs2)));
+instr!(FENCE,  Ntype,      _i, _m, Ok(()));
 instr!(ECALL,  Ntype,      _i, _m, Err(VmExit::Syscall));
-instr!(EBREAK, Ntype,      _i, _m, Err(VmExit::NotImpl));
-// TODO: CSRR
+instr!(EBREAK, Ntype,      _i, m, Err(VmExit::Exception(Exception::Breakpoint, m.get_r(Register::Pc))));
+
+// Privileged
+instr!(SRET,   Ntype,      _i, m, m.sret());
+instr!(MRET,   Ntype,      _i, m, m.mret());
+instr!(WFI,    Ntype,      _i, m, {
+    // Completes right away, the hart polls for interrupts between
+    // instructions anyway. This only has to check that lower privilege
+    // levels are allowed to stall the hart.
+    let status = m.csrs.get(csr::MSTATUS).unwrap();
+    if m.get_privilege() < Privilege::Machine && status & csr::MSTATUS_TW != 0 {
+        return Err(VmExit::IllegalInstruction);
+    }
+    Ok(())});
+instr!(SFENCE_VMA, ItypeOp, i, m, m.sfence_vma(i.rs1, i.rs2));
+
+// Zifencei
+instr!(FENCE_I, Ntype,     _i, m, {
+    m.flush_icache();
+    Ok(())});
+
+// Zicsr
+instr!(CSRRW,  Csrtype,    i, m, {
+    let src = m.get_r(Register::from(i.rs1));
+    if i.rd != Zero {
+        let old = m.read_csr(i.csr)?;
+        m.write_csr(i.csr, src)?;
+        m.set_r(i.rd, old)
+    } else {
+        m.write_csr(i.csr, src)
+    }});
+instr!(CSRRS,  Csrtype,    i, m, {
+    let src = m.get_r(Register::from(i.rs1));
+    let old = m.read_csr(i.csr)?;
+    if i.rs1 != 0 { m.write_csr(i.csr, old | src)?; }
+    m.set_r(i.rd, old)});
+instr!(CSRRC,  Csrtype,    i, m, {
+    let src = m.get_r(Register::from(i.rs1));
+    let old = m.read_csr(i.csr)?;
+    if i.rs1 != 0 { m.write_csr(i.csr, old & !src)?; }
+    m.set_r(i.rd, old)});
+instr!(CSRRWI, Csrtype,    i, m, {
+    if i.rd != Zero {
+        let old = m.read_csr(i.csr)?;
+        m.write_csr(i.csr, i.rs1 as u64)?;
+        m.set_r(i.rd, old)
+    } else {
+        m.write_csr(i.csr, i.rs1 as u64)
+    }});
+instr!(CSRRSI, Csrtype,    i, m, {
+    let old = m.read_csr(i.csr)?;
+    if i.rs1 != 0 { m.write_csr(i.csr, old | i.rs1 as u64)?; }
+    m.set_r(i.rd, old)});
+instr!(CSRRCI, Csrtype,    i, m, {
+    let old = m.read_csr(i.csr)?;
+    if i.rs1 != 0 { m.write_csr(i.csr, old & !(i.rs1 as u64))?; }
+    m.set_r(i.rd, old)});
 
 // RV64I
-instr!(LWU,   Itype,       _i, _m, Err(VmExit::NotImpl));
-instr!(LD,    Itype,       _i, _m, Err(VmExit::NotImpl));
-instr!(SD,    Stype,       _i, _m, Err(VmExit::NotImpl));
-// TODO: SSLI
-// TODO: SRLI
-// TODO: SRAI
-instr!(ADDIW, Itype,       _i, _m, Err(VmExit::NotImpl));
-instr!(SLLIW, ItypeShift,  _i, _m, Err(VmExit::NotImpl));
-instr!(SRLIW, ItypeShift,  _i, _m, Err(VmExit::NotImpl));
-instr!(SRAIW, ItypeShift,  _i, _m, Err(VmExit::NotImpl));
-instr!(ADDW,  ItypeOp,     _i, _m, Err(VmExit::NotImpl));
-instr!(SUBW,  ItypeOp,     _i, _m, Err(VmExit::NotImpl));
-instr!(SLLW,  ItypeOp,     _i, _m, Err(VmExit::NotImpl));
-instr!(SRLW,  ItypeOp,     _i, _m, Err(VmExit::NotImpl));
-instr!(SRAW,  ItypeOp,     _i, _m, Err(VmExit::NotImpl));
-
-
-pub fn parse_instruction(i: u32) -> Result<Box<dyn Instruction>, VmExit> {
+instr!(LWU,   Itype,       i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    let v = m.mmu.read_u32(m.vaddr(addr))?;
+    m.set_r(i.rd, v as u64)});
+instr!(LD,    Itype,       i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    let v = m.mmu.read_u64(m.vaddr(addr))?;
+    m.set_r(i.rd, v)});
+instr!(SD,    Stype,       i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    m.invalidate_reservation(m.vaddr(addr), 8);
+    m.mmu.write_u64(m.vaddr(addr), m.get_r(i.rs2))?;
+    Ok(())});
+instr!(ADDIW, Itype,       i, m, m.set_r(i.rd, (m.get_r(i.rs1) as i32).wrapping_add(i.imm) as i64 as u64));
+instr!(SLLIW, ItypeShift,  i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as u32) << i.shamt) as i32 as i64 as u64));
+instr!(SRLIW, ItypeShift,  i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as u32) >> i.shamt) as i32 as i64 as u64));
+instr!(SRAIW, ItypeShift,  i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as i32) >> i.shamt) as i64 as u64));
+instr!(ADDW,  ItypeOp,     i, m, m.set_r(i.rd, (m.get_r(i.rs1) as i32).wrapping_add(m.get_r(i.rs2) as i32) as i64 as u64));
+instr!(SUBW,  ItypeOp,     i, m, m.set_r(i.rd, (m.get_r(i.rs1) as i32).wrapping_sub(m.get_r(i.rs2) as i32) as i64 as u64));
+instr!(SLLW,  ItypeOp,     i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as u32) << (m.get_r(i.rs2) & 0x1f)) as i32 as i64 as u64));
+instr!(SRLW,  ItypeOp,     i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as u32) >> (m.get_r(i.rs2) & 0x1f)) as i32 as i64 as u64));
+instr!(SRAW,  ItypeOp,     i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as i32) >> (m.get_r(i.rs2) & 0x1f)) as i64 as u64));
+
+
+// RV32M
+instr!(MUL,    ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1).wrapping_mul(m.get_r(i.rs2))));
+instr!(MULH,   ItypeOp,    i, m, {
+    let r = (m.get_r(i.rs1) as i64 as i128) * (m.get_r(i.rs2) as i64 as i128);
+    m.set_r(i.rd, (r >> m.xlen().bits()) as u64)});
+instr!(MULHSU, ItypeOp,    i, m, {
+    let r = (m.get_r(i.rs1) as i64 as i128) * (m.get_ru(i.rs2) as i128);
+    m.set_r(i.rd, (r >> m.xlen().bits()) as u64)});
+instr!(MULHU,  ItypeOp,    i, m, {
+    let r = (m.get_ru(i.rs1) as u128) * (m.get_ru(i.rs2) as u128);
+    m.set_r(i.rd, (r >> m.xlen().bits()) as u64)});
+instr!(DIV,    ItypeOp,    i, m, {
+    let (a, b) = (m.get_r(i.rs1) as i64, m.get_r(i.rs2) as i64);
+    m.set_r(i.rd, if b == 0 { u64::MAX } else { a.wrapping_div(b) as u64 })});
+instr!(DIVU,   ItypeOp,    i, m, {
+    let (a, b) = (m.get_ru(i.rs1), m.get_ru(i.rs2));
+    m.set_r(i.rd, a.checked_div(b).unwrap_or(u64::MAX))});
+instr!(REM,    ItypeOp,    i, m, {
+    let (a, b) = (m.get_r(i.rs1) as i64, m.get_r(i.rs2) as i64);
+    m.set_r(i.rd, if b == 0 { a as u64 } else { a.wrapping_rem(b) as u64 })});
+instr!(REMU,   ItypeOp,    i, m, {
+    let (a, b) = (m.get_ru(i.rs1), m.get_ru(i.rs2));
+    m.set_r(i.rd, a.checked_rem(b).unwrap_or(a))});
+
+// RV64M
+instr!(MULW,   ItypeOp,    i, m, m.set_r(i.rd, (m.get_r(i.rs1) as i32).wrapping_mul(m.get_r(i.rs2) as i32) as i64 as u64));
+instr!(DIVW,   ItypeOp,    i, m, {
+    let (a, b) = (m.get_r(i.rs1) as i32, m.get_r(i.rs2) as i32);
+    m.set_r(i.rd, if b == 0 { u64::MAX } else { a.wrapping_div(b) as i64 as u64 })});
+instr!(DIVUW,  ItypeOp,    i, m, {
+    let (a, b) = (m.get_r(i.rs1) as u32, m.get_r(i.rs2) as u32);
+    m.set_r(i.rd, a.checked_div(b).map_or(u64::MAX, |q| q as i32 as i64 as u64))});
+instr!(REMW,   ItypeOp,    i, m, {
+    let (a, b) = (m.get_r(i.rs1) as i32, m.get_r(i.rs2) as i32);
+    m.set_r(i.rd, if b == 0 { a as i64 as u64 } else { a.wrapping_rem(b) as i64 as u64 })});
+instr!(REMUW,  ItypeOp,    i, m, {
+    let (a, b) = (m.get_r(i.rs1) as u32, m.get_r(i.rs2) as u32);
+    m.set_r(i.rd, a.checked_rem(b).unwrap_or(a) as i32 as i64 as u64)});
+
+// RV32A
+/// Address operand of an AMO or LR/SC, which has to be naturally aligned.
+/// Faults on everything but LR are reported as store faults.
+fn amo_addr(m: &Machine, i: Rtype, size: u64, load: bool) -> Result<VirtAddr, VmExit> {
+    let addr = m.get_r(i.rs1);
+    if !addr.is_multiple_of(size) {
+        let e = if load { Exception::LoadAddressMisaligned } else { Exception::StoreAddressMisaligned };
+        return Err(VmExit::Exception(e, addr));
+    }
+    Ok(m.vaddr(addr))
+}
+
+/// Fault of the read of an AMO, which lacks write permission rather than
+/// read permission as the AMO checked for a store first
+fn amo_fault(e: VmExit) -> VmExit {
+    match e {
+        VmExit::ReadFault(addr) => VmExit::WriteFault(addr),
+        e => e,
+    }
+}
+
+fn amo_w(m: &mut Machine, i: Rtype, op: fn(u32, u32) -> u32) -> Result<(), VmExit> {
+    let addr = amo_addr(m, i, 4, false)?;
+    let src = m.get_r(i.rs2) as u32;
+    // AMOs need write permission even for the read
+    m.mmu.translate(addr, Access::Write)?;
+    let old = m.mmu.read_u32(addr).map_err(amo_fault)?;
+    m.invalidate_reservation(addr, 4);
+    m.mmu.write_u32(addr, op(old, src))?;
+    m.set_r(i.rd, old as i32 as i64 as u64)
+}
+
+instr!(LR_W,      Rtype,   i, m, {
+    let addr = amo_addr(m, i, 4, true)?;
+    let v = m.mmu.read_u32(addr)?;
+    m.reserve(addr);
+    m.set_r(i.rd, v as i32 as i64 as u64)});
+instr!(SC_W,      Rtype,   i, m, {
+    let addr = amo_addr(m, i, 4, false)?;
+    if m.take_reservation(addr) {
+        m.mmu.write_u32(addr, m.get_r(i.rs2) as u32)?;
+        m.set_r(i.rd, 0)
+    } else {
+        m.set_r(i.rd, 1)
+    }});
+instr!(AMOSWAP_W, Rtype,   i, m, amo_w(m, i, |_, b| b));
+instr!(AMOADD_W,  Rtype,   i, m, amo_w(m, i, |a, b| a.wrapping_add(b)));
+instr!(AMOXOR_W,  Rtype,   i, m, amo_w(m, i, |a, b| a ^ b));
+instr!(AMOAND_W,  Rtype,   i, m, amo_w(m, i, |a, b| a & b));
+instr!(AMOOR_W,   Rtype,   i, m, amo_w(m, i, |a, b| a | b));
+instr!(AMOMIN_W,  Rtype,   i, m, amo_w(m, i, |a, b| std::cmp::min(a as i32, b as i32) as u32));
+instr!(AMOMAX_W,  Rtype,   i, m, amo_w(m, i, |a, b| std::cmp::max(a as i32, b as i32) as u32));
+instr!(AMOMINU_W, Rtype,   i, m, amo_w(m, i, std::cmp::min));
+instr!(AMOMAXU_W, Rtype,   i, m, amo_w(m, i, std::cmp::max));
+
+// RV64A
+fn amo_d(m: &mut Machine, i: Rtype, op: fn(u64, u64) -> u64) -> Result<(), VmExit> {
+    let addr = amo_addr(m, i, 8, false)?;
+    let src = m.get_r(i.rs2);
+    // AMOs need write permission even for the read
+    m.mmu.translate(addr, Access::Write)?;
+    let old = m.mmu.read_u64(addr).map_err(amo_fault)?;
+    m.invalidate_reservation(addr, 8);
+    m.mmu.write_u64(addr, op(old, src))?;
+    m.set_r(i.rd, old)
+}
+
+instr!(LR_D,      Rtype,   i, m, {
+    let addr = amo_addr(m, i, 8, true)?;
+    let v = m.mmu.read_u64(addr)?;
+    m.reserve(addr);
+    m.set_r(i.rd, v)});
+instr!(SC_D,      Rtype,   i, m, {
+    let addr = amo_addr(m, i, 8, false)?;
+    if m.take_reservation(addr) {
+        m.mmu.write_u64(addr, m.get_r(i.rs2))?;
+        m.set_r(i.rd, 0)
+    } else {
+        m.set_r(i.rd, 1)
+    }});
+instr!(AMOSWAP_D, Rtype,   i, m, amo_d(m, i, |_, b| b));
+instr!(AMOADD_D,  Rtype,   i, m, amo_d(m, i, |a, b| a.wrapping_add(b)));
+instr!(AMOXOR_D,  Rtype,   i, m, amo_d(m, i, |a, b| a ^ b));
+instr!(AMOAND_D,  Rtype,   i, m, amo_d(m, i, |a, b| a & b));
+instr!(AMOOR_D,   Rtype,   i, m, amo_d(m, i, |a, b| a | b));
+instr!(AMOMIN_D,  Rtype,   i, m, amo_d(m, i, |a, b| std::cmp::min(a as i64, b as i64) as u64));
+instr!(AMOMAX_D,  Rtype,   i, m, amo_d(m, i, |a, b| std::cmp::max(a as i64, b as i64) as u64));
+instr!(AMOMINU_D, Rtype,   i, m, amo_d(m, i, std::cmp::min));
+instr!(AMOMAXU_D, Rtype,   i, m, amo_d(m, i, std::cmp::max));
+
+// RV32F/RV32D
+trait FpValue: Float {
+    fn get(m: &Machine, reg: FRegister) -> Self;
+    fn set(m: &mut Machine, reg: FRegister, value: Self) -> Result<(), VmExit>;
+}
+
+impl FpValue for f32 {
+    fn get(m: &Machine, reg: FRegister) -> Self { m.get_f32(reg) }
+    fn set(m: &mut Machine, reg: FRegister, value: Self) -> Result<(), VmExit> { m.set_f32(reg, value) }
+}
+
+impl FpValue for f64 {
+    fn get(m: &Machine, reg: FRegister) -> Self { m.get_f64(reg) }
+    fn set(m: &mut Machine, reg: FRegister, value: Self) -> Result<(), VmExit> { m.set_f64(reg, value) }
+}
+
+fn fop<F: FpValue>(m: &mut Machine, i: FRtype,
+                   op: fn(F, F, RoundingMode, &mut u32) -> F) -> Result<(), VmExit> {
+    let rm = m.rounding_mode(i.rm)?;
+    let mut flags = 0;
+    let r = op(F::get(m, i.rs1), F::get(m, i.rs2), rm, &mut flags);
+    m.set_fflags(flags);
+    F::set(m, i.rd, r)
+}
+
+fn fsqrt<F: FpValue>(m: &mut Machine, i: FRtype) -> Result<(), VmExit> {
+    let rm = m.rounding_mode(i.rm)?;
+    let mut flags = 0;
+    let r = float::sqrt(F::get(m, i.rs1), rm, &mut flags);
+    m.set_fflags(flags);
+    F::set(m, i.rd, r)
+}
+
+fn fminmax<F: FpValue>(m: &mut Machine, i: FRtype, op: fn(F, F, &mut u32) -> F) -> Result<(), VmExit> {
+    let mut flags = 0;
+    let r = op(F::get(m, i.rs1), F::get(m, i.rs2), &mut flags);
+    m.set_fflags(flags);
+    F::set(m, i.rd, r)
+}
+
+/// Fused multiply-add, where the product and addend are negated according to
+/// `neg_prod` and `neg_add`
+fn fmadd<F: FpValue>(m: &mut Machine, i: R4type, neg_prod: bool, neg_add: bool) -> Result<(), VmExit> {
+    let rm = m.rounding_mode(i.rm)?;
+    let (a, b, c) = (F::get(m, i.rs1), F::get(m, i.rs2), F::get(m, i.rs3));
+    let a = if neg_prod && !a.is_nan() { -a } else { a };
+    let c = if neg_add && !c.is_nan() { -c } else { c };
+    let mut flags = 0;
+    let r = float::fma(a, b, c, rm, &mut flags);
+    m.set_fflags(flags);
+    F::set(m, i.rd, r)
+}
+
+/// Sign injection, `op` computes the result sign from the signs of rs1 and rs2
+fn fsgnj<F: FpValue>(m: &mut Machine, i: FRtype, op: fn(bool, bool) -> bool) -> Result<(), VmExit> {
+    let (a, b) = (F::get(m, i.rs1), F::get(m, i.rs2));
+    let r = if op(a.is_sign_negative(), b.is_sign_negative()) { -a.abs() } else { a.abs() };
+    F::set(m, i.rd, r)
+}
+
+fn fcmp<F: FpValue>(m: &mut Machine, i: FRtypeToInt, op: fn(F, F, &mut u32) -> bool) -> Result<(), VmExit> {
+    let mut flags = 0;
+    let r = op(F::get(m, i.rs1), F::get(m, i.rs2), &mut flags);
+    m.set_fflags(flags);
+    m.set_r(i.rd, r as u64)
+}
+
+fn fclass<F: FpValue>(m: &mut Machine, i: FRtypeToInt) -> Result<(), VmExit> {
+    m.set_r(i.rd, float::classify(F::get(m, i.rs1)))
+}
+
+/// Conversion to an integer in `[min, max]`, `ext` widens the result to XLEN
+fn fcvt_to_int<F: FpValue>(m: &mut Machine, i: FRtypeToInt, min: i128, max: i128,
+                           ext: fn(i128) -> u64) -> Result<(), VmExit> {
+    let rm = m.rounding_mode(i.rm)?;
+    let mut flags = 0;
+    let r = float::to_int(F::get(m, i.rs1), min, max, rm, &mut flags);
+    m.set_fflags(flags);
+    m.set_r(i.rd, ext(r))
+}
+
+/// Conversion from an integer, `ext` interprets rs1 as signed or unsigned
+fn fcvt_from_int<F: FpValue>(m: &mut Machine, i: FRtypeFromInt, ext: fn(u64) -> i128) -> Result<(), VmExit> {
+    let rm = m.rounding_mode(i.rm)?;
+    let mut flags = 0;
+    let r = float::from_int(ext(m.get_r(i.rs1)), rm, &mut flags);
+    m.set_fflags(flags);
+    F::set(m, i.rd, r)
+}
+
+instr!(FLW,       FItype,        i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    let v = m.mmu.read_u32(m.vaddr(addr))?;
+    m.set_f(i.rd, 0xffff_ffff_0000_0000 | v as u64)});
+instr!(FSW,       FStype,        i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    m.invalidate_reservation(m.vaddr(addr), 4);
+    m.mmu.write_u32(m.vaddr(addr), m.get_f(i.rs2) as u32)?;
+    Ok(())});
+instr!(FMADD_S,   R4type,        i, m, fmadd::<f32>(m, i, false, false));
+instr!(FMSUB_S,   R4type,        i, m, fmadd::<f32>(m, i, false, true));
+instr!(FNMSUB_S,  R4type,        i, m, fmadd::<f32>(m, i, true, false));
+instr!(FNMADD_S,  R4type,        i, m, fmadd::<f32>(m, i, true, true));
+instr!(FADD_S,    FRtype,        i, m, fop::<f32>(m, i, float::add));
+instr!(FSUB_S,    FRtype,        i, m, fop::<f32>(m, i, float::sub));
+instr!(FMUL_S,    FRtype,        i, m, fop::<f32>(m, i, float::mul));
+instr!(FDIV_S,    FRtype,        i, m, fop::<f32>(m, i, float::div));
+instr!(FSQRT_S,   FRtype,        i, m, fsqrt::<f32>(m, i));
+instr!(FSGNJ_S,   FRtype,        i, m, fsgnj::<f32>(m, i, |_, b| b));
+instr!(FSGNJN_S,  FRtype,        i, m, fsgnj::<f32>(m, i, |_, b| !b));
+instr!(FSGNJX_S,  FRtype,        i, m, fsgnj::<f32>(m, i, |a, b| a ^ b));
+instr!(FMIN_S,    FRtype,        i, m, fminmax::<f32>(m, i, float::min));
+instr!(FMAX_S,    FRtype,        i, m, fminmax::<f32>(m, i, float::max));
+instr!(FCVT_W_S,  FRtypeToInt,   i, m, fcvt_to_int::<f32>(m, i, i32::MIN as i128, i32::MAX as i128, |v| v as i32 as i64 as u64));
+instr!(FCVT_WU_S, FRtypeToInt,   i, m, fcvt_to_int::<f32>(m, i, 0, u32::MAX as i128, |v| v as u32 as i32 as i64 as u64));
+instr!(FMV_X_W,   FRtypeToInt,   i, m, m.set_r(i.rd, m.get_f(i.rs1) as i32 as i64 as u64));
+instr!(FEQ_S,     FRtypeToInt,   i, m, fcmp::<f32>(m, i, float::eq));
+instr!(FLT_S,     FRtypeToInt,   i, m, fcmp::<f32>(m, i, float::lt));
+instr!(FLE_S,     FRtypeToInt,   i, m, fcmp::<f32>(m, i, float::le));
+instr!(FCLASS_S,  FRtypeToInt,   i, m, fclass::<f32>(m, i));
+instr!(FCVT_S_W,  FRtypeFromInt, i, m, fcvt_from_int::<f32>(m, i, |v| v as i32 as i128));
+instr!(FCVT_S_WU, FRtypeFromInt, i, m, fcvt_from_int::<f32>(m, i, |v| v as u32 as i128));
+instr!(FMV_W_X,   FRtypeFromInt, i, m, m.set_f(i.rd, 0xffff_ffff_0000_0000 | (m.get_r(i.rs1) & 0xffff_ffff)));
+
+instr!(FLD,       FItype,        i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    let v = m.mmu.read_u64(m.vaddr(addr))?;
+    m.set_f(i.rd, v)});
+instr!(FSD,       FStype,        i, m, {
+    let addr = m.get_r(i.rs1).wrapping_add(i.imm as i64 as u64);
+    m.invalidate_reservation(m.vaddr(addr), 8);
+    m.mmu.write_u64(m.vaddr(addr), m.get_f(i.rs2))?;
+    Ok(())});
+instr!(FMADD_D,   R4type,        i, m, fmadd::<f64>(m, i, false, false));
+instr!(FMSUB_D,   R4type,        i, m, fmadd::<f64>(m, i, false, true));
+instr!(FNMSUB_D,  R4type,        i, m, fmadd::<f64>(m, i, true, false));
+instr!(FNMADD_D,  R4type,        i, m, fmadd::<f64>(m, i, true, true));
+instr!(FADD_D,    FRtype,        i, m, fop::<f64>(m, i, float::add));
+instr!(FSUB_D,    FRtype,        i, m, fop::<f64>(m, i, float::sub));
+instr!(FMUL_D,    FRtype,        i, m, fop::<f64>(m, i, float::mul));
+instr!(FDIV_D,    FRtype,        i, m, fop::<f64>(m, i, float::div));
+instr!(FSQRT_D,   FRtype,        i, m, fsqrt::<f64>(m, i));
+instr!(FSGNJ_D,   FRtype,        i, m, fsgnj::<f64>(m, i, |_, b| b));
+instr!(FSGNJN_D,  FRtype,        i, m, fsgnj::<f64>(m, i, |_, b| !b));
+instr!(FSGNJX_D,  FRtype,        i, m, fsgnj::<f64>(m, i, |a, b| a ^ b));
+instr!(FMIN_D,    FRtype,        i, m, fminmax::<f64>(m, i, float::min));
+instr!(FMAX_D,    FRtype,        i, m, fminmax::<f64>(m, i, float::max));
+instr!(FCVT_S_D,  FRtype,        i, m, {
+    let rm = m.rounding_mode(i.rm)?;
+    let mut flags = 0;
+    let r = float::f64_to_f32(m.get_f64(i.rs1), rm, &mut flags);
+    m.set_fflags(flags);
+    m.set_f32(i.rd, r)});
+instr!(FCVT_D_S,  FRtype,        i, m, {
+    let mut flags = 0;
+    let r = float::f32_to_f64(m.get_f32(i.rs1), &mut flags);
+    m.set_fflags(flags);
+    m.set_f64(i.rd, r)});
+instr!(FEQ_D,     FRtypeToInt,   i, m, fcmp::<f64>(m, i, float::eq));
+instr!(FLT_D,     FRtypeToInt,   i, m, fcmp::<f64>(m, i, float::lt));
+instr!(FLE_D,     FRtypeToInt,   i, m, fcmp::<f64>(m, i, float::le));
+instr!(FCLASS_D,  FRtypeToInt,   i, m, fclass::<f64>(m, i));
+instr!(FCVT_W_D,  FRtypeToInt,   i, m, fcvt_to_int::<f64>(m, i, i32::MIN as i128, i32::MAX as i128, |v| v as i32 as i64 as u64));
+instr!(FCVT_WU_D, FRtypeToInt,   i, m, fcvt_to_int::<f64>(m, i, 0, u32::MAX as i128, |v| v as u32 as i32 as i64 as u64));
+instr!(FCVT_D_W,  FRtypeFromInt, i, m, fcvt_from_int::<f64>(m, i, |v| v as i32 as i128));
+instr!(FCVT_D_WU, FRtypeFromInt, i, m, fcvt_from_int::<f64>(m, i, |v| v as u32 as i128));
+
+// RV64F/RV64D
+instr!(FCVT_L_S,  FRtypeToInt,   i, m, fcvt_to_int::<f32>(m, i, i64::MIN as i128, i64::MAX as i128, |v| v as u64));
+instr!(FCVT_LU_S, FRtypeToInt,   i, m, fcvt_to_int::<f32>(m, i, 0, u64::MAX as i128, |v| v as u64));
+instr!(FCVT_S_L,  FRtypeFromInt, i, m, fcvt_from_int::<f32>(m, i, |v| v as i64 as i128));
+instr!(FCVT_S_LU, FRtypeFromInt, i, m, fcvt_from_int::<f32>(m, i, |v| v as i128));
+instr!(FCVT_L_D,  FRtypeToInt,   i, m, fcvt_to_int::<f64>(m, i, i64::MIN as i128, i64::MAX as i128, |v| v as u64));
+instr!(FCVT_LU_D, FRtypeToInt,   i, m, fcvt_to_int::<f64>(m, i, 0, u64::MAX as i128, |v| v as u64));
+instr!(FMV_X_D,   FRtypeToInt,   i, m, m.set_r(i.rd, m.get_f(i.rs1)));
+instr!(FCVT_D_L,  FRtypeFromInt, i, m, fcvt_from_int::<f64>(m, i, |v| v as i64 as i128));
+instr!(FCVT_D_LU, FRtypeFromInt, i, m, fcvt_from_int::<f64>(m, i, |v| v as i128));
+instr!(FMV_D_X,   FRtypeFromInt, i, m, m.set_f(i.rd, m.get_r(i.rs1)));
+
+// Zba
+instr!(SH1ADD,    ItypeOp,    i, m, m.set_r(i.rd, (m.get_r(i.rs1) << 1).wrapping_add(m.get_r(i.rs2))));
+instr!(SH2ADD,    ItypeOp,    i, m, m.set_r(i.rd, (m.get_r(i.rs1) << 2).wrapping_add(m.get_r(i.rs2))));
+instr!(SH3ADD,    ItypeOp,    i, m, m.set_r(i.rd, (m.get_r(i.rs1) << 3).wrapping_add(m.get_r(i.rs2))));
+instr!(ADD_UW,    ItypeOp,    i, m, m.set_r(i.rd, (m.get_r(i.rs1) as u32 as u64).wrapping_add(m.get_r(i.rs2))));
+instr!(SH1ADD_UW, ItypeOp,    i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as u32 as u64) << 1).wrapping_add(m.get_r(i.rs2))));
+instr!(SH2ADD_UW, ItypeOp,    i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as u32 as u64) << 2).wrapping_add(m.get_r(i.rs2))));
+instr!(SH3ADD_UW, ItypeOp,    i, m, m.set_r(i.rd, ((m.get_r(i.rs1) as u32 as u64) << 3).wrapping_add(m.get_r(i.rs2))));
+instr!(SLLI_UW,   ItypeShift, i, m, m.set_r(i.rd, (m.get_r(i.rs1) as u32 as u64) << i.shamt));
+
+// Zbb
+instr!(ANDN,   ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1) & !m.get_r(i.rs2)));
+instr!(ORN,    ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1) | !m.get_r(i.rs2)));
+instr!(XNOR,   ItypeOp,    i, m, m.set_r(i.rd, !(m.get_r(i.rs1) ^ m.get_r(i.rs2))));
+instr!(CLZ,    ItypeUnary, i, m, m.set_r(i.rd, (m.get_ru(i.rs1).leading_zeros() - (64 - m.xlen().bits())) as u64));
+instr!(CTZ,    ItypeUnary, i, m, m.set_r(i.rd, std::cmp::min(m.get_r(i.rs1).trailing_zeros(), m.xlen().bits()) as u64));
+instr!(CPOP,   ItypeUnary, i, m, m.set_r(i.rd, m.get_ru(i.rs1).count_ones() as u64));
+instr!(CLZW,   ItypeUnary, i, m, m.set_r(i.rd, (m.get_r(i.rs1) as u32).leading_zeros() as u64));
+instr!(CTZW,   ItypeUnary, i, m, m.set_r(i.rd, (m.get_r(i.rs1) as u32).trailing_zeros() as u64));
+instr!(CPOPW,  ItypeUnary, i, m, m.set_r(i.rd, (m.get_r(i.rs1) as u32).count_ones() as u64));
+instr!(MAX,    ItypeOp,    i, m, m.set_r(i.rd, std::cmp::max(m.get_r(i.rs1) as i64, m.get_r(i.rs2) as i64) as u64));
+instr!(MAXU,   ItypeOp,    i, m, m.set_r(i.rd, std::cmp::max(m.get_r(i.rs1), m.get_r(i.rs2))));
+instr!(MIN,    ItypeOp,    i, m, m.set_r(i.rd, std::cmp::min(m.get_r(i.rs1) as i64, m.get_r(i.rs2) as i64) as u64));
+instr!(MINU,   ItypeOp,    i, m, m.set_r(i.rd, std::cmp::min(m.get_r(i.rs1), m.get_r(i.rs2))));
+instr!(SEXT_B, ItypeUnary, i, m, m.set_r(i.rd, m.get_r(i.rs1) as i8 as i64 as u64));
+instr!(SEXT_H, ItypeUnary, i, m, m.set_r(i.rd, m.get_r(i.rs1) as i16 as i64 as u64));
+instr!(ZEXT_H, ItypeUnary, i, m, m.set_r(i.rd, m.get_r(i.rs1) as u16 as u64));
+/// Rotates the low XLEN bits of `v` right by `n`
+fn rotate_right(xlen: Xlen, v: u64, n: u32) -> u64 {
+    match xlen {
+        Xlen::Rv32 => (v as u32).rotate_right(n) as u64,
+        Xlen::Rv64 => v.rotate_right(n),
+    }
+}
+
+instr!(ROL,    ItypeOp,    i, m, {
+    let n = m.xlen().bits() - shamt(m, i.rs2);
+    m.set_r(i.rd, rotate_right(m.xlen(), m.get_r(i.rs1), n))});
+instr!(ROR,    ItypeOp,    i, m, m.set_r(i.rd, rotate_right(m.xlen(), m.get_r(i.rs1), shamt(m, i.rs2))));
+instr!(RORI,   ItypeShift, i, m, m.set_r(i.rd, rotate_right(m.xlen(), m.get_r(i.rs1), i.shamt)));
+instr!(ROLW,   ItypeOp,    i, m, m.set_r(i.rd, (m.get_r(i.rs1) as u32).rotate_left((m.get_r(i.rs2) & 0x1f) as u32) as i32 as i64 as u64));
+instr!(RORW,   ItypeOp,    i, m, m.set_r(i.rd, (m.get_r(i.rs1) as u32).rotate_right((m.get_r(i.rs2) & 0x1f) as u32) as i32 as i64 as u64));
+instr!(RORIW,  ItypeShift, i, m, m.set_r(i.rd, (m.get_r(i.rs1) as u32).rotate_right(i.shamt) as i32 as i64 as u64));
+instr!(ORC_B,  ItypeUnary, i, m, {
+    let bytes = m.get_r(i.rs1).to_le_bytes().map(|b| if b != 0 { 0xff } else { 0 });
+    m.set_r(i.rd, u64::from_le_bytes(bytes))});
+instr!(REV8,   ItypeUnary, i, m, m.set_r(i.rd, match m.xlen() {
+    Xlen::Rv32 => (m.get_r(i.rs1) as u32).swap_bytes() as u64,
+    Xlen::Rv64 => m.get_r(i.rs1).swap_bytes(),
+}));
+
+// Zbc
+/// Carry-less product of `a` and `b`
+fn clmul(a: u64, b: u64) -> u128 {
+    (0..64).filter(|n| (b >> n) & 1 != 0).fold(0, |r, n| r ^ ((a as u128) << n))
+}
+
+instr!(CLMUL,  ItypeOp,    i, m, m.set_r(i.rd, clmul(m.get_r(i.rs1), m.get_r(i.rs2)) as u64));
+instr!(CLMULH, ItypeOp,    i, m, m.set_r(i.rd, (clmul(m.get_ru(i.rs1), m.get_ru(i.rs2)) >> m.xlen().bits()) as u64));
+instr!(CLMULR, ItypeOp,    i, m, m.set_r(i.rd, (clmul(m.get_ru(i.rs1), m.get_ru(i.rs2)) >> (m.xlen().bits() - 1)) as u64));
+
+// Zbs
+instr!(BCLR,   ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1) & !(1 << shamt(m, i.rs2))));
+instr!(BCLRI,  ItypeShift, i, m, m.set_r(i.rd, m.get_r(i.rs1) & !(1 << i.shamt)));
+instr!(BEXT,   ItypeOp,    i, m, m.set_r(i.rd, (m.get_r(i.rs1) >> shamt(m, i.rs2)) & 1));
+instr!(BEXTI,  ItypeShift, i, m, m.set_r(i.rd, (m.get_r(i.rs1) >> i.shamt) & 1));
+instr!(BINV,   ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1) ^ (1 << shamt(m, i.rs2))));
+instr!(BINVI,  ItypeShift, i, m, m.set_r(i.rd, m.get_r(i.rs1) ^ (1 << i.shamt)));
+instr!(BSET,   ItypeOp,    i, m, m.set_r(i.rd, m.get_r(i.rs1) | (1 << shamt(m, i.rs2))));
+instr!(BSETI,  ItypeShift, i, m, m.set_r(i.rd, m.get_r(i.rs1) | (1 << i.shamt)));
+
+// Zbkb
+instr!(PACK,   ItypeOp,    i, m, {
+    let half = m.xlen().bits() / 2;
+    m.set_r(i.rd, (m.get_r(i.rs2) << half) | (m.get_r(i.rs1) & ((1 << half) - 1)))});
+instr!(PACKH,  ItypeOp,    i, m, m.set_r(i.rd, ((m.get_r(i.rs2) as u8 as u64) << 8) | (m.get_r(i.rs1) as u8 as u64)));
+instr!(PACKW,  ItypeOp,    i, m, {
+    let v = ((m.get_r(i.rs2) as u16 as u32) << 16) | (m.get_r(i.rs1) as u16 as u32);
+    m.set_r(i.rd, v as i32 as i64 as u64)});
+instr!(BREV8,  ItypeUnary, i, m, {
+    let bytes = m.get_r(i.rs1).to_le_bytes().map(u8::reverse_bits);
+    m.set_r(i.rd, u64::from_le_bytes(bytes))});
+instr!(ZIP,    ItypeUnary, i, m, {
+    let x = m.get_r(i.rs1);
+    let v = (0..16).fold(0, |r, n| r | (((x >> n) & 1) << (2 * n)) | (((x >> (n + 16)) & 1) << (2 * n + 1)));
+    m.set_r(i.rd, v)});
+instr!(UNZIP,  ItypeUnary, i, m, {
+    let x = m.get_r(i.rs1);
+    let v = (0..16).fold(0, |r, n| r | (((x >> (2 * n)) & 1) << n) | (((x >> (2 * n + 1)) & 1) << (n + 16)));
+    m.set_r(i.rd, v)});
+
+// Zbkx
+/// Replaces each `bits`-wide element of `idx` by the element of `table` it
+/// indexes in the low `xlen` bits of `table`, or zero if the index is out of
+/// range
+fn xperm(table: u64, idx: u64, bits: u32, xlen: u32) -> u64 {
+    let mask = (1 << bits) - 1;
+    (0..xlen).step_by(bits as usize).fold(0, |r, n| {
+        let pos = ((idx >> n) & mask) * bits as u64;
+        let v = if pos < xlen as u64 { (table >> pos) & mask } else { 0 };
+        r | (v << n)
+    })
+}
+
+instr!(XPERM4, ItypeOp,    i, m, m.set_r(i.rd, xperm(m.get_r(i.rs1), m.get_r(i.rs2), 4, m.xlen().bits())));
+instr!(XPERM8, ItypeOp,    i, m, m.set_r(i.rd, xperm(m.get_r(i.rs1), m.get_r(i.rs2), 8, m.xlen().bits())));
+
+// Zkne and Zknd
+instr!(AES64ES,   ItypeOp,    i, m, m.set_r(i.rd, crypto::aes64_encrypt(m.get_r(i.rs1), m.get_r(i.rs2), false)));
+instr!(AES64ESM,  ItypeOp,    i, m, m.set_r(i.rd, crypto::aes64_encrypt(m.get_r(i.rs1), m.get_r(i.rs2), true)));
+instr!(AES64DS,   ItypeOp,    i, m, m.set_r(i.rd, crypto::aes64_decrypt(m.get_r(i.rs1), m.get_r(i.rs2), false)));
+instr!(AES64DSM,  ItypeOp,    i, m, m.set_r(i.rd, crypto::aes64_decrypt(m.get_r(i.rs1), m.get_r(i.rs2), true)));
+instr!(AES64IM,   ItypeUnary, i, m, m.set_r(i.rd, crypto::aes64_im(m.get_r(i.rs1))));
+instr!(AES64KS1I, ItypeRnum,  i, m, m.set_r(i.rd, crypto::aes64_ks1i(m.get_r(i.rs1), i.rnum)));
+instr!(AES64KS2,  ItypeOp,    i, m, m.set_r(i.rd, crypto::aes64_ks2(m.get_r(i.rs1), m.get_r(i.rs2))));
+instr!(AES32ESI,  RtypeBs,    i, m, m.set_r(i.rd, crypto::aes32(m.get_r(i.rs1), m.get_r(i.rs2), i.bs, false, false)));
+instr!(AES32ESMI, RtypeBs,    i, m, m.set_r(i.rd, crypto::aes32(m.get_r(i.rs1), m.get_r(i.rs2), i.bs, false, true)));
+instr!(AES32DSI,  RtypeBs,    i, m, m.set_r(i.rd, crypto::aes32(m.get_r(i.rs1), m.get_r(i.rs2), i.bs, true, false)));
+instr!(AES32DSMI, RtypeBs,    i, m, m.set_r(i.rd, crypto::aes32(m.get_r(i.rs1), m.get_r(i.rs2), i.bs, true, true)));
+
+// Zknh, the SHA-256 results are sign-extended from 32 bits
+/// XOR of the low word of `x` rotated right by `a` and `b`, and rotated or,
+/// with `shift`, shifted right by `c`
+fn sha256_mix(x: u64, a: u32, b: u32, c: u32, shift: bool) -> u64 {
+    let x = x as u32;
+    let last = if shift { x >> c } else { x.rotate_right(c) };
+    (x.rotate_right(a) ^ x.rotate_right(b) ^ last) as i32 as i64 as u64
+}
+
+instr!(SHA256SIG0, ItypeUnary, i, m, m.set_r(i.rd, sha256_mix(m.get_r(i.rs1), 7, 18, 3, true)));
+instr!(SHA256SIG1, ItypeUnary, i, m, m.set_r(i.rd, sha256_mix(m.get_r(i.rs1), 17, 19, 10, true)));
+instr!(SHA256SUM0, ItypeUnary, i, m, m.set_r(i.rd, sha256_mix(m.get_r(i.rs1), 2, 13, 22, false)));
+instr!(SHA256SUM1, ItypeUnary, i, m, m.set_r(i.rd, sha256_mix(m.get_r(i.rs1), 6, 11, 25, false)));
+instr!(SHA512SIG0, ItypeUnary, i, m, {
+    let x = m.get_r(i.rs1);
+    m.set_r(i.rd, x.rotate_right(1) ^ x.rotate_right(8) ^ (x >> 7))});
+instr!(SHA512SIG1, ItypeUnary, i, m, {
+    let x = m.get_r(i.rs1);
+    m.set_r(i.rd, x.rotate_right(19) ^ x.rotate_right(61) ^ (x >> 6))});
+instr!(SHA512SUM0, ItypeUnary, i, m, {
+    let x = m.get_r(i.rs1);
+    m.set_r(i.rd, x.rotate_right(28) ^ x.rotate_right(34) ^ x.rotate_right(39))});
+instr!(SHA512SUM1, ItypeUnary, i, m, {
+    let x = m.get_r(i.rs1);
+    m.set_r(i.rd, x.rotate_right(14) ^ x.rotate_right(18) ^ x.rotate_right(41))});
+
+/// One half of a SHA-512 sigma or sum as computed by the RV32 instructions,
+/// from the halves of the operand in rs1 and rs2: the XOR of `a` shifted by
+/// each amount in `ra` and `b` by each amount in `rb`, where positive
+/// amounts shift left and negative ones right
+fn sha512_half(a: u64, ra: &[i32], b: u64, rb: &[i32]) -> u64 {
+    let shift = |x: u32, n: &i32| if *n >= 0 { x << n } else { x >> -n };
+    let a = ra.iter().fold(0, |r, n| r ^ shift(a as u32, n));
+    let b = rb.iter().fold(0, |r, n| r ^ shift(b as u32, n));
+    (a ^ b) as i32 as i64 as u64
+}
+
+instr!(SHA512SIG0H, ItypeOp, i, m, m.set_r(i.rd, sha512_half(m.get_r(i.rs1), &[-1, -7, -8], m.get_r(i.rs2), &[31, 24])));
+instr!(SHA512SIG0L, ItypeOp, i, m, m.set_r(i.rd, sha512_half(m.get_r(i.rs1), &[-1, -7, -8], m.get_r(i.rs2), &[31, 25, 24])));
+instr!(SHA512SIG1H, ItypeOp, i, m, m.set_r(i.rd, sha512_half(m.get_r(i.rs1), &[3, -6, -19], m.get_r(i.rs2), &[-29, 13])));
+instr!(SHA512SIG1L, ItypeOp, i, m, m.set_r(i.rd, sha512_half(m.get_r(i.rs1), &[3, -6, -19], m.get_r(i.rs2), &[-29, 26, 13])));
+instr!(SHA512SUM0R, ItypeOp, i, m, m.set_r(i.rd, sha512_half(m.get_r(i.rs1), &[25, 30, -28], m.get_r(i.rs2), &[-7, -2, 4])));
+instr!(SHA512SUM1R, ItypeOp, i, m, m.set_r(i.rd, sha512_half(m.get_r(i.rs1), &[23, -14, -18], m.get_r(i.rs2), &[-9, 18, 14])));
+
+// Zksh and Zksed
+instr!(SM3P0,  ItypeUnary, i, m, {
+    let x = m.get_r(i.rs1) as u32;
+    m.set_r(i.rd, (x ^ x.rotate_left(9) ^ x.rotate_left(17)) as i32 as i64 as u64)});
+instr!(SM3P1,  ItypeUnary, i, m, {
+    let x = m.get_r(i.rs1) as u32;
+    m.set_r(i.rd, (x ^ x.rotate_left(15) ^ x.rotate_left(23)) as i32 as i64 as u64)});
+instr!(SM4ED,  RtypeBs,    i, m, m.set_r(i.rd, crypto::sm4(m.get_r(i.rs1), m.get_r(i.rs2), i.bs, false)));
+instr!(SM4KS,  RtypeBs,    i, m, m.set_r(i.rd, crypto::sm4(m.get_r(i.rs1), m.get_r(i.rs2), i.bs, true)));
+
+instr_enum!(Inst {
+    LUI, AUIPC, JAL, JALR, BEQ, BNE, BLT, BGE, BLTU, BGEU, LB, LH, LW, LBU, LHU,
+    SB, SH, SW, ADDI, SLTI, SLTIU, XORI, ORI, ANDI, SLLI, SRLI, SRAI, ADD, SUB,
+    SLL, SLT, SLTU, XOR, SRL, SRA, OR, AND, FENCE, ECALL, EBREAK, SRET, MRET,
+    WFI, SFENCE_VMA, FENCE_I, CSRRW, CSRRS, CSRRC, CSRRWI, CSRRSI, CSRRCI, LWU,
+    LD, SD, ADDIW, SLLIW, SRLIW, SRAIW, ADDW, SUBW, SLLW, SRLW, SRAW, MUL, MULH,
+    MULHSU, MULHU, DIV, DIVU, REM, REMU, MULW, DIVW, DIVUW, REMW, REMUW, LR_W,
+    SC_W, AMOSWAP_W, AMOADD_W, AMOXOR_W, AMOAND_W, AMOOR_W, AMOMIN_W, AMOMAX_W,
+    AMOMINU_W, AMOMAXU_W, LR_D, SC_D, AMOSWAP_D, AMOADD_D, AMOXOR_D, AMOAND_D,
+    AMOOR_D, AMOMIN_D, AMOMAX_D, AMOMINU_D, AMOMAXU_D, FLW, FSW, FMADD_S,
+    FMSUB_S, FNMSUB_S, FNMADD_S, FADD_S, FSUB_S, FMUL_S, FDIV_S, FSQRT_S,
+    FSGNJ_S, FSGNJN_S, FSGNJX_S, FMIN_S, FMAX_S, FCVT_W_S, FCVT_WU_S, FMV_X_W,
+    FEQ_S, FLT_S, FLE_S, FCLASS_S, FCVT_S_W, FCVT_S_WU, FMV_W_X, FLD, FSD,
+    FMADD_D, FMSUB_D, FNMSUB_D, FNMADD_D, FADD_D, FSUB_D, FMUL_D, FDIV_D,
+    FSQRT_D, FSGNJ_D, FSGNJN_D, FSGNJX_D, FMIN_D, FMAX_D, FCVT_S_D, FCVT_D_S,
+    FEQ_D, FLT_D, FLE_D, FCLASS_D, FCVT_W_D, FCVT_WU_D, FCVT_D_W, FCVT_D_WU,
+    FCVT_L_S, FCVT_LU_S, FCVT_S_L, FCVT_S_LU, FCVT_L_D, FCVT_LU_D, FMV_X_D,
+    FCVT_D_L, FCVT_D_LU, FMV_D_X, SH1ADD, SH2ADD, SH3ADD, ADD_UW, SH1ADD_UW,
+    SH2ADD_UW, SH3ADD_UW, SLLI_UW, ANDN, ORN, XNOR, CLZ, CTZ, CPOP, CLZW, CTZW,
+    CPOPW, MAX, MAXU, MIN, MINU, SEXT_B, SEXT_H, ZEXT_H, ROL, ROR, RORI, ROLW,
+    RORW, RORIW, ORC_B, REV8, CLMUL, CLMULH, CLMULR, BCLR, BCLRI, BEXT, BEXTI,
+    BINV, BINVI, BSET, BSETI, PACK, PACKH, PACKW, BREV8, ZIP, UNZIP, XPERM4,
+    XPERM8, AES64ES, AES64ESM, AES64DS, AES64DSM, AES64IM, AES64KS1I, AES64KS2,
+    AES32ESI, AES32ESMI, AES32DSI, AES32DSMI, SHA256SIG0, SHA256SIG1,
+    SHA256SUM0, SHA256SUM1, SHA512SIG0, SHA512SIG1, SHA512SUM0, SHA512SUM1,
+    SHA512SIG0H, SHA512SIG0L, SHA512SIG1H, SHA512SIG1L, SHA512SUM0R,
+    SHA512SUM1R, SM3P0, SM3P1, SM4ED, SM4KS,
+} + Vector(vector::VectorInst));
+
+impl Inst {
+    /// Whether the instruction transfers control, which ends a basic block
+    pub fn ends_block(&self) -> bool {
+        matches!(self,
+            Inst::JAL(_) | Inst::JALR(_) | Inst::BEQ(_) | Inst::BNE(_) | Inst::BLT(_) |
+            Inst::BGE(_) | Inst::BLTU(_) | Inst::BGEU(_) | Inst::ECALL(_) | Inst::EBREAK(_) |
+            Inst::SRET(_) | Inst::MRET(_))
+    }
+}
+
+/// Decodes `i` for a hart implementing `isa`. Instructions of disabled
+/// extensions or only defined for the other XLEN are invalid.
+pub fn parse_instruction(i: u32, isa: &Isa) -> Result<Inst, VmExit> {
     let opcode = i & 0b1111111;
+    let rv64 = isa.xlen == Xlen::Rv64;
+    // Floating-point formats: S needs F, D needs D
+    let fmt_ok = |fmt: u32| match fmt {
+        0b00 => isa.has(F),
+        0b01 => isa.has(D),
+        _ => false,
+    };
 
     match opcode {
-        0b0010111 => {Ok(Box::new(AUIPC::new(Utype::from(i))))},
-        0b0110111 => {Ok(Box::new(LUI::new(Utype::from(i))))},
-        0b1101111 => {Ok(Box::new(JAL::new(Jtype::from(i))))},
-        0b1100111 => {Ok(Box::new(JALR::new(Itype::from(i))))},
+        0b0010111 => {Ok(AUIPC::new(Utype::from(i)).into())},
+        0b0110111 => {Ok(LUI::new(Utype::from(i)).into())},
+        0b1101111 => {Ok(JAL::new(Jtype::from(i)).into())},
+        0b1100111 => {Ok(JALR::new(Itype::from(i)).into())},
         0b1100011 => {
             let inst = Btype::from(i);
             match inst.funct3 {
-                0b000 => {Ok(Box::new(BEQ::new(inst)))},
-                0b001 => {Ok(Box::new(BNE::new(inst)))},
-                0b100 => {Ok(Box::new(BLT::new(inst)))},
-                0b101 => {Ok(Box::new(BGE::new(inst)))},
-                0b110 => {Ok(Box::new(BLTU::new(inst)))},
-                0b111 => {Ok(Box::new(BGEU::new(inst)))},
+                0b000 => {Ok(BEQ::new(inst).into())},
+                0b001 => {Ok(BNE::new(inst).into())},
+                0b100 => {Ok(BLT::new(inst).into())},
+                0b101 => {Ok(BGE::new(inst).into())},
+                0b110 => {Ok(BLTU::new(inst).into())},
+                0b111 => {Ok(BGEU::new(inst).into())},
                 _ => Err(VmExit::InvalidOpcode(i)),
             }
         },
         0b0000011 => {
             let inst = Itype::from(i);
             match inst.funct3 {
-                0b000 => {Ok(Box::new(LB::new(inst)))},
-                0b001 => {Ok(Box::new(LH::new(inst)))},
-                0b010 => {Ok(Box::new(LW::new(inst)))},
-                0b100 => {Ok(Box::new(LBU::new(inst)))},
-                0b101 => {Ok(Box::new(LHU::new(inst)))},
+                0b000 => {Ok(LB::new(inst).into())},
+                0b001 => {Ok(LH::new(inst).into())},
+                0b010 => {Ok(LW::new(inst).into())},
+                0b100 => {Ok(LBU::new(inst).into())},
+                0b101 => {Ok(LHU::new(inst).into())},
                 // RV64I
-                0b110 => {Ok(Box::new(LWU::new(inst)))},
-                0b011 => {Ok(Box::new(LD::new(inst)))},
+                0b110 if rv64 => {Ok(LWU::new(inst).into())},
+                0b011 if rv64 => {Ok(LD::new(inst).into())},
                 _ => Err(VmExit::InvalidOpcode(i)),
             }
         },
         0b0100011 => {
             let inst = Stype::from(i);
             match inst.funct3 {
-                0b000 => {Ok(Box::new(SB::new(inst)))},
-                0b001 => {Ok(Box::new(SH::new(inst)))},
-                0b010 => {Ok(Box::new(SW::new(inst)))},
+                0b000 => {Ok(SB::new(inst).into())},
+                0b001 => {Ok(SH::new(inst).into())},
+                0b010 => {Ok(SW::new(inst).into())},
                 // RV64I
-                0b011 => {Ok(Box::new(SD::new(inst)))},
+                0b011 if rv64 => {Ok(SD::new(inst).into())},
                 _ => Err(VmExit::InvalidOpcode(i)),
             }
 
@@ -132,19 +768,62 @@ pub fn parse_instruction(i: u32) -> Result<Box<dyn Instruction>, VmExit> {
         0b0010011 => {
             let inst = Itype::from(i);
             match inst.funct3 {
-                0b000 => {Ok(Box::new(ADDI::new(inst)))},
-                0b010 => {Ok(Box::new(SLTI::new(inst)))},
-                0b011 => {Ok(Box::new(SLTIU::new(inst)))},
-                0b100 => {Ok(Box::new(XORI::new(inst)))},
-                0b110 => {Ok(Box::new(ORI::new(inst)))},
-                0b111 => {Ok(Box::new(ANDI::new(inst)))},
-                // RV64I
-                0b001 => {Ok(Box::new(SLLI::new(ItypeShift::from(i))))},
+                0b000 => {Ok(ADDI::new(inst).into())},
+                0b010 => {Ok(SLTI::new(inst).into())},
+                0b011 => {Ok(SLTIU::new(inst).into())},
+                0b100 => {Ok(XORI::new(inst).into())},
+                0b110 => {Ok(ORI::new(inst).into())},
+                0b111 => {Ok(ANDI::new(inst).into())},
+                // Shift amounts are 5 bits in RV32, shamt[5] is reserved
+                0b001 | 0b101 if !rv64 && (i >> 25) & 1 != 0 => Err(VmExit::InvalidOpcode(i)),
+                0b001 => {
+                    let mode = i >> 26;
+                    match (mode, i >> 20) {
+                        (0b000000, _) => {Ok(SLLI::new(ItypeShift::from(i)).into())}
+                        // Zbb
+                        (_, 0x600) if isa.has(Zbb) => {Ok(CLZ::new(ItypeUnary::from(i)).into())},
+                        (_, 0x601) if isa.has(Zbb) => {Ok(CTZ::new(ItypeUnary::from(i)).into())},
+                        (_, 0x602) if isa.has(Zbb) => {Ok(CPOP::new(ItypeUnary::from(i)).into())},
+                        (_, 0x604) if isa.has(Zbb) => {Ok(SEXT_B::new(ItypeUnary::from(i)).into())},
+                        (_, 0x605) if isa.has(Zbb) => {Ok(SEXT_H::new(ItypeUnary::from(i)).into())},
+                        // Zbs
+                        (0b010010, _) if isa.has(Zbs) => {Ok(BCLRI::new(ItypeShift::from(i)).into())},
+                        (0b011010, _) if isa.has(Zbs) => {Ok(BINVI::new(ItypeShift::from(i)).into())},
+                        (0b001010, _) if isa.has(Zbs) => {Ok(BSETI::new(ItypeShift::from(i)).into())},
+                        // Zkne, Zknd and Zknh
+                        (_, 0x300) if rv64 && isa.has(Zknd) => {Ok(AES64IM::new(ItypeUnary::from(i)).into())},
+                        (_, 0x310..=0x31a) if rv64 && isa.has_any(&[Zkne, Zknd]) => {Ok(AES64KS1I::new(ItypeRnum::from(i)).into())},
+                        (_, 0x100) if isa.has(Zknh) => {Ok(SHA256SUM0::new(ItypeUnary::from(i)).into())},
+                        (_, 0x101) if isa.has(Zknh) => {Ok(SHA256SUM1::new(ItypeUnary::from(i)).into())},
+                        (_, 0x102) if isa.has(Zknh) => {Ok(SHA256SIG0::new(ItypeUnary::from(i)).into())},
+                        (_, 0x103) if isa.has(Zknh) => {Ok(SHA256SIG1::new(ItypeUnary::from(i)).into())},
+                        (_, 0x104) if rv64 && isa.has(Zknh) => {Ok(SHA512SUM0::new(ItypeUnary::from(i)).into())},
+                        (_, 0x105) if rv64 && isa.has(Zknh) => {Ok(SHA512SUM1::new(ItypeUnary::from(i)).into())},
+                        (_, 0x106) if rv64 && isa.has(Zknh) => {Ok(SHA512SIG0::new(ItypeUnary::from(i)).into())},
+                        (_, 0x107) if rv64 && isa.has(Zknh) => {Ok(SHA512SIG1::new(ItypeUnary::from(i)).into())},
+                        // Zksh
+                        (_, 0x108) if isa.has(Zksh) => {Ok(SM3P0::new(ItypeUnary::from(i)).into())},
+                        (_, 0x109) if isa.has(Zksh) => {Ok(SM3P1::new(ItypeUnary::from(i)).into())},
+                        // Zbkb, RV32 only
+                        (_, 0x08f) if !rv64 && isa.has(Zbkb) => {Ok(ZIP::new(ItypeUnary::from(i)).into())},
+                        _ => Err(VmExit::InvalidOpcode(i)),
+                    }
+                },
                 0b101 => {
                     let mode = i >> 26;
-                    match mode {
-                        0b000000 => {Ok(Box::new(SRLI::new(ItypeShift::from(i))))}
-                        0b010000 => {Ok(Box::new(SRAI::new(ItypeShift::from(i))))}
+                    match (mode, i >> 20) {
+                        (0b000000, _) => {Ok(SRLI::new(ItypeShift::from(i)).into())}
+                        (0b010000, _) => {Ok(SRAI::new(ItypeShift::from(i)).into())}
+                        // Zbb
+                        (_, 0x287) if isa.has(Zbb) => {Ok(ORC_B::new(ItypeUnary::from(i)).into())},
+                        (_, 0x6b8) if rv64 && isa.has_any(&[Zbb, Zbkb]) => {Ok(REV8::new(ItypeUnary::from(i)).into())},
+                        (_, 0x698) if !rv64 && isa.has_any(&[Zbb, Zbkb]) => {Ok(REV8::new(ItypeUnary::from(i)).into())},
+                        // Zbkb
+                        (_, 0x687) if isa.has(Zbkb) => {Ok(BREV8::new(ItypeUnary::from(i)).into())},
+                        (_, 0x08f) if !rv64 && isa.has(Zbkb) => {Ok(UNZIP::new(ItypeUnary::from(i)).into())},
+                        (0b011000, _) if isa.has_any(&[Zbb, Zbkb]) => {Ok(RORI::new(ItypeShift::from(i)).into())},
+                        // Zbs
+                        (0b010010, _) if isa.has(Zbs) => {Ok(BEXTI::new(ItypeShift::from(i)).into())},
                         _ => Err(VmExit::InvalidOpcode(i)),
                     }
                 },
@@ -156,62 +835,1200 @@ pub fn parse_instruction(i: u32) -> Result<Box<dyn Instruction>, VmExit> {
             let inst = ItypeOp::from(i);
             let mode = i >> 25;
             match (inst.funct3, mode) {
-                (0b000,  0b0000000) => {Ok(Box::new(ADD::new(inst)))},
-                (0b000,  0b0100000) => {Ok(Box::new(SUB::new(inst)))},
-                (0b001,  0b0000000) => {Ok(Box::new(SLL::new(inst)))},
-                (0b010,  0b0000000) => {Ok(Box::new(SLT::new(inst)))},
-                (0b011,  0b0000000) => {Ok(Box::new(SLTU::new(inst)))},
-                (0b100,  0b0000000) => {Ok(Box::new(XOR::new(inst)))},
-                (0b101,  0b0000000) => {Ok(Box::new(SRL::new(inst)))},
-                (0b101,  0b0100000) => {Ok(Box::new(SRA::new(inst)))},
-                (0b110,  0b0000000) => {Ok(Box::new(OR::new(inst)))},
-                (0b111,  0b0000000) => {Ok(Box::new(AND::new(inst)))},
+                (0b000,  0b0000000) => {Ok(ADD::new(inst).into())},
+                (0b000,  0b0100000) => {Ok(SUB::new(inst).into())},
+                (0b001,  0b0000000) => {Ok(SLL::new(inst).into())},
+                (0b010,  0b0000000) => {Ok(SLT::new(inst).into())},
+                (0b011,  0b0000000) => {Ok(SLTU::new(inst).into())},
+                (0b100,  0b0000000) => {Ok(XOR::new(inst).into())},
+                (0b101,  0b0000000) => {Ok(SRL::new(inst).into())},
+                (0b101,  0b0100000) => {Ok(SRA::new(inst).into())},
+                (0b110,  0b0000000) => {Ok(OR::new(inst).into())},
+                (0b111,  0b0000000) => {Ok(AND::new(inst).into())},
+                // RV32M
+                (0b000,  0b0000001) if isa.has(M) => {Ok(MUL::new(inst).into())},
+                (0b001,  0b0000001) if isa.has(M) => {Ok(MULH::new(inst).into())},
+                (0b010,  0b0000001) if isa.has(M) => {Ok(MULHSU::new(inst).into())},
+                (0b011,  0b0000001) if isa.has(M) => {Ok(MULHU::new(inst).into())},
+                (0b100,  0b0000001) if isa.has(M) => {Ok(DIV::new(inst).into())},
+                (0b101,  0b0000001) if isa.has(M) => {Ok(DIVU::new(inst).into())},
+                (0b110,  0b0000001) if isa.has(M) => {Ok(REM::new(inst).into())},
+                (0b111,  0b0000001) if isa.has(M) => {Ok(REMU::new(inst).into())},
+                // Zba
+                (0b010,  0b0010000) if isa.has(Zba) => {Ok(SH1ADD::new(inst).into())},
+                (0b100,  0b0010000) if isa.has(Zba) => {Ok(SH2ADD::new(inst).into())},
+                (0b110,  0b0010000) if isa.has(Zba) => {Ok(SH3ADD::new(inst).into())},
+                // Zbb
+                (0b111,  0b0100000) if isa.has_any(&[Zbb, Zbkb]) => {Ok(ANDN::new(inst).into())},
+                (0b110,  0b0100000) if isa.has_any(&[Zbb, Zbkb]) => {Ok(ORN::new(inst).into())},
+                (0b100,  0b0100000) if isa.has_any(&[Zbb, Zbkb]) => {Ok(XNOR::new(inst).into())},
+                (0b110,  0b0000101) if isa.has(Zbb) => {Ok(MAX::new(inst).into())},
+                (0b111,  0b0000101) if isa.has(Zbb) => {Ok(MAXU::new(inst).into())},
+                (0b100,  0b0000101) if isa.has(Zbb) => {Ok(MIN::new(inst).into())},
+                (0b101,  0b0000101) if isa.has(Zbb) => {Ok(MINU::new(inst).into())},
+                (0b001,  0b0110000) if isa.has_any(&[Zbb, Zbkb]) => {Ok(ROL::new(inst).into())},
+                (0b101,  0b0110000) if isa.has_any(&[Zbb, Zbkb]) => {Ok(ROR::new(inst).into())},
+                // Zbc
+                (0b001,  0b0000101) if isa.has_any(&[Zbc, Zbkc]) => {Ok(CLMUL::new(inst).into())},
+                (0b011,  0b0000101) if isa.has_any(&[Zbc, Zbkc]) => {Ok(CLMULH::new(inst).into())},
+                (0b010,  0b0000101) if isa.has(Zbc) => {Ok(CLMULR::new(inst).into())},
+                // Zbs
+                (0b001,  0b0100100) if isa.has(Zbs) => {Ok(BCLR::new(inst).into())},
+                (0b101,  0b0100100) if isa.has(Zbs) => {Ok(BEXT::new(inst).into())},
+                (0b001,  0b0110100) if isa.has(Zbs) => {Ok(BINV::new(inst).into())},
+                (0b001,  0b0010100) if isa.has(Zbs) => {Ok(BSET::new(inst).into())},
+                // Zbkb and Zbkx, zext.h is pack with rs2=x0 in RV32
+                (0b100,  0b0000100) if !rv64 && inst.rs2 == Zero && isa.has_any(&[Zbb, Zbkb]) => {Ok(ZEXT_H::new(ItypeUnary::from(i)).into())},
+                (0b100,  0b0000100) if isa.has(Zbkb) => {Ok(PACK::new(inst).into())},
+                (0b111,  0b0000100) if isa.has(Zbkb) => {Ok(PACKH::new(inst).into())},
+                (0b010,  0b0010100) if isa.has(Zbkx) => {Ok(XPERM4::new(inst).into())},
+                (0b100,  0b0010100) if isa.has(Zbkx) => {Ok(XPERM8::new(inst).into())},
+                // Zkne and Zknd
+                (0b000,  0b0011001) if rv64 && isa.has(Zkne) => {Ok(AES64ES::new(inst).into())},
+                (0b000,  0b0011011) if rv64 && isa.has(Zkne) => {Ok(AES64ESM::new(inst).into())},
+                (0b000,  0b0011101) if rv64 && isa.has(Zknd) => {Ok(AES64DS::new(inst).into())},
+                (0b000,  0b0011111) if rv64 && isa.has(Zknd) => {Ok(AES64DSM::new(inst).into())},
+                (0b000,  0b0111111) if rv64 && isa.has_any(&[Zkne, Zknd]) => {Ok(AES64KS2::new(inst).into())},
+                (0b000,  mode) if !rv64 && mode & 0b11111 == 0b10001 && isa.has(Zkne) => {Ok(AES32ESI::new(RtypeBs::from(i)).into())},
+                (0b000,  mode) if !rv64 && mode & 0b11111 == 0b10011 && isa.has(Zkne) => {Ok(AES32ESMI::new(RtypeBs::from(i)).into())},
+                (0b000,  mode) if !rv64 && mode & 0b11111 == 0b10101 && isa.has(Zknd) => {Ok(AES32DSI::new(RtypeBs::from(i)).into())},
+                (0b000,  mode) if !rv64 && mode & 0b11111 == 0b10111 && isa.has(Zknd) => {Ok(AES32DSMI::new(RtypeBs::from(i)).into())},
+                // Zknh, RV32 only
+                (0b000,  0b0101000) if !rv64 && isa.has(Zknh) => {Ok(SHA512SUM0R::new(inst).into())},
+                (0b000,  0b0101001) if !rv64 && isa.has(Zknh) => {Ok(SHA512SUM1R::new(inst).into())},
+                (0b000,  0b0101010) if !rv64 && isa.has(Zknh) => {Ok(SHA512SIG0L::new(inst).into())},
+                (0b000,  0b0101011) if !rv64 && isa.has(Zknh) => {Ok(SHA512SIG1L::new(inst).into())},
+                (0b000,  0b0101110) if !rv64 && isa.has(Zknh) => {Ok(SHA512SIG0H::new(inst).into())},
+                (0b000,  0b0101111) if !rv64 && isa.has(Zknh) => {Ok(SHA512SIG1H::new(inst).into())},
+                // Zksed
+                (0b000,  mode) if mode & 0b11111 == 0b11000 && isa.has(Zksed) => {Ok(SM4ED::new(RtypeBs::from(i)).into())},
+                (0b000,  mode) if mode & 0b11111 == 0b11010 && isa.has(Zksed) => {Ok(SM4KS::new(RtypeBs::from(i)).into())},
+                _ => Err(VmExit::InvalidOpcode(i)),
+            }
+        },
+        0b0001111 => {
+            let inst = Itype::from(i);
+            match inst.funct3 {
+                0b000 => {Ok(FENCE::new(Ntype::from(i)).into())},
+                // Zifencei
+                0b001 if isa.has(Zifencei) => {Ok(FENCE_I::new(Ntype::from(i)).into())},
                 _ => Err(VmExit::InvalidOpcode(i)),
             }
         },
         0b1110011 => {
             let inst = ItypeOp::from(i);
             match inst.funct3 {
+                0b000 if i >> 25 == 0b0001001 && inst.rd == Zero => {
+                    Ok(SFENCE_VMA::new(inst).into())
+                },
                 0b000 => {
                     let mode = i >> 20;
                     match mode {
-                        0b000000000000 => {Ok(Box::new(ECALL::new(Ntype::from(i))))},
-                        0b000000000001 => {Ok(Box::new(EBREAK::new(Ntype::from(i))))},
+                        0b000000000000 => {Ok(ECALL::new(Ntype::from(i)).into())},
+                        0b000000000001 => {Ok(EBREAK::new(Ntype::from(i)).into())},
+                        0b000100000010 => {Ok(SRET::new(Ntype::from(i)).into())},
+                        0b001100000010 => {Ok(MRET::new(Ntype::from(i)).into())},
+                        0b000100000101 => {Ok(WFI::new(Ntype::from(i)).into())},
                         _ => Err(VmExit::InvalidOpcode(i)),
                     }
                 },
+                // Zicsr
+                0b001 if isa.has(Zicsr) => {Ok(CSRRW::new(Csrtype::from(i)).into())},
+                0b010 if isa.has(Zicsr) => {Ok(CSRRS::new(Csrtype::from(i)).into())},
+                0b011 if isa.has(Zicsr) => {Ok(CSRRC::new(Csrtype::from(i)).into())},
+                0b101 if isa.has(Zicsr) => {Ok(CSRRWI::new(Csrtype::from(i)).into())},
+                0b110 if isa.has(Zicsr) => {Ok(CSRRSI::new(Csrtype::from(i)).into())},
+                0b111 if isa.has(Zicsr) => {Ok(CSRRCI::new(Csrtype::from(i)).into())},
                 _ => Err(VmExit::InvalidOpcode(i)),
             }
         },
         // RV64I
-        0b0011011 => {
+        0b0011011 if rv64 => {
             let inst = Itype::from(i);
             let mode = i >> 25;
             match inst.funct3 {
-                0b000 => {Ok(Box::new(ADDIW::new(inst)))},
-                0b001 => {Ok(Box::new(SLLIW::new(ItypeShift::from(i))))},
+                0b000 => {Ok(ADDIW::new(inst).into())},
+                0b001 => {
+                    match (mode, i >> 20) {
+                        (0b0000000, _) => {Ok(SLLIW::new(ItypeShift::from(i)).into())},
+                        // Zba
+                        _ if i >> 26 == 0b000010 && isa.has(Zba) => {Ok(SLLI_UW::new(ItypeShift::from(i)).into())},
+                        // Zbb
+                        (_, 0x600) if isa.has(Zbb) => {Ok(CLZW::new(ItypeUnary::from(i)).into())},
+                        (_, 0x601) if isa.has(Zbb) => {Ok(CTZW::new(ItypeUnary::from(i)).into())},
+                        (_, 0x602) if isa.has(Zbb) => {Ok(CPOPW::new(ItypeUnary::from(i)).into())},
+                        _ => Err(VmExit::InvalidOpcode(i)),
+                    }
+                },
                 0b101 => {
                     match mode {
-                        0b0000000 => {Ok(Box::new(SRLIW::new(ItypeShift::from(i))))},
-                        0b0100000 => {Ok(Box::new(SRAIW::new(ItypeShift::from(i))))},
+                        0b0000000 => {Ok(SRLIW::new(ItypeShift::from(i)).into())},
+                        0b0100000 => {Ok(SRAIW::new(ItypeShift::from(i)).into())},
+                        // Zbb
+                        0b0110000 if isa.has_any(&[Zbb, Zbkb]) => {Ok(RORIW::new(ItypeShift::from(i)).into())},
                         _ => Err(VmExit::InvalidOpcode(i)),
                     }
                 },
                 _ => Err(VmExit::InvalidOpcode(i)),
             }
         },
-        0b0111011 => {
+        0b0111011 if rv64 => {
             let inst = ItypeOp::from(i);
             let mode = i >> 25;
             match (inst.funct3, mode) {
-                (0b000,  0b0000000) => {Ok(Box::new(ADDW::new(inst)))},
-                (0b000,  0b0100000) => {Ok(Box::new(SUBW::new(inst)))},
-                (0b001,  0b0000000) => {Ok(Box::new(SLLW::new(inst)))},
-                (0b101,  0b0000000) => {Ok(Box::new(SRLW::new(inst)))},
-                (0b101,  0b0100000) => {Ok(Box::new(SRAW::new(inst)))},
+                (0b000,  0b0000000) => {Ok(ADDW::new(inst).into())},
+                (0b000,  0b0100000) => {Ok(SUBW::new(inst).into())},
+                (0b001,  0b0000000) => {Ok(SLLW::new(inst).into())},
+                (0b101,  0b0000000) => {Ok(SRLW::new(inst).into())},
+                (0b101,  0b0100000) => {Ok(SRAW::new(inst).into())},
+                // RV64M
+                (0b000,  0b0000001) if isa.has(M) => {Ok(MULW::new(inst).into())},
+                (0b100,  0b0000001) if isa.has(M) => {Ok(DIVW::new(inst).into())},
+                (0b101,  0b0000001) if isa.has(M) => {Ok(DIVUW::new(inst).into())},
+                (0b110,  0b0000001) if isa.has(M) => {Ok(REMW::new(inst).into())},
+                (0b111,  0b0000001) if isa.has(M) => {Ok(REMUW::new(inst).into())},
+                // Zba
+                (0b000,  0b0000100) if isa.has(Zba) => {Ok(ADD_UW::new(inst).into())},
+                (0b010,  0b0010000) if isa.has(Zba) => {Ok(SH1ADD_UW::new(inst).into())},
+                (0b100,  0b0010000) if isa.has(Zba) => {Ok(SH2ADD_UW::new(inst).into())},
+                (0b110,  0b0010000) if isa.has(Zba) => {Ok(SH3ADD_UW::new(inst).into())},
+                // Zbb
+                (0b100,  0b0000100) if inst.rs2 == Zero && isa.has_any(&[Zbb, Zbkb]) => {Ok(ZEXT_H::new(ItypeUnary::from(i)).into())},
+                // Zbkb
+                (0b100,  0b0000100) if isa.has(Zbkb) => {Ok(PACKW::new(inst).into())},
+                (0b001,  0b0110000) if isa.has_any(&[Zbb, Zbkb]) => {Ok(ROLW::new(inst).into())},
+                (0b101,  0b0110000) if isa.has_any(&[Zbb, Zbkb]) => {Ok(RORW::new(inst).into())},
                 _ => Err(VmExit::InvalidOpcode(i)),
             }
         }
+        0b0101111 if isa.has(A) => {
+            let inst = Rtype::from(i);
+            let funct5 = inst.funct7 >> 2;
+            match (inst.funct3, funct5) {
+                // RV32A
+                (0b010, 0b00010) if inst.rs2 == Zero => {Ok(LR_W::new(inst).into())},
+                (0b010, 0b00011) => {Ok(SC_W::new(inst).into())},
+                (0b010, 0b00001) => {Ok(AMOSWAP_W::new(inst).into())},
+                (0b010, 0b00000) => {Ok(AMOADD_W::new(inst).into())},
+                (0b010, 0b00100) => {Ok(AMOXOR_W::new(inst).into())},
+                (0b010, 0b01100) => {Ok(AMOAND_W::new(inst).into())},
+                (0b010, 0b01000) => {Ok(AMOOR_W::new(inst).into())},
+                (0b010, 0b10000) => {Ok(AMOMIN_W::new(inst).into())},
+                (0b010, 0b10100) => {Ok(AMOMAX_W::new(inst).into())},
+                (0b010, 0b11000) => {Ok(AMOMINU_W::new(inst).into())},
+                (0b010, 0b11100) => {Ok(AMOMAXU_W::new(inst).into())},
+                // RV64A
+                (0b011, _) if !rv64 => Err(VmExit::InvalidOpcode(i)),
+                (0b011, 0b00010) if inst.rs2 == Zero => {Ok(LR_D::new(inst).into())},
+                (0b011, 0b00011) => {Ok(SC_D::new(inst).into())},
+                (0b011, 0b00001) => {Ok(AMOSWAP_D::new(inst).into())},
+                (0b011, 0b00000) => {Ok(AMOADD_D::new(inst).into())},
+                (0b011, 0b00100) => {Ok(AMOXOR_D::new(inst).into())},
+                (0b011, 0b01100) => {Ok(AMOAND_D::new(inst).into())},
+                (0b011, 0b01000) => {Ok(AMOOR_D::new(inst).into())},
+                (0b011, 0b10000) => {Ok(AMOMIN_D::new(inst).into())},
+                (0b011, 0b10100) => {Ok(AMOMAX_D::new(inst).into())},
+                (0b011, 0b11000) => {Ok(AMOMINU_D::new(inst).into())},
+                (0b011, 0b11100) => {Ok(AMOMAXU_D::new(inst).into())},
+                _ => Err(VmExit::InvalidOpcode(i)),
+            }
+        },
+        0b0000111 => {
+            let inst = FItype::from(i);
+            match inst.funct3 {
+                0b010 if isa.has(F) => {Ok(FLW::new(inst).into())},
+                0b011 if isa.has(D) => {Ok(FLD::new(inst).into())},
+                0b000 | 0b101 | 0b110 | 0b111 if isa.has(V) => vector::parse_vector(i).map(Inst::Vector),
+                _ => Err(VmExit::InvalidOpcode(i)),
+            }
+        },
+        0b0100111 => {
+            let inst = FStype::from(i);
+            match inst.funct3 {
+                0b010 if isa.has(F) => {Ok(FSW::new(inst).into())},
+                0b011 if isa.has(D) => {Ok(FSD::new(inst).into())},
+                0b000 | 0b101 | 0b110 | 0b111 if isa.has(V) => vector::parse_vector(i).map(Inst::Vector),
+                _ => Err(VmExit::InvalidOpcode(i)),
+            }
+        },
+        0b1010111 if isa.has(V) => vector::parse_vector(i).map(Inst::Vector),
+        0b1000011 | 0b1000111 | 0b1001011 | 0b1001111 if fmt_ok((i >> 25) & 0b11) => {
+            let inst = R4type::from(i);
+            match (opcode, inst.fmt) {
+                (0b1000011, 0b00) => {Ok(FMADD_S::new(inst).into())},
+                (0b1000111, 0b00) => {Ok(FMSUB_S::new(inst).into())},
+                (0b1001011, 0b00) => {Ok(FNMSUB_S::new(inst).into())},
+                (0b1001111, 0b00) => {Ok(FNMADD_S::new(inst).into())},
+                (0b1000011, 0b01) => {Ok(FMADD_D::new(inst).into())},
+                (0b1000111, 0b01) => {Ok(FMSUB_D::new(inst).into())},
+                (0b1001011, 0b01) => {Ok(FNMSUB_D::new(inst).into())},
+                (0b1001111, 0b01) => {Ok(FNMADD_D::new(inst).into())},
+                _ => Err(VmExit::InvalidOpcode(i)),
+            }
+        },
+        0b1010011 if fmt_ok((i >> 25) & 0b11) => {
+            let funct7 = i >> 25;
+            let rs2 = (i >> 20) & 0b11111;
+            let funct3 = (i >> 12) & 0b111;
+            match (funct7, funct3, rs2) {
+                // RV32F
+                (0b0000000, _, _) => {Ok(FADD_S::new(FRtype::from(i)).into())},
+                (0b0000100, _, _) => {Ok(FSUB_S::new(FRtype::from(i)).into())},
+                (0b0001000, _, _) => {Ok(FMUL_S::new(FRtype::from(i)).into())},
+                (0b0001100, _, _) => {Ok(FDIV_S::new(FRtype::from(i)).into())},
+                (0b0101100, _, 0) => {Ok(FSQRT_S::new(FRtype::from(i)).into())},
+                (0b0010000, 0b000, _) => {Ok(FSGNJ_S::new(FRtype::from(i)).into())},
+                (0b0010000, 0b001, _) => {Ok(FSGNJN_S::new(FRtype::from(i)).into())},
+                (0b0010000, 0b010, _) => {Ok(FSGNJX_S::new(FRtype::from(i)).into())},
+                (0b0010100, 0b000, _) => {Ok(FMIN_S::new(FRtype::from(i)).into())},
+                (0b0010100, 0b001, _) => {Ok(FMAX_S::new(FRtype::from(i)).into())},
+                (0b1100000, _, 0) => {Ok(FCVT_W_S::new(FRtypeToInt::from(i)).into())},
+                (0b1100000, _, 1) => {Ok(FCVT_WU_S::new(FRtypeToInt::from(i)).into())},
+                (0b1110000, 0b000, 0) => {Ok(FMV_X_W::new(FRtypeToInt::from(i)).into())},
+                (0b1010000, 0b010, _) => {Ok(FEQ_S::new(FRtypeToInt::from(i)).into())},
+                (0b1010000, 0b001, _) => {Ok(FLT_S::new(FRtypeToInt::from(i)).into())},
+                (0b1010000, 0b000, _) => {Ok(FLE_S::new(FRtypeToInt::from(i)).into())},
+                (0b1110000, 0b001, 0) => {Ok(FCLASS_S::new(FRtypeToInt::from(i)).into())},
+                (0b1101000, _, 0) => {Ok(FCVT_S_W::new(FRtypeFromInt::from(i)).into())},
+                (0b1101000, _, 1) => {Ok(FCVT_S_WU::new(FRtypeFromInt::from(i)).into())},
+                (0b1111000, 0b000, 0) => {Ok(FMV_W_X::new(FRtypeFromInt::from(i)).into())},
+                // RV64F
+                (0b1100000, _, 2) if rv64 => {Ok(FCVT_L_S::new(FRtypeToInt::from(i)).into())},
+                (0b1100000, _, 3) if rv64 => {Ok(FCVT_LU_S::new(FRtypeToInt::from(i)).into())},
+                (0b1101000, _, 2) if rv64 => {Ok(FCVT_S_L::new(FRtypeFromInt::from(i)).into())},
+                (0b1101000, _, 3) if rv64 => {Ok(FCVT_S_LU::new(FRtypeFromInt::from(i)).into())},
+                // RV32D
+                (0b0000001, _, _) => {Ok(FADD_D::new(FRtype::from(i)).into())},
+                (0b0000101, _, _) => {Ok(FSUB_D::new(FRtype::from(i)).into())},
+                (0b0001001, _, _) => {Ok(FMUL_D::new(FRtype::from(i)).into())},
+                (0b0001101, _, _) => {Ok(FDIV_D::new(FRtype::from(i)).into())},
+                (0b0101101, _, 0) => {Ok(FSQRT_D::new(FRtype::from(i)).into())},
+                (0b0010001, 0b000, _) => {Ok(FSGNJ_D::new(FRtype::from(i)).into())},
+                (0b0010001, 0b001, _) => {Ok(FSGNJN_D::new(FRtype::from(i)).into())},
+                (0b0010001, 0b010, _) => {Ok(FSGNJX_D::new(FRtype::from(i)).into())},
+                (0b0010101, 0b000, _) => {Ok(FMIN_D::new(FRtype::from(i)).into())},
+                (0b0010101, 0b001, _) => {Ok(FMAX_D::new(FRtype::from(i)).into())},
+                (0b0100000, _, 1) if isa.has(D) => {Ok(FCVT_S_D::new(FRtype::from(i)).into())},
+                (0b0100001, _, 0) => {Ok(FCVT_D_S::new(FRtype::from(i)).into())},
+                (0b1010001, 0b010, _) => {Ok(FEQ_D::new(FRtypeToInt::from(i)).into())},
+                (0b1010001, 0b001, _) => {Ok(FLT_D::new(FRtypeToInt::from(i)).into())},
+                (0b1010001, 0b000, _) => {Ok(FLE_D::new(FRtypeToInt::from(i)).into())},
+                (0b1110001, 0b001, 0) => {Ok(FCLASS_D::new(FRtypeToInt::from(i)).into())},
+                (0b1100001, _, 0) => {Ok(FCVT_W_D::new(FRtypeToInt::from(i)).into())},
+                (0b1100001, _, 1) => {Ok(FCVT_WU_D::new(FRtypeToInt::from(i)).into())},
+                (0b1101001, _, 0) => {Ok(FCVT_D_W::new(FRtypeFromInt::from(i)).into())},
+                (0b1101001, _, 1) => {Ok(FCVT_D_WU::new(FRtypeFromInt::from(i)).into())},
+                // RV64D
+                (0b1100001, _, 2) if rv64 => {Ok(FCVT_L_D::new(FRtypeToInt::from(i)).into())},
+                (0b1100001, _, 3) if rv64 => {Ok(FCVT_LU_D::new(FRtypeToInt::from(i)).into())},
+                (0b1110001, 0b000, 0) if rv64 => {Ok(FMV_X_D::new(FRtypeToInt::from(i)).into())},
+                (0b1101001, _, 2) if rv64 => {Ok(FCVT_D_L::new(FRtypeFromInt::from(i)).into())},
+                (0b1101001, _, 3) if rv64 => {Ok(FCVT_D_LU::new(FRtypeFromInt::from(i)).into())},
+                (0b1111001, 0b000, 0) if rv64 => {Ok(FMV_D_X::new(FRtypeFromInt::from(i)).into())},
+                _ => Err(VmExit::InvalidOpcode(i)),
+            }
+        },
         _ => Err(VmExit::InvalidOpcode(i)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::{Mmu, PhysAddr, PERM_RAW, PERM_WRITE};
+    use crate::riscv::csr::{self, Privilege};
+    use crate::riscv::register::VRegister;
+
+    fn run(prog: &[u32], regs: &[(Register, u64)], steps: usize) -> Machine {
+        run_xlen(prog, regs, steps, Xlen::Rv64)
+    }
+
+    fn run_xlen(prog: &[u32], regs: &[(Register, u64)], steps: usize, xlen: Xlen) -> Machine {
+        let mut mmu = Mmu::new(4096);
+        for (n, inst) in prog.iter().enumerate() {
+            mmu.write_u32(VirtAddr(n * 4), *inst).unwrap();
+        }
+        mmu.entry_point = Some(VirtAddr(0));
+
+        let mut m = Machine::new_with_xlen(mmu, xlen);
+        for (r, v) in regs {
+            m.set_r(*r, *v).unwrap();
+        }
+        for _ in 0..steps {
+            m.step().unwrap();
+        }
+        m
+    }
+
+    #[test]
+    fn test_upper_immediates() {
+        let m = run(&[
+            0xfffff537, // lui a0, 1048575
+            0x00001597, // auipc a1, 1
+            0x80000637, // lui a2, 524288
+            0xfff6069b, // addiw a3, a2, -1
+        ], &[], 4);
+
+        assert_eq!(m.get_r(A0), 0xffff_ffff_ffff_f000);
+        assert_eq!(m.get_r(A1), 0x1004);
+        assert_eq!(m.get_r(A2), 0xffff_ffff_8000_0000);
+        assert_eq!(m.get_r(A3), 0x7fff_ffff);
+    }
+
+    #[test]
+    fn test_shifts() {
+        let m = run(&[
+            0x03f51593, // slli a1, a0, 63
+            0x03f55613, // srli a2, a0, 63
+            0x43f5d693, // srai a3, a1, 63
+            0x01f5171b, // slliw a4, a0, 31
+            0x01f7579b, // srliw a5, a4, 31
+            0x41f7581b, // sraiw a6, a4, 31
+            0x005518b3, // sll a7, a0, t0
+            0x00b5893b, // addw s2, a1, a1
+            0x40a009bb, // negw s3, a0
+            0x40575a3b, // sraw s4, a4, t0
+        ], &[(A0, 1), (T0, 0x41)], 10);
+
+        assert_eq!(m.get_r(A1), 1 << 63);
+        assert_eq!(m.get_r(A2), 0);
+        assert_eq!(m.get_r(A3), u64::MAX);
+        assert_eq!(m.get_r(A4), 0xffff_ffff_8000_0000);
+        assert_eq!(m.get_r(A5), 1);
+        assert_eq!(m.get_r(A6), u64::MAX);
+        assert_eq!(m.get_r(A7), 2);
+        assert_eq!(m.get_r(S2), 0);
+        assert_eq!(m.get_r(S3), u64::MAX);
+        assert_eq!(m.get_r(S4), 0xffff_ffff_c000_0000);
+    }
+
+    #[test]
+    fn test_loads_stores() {
+        let m = run(&[
+            0x00a13423, // sd a0, 8(sp)
+            0x00810583, // lb a1, 8(sp)
+            0x00814603, // lbu a2, 8(sp)
+            0x00811683, // lh a3, 8(sp)
+            0x00815703, // lhu a4, 8(sp)
+            0x00812783, // lw a5, 8(sp)
+            0x00816803, // lwu a6, 8(sp)
+            0x00813883, // ld a7, 8(sp)
+            0x000107a3, // sb zero, 15(sp)
+            0x00813903, // ld s2, 8(sp)
+        ], &[(A0, 0x8899_aabb_ccdd_eeff), (Sp, 0x100)], 10);
+
+        assert_eq!(m.get_r(A1), 0xffff_ffff_ffff_ffff);
+        assert_eq!(m.get_r(A2), 0xff);
+        assert_eq!(m.get_r(A3), 0xffff_ffff_ffff_eeff);
+        assert_eq!(m.get_r(A4), 0xeeff);
+        assert_eq!(m.get_r(A5), 0xffff_ffff_ccdd_eeff);
+        assert_eq!(m.get_r(A6), 0xccdd_eeff);
+        assert_eq!(m.get_r(A7), 0x8899_aabb_ccdd_eeff);
+        assert_eq!(m.get_r(S2), 0x0099_aabb_ccdd_eeff);
+    }
+
+    #[test]
+    fn test_compares_and_logic() {
+        let m = run(&[
+            0x00b52633, // slt a2, a0, a1
+            0x00b536b3, // sltu a3, a0, a1
+            0x00052713, // slti a4, a0, 0
+            0xfff53793, // sltiu a5, a0, -1
+            0xfff54813, // xori a6, a0, -1
+            0x80006893, // ori a7, zero, -2048
+            0x0ff57913, // andi s2, a0, 255
+        ], &[(A0, -2i64 as u64), (A1, 1)], 7);
+
+        assert_eq!(m.get_r(A2), 1);
+        assert_eq!(m.get_r(A3), 0);
+        assert_eq!(m.get_r(A4), 1);
+        assert_eq!(m.get_r(A5), 1);
+        assert_eq!(m.get_r(A6), 1);
+        assert_eq!(m.get_r(A7), -2048i64 as u64);
+        assert_eq!(m.get_r(S2), 0xfe);
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let m = run(&[
+            0x00b54463, // blt a0, a1, 8
+            0x00100613, // addi a2, zero, 1
+            0x00b56463, // bltu a0, a1, 8
+            0x00100693, // addi a3, zero, 1
+            0x008000ef, // jal ra, 8
+            0x00100713, // addi a4, zero, 1
+            0x001782e7, // jalr t0, 1(a5)
+        ], &[(A0, u64::MAX), (A1, 1), (A5, 0x40)], 5);
+
+        assert_eq!(m.get_r(A2), 0);
+        assert_eq!(m.get_r(A3), 1);
+        assert_eq!(m.get_r(A4), 0);
+        assert_eq!(m.get_r(Ra), 20);
+        assert_eq!(m.get_r(T0), 28);
+        assert_eq!(m.get_r(Pc), 0x40);
+    }
+
+    #[test]
+    fn test_multiply_divide() {
+        let m = run(&[
+            0x02b50633, // mul a2, a0, a1
+            0x02b516b3, // mulh a3, a0, a1
+            0x02b52733, // mulhsu a4, a0, a1
+            0x02b537b3, // mulhu a5, a0, a1
+            0x02054833, // div a6, a0, zero
+            0x020558b3, // divu a7, a0, zero
+            0x02056933, // rem s2, a0, zero
+            0x020579b3, // remu s3, a0, zero
+            0x02b54a33, // div s4, a0, a1
+            0x02b56ab3, // rem s5, a0, a1
+        ], &[(A0, i64::MIN as u64), (A1, u64::MAX)], 10);
+
+        assert_eq!(m.get_r(A2), i64::MIN as u64);
+        assert_eq!(m.get_r(A3), 0);
+        assert_eq!(m.get_r(A4), 0x8000_0000_0000_0000);
+        assert_eq!(m.get_r(A5), 0x7fff_ffff_ffff_ffff);
+        assert_eq!(m.get_r(A6), u64::MAX);
+        assert_eq!(m.get_r(A7), u64::MAX);
+        assert_eq!(m.get_r(S2), i64::MIN as u64);
+        assert_eq!(m.get_r(S3), i64::MIN as u64);
+        assert_eq!(m.get_r(S4), i64::MIN as u64);
+        assert_eq!(m.get_r(S5), 0);
+    }
+
+    #[test]
+    fn test_multiply_divide_word() {
+        let m = run(&[
+            0x02a5063b, // mulw a2, a0, a0
+            0x020546bb, // divw a3, a0, zero
+            0x02b5573b, // divuw a4, a0, a1
+            0x020567bb, // remw a5, a0, zero
+            0x02b5783b, // remuw a6, a0, a1
+            0x02b548bb, // divw a7, a0, a1
+            0x02b5693b, // remw s2, a0, a1
+        ], &[(A0, 0xffff_ffff_8000_0000), (A1, u64::MAX)], 7);
+
+        assert_eq!(m.get_r(A2), 0);
+        assert_eq!(m.get_r(A3), u64::MAX);
+        assert_eq!(m.get_r(A4), 0);
+        assert_eq!(m.get_r(A5), 0xffff_ffff_8000_0000);
+        assert_eq!(m.get_r(A6), 0xffff_ffff_8000_0000);
+        assert_eq!(m.get_r(A7), 0xffff_ffff_8000_0000);
+        assert_eq!(m.get_r(S2), 0);
+    }
+
+    #[test]
+    fn test_bitmanip() {
+        let m = run(&[
+            0x20c5a533, // sh1add a0, a1, a2
+            0x20c5e6bb, // sh3add.uw a3, a1, a2
+            0x08c5873b, // add.uw a4, a1, a2
+            0x0845979b, // slli.uw a5, a1, 4
+            0x40c5f933, // andn s2, a1, a2
+            0x40c5e9b3, // orn s3, a1, a2
+            0x40c5ca33, // xnor s4, a1, a2
+            0x60059a93, // clz s5, a1
+            0x60159b1b, // ctzw s6, a1
+            0x60259b93, // cpop s7, a1
+            0x0ac5cc33, // min s8, a1, a2
+            0x0ac5fcb3, // maxu s9, a1, a2
+            0x60459d13, // sext.b s10, a1
+            0x0805cdbb, // zext.h s11, a1
+            0x60c59e33, // rol t3, a1, a2
+            0x6045de9b, // roriw t4, a1, 4
+            0x2875df13, // orc.b t5, a1
+            0x6b85df93, // rev8 t6, a1
+        ], &[(A1, 0x8000_0000_1234_0080), (A2, 3)], 18);
+
+        assert_eq!(m.get_r(A0), 0x2468_0103);
+        assert_eq!(m.get_r(A3), 0x91a0_0403);
+        assert_eq!(m.get_r(A4), 0x1234_0083);
+        assert_eq!(m.get_r(A5), 0x1_2340_0800);
+        assert_eq!(m.get_r(S2), 0x8000_0000_1234_0080);
+        assert_eq!(m.get_r(S3), 0xffff_ffff_ffff_fffc);
+        assert_eq!(m.get_r(S4), 0x7fff_ffff_edcb_ff7c);
+        assert_eq!(m.get_r(S5), 0);
+        assert_eq!(m.get_r(S6), 7);
+        assert_eq!(m.get_r(S7), 7);
+        assert_eq!(m.get_r(S8), 0x8000_0000_1234_0080);
+        assert_eq!(m.get_r(S9), 0x8000_0000_1234_0080);
+        assert_eq!(m.get_r(S10), 0xffff_ffff_ffff_ff80);
+        assert_eq!(m.get_r(S11), 0x80);
+        assert_eq!(m.get_r(T3), 0x91a0_0404);
+        assert_eq!(m.get_r(T4), 0x0123_4008);
+        assert_eq!(m.get_r(T5), 0xff00_0000_ffff_00ff);
+        assert_eq!(m.get_r(T6), 0x8000_3412_0000_0080);
+    }
+
+    #[test]
+    fn test_carryless_and_single_bit() {
+        let m = run(&[
+            0x0ac59533, // clmul a0, a1, a2
+            0x0ac5b833, // clmulh a6, a1, a2
+            0x0ac5a8b3, // clmulr a7, a1, a2
+            0x4bf59913, // bclri s2, a1, 63
+            0x48c5d9b3, // bext s3, a1, a2
+            0x68059a13, // binvi s4, a1, 0
+            0x28c59ab3, // bset s5, a1, a2
+        ], &[(A1, 0x8000_0000_1234_0080), (A2, 3)], 7);
+
+        assert_eq!(m.get_r(A0), 0x8000_0000_365c_0180);
+        assert_eq!(m.get_r(A6), 1);
+        assert_eq!(m.get_r(A7), 3);
+        assert_eq!(m.get_r(S2), 0x1234_0080);
+        assert_eq!(m.get_r(S3), 0);
+        assert_eq!(m.get_r(S4), 0x8000_0000_1234_0081);
+        assert_eq!(m.get_r(S5), 0x8000_0000_1234_0088);
+    }
+
+    #[test]
+    fn test_scalar_crypto() {
+        let m = run(&[
+            0x10259513, // sha256sig0 a0, a1
+            0x10159693, // sha256sum1 a3, a1
+            0x10759713, // sha512sig1 a4, a1
+            0x10459793, // sha512sum0 a5, a1
+            0x10859813, // sm3p0 a6, a1
+            0xf0c588b3, // sm4ed a7, a1, a2, 3
+            0x74c58933, // sm4ks s2, a1, a2, 1
+            0x08c5c9b3, // pack s3, a1, a2
+            0x08c5fa33, // packh s4, a1, a2
+            0x08c5cabb, // packw s5, a1, a2
+            0x6875db13, // brev8 s6, a1
+            0x28c5abb3, // xperm4 s7, a1, a2
+            0x28b5cc33, // xperm8 s8, a1, a1
+            0x31a59c93, // aes64ks1i s9, a1, 10
+        ], &[(A1, 0x0123_4567_89ab_cdef), (A2, 0xfedc_ba98_7654_3210)], 14);
+
+        assert_eq!(m.get_r(A0), 0x3d5d_cc4c);
+        assert_eq!(m.get_r(A3), 0xffff_ffff_d631_6d8a);
+        assert_eq!(m.get_r(A4), 0x70a3_460d_bbd4_317a);
+        assert_eq!(m.get_r(A5), 0xb7c5_7a10_0c7e_c1ab);
+        assert_eq!(m.get_r(A6), 0x45ef_01ab);
+        assert_eq!(m.get_r(A7), 0x3237_51c8);
+        assert_eq!(m.get_r(S2), 0xffff_ffff_8aa5_d16f);
+        assert_eq!(m.get_r(S3), 0x7654_3210_89ab_cdef);
+        assert_eq!(m.get_r(S4), 0x10ef);
+        assert_eq!(m.get_r(S5), 0x3210_cdef);
+        assert_eq!(m.get_r(S6), 0x80c4_a2e6_91d5_b3f7);
+        assert_eq!(m.get_r(S7), 0x0123_4567_89ab_cdef);
+        assert_eq!(m.get_r(S8), 0xcd00_0000_0000_0000);
+        assert_eq!(m.get_r(S9), 0x7c26_6e85_7c26_6e85);
+
+        // Round numbers above 10 are reserved
+        assert!(parse_instruction(0x31b59c93, &Isa::full(Xlen::Rv64)).is_err());
+    }
+
+    #[test]
+    fn test_vector_config() {
+        let mut m = run(&[
+            0x0d95f557, // vsetvli a0, a1, e64, m2, ta, ma
+            0xccf1f657, // vsetivli a2, 3, e16, mf2, ta, ma
+            0x0c3076d7, // vsetvli a3, zero, e8, m8, ta, ma
+            0x0dd07757, // vsetvli a4, zero, e64, mf8, ta, ma
+            0xc21027f3, // csrr a5, vtype
+            0xc2202873, // csrr a6, vlenb
+            0x022180d7, // vadd.vv v1, v2, v3
+        ], &[(A1, 100)], 6);
+
+        assert_eq!(m.get_r(A0), 4);
+        assert_eq!(m.get_r(A2), 3);
+        assert_eq!(m.get_r(A3), 128);
+        // LMUL = 1/8 can't hold a 64-bit element
+        assert_eq!(m.get_r(A4), 0);
+        assert_eq!(m.get_r(A5), vector::VTYPE_VILL);
+        assert_eq!(m.get_r(A6), 16);
+        assert_eq!(m.step(), Err(VmExit::IllegalInstruction));
+
+        // Register groups have to be aligned to LMUL
+        let mut m = run(&[
+            0x0d15f557, // vsetvli a0, a1, e32, m2, ta, ma
+            0x022200d7, // vadd.vv v1, v2, v4
+        ], &[(A1, 100)], 1);
+        assert_eq!(m.get_r(A0), 8);
+        assert_eq!(m.step(), Err(VmExit::IllegalInstruction));
+    }
+
+    #[test]
+    fn test_vector_status() {
+        let vs = |m: &Machine| m.csrs.get(csr::MSTATUS).unwrap() & csr::MSTATUS_VS;
+        let mut m = run(&[
+            0xc2202573, // csrr a0, vlenb
+            0x0d95f557, // vsetvli a0, a1, e64, m2, ta, ma
+        ], &[(A1, 100)], 1);
+        // Reading a vector CSR leaves the state clean
+        assert_eq!(vs(&m), csr::MSTATUS_VS_INITIAL);
+        m.step().unwrap();
+        assert_eq!(vs(&m), csr::MSTATUS_VS_DIRTY);
+
+        // Vector instructions and CSRs are illegal with VS Off
+        let status = m.csrs.get(csr::MSTATUS).unwrap();
+        m.write_csr(csr::MSTATUS, status & !csr::MSTATUS_VS).unwrap();
+        for pc in [0, 4] {
+            m.set_r(Pc, pc).unwrap();
+            assert_eq!(m.step(), Err(VmExit::IllegalInstruction));
+        }
+        assert_eq!(m.get_r(A0), 4);
+    }
+
+    #[test]
+    fn test_vector_arith_and_memory() {
+        let m = run(&[
+            0x0d05f557, // vsetvli a0, a1, e32, m1, ta, ma
+            0x5208a0d7, // vid.v v1
+            0x62113057, // vmseq.vi v0, v1, 2
+            0x001fb4d7, // vadd.vi v9, v1, -1, v0.t
+            0x020760a7, // vse32.v v1, (a4)
+            0x02076107, // vle32.v v2, (a4)
+            0x021101d7, // vadd.vv v3, v1, v2
+            0x9617e257, // vmul.vx v4, v1, a5
+            0x021022d7, // vredsum.vs v5, v1, v0
+            0x42502857, // vmv.x.s a6, v5
+            0xc2112557, // vwaddu.vv v10, v1, v2
+            0x8218c657, // vsaddu.vx v12, v1, a7
+            0x3e10b6d7, // vslidedown.vi v13, v1, 1
+            0x3a17e757, // vslide1up.vx v14, v1, a5
+            0x0a536307, // vlse32.v v6, (t1), t0
+            0x96113457, // vsll.vi v8, v1, 2
+            0x0e83c457, // vrsub.vx v8, v8, t2
+            0x06876387, // vluxei32.v v7, (a4), v8
+            0x00902973, // csrr s2, vxsat
+        ], &[(A1, 4), (A4, 0x400), (A5, 5), (A7, 0xffff_fffe),
+             (T0, (-4i64) as u64), (T1, 0x40c), (T2, 12)], 19);
+
+        let v = |r: VRegister, eew: usize| -> Vec<u64> {
+            (0..4).map(|n| m.vregs.get(r, n, eew)).collect()
+        };
+        assert_eq!(m.get_r(A0), 4);
+        assert_eq!(v(VRegister::V1, 32), [0, 1, 2, 3]);
+        assert_eq!(v(VRegister::V9, 32), [0, 0, 1, 0]);
+        assert_eq!(v(VRegister::V2, 32), [0, 1, 2, 3]);
+        assert_eq!(v(VRegister::V3, 32), [0, 2, 4, 6]);
+        assert_eq!(v(VRegister::V4, 32), [0, 5, 10, 15]);
+        // The mask register holds 0b100 in its first element
+        assert_eq!(m.get_r(A6), 10);
+        assert_eq!(v(VRegister::V10, 64), [0, 2, 4, 6]);
+        assert_eq!(v(VRegister::V12, 32), [0xffff_fffe, 0xffff_ffff, 0xffff_ffff, 0xffff_ffff]);
+        assert_eq!(m.get_r(S2), 1);
+        assert_eq!(v(VRegister::V13, 32), [1, 2, 3, 0]);
+        assert_eq!(v(VRegister::V14, 32), [5, 0, 1, 2]);
+        assert_eq!(v(VRegister::V6, 32), [3, 2, 1, 0]);
+        assert_eq!(v(VRegister::V7, 32), [3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn test_atomics() {
+        let m = run(&[
+            0x1005262f, // lr.w a2, (a0)
+            0x18b526af, // sc.w a3, a1, (a0)
+            0x18b5272f, // sc.w a4, a1, (a0)
+            0x100537af, // lr.d a5, (a0)
+            0x00053023, // sd zero, 0(a0)
+            0x18b5382f, // sc.d a6, a1, (a0)
+            0x00b528af, // amoadd.w a7, a1, (a0)
+            0x80b5292f, // amomin.w s2, a1, (a0)
+            0xe0b539af, // amomaxu.d s3, a1, (a0)
+            0x08053a2f, // amoswap.d s4, zero, (a0)
+            0x00052a83, // lw s5, 0(a0)
+        ], &[(A0, 0x100), (A1, u64::MAX)], 11);
+
+        assert_eq!(m.get_r(A2), 0);
+        assert_eq!(m.get_r(A3), 0);
+        assert_eq!(m.get_r(A4), 1);
+        assert_eq!(m.get_r(A5), 0xffff_ffff);
+        assert_eq!(m.get_r(A6), 1);
+        assert_eq!(m.get_r(A7), 0);
+        assert_eq!(m.get_r(S2), u64::MAX);
+        assert_eq!(m.get_r(S3), 0xffff_ffff);
+        assert_eq!(m.get_r(S4), u64::MAX);
+        assert_eq!(m.get_r(S5), 0);
+    }
+
+    #[test]
+    fn test_atomic_faults() {
+        let mut m = run(&[
+            0x1005262f, // lr.w a2, (a0)
+            0x18b526af, // sc.w a3, a1, (a0)
+            0x00b528af, // amoadd.w a7, a1, (a0)
+        ], &[(A0, 0x800), (A1, 1)], 0);
+        m.mmu.set_perms(PhysAddr(0x800), 8, PERM_WRITE | PERM_RAW);
+
+        // An LR that faults leaves no reservation for the SC
+        assert_eq!(m.step(), Err(VmExit::UninitFault(VirtAddr(0x800))));
+        m.set_r(Pc, 4).unwrap();
+        m.step().unwrap();
+        assert_eq!(m.get_r(A3), 1);
+
+        // The read of an AMO is checked for uninitialized memory too
+        assert_eq!(m.step(), Err(VmExit::UninitFault(VirtAddr(0x800))));
+    }
+
+    #[test]
+    fn test_floating_point() {
+        let m = run(&[
+            0xd0057553, // fcvt.s.w fa0, a0
+            0xd20585d3, // fcvt.d.w fa1, a1
+            0xd2050653, // fcvt.d.w fa2, a0
+            0x1ab676d3, // fdiv.d fa3, fa2, fa1
+            0x5a05f753, // fsqrt.d fa4, fa1
+            0xc2069653, // fcvt.w.d a2, fa3, rtz
+            0xc206b6d3, // fcvt.w.d a3, fa3, rup
+            0x5ab5f7c3, // fmadd.d fa5, fa1, fa1, fa1
+            0xe2078753, // fmv.x.d a4, fa5
+            0x20a51853, // fsgnjn.s fa6, fa0, fa0
+            0xe00807d3, // fmv.x.w a5, fa6
+            0xe0081853, // fclass.s a6, fa6
+            0xa2c598d3, // flt.d a7, fa1, fa2
+            0x00f13027, // fsd fa5, 0(sp)
+            0x00012887, // flw fa7, 0(sp)
+            0xa118a953, // feq.s s2, fa7, fa7
+            0x4016f053, // fcvt.s.d ft0, fa3
+            0x00012427, // fsw ft0, 8(sp)
+            0x00812983, // lw s3, 8(sp)
+        ], &[(A0, 7), (A1, 2), (Sp, 0x100)], 19);
+
+        assert_eq!(m.get_f32(FRegister::Fa0), 7.0);
+        assert_eq!(m.get_f(FRegister::Fa0) >> 32, 0xffff_ffff);
+        assert_eq!(m.get_f64(FRegister::Fa3), 3.5);
+        assert_eq!(m.get_f64(FRegister::Fa4), 2.0f64.sqrt());
+        assert_eq!(m.get_r(A2), 3);
+        assert_eq!(m.get_r(A3), 4);
+        assert_eq!(m.get_r(A4), 0x4018_0000_0000_0000);
+        assert_eq!(m.get_r(A5), 0xffff_ffff_c0e0_0000);
+        assert_eq!(m.get_r(A6), 1 << 1);
+        assert_eq!(m.get_r(A7), 1);
+        assert_eq!(m.get_r(S2), 1);
+        assert_eq!(m.get_r(S3), 0x4060_0000);
+        assert_eq!(m.get_fcsr(), float::FFLAG_NX);
+    }
+
+    #[test]
+    fn test_invalid_rounding_mode() {
+        let mut m = run(&[
+            0x02c5f553, // fadd.d fa0, fa1, fa2, dyn
+        ], &[], 0);
+
+        m.set_fcsr(0b101 << 5);
+        assert_eq!(m.step(), Err(VmExit::IllegalInstruction));
+    }
+
+    #[test]
+    fn test_csr() {
+        let mut m = run(&[
+            0x34051673, // csrrw a2, mscratch, a0
+            0x3405a6f3, // csrrs a3, mscratch, a1
+            0x34053773, // csrrc a4, mscratch, a0
+            0x0021d073, // csrrwi zero, frm, 3
+            0x0012e7f3, // csrrsi a5, fflags, 5
+            0x00302873, // csrrs a6, fcsr, zero
+            0xc02028f3, // csrrs a7, instret, zero
+            0xc0002973, // csrrs s2, cycle, zero
+            0xc0051073, // csrrw zero, cycle, a0
+        ], &[(A0, 0xf0), (A1, 0x0f)], 8);
+
+        assert_eq!(m.get_r(A2), 0);
+        assert_eq!(m.get_r(A3), 0xf0);
+        assert_eq!(m.get_r(A4), 0xff);
+        assert_eq!(m.read_csr(csr::MSCRATCH), Ok(0x0f));
+        assert_eq!(m.get_r(A5), 0);
+        assert_eq!(m.get_r(A6), 0x65);
+        assert_eq!(m.get_r(A7), 6);
+        assert_eq!(m.get_r(S2), 7);
+
+        // cycle is read-only
+        assert_eq!(m.step(), Err(VmExit::IllegalInstruction));
+    }
+
+    #[test]
+    fn test_csr_privilege() {
+        let mut m = run(&[
+            0xf14029f3, // csrrs s3, mhartid, zero
+            0xc0002973, // csrrs s2, cycle, zero
+        ], &[], 0);
+
+        m.set_privilege(Privilege::User);
+        assert_eq!(m.step(), Err(VmExit::IllegalInstruction));
+
+        m.set_r(Pc, 4).unwrap();
+        assert_eq!(m.step(), Err(VmExit::IllegalInstruction));
+        m.csrs.set(csr::MCOUNTEREN, 1);
+        assert_eq!(m.step(), Err(VmExit::IllegalInstruction));
+        m.csrs.set(csr::SCOUNTEREN, 1);
+        assert_eq!(m.step(), Ok(()));
+    }
+
+    #[test]
+    fn test_illegal_instruction_trap() {
+        let mut m = run(&[
+            0x30529073, // csrw mtvec, t0
+            0x7ff00073, // (reserved SYSTEM encoding)
+        ], &[(T0, 0x100)], 1);
+        m.set_emulate_syscalls(false);
+        m.mmu.write_u32(VirtAddr(0x100), 0x34102573).unwrap(); // csrr a0, mepc
+        m.mmu.write_u32(VirtAddr(0x104), 0x342025f3).unwrap(); // csrr a1, mcause
+        m.mmu.write_u32(VirtAddr(0x108), 0x34302673).unwrap(); // csrr a2, mtval
+        for _ in 0..4 {
+            m.step().unwrap();
+        }
+
+        assert_eq!(m.get_r(A0), 4);
+        assert_eq!(m.get_r(A1), Exception::IllegalInstruction as u64);
+        assert_eq!(m.get_r(A2), 0x7ff00073);
+        assert_eq!(m.get_r(Pc), 0x10c);
+        assert_eq!(m.read_csr(csr::MINSTRET), Ok(4));
+    }
+
+    #[test]
+    fn test_ecall_and_mret() {
+        let mut m = run(&[
+            0x30529073, // csrw mtvec, t0
+            0x34131073, // csrw mepc, t1
+            0x30200073, // mret
+            0x00000073, // ecall
+            0x00000073, // ecall
+        ], &[(T0, 0x100), (T1, 12)], 0);
+        m.set_emulate_syscalls(false);
+        m.mmu.write_u32(VirtAddr(0x100), 0x34102573).unwrap(); // csrr a0, mepc
+        m.mmu.write_u32(VirtAddr(0x104), 0x342025f3).unwrap(); // csrr a1, mcause
+        m.mmu.write_u32(VirtAddr(0x108), 0x00450513).unwrap(); // addi a0, a0, 4
+        m.mmu.write_u32(VirtAddr(0x10c), 0x34151073).unwrap(); // csrw mepc, a0
+        m.mmu.write_u32(VirtAddr(0x110), 0x30200073).unwrap(); // mret
+
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.get_privilege(), Privilege::User);
+        assert_eq!(m.get_r(Pc), 12);
+
+        for _ in 0..6 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.get_r(A1), Exception::EcallFromU as u64);
+        assert_eq!(m.get_privilege(), Privilege::User);
+        assert_eq!(m.get_r(Pc), 16);
+
+        // MRET and WFI with TW are not allowed from user mode
+        m.csrs.set(csr::MSTATUS, csr::MSTATUS_TW);
+        m.mmu.write_u32(VirtAddr(0x20), 0x30200073).unwrap(); // mret
+        m.mmu.write_u32(VirtAddr(0x24), 0x10500073).unwrap(); // wfi
+        for pc in [0x20, 0x24] {
+            m.set_privilege(Privilege::User);
+            m.set_r(Pc, pc).unwrap();
+            m.step().unwrap();
+            assert_eq!(m.csrs.get(csr::MCAUSE), Some(Exception::IllegalInstruction as u64));
+            assert_eq!(m.csrs.get(csr::MEPC), Some(pc));
+        }
+    }
+
+    #[test]
+    fn test_memory_exceptions() {
+        let mut m = run(&[
+            0x0003a683, // lw a3, 0(t2)
+            0x00fe272f, // amoadd.w a4, a5, (t3)
+            0x00100073, // ebreak
+        ], &[(T2, 0x10000), (T3, 0x102)], 0);
+        assert_eq!(m.step(), Err(VmExit::ReadFault(VirtAddr(0x10000))));
+
+        m.set_emulate_syscalls(false);
+        let (cycle, instret) = (m.read_csr(csr::MCYCLE), m.read_csr(csr::MINSTRET));
+        let expected = [
+            (Exception::LoadAccessFault, 0x10000),
+            (Exception::StoreAddressMisaligned, 0x102),
+            (Exception::Breakpoint, 8),
+        ];
+        for (pc, (cause, tval)) in expected.iter().enumerate() {
+            m.set_r(Pc, pc as u64 * 4).unwrap();
+            m.step().unwrap();
+            assert_eq!(m.csrs.get(csr::MCAUSE), Some(*cause as u64));
+            assert_eq!(m.csrs.get(csr::MTVAL), Some(*tval));
+            assert_eq!(m.get_r(Pc), 0);
+        }
+        assert_eq!(m.get_r(A3), 0);
+        assert_eq!(m.mmu.read_u32(VirtAddr(0x100)).unwrap(), 0);
+
+        // Trapping instructions take a cycle but do not retire
+        assert_eq!(m.read_csr(csr::MCYCLE), cycle.map(|c| c + 3));
+        assert_eq!(m.read_csr(csr::MINSTRET), instret);
+    }
+
+    #[test]
+    fn test_supervisor_mode() {
+        let mut mmu = Mmu::new(0x10000);
+        let code: &[(usize, &[u32])] = &[
+            (0x0000, &[
+                0x18029073, // csrw satp, t0
+                0x30231073, // csrw medeleg, t1
+                0x34139073, // csrw mepc, t2
+                0x300e1073, // csrw mstatus, t3
+                0x305e9073, // csrw mtvec, t4
+                0x30200073, // mret
+            ]),
+            // M-mode handler
+            (0x0200, &[
+                0x342025f3, // csrr a1, mcause
+                0x34102673, // csrr a2, mepc
+            ]),
+            // S-mode, and its handler at 0x2100
+            (0x2000, &[
+                0x12000073, // sfence.vma
+                0x10541073, // csrw stvec, s0
+                0x14149073, // csrw sepc, s1
+                0x10200073, // sret
+            ]),
+            (0x2100, &[
+                0x14202573, // csrr a0, scause
+                0x00000073, // ecall
+            ]),
+            // U-mode
+            (0x3000, &[
+                0x00000073, // ecall
+            ]),
+        ];
+        for (addr, insts) in code {
+            for (n, inst) in insts.iter().enumerate() {
+                mmu.write_u32(VirtAddr(addr + n * 4), *inst).unwrap();
+            }
+        }
+
+        // Physical memory mapped at 0x4000_0000 for S-mode and at
+        // 0x8000_0000 for U-mode, with 1 GiB pages
+        mmu.write_u64(VirtAddr(0x1008), 0xcf).unwrap();
+        mmu.write_u64(VirtAddr(0x1010), 0xdf).unwrap();
+        mmu.entry_point = Some(VirtAddr(0));
+
+        let mut m = Machine::new(mmu);
+        m.set_emulate_syscalls(false);
+        for (r, v) in [
+            (T0, (8 << 60) | 1),
+            (T1, 1 << Exception::EcallFromU as u64),
+            (T2, 0x4000_2000),
+            (T3, (Privilege::Supervisor as u64) << csr::MSTATUS_MPP_SHIFT),
+            (T4, 0x200),
+            (S0, 0x4000_2100),
+            (S1, 0x8000_3000),
+        ] {
+            m.set_r(r, v).unwrap();
+        }
+
+        for _ in 0..10 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.get_privilege(), Privilege::User);
+        assert_eq!(m.get_r(Pc), 0x8000_3000);
+
+        for _ in 0..5 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.get_r(A0), Exception::EcallFromU as u64);
+        assert_eq!(m.read_csr(csr::SEPC), Ok(0x8000_3000));
+        assert_eq!(m.get_r(A1), Exception::EcallFromS as u64);
+        assert_eq!(m.get_r(A2), 0x4000_2104);
+        assert_eq!(m.read_csr(csr::MSTATUS).unwrap() & csr::MSTATUS_MPP,
+            (Privilege::Supervisor as u64) << csr::MSTATUS_MPP_SHIFT);
+        assert_eq!(m.read_csr(csr::SSTATUS).unwrap() & csr::MSTATUS_SPP, 0);
+
+        // The user page is not executable from S-mode
+        m.set_privilege(Privilege::Supervisor);
+        m.set_r(Pc, 0x8000_3000).unwrap();
+        m.step().unwrap();
+        assert_eq!(m.read_csr(csr::MCAUSE), Ok(Exception::InstructionPageFault as u64));
+        assert_eq!(m.read_csr(csr::MTVAL), Ok(0x8000_3000));
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut m = run(&[
+            0x020042b7, // lui t0, 0x2004
+            0x00a2b023, // sd a0, 0(t0)
+            0x30432073, // csrs mie, t1
+            0x3003a073, // csrs mstatus, t2
+            0x10500073, // wfi
+            0x0000006f, // j 0
+        ], &[
+            (A0, 10),
+            (T1, csr::MIP_MTIP | csr::MIP_MSIP),
+            (T2, csr::MSTATUS_MIE),
+        ], 0);
+        m.set_emulate_syscalls(false);
+        m.write_csr(csr::MTVEC, 0x100).unwrap();
+        m.mmu.write_u32(VirtAddr(0x100), 0x342026f3).unwrap(); // csrr a3, mcause
+        m.mmu.write_u32(VirtAddr(0x104), 0xc0102673).unwrap(); // rdtime a2
+        m.mmu.write_u32(VirtAddr(0x108), 0x020002b7).unwrap(); // lui t0, 0x2000
+        m.mmu.write_u32(VirtAddr(0x10c), 0x00b2a023).unwrap(); // sw a1, 0(t0)
+        m.mmu.write_u32(VirtAddr(0x110), 0x34402773).unwrap(); // csrr a4, mip
+
+        // The timer fires once mtime reaches mtimecmp, at the start of the
+        // tenth step
+        for _ in 0..9 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.get_r(Pc), 0x14);
+        m.step().unwrap();
+        assert_eq!(m.get_r(Pc), 0x100);
+        assert_eq!(m.read_csr(csr::MEPC), Ok(0x14));
+        assert_eq!(m.read_csr(csr::MSTATUS).unwrap() & (csr::MSTATUS_MIE | csr::MSTATUS_MPIE),
+            csr::MSTATUS_MPIE);
+
+        m.set_r(A1, 1).unwrap();
+        for _ in 0..5 {
+            m.step().unwrap();
+        }
+        assert_eq!(m.get_r(A3), csr::MCAUSE_INTERRUPT | 7);
+        assert_eq!(m.get_r(A2), 12);
+        assert_eq!(m.get_r(A4), csr::MIP_MTIP | csr::MIP_MSIP);
+
+        // Software interrupts go before timer interrupts
+        m.write_csr(csr::MSTATUS, csr::MSTATUS_MIE).unwrap();
+        m.step().unwrap();
+        assert_eq!(m.read_csr(csr::MCAUSE), Ok(csr::MCAUSE_INTERRUPT | 3));
+    }
+
+    #[test]
+    fn test_code_writes() {
+        let mut m = run(&[
+            0x00100513, // addi a0, zero, 1
+            0x0330000f, // fence rw, rw
+            0x0000100f, // fence.i
+        ], &[], 2);
+        assert_eq!(m.get_r(A0), 1);
+
+        // Writes to decoded code take effect right away, FENCE.I or not
+        m.mmu.write_u32(VirtAddr(0), 0x00200513).unwrap(); // addi a0, zero, 2
+        m.set_r(Pc, 0).unwrap();
+        m.step().unwrap();
+        assert_eq!(m.get_r(A0), 2);
+
+        m.set_r(Pc, 8).unwrap();
+        m.step().unwrap();
+        m.set_r(Pc, 0).unwrap();
+        m.step().unwrap();
+        assert_eq!(m.get_r(A0), 2);
+
+        // Including stores by the guest to the instruction after them
+        let m = run(&[
+            0x00100513, // addi a0, zero, 1
+            0x00b02423, // sw a1, 8(zero)
+            0x00000013, // nop, replaced by addi a0, zero, 3
+        ], &[(A1, 0x00300513)], 3);
+        assert_eq!(m.get_r(A0), 3);
+    }
+
+    #[test]
+    fn test_inst_size() {
+        // Decoded instructions are cached inline, one slot per halfword of
+        // every code page and one entry per instruction of every block, so
+        // an operand struct growing past this bloats every cached page
+        assert!(std::mem::size_of::<Inst>() <= 32);
+    }
+
+    #[test]
+    fn test_rv32() {
+        let m = run_xlen(&[
+            0x00150513, // addi a0, a0, 1
+            0x00d5d633, // srl a2, a1, a3
+            0x01c5d693, // srli a3, a1, 28
+            0x00559733, // sll a4, a1, t0
+            0x02b5b7b3, // mulhu a5, a1, a1
+            0x02b59833, // mulh a6, a1, a1
+            0x0265d8b3, // divu a7, a1, t1
+            0x6985d913, // rev8 s2, a1
+            0x60069993, // clz s3, a3
+            0x08f31a13, // zip s4, t1
+            0x60535ab3, // ror s5, t1, t0
+            0x0805cb33, // zext.h s6, a1
+        ], &[(A0, 0x7fff_ffff), (A1, -16i64 as u64), (A3, 4), (T0, 33), (T1, 16)], 12, Xlen::Rv32);
+
+        // Results wrap at 32 bits and are kept sign-extended
+        assert_eq!(m.get_r(A0), 0xffff_ffff_8000_0000);
+        assert_eq!(m.get_r(A2), 0x0fff_ffff);
+        assert_eq!(m.get_r(A3), 0xf);
+        assert_eq!(m.get_r(A4), 0xffff_ffff_ffff_ffe0);
+        assert_eq!(m.get_r(A5), 0xffff_ffff_ffff_ffe0);
+        assert_eq!(m.get_r(A6), 0);
+        assert_eq!(m.get_r(A7), 0x0fff_ffff);
+        assert_eq!(m.get_r(S2), 0xffff_ffff_f0ff_ffff);
+        assert_eq!(m.get_r(S3), 28);
+        assert_eq!(m.get_r(S4), 0x100);
+        assert_eq!(m.get_r(S5), 8);
+        assert_eq!(m.get_r(S6), 0xfff0);
+        assert_eq!(m.get_ru(A1), 0xffff_fff0);
+
+        // Shift amounts of 32 and up, and RV64-only instructions
+        for inst in &[0x02051513, 0x0005b503, 0x0015051b, 0x36c58533, 0xc0207553] {
+            assert!(parse_instruction(*inst, &Isa::full(Xlen::Rv32)).is_err(), "{:08x}", inst);
+            assert!(parse_instruction(*inst, &Isa::full(Xlen::Rv64)).is_ok(), "{:08x}", inst);
+        }
+        // RV32-only instructions
+        for inst in &[0x6985d913, 0x08f31a13, 0xe6c58533, 0x54c58533] {
+            assert!(parse_instruction(*inst, &Isa::full(Xlen::Rv64)).is_err(), "{:08x}", inst);
+        }
+    }
+
+    #[test]
+    fn test_isa_gating() {
+        let base: Isa = "rv64i_zicsr".parse().unwrap();
+        let zbkb: Isa = "rv64i_zbkb".parse().unwrap();
+        // mul, clz, fadd.s, fence.i
+        for inst in &[0x02b50533, 0x60059513, 0x0020f053, 0x0000100f] {
+            assert!(parse_instruction(*inst, &base).is_err(), "{:08x}", inst);
+        }
+        // rev8, pack and andn are in Zbkb, clz is not
+        for inst in &[0x6b85d513, 0x08c5c533, 0x40c5f533] {
+            assert!(parse_instruction(*inst, &base).is_err(), "{:08x}", inst);
+            assert!(parse_instruction(*inst, &zbkb).is_ok(), "{:08x}", inst);
+        }
+        assert!(parse_instruction(0x60059513, &zbkb).is_err());
+        assert!(parse_instruction(0x30102573, &zbkb).is_err());
+
+        let mut mmu = Mmu::new(4096);
+        for (n, inst) in [0x30102573, 0x003025f3].iter().enumerate() {
+            mmu.write_u32(VirtAddr(n * 4), *inst).unwrap(); // csrr a0, misa; frcsr a1
+        }
+        mmu.write_u16(VirtAddr(8), 0x0001).unwrap(); // c.nop
+        mmu.entry_point = Some(VirtAddr(0));
+        let mut m = Machine::new_with_isa(mmu, base);
+
+        m.step().unwrap();
+        assert_eq!(m.get_r(A0), (2 << 62) | (1 << 8) | (1 << 18) | (1 << 20));
+        // No F, so no fcsr, and no C
+        assert_eq!(m.step(), Err(VmExit::IllegalInstruction));
+        m.set_r(Pc, 8).unwrap();
+        assert!(m.step().is_err());
+
+        // Instructions of missing extensions trap with the bits that were
+        // fetched in mtval, not those of the expanded compressed instruction
+        let mut mmu = Mmu::new(4096);
+        mmu.write_u16(VirtAddr(0), 0x2000).unwrap(); // c.fld fs0, 0(s0)
+        mmu.entry_point = Some(VirtAddr(0));
+        let mut m = Machine::new_with_isa(mmu, "rv64ic_zicsr".parse().unwrap());
+        m.set_emulate_syscalls(false);
+        m.step().unwrap();
+        assert_eq!(m.read_csr(csr::MCAUSE), Ok(Exception::IllegalInstruction as u64));
+        assert_eq!(m.read_csr(csr::MTVAL), Ok(0x2000));
+    }
+
+    #[test]
+    fn test_rv32_sha512() {
+        let x: u64 = 0x0123_4567_89ab_cdef;
+        let (lo, hi) = (x as u32 as i32 as i64 as u64, (x >> 32) as u32 as i32 as i64 as u64);
+        let m = run_xlen(&[
+            0x54c58533, // sha512sig0l a0, a1, a2
+            0x5cb606b3, // sha512sig0h a3, a2, a1
+            0x50c58733, // sha512sum0r a4, a1, a2
+            0x50b607b3, // sha512sum0r a5, a2, a1
+        ], &[(A1, lo), (A2, hi)], 4, Xlen::Rv32);
+
+        let sig0 = x.rotate_right(1) ^ x.rotate_right(8) ^ (x >> 7);
+        let sum0 = x.rotate_right(28) ^ x.rotate_right(34) ^ x.rotate_right(39);
+        assert_eq!(m.get_ru(A0) | (m.get_ru(A3) << 32), sig0);
+        assert_eq!(m.get_ru(A4) | (m.get_ru(A5) << 32), sum0);
+    }
+
+    #[test]
+    fn test_zero_register() {
+        let m = run(&[
+            0x00100013, // addi zero, zero, 1
+        ], &[], 1);
+
+        assert_eq!(m.get_r(Zero), 0);
+    }
+}
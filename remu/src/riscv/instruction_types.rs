@@ -1,6 +1,6 @@
 // https://github.com/gamozolabs/fuzz_with_emus/blob/master/src/emulator.rs
 
-use super::register::Register;
+use super::register::{Register, FRegister, VRegister};
 use crate::common::Disassemble;
 
 #[derive(Debug, Copy, Clone)]
@@ -24,6 +24,12 @@ impl From<u32> for Rtype {
     }
 }
 
+impl Disassemble for Rtype {
+    fn disassemble(&self) -> String {
+        format!("{:?},{:?},({:?})", self.rd, self.rs2, self.rs1)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Stype {
     pub imm:    i32,
@@ -41,7 +47,7 @@ impl From<u32> for Stype {
         let imm = ((imm as i32) << 20) >> 20;
 
         Stype {
-            imm:    imm,
+            imm,
             rs2:    Register::from((inst >> 20) & 0b11111),
             rs1:    Register::from((inst >> 15) & 0b11111),
             funct3: (inst >> 12) & 0b111,
@@ -73,7 +79,7 @@ impl From<u32> for Jtype {
         let imm = ((imm as i32) << 11) >> 11;
 
         Jtype {
-            imm: imm,
+            imm,
             rd:  Register::from((inst >> 7) & 0b11111),
         }
     }
@@ -104,7 +110,7 @@ impl From<u32> for Btype {
         let imm = ((imm as i32) << 19) >> 19;
 
         Btype {
-            imm:    imm,
+            imm,
             rs2:    Register::from((inst >> 20) & 0b11111),
             rs1:    Register::from((inst >> 15) & 0b11111),
             funct3: (inst >> 12) & 0b111,
@@ -130,7 +136,7 @@ impl From<u32> for Itype {
     fn from(inst: u32) -> Self {
         let imm = (inst as i32) >> 20;
         Itype {
-            imm:    imm,
+            imm,
             rs1:    Register::from((inst >> 15) & 0b11111),
             funct3: (inst >> 12) & 0b111,
             rd:     Register::from((inst >>  7) & 0b11111),
@@ -144,6 +150,7 @@ impl Disassemble for Itype {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Copy, Clone)]
 pub struct ItypeShift {
     pub shamt:    u32,
@@ -194,6 +201,80 @@ impl Disassemble for ItypeOp {
     }
 }
 
+/// I-type layout of instructions with a single source register and the
+/// immediate selecting the operation, like CLZ
+#[derive(Debug, Copy, Clone)]
+pub struct ItypeUnary {
+    pub rs1:    Register,
+    pub rd:     Register,
+}
+
+impl From<u32> for ItypeUnary {
+    fn from(inst: u32) -> Self {
+        ItypeUnary {
+            rs1:    Register::from((inst >> 15) & 0b11111),
+            rd:     Register::from((inst >>  7) & 0b11111),
+        }
+    }
+}
+
+impl Disassemble for ItypeUnary {
+    fn disassemble(&self) -> String {
+        format!("{:?},{:?}", self.rd, self.rs1)
+    }
+}
+
+/// I-type layout of AES64KS1I, with the round number in the immediate
+#[derive(Debug, Copy, Clone)]
+pub struct ItypeRnum {
+    pub rnum:   u32,
+    pub rs1:    Register,
+    pub rd:     Register,
+}
+
+impl From<u32> for ItypeRnum {
+    fn from(inst: u32) -> Self {
+        ItypeRnum {
+            rnum:   (inst >> 20) & 0b1111,
+            rs1:    Register::from((inst >> 15) & 0b11111),
+            rd:     Register::from((inst >>  7) & 0b11111),
+        }
+    }
+}
+
+impl Disassemble for ItypeRnum {
+    fn disassemble(&self) -> String {
+        format!("{:?},{:?},{:}", self.rd, self.rs1, self.rnum)
+    }
+}
+
+/// R-type layout of the SM4 instructions, with the byte select in the top
+/// two bits of funct7
+#[derive(Debug, Copy, Clone)]
+pub struct RtypeBs {
+    pub bs:     u32,
+    pub rs2:    Register,
+    pub rs1:    Register,
+    pub rd:     Register,
+}
+
+impl From<u32> for RtypeBs {
+    fn from(inst: u32) -> Self {
+        RtypeBs {
+            bs:     inst >> 30,
+            rs2:    Register::from((inst >> 20) & 0b11111),
+            rs1:    Register::from((inst >> 15) & 0b11111),
+            rd:     Register::from((inst >>  7) & 0b11111),
+        }
+    }
+}
+
+impl Disassemble for RtypeBs {
+    fn disassemble(&self) -> String {
+        format!("{:?},{:?},{:?},{:}", self.rd, self.rs1, self.rs2, self.bs)
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Utype {
     pub imm: i32,
@@ -203,7 +284,7 @@ pub struct Utype {
 impl From<u32> for Utype {
     fn from(inst: u32) -> Self {
         Utype {
-            imm: ((inst & !0xfff) >> 12) as i32,
+            imm: (inst & !0xfff) as i32,
             rd:  Register::from((inst >> 7) & 0b11111),
         }
     }
@@ -211,10 +292,42 @@ impl From<u32> for Utype {
 
 impl Disassemble for Utype {
     fn disassemble(&self) -> String {
-        format!("{:?},{:#02x?}", self.rd, self.imm)
+        format!("{:?},{:#x}", self.rd, (self.imm as u32) >> 12)
+    }
+}
+
+
+/// I-type layout of the Zicsr instructions, `rs1` is the immediate for the
+/// CSRR*I forms.
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+pub struct Csrtype {
+    pub csr:    u16,
+    pub rs1:    u32,
+    pub funct3: u32,
+    pub rd:     Register,
+}
+
+impl From<u32> for Csrtype {
+    fn from(inst: u32) -> Self {
+        Csrtype {
+            csr:    (inst >> 20) as u16,
+            rs1:    (inst >> 15) & 0b11111,
+            funct3: (inst >> 12) & 0b111,
+            rd:     Register::from((inst >>  7) & 0b11111),
+        }
     }
 }
 
+impl Disassemble for Csrtype {
+    fn disassemble(&self) -> String {
+        if self.funct3 & 0b100 != 0 {
+            format!("{:?},{:#x},{:}", self.rd, self.csr, self.rs1)
+        } else {
+            format!("{:?},{:#x},{:?}", self.rd, self.csr, Register::from(self.rs1))
+        }
+    }
+}
 
 #[derive(Debug, Copy, Clone)]
 pub struct Ntype {
@@ -231,3 +344,240 @@ impl Disassemble for Ntype {
         "".to_string()
     }
 }
+
+/// R-type layout of OP-FP instructions. The destination and source register
+/// files differ between instructions, e.g. FEQ writes an integer register.
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+pub struct FpRtype<D, S> {
+    pub funct7: u32,
+    pub rs2:    S,
+    pub rs1:    S,
+    pub rm:     u32,
+    pub rd:     D,
+}
+
+pub type FRtype = FpRtype<FRegister, FRegister>;
+pub type FRtypeToInt = FpRtype<Register, FRegister>;
+pub type FRtypeFromInt = FpRtype<FRegister, Register>;
+
+impl<D: From<u32>, S: From<u32>> From<u32> for FpRtype<D, S> {
+    fn from(inst: u32) -> Self {
+        FpRtype {
+            funct7: inst >> 25,
+            rs2:    S::from((inst >> 20) & 0b11111),
+            rs1:    S::from((inst >> 15) & 0b11111),
+            rm:     (inst >> 12) & 0b111,
+            rd:     D::from((inst >>  7) & 0b11111),
+        }
+    }
+}
+
+impl<D: std::fmt::Debug, S: std::fmt::Debug> Disassemble for FpRtype<D, S> {
+    fn disassemble(&self) -> String {
+        format!("{:?},{:?},{:?}", self.rd, self.rs1, self.rs2)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+pub struct R4type {
+    pub rs3:    FRegister,
+    pub fmt:    u32,
+    pub rs2:    FRegister,
+    pub rs1:    FRegister,
+    pub rm:     u32,
+    pub rd:     FRegister,
+}
+
+impl From<u32> for R4type {
+    fn from(inst: u32) -> Self {
+        R4type {
+            rs3:    FRegister::from(inst >> 27),
+            fmt:    (inst >> 25) & 0b11,
+            rs2:    FRegister::from((inst >> 20) & 0b11111),
+            rs1:    FRegister::from((inst >> 15) & 0b11111),
+            rm:     (inst >> 12) & 0b111,
+            rd:     FRegister::from((inst >>  7) & 0b11111),
+        }
+    }
+}
+
+impl Disassemble for R4type {
+    fn disassemble(&self) -> String {
+        format!("{:?},{:?},{:?},{:?}", self.rd, self.rs1, self.rs2, self.rs3)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+pub struct FItype {
+    pub imm:    i32,
+    pub rs1:    Register,
+    pub funct3: u32,
+    pub rd:     FRegister,
+}
+
+impl From<u32> for FItype {
+    fn from(inst: u32) -> Self {
+        let i = Itype::from(inst);
+        FItype {
+            imm:    i.imm,
+            rs1:    i.rs1,
+            funct3: i.funct3,
+            rd:     FRegister::from((inst >>  7) & 0b11111),
+        }
+    }
+}
+
+impl Disassemble for FItype {
+    fn disassemble(&self) -> String {
+        format!("{:?},{:}({:?})", self.rd, self.imm, self.rs1)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+pub struct FStype {
+    pub imm:    i32,
+    pub rs2:    FRegister,
+    pub rs1:    Register,
+    pub funct3: u32,
+}
+
+impl From<u32> for FStype {
+    fn from(inst: u32) -> Self {
+        let s = Stype::from(inst);
+        FStype {
+            imm:    s.imm,
+            rs2:    FRegister::from((inst >> 20) & 0b11111),
+            rs1:    s.rs1,
+            funct3: s.funct3,
+        }
+    }
+}
+
+impl Disassemble for FStype {
+    fn disassemble(&self) -> String {
+        format!("{:?},{:}({:?})", self.rs2, self.imm, self.rs1)
+    }
+}
+
+/// Layout of vsetvli and vsetivli. `rs1` is the register holding the AVL,
+/// or the AVL itself for vsetivli.
+#[derive(Debug, Copy, Clone)]
+pub struct VsetType {
+    pub imm:    bool,
+    pub zimm:   u32,
+    pub rs1:    u32,
+    pub rd:     Register,
+}
+
+impl From<u32> for VsetType {
+    fn from(inst: u32) -> Self {
+        let imm = inst >> 30 == 0b11;
+        VsetType {
+            imm,
+            zimm:   (inst >> 20) & if imm { 0x3ff } else { 0x7ff },
+            rs1:    (inst >> 15) & 0b11111,
+            rd:     Register::from((inst >>  7) & 0b11111),
+        }
+    }
+}
+
+impl Disassemble for VsetType {
+    fn disassemble(&self) -> String {
+        if self.imm {
+            format!("{:?},{:},{:#x}", self.rd, self.rs1, self.zimm)
+        } else {
+            format!("{:?},{:?},{:#x}", self.rd, Register::from(self.rs1), self.zimm)
+        }
+    }
+}
+
+/// Layout of the OP-V arithmetic instructions. `rs1` is vs1, a scalar
+/// register or a 5-bit immediate, as selected by funct3.
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
+pub struct VArith {
+    pub funct6: u32,
+    pub vm:     bool,
+    pub vs2:    VRegister,
+    pub rs1:    u32,
+    pub funct3: u32,
+    pub vd:     VRegister,
+}
+
+impl VArith {
+    /// The immediate of the OPIVI forms, sign-extended
+    pub fn simm(&self) -> i64 {
+        (((self.rs1 as i32) << 27) >> 27) as i64
+    }
+}
+
+impl From<u32> for VArith {
+    fn from(inst: u32) -> Self {
+        VArith {
+            funct6: inst >> 26,
+            vm:     (inst >> 25) & 1 != 0,
+            vs2:    VRegister::from((inst >> 20) & 0b11111),
+            rs1:    (inst >> 15) & 0b11111,
+            funct3: (inst >> 12) & 0b111,
+            vd:     VRegister::from((inst >>  7) & 0b11111),
+        }
+    }
+}
+
+impl Disassemble for VArith {
+    fn disassemble(&self) -> String {
+        let op1 = match self.funct3 {
+            0b000 | 0b010 => format!("{:?}", VRegister::from(self.rs1)),
+            0b011 => format!("{:}", self.simm()),
+            _ => format!("{:?}", Register::from(self.rs1)),
+        };
+        let mask = if self.vm { "" } else { ",V0.t" };
+        format!("{:?},{:?},{:}{:}", self.vd, self.vs2, op1, mask)
+    }
+}
+
+/// Layout of the vector loads and stores in the LOAD-FP and STORE-FP
+/// opcodes. `rs2` is the stride register, the index register or selects
+/// the kind of unit-stride access, depending on mop.
+#[derive(Debug, Copy, Clone)]
+pub struct VMem {
+    pub nf:     u32,
+    pub mew:    u32,
+    pub mop:    u32,
+    pub vm:     bool,
+    pub rs2:    u32,
+    pub rs1:    Register,
+    pub width:  u32,
+    pub vd:     VRegister,
+}
+
+impl From<u32> for VMem {
+    fn from(inst: u32) -> Self {
+        VMem {
+            nf:     inst >> 29,
+            mew:    (inst >> 28) & 1,
+            mop:    (inst >> 26) & 0b11,
+            vm:     (inst >> 25) & 1 != 0,
+            rs2:    (inst >> 20) & 0b11111,
+            rs1:    Register::from((inst >> 15) & 0b11111),
+            width:  (inst >> 12) & 0b111,
+            vd:     VRegister::from((inst >>  7) & 0b11111),
+        }
+    }
+}
+
+impl Disassemble for VMem {
+    fn disassemble(&self) -> String {
+        let op2 = match self.mop {
+            0b00 => "".to_string(),
+            0b10 => format!(",{:?}", Register::from(self.rs2)),
+            _ => format!(",{:?}", VRegister::from(self.rs2)),
+        };
+        let mask = if self.vm { "" } else { ",V0.t" };
+        format!("{:?},({:?}){:}{:}", self.vd, self.rs1, op2, mask)
+    }
+}
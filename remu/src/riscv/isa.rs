@@ -0,0 +1,201 @@
+// ISA configuration of a hart, parsed from strings like `rv64imac_zicsr_zba`.
+// The decoder rejects instructions of disabled extensions, so they raise an
+// illegal instruction exception just like on a core without them.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::riscv::register::Xlen;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Extension {
+    M,
+    A,
+    F,
+    D,
+    C,
+    V,
+    Zicsr,
+    Zifencei,
+    Zba,
+    Zbb,
+    Zbc,
+    Zbs,
+    Zbkb,
+    Zbkc,
+    Zbkx,
+    Zkne,
+    Zknd,
+    Zknh,
+    Zksed,
+    Zksh,
+}
+
+use Extension::*;
+
+/// Single-letter extensions, in canonical order
+const SINGLE: &[(char, Extension)] = &[('m', M), ('a', A), ('f', F), ('d', D), ('c', C), ('v', V)];
+
+/// Multi-letter extensions and the extensions they stand for
+const MULTI: &[(&str, &[Extension])] = &[
+    ("zicsr", &[Zicsr]),
+    ("zifencei", &[Zifencei]),
+    ("zba", &[Zba]),
+    ("zbb", &[Zbb]),
+    ("zbc", &[Zbc]),
+    ("zbs", &[Zbs]),
+    ("zbkb", &[Zbkb]),
+    ("zbkc", &[Zbkc]),
+    ("zbkx", &[Zbkx]),
+    ("zkne", &[Zkne]),
+    ("zknd", &[Zknd]),
+    ("zknh", &[Zknh]),
+    ("zksed", &[Zksed]),
+    ("zksh", &[Zksh]),
+    ("zkn", &[Zbkb, Zbkc, Zbkx, Zkne, Zknd, Zknh]),
+    ("zks", &[Zbkb, Zbkc, Zbkx, Zksed, Zksh]),
+];
+
+/// Base ISA and the enabled extensions. RV32I/RV64I is always included.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Isa {
+    pub xlen: Xlen,
+    extensions: u32,
+}
+
+impl Isa {
+    /// Every extension the emulator implements
+    pub fn full(xlen: Xlen) -> Self {
+        format!("rv{}imafdcv_zicsr_zifencei_zba_zbb_zbc_zbs_zkn_zks", xlen.bits()).parse().unwrap()
+    }
+
+    pub fn has(&self, ext: Extension) -> bool {
+        self.extensions & (1 << ext as u32) != 0
+    }
+
+    /// Whether any of `exts` is enabled, for instructions that are part of
+    /// several extensions
+    pub fn has_any(&self, exts: &[Extension]) -> bool {
+        exts.iter().any(|&e| self.has(e))
+    }
+
+    fn enable(&mut self, ext: Extension) {
+        self.extensions |= 1 << ext as u32;
+    }
+
+    /// misa with MXL and the single-letter extensions. S-mode and U-mode
+    /// are always implemented.
+    pub fn misa(&self) -> u64 {
+        let letter = |c: char| 1 << (c as u32 - 'a' as u32);
+        let mut misa = letter('i') | letter('s') | letter('u');
+        for &(c, ext) in SINGLE {
+            if self.has(ext) {
+                misa |= letter(c);
+            }
+        }
+        match self.xlen {
+            Xlen::Rv32 => (1 << 30) | misa,
+            Xlen::Rv64 => (2 << 62) | misa,
+        }
+    }
+}
+
+impl FromStr for Isa {
+    type Err = String;
+
+    /// Parses an ISA string such as `rv64gc` or `rv32imac_zicsr_zba`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let (xlen, rest) = if let Some(rest) = s.strip_prefix("rv32") {
+            (Xlen::Rv32, rest)
+        } else if let Some(rest) = s.strip_prefix("rv64") {
+            (Xlen::Rv64, rest)
+        } else {
+            return Err(format!("ISA string '{}' has to start with rv32 or rv64", s));
+        };
+
+        let mut isa = Isa { xlen, extensions: 0 };
+        let mut parts = rest.split('_');
+        let letters = parts.next().unwrap_or("");
+        let mut chars = letters.chars();
+        match chars.next() {
+            Some('i') => {},
+            Some('g') => {
+                for &e in &[M, A, F, D, Zicsr, Zifencei] {
+                    isa.enable(e);
+                }
+            },
+            _ => return Err(format!("ISA string '{}' has to start with i or g", s)),
+        }
+        for c in chars {
+            match SINGLE.iter().find(|&&(l, _)| l == c) {
+                Some(&(_, ext)) => isa.enable(ext),
+                None => return Err(format!("unsupported extension '{}' in '{}'", c, s)),
+            }
+        }
+
+        for name in parts {
+            match MULTI.iter().find(|&&(n, _)| n == name) {
+                Some(&(_, exts)) => exts.iter().for_each(|&e| isa.enable(e)),
+                None => return Err(format!("unsupported extension '{}' in '{}'", name, s)),
+            }
+        }
+
+        // F brings the CSRs it needs along, D builds on F
+        if isa.has(F) {
+            isa.enable(Zicsr);
+        }
+        if isa.has(D) && !isa.has(F) {
+            return Err(format!("D requires F in '{}'", s));
+        }
+        Ok(isa)
+    }
+}
+
+impl fmt::Display for Isa {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rv{}i", self.xlen.bits())?;
+        for &(c, ext) in SINGLE {
+            if self.has(ext) {
+                write!(f, "{}", c)?;
+            }
+        }
+        for &(name, exts) in MULTI {
+            if let [ext] = exts {
+                if self.has(*ext) {
+                    write!(f, "_{}", name)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let isa: Isa = "rv64imac_zicsr_zba".parse().unwrap();
+        assert_eq!(isa.xlen, Xlen::Rv64);
+        assert!(isa.has(M) && isa.has(A) && isa.has(C) && isa.has(Zicsr) && isa.has(Zba));
+        assert!(!isa.has(F) && !isa.has(Zbb) && !isa.has(Zifencei));
+        assert_eq!(isa.to_string(), "rv64imac_zicsr_zba");
+
+        let isa: Isa = "RV32GC_Zkn".parse().unwrap();
+        assert_eq!(isa.xlen, Xlen::Rv32);
+        assert!(isa.has_any(&[Zbb, Zbkb]) && isa.has(Zknh) && isa.has(Zifencei));
+        assert_eq!(isa.misa(), (1 << 30) | 0x14112d);
+
+        assert!("rv64e".parse::<Isa>().is_err());
+        assert!("rv64imq".parse::<Isa>().is_err());
+        assert!("rv64id".parse::<Isa>().is_err());
+        assert!("rv64i_zfoo".parse::<Isa>().is_err());
+
+        let full = Isa::full(Xlen::Rv32);
+        assert_eq!(full.xlen, Xlen::Rv32);
+        assert!(full.has(V) && full.has(Zksh));
+    }
+}
@@ -1,3 +1,10 @@
 pub mod instruction;
 pub mod register;
 pub mod instruction_types;
+pub mod float;
+pub mod compressed;
+pub mod csr;
+pub mod trap;
+pub mod vector;
+pub mod crypto;
+pub mod isa;
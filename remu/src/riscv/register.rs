@@ -1,6 +1,6 @@
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[repr(usize)]
+#[repr(u8)]
 pub enum Register {
     Zero = 0,
     Ra,
@@ -47,3 +47,134 @@ impl From<u32> for Register {
         }
     }
 }
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FRegister {
+    Ft0 = 0,
+    Ft1,
+    Ft2,
+    Ft3,
+    Ft4,
+    Ft5,
+    Ft6,
+    Ft7,
+    Fs0,
+    Fs1,
+    Fa0,
+    Fa1,
+    Fa2,
+    Fa3,
+    Fa4,
+    Fa5,
+    Fa6,
+    Fa7,
+    Fs2,
+    Fs3,
+    Fs4,
+    Fs5,
+    Fs6,
+    Fs7,
+    Fs8,
+    Fs9,
+    Fs10,
+    Fs11,
+    Ft8,
+    Ft9,
+    Ft10,
+    Ft11,
+}
+
+
+impl From<u32> for FRegister {
+    fn from(val: u32) -> Self {
+        assert!(val < 32);
+        unsafe {
+            core::ptr::read_unaligned(&(val as usize) as
+                                      *const usize as *const FRegister)
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum VRegister {
+    V0 = 0,
+    V1,
+    V2,
+    V3,
+    V4,
+    V5,
+    V6,
+    V7,
+    V8,
+    V9,
+    V10,
+    V11,
+    V12,
+    V13,
+    V14,
+    V15,
+    V16,
+    V17,
+    V18,
+    V19,
+    V20,
+    V21,
+    V22,
+    V23,
+    V24,
+    V25,
+    V26,
+    V27,
+    V28,
+    V29,
+    V30,
+    V31,
+}
+
+
+impl From<u32> for VRegister {
+    fn from(val: u32) -> Self {
+        assert!(val < 32);
+        unsafe {
+            core::ptr::read_unaligned(&(val as usize) as
+                                      *const usize as *const VRegister)
+        }
+    }
+}
+
+/// Width of the integer registers. RV32 values are kept sign-extended to 64
+/// bits, so most RV64 instructions compute the right RV32 result as is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Xlen {
+    Rv32,
+    Rv64,
+}
+
+impl Xlen {
+    pub fn bits(self) -> u32 {
+        match self {
+            Xlen::Rv32 => 32,
+            Xlen::Rv64 => 64,
+        }
+    }
+
+    /// Sign extends the low XLEN bits of `value`, the form registers hold
+    pub fn sext(self, value: u64) -> u64 {
+        match self {
+            Xlen::Rv32 => value as i32 as i64 as u64,
+            Xlen::Rv64 => value,
+        }
+    }
+
+    /// Zero extends the low XLEN bits of `value`, as used for addresses
+    pub fn zext(self, value: u64) -> u64 {
+        match self {
+            Xlen::Rv32 => value as u32 as u64,
+            Xlen::Rv64 => value,
+        }
+    }
+}
@@ -0,0 +1,34 @@
+// Synchronous exceptions, numbered by the exception code they report in
+// mcause.
+
+use crate::riscv::csr::Privilege;
+
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Exception {
+    InstructionAddressMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadAddressMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreAddressMisaligned = 6,
+    StoreAccessFault = 7,
+    EcallFromU = 8,
+    EcallFromS = 9,
+    EcallFromM = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception {
+    /// Environment call raised by an ECALL executed at `privilege`
+    pub fn ecall_from(privilege: Privilege) -> Self {
+        match privilege {
+            Privilege::User => Exception::EcallFromU,
+            Privilege::Supervisor => Exception::EcallFromS,
+            Privilege::Machine => Exception::EcallFromM,
+        }
+    }
+}
@@ -0,0 +1,1668 @@
+// Vector extension (RVV 1.0) with the vector loads and stores and the
+// integer instructions. The register file keeps the 32 registers of VLEN bits
+// as little-endian bytes, so a register group is a contiguous slice and
+// element n of any width sits n * EEW / 8 bytes from the start of its group.
+// Tail and masked-off elements are always left undisturbed, which is also a
+// valid implementation of the agnostic policies.
+
+#![allow(clippy::upper_case_acronyms, non_camel_case_types)]
+
+use super::instruction_types::{ItypeOp, VArith, VMem, VsetType};
+use crate::riscv::register::{Register, VRegister};
+use crate::common::{Emulate, Disassemble, Machine, VmExit};
+use crate::mmu::VirtAddr;
+use crate::{instr, instr_enum};
+
+/// VLEN in bits unless configured otherwise
+pub const DEFAULT_VLEN: usize = 128;
+
+/// Widest supported element in bits
+const ELEN: usize = 64;
+
+/// Set in vtype when software asked for an unsupported configuration
+pub const VTYPE_VILL: u64 = 1 << 63;
+
+/// A valid vtype setting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VType {
+    /// Selected element width in bits
+    pub sew: usize,
+    /// log2 of the register group multiplier, from -3 to 3
+    pub lmul: i32,
+}
+
+impl VType {
+    /// Decodes a vtype value, returning None for the settings that set vill
+    pub fn decode(bits: u64) -> Option<Self> {
+        let vsew = (bits >> 3) & 0b111;
+        if bits >> 8 != 0 || vsew > 3 || bits & 0b111 == 0b100 {
+            return None;
+        }
+
+        let sew = 8 << vsew;
+        let lmul = ((bits as i32) << 29) >> 29;
+        // A fractional group still has to hold an element of ELEN bits
+        // scaled down by LMUL
+        if lmul < 0 && sew > ELEN >> -lmul {
+            return None;
+        }
+        Some(VType { sew, lmul })
+    }
+
+    /// Number of elements in a register group
+    pub fn vlmax(&self, vlen: usize) -> usize {
+        scale(vlen, self.lmul) / self.sew
+    }
+}
+
+/// Multiplies `v` by 2^`log2`
+fn scale(v: usize, log2: i32) -> usize {
+    if log2 >= 0 { v << log2 } else { v >> -log2 }
+}
+
+/// The vector registers together with the vector CSRs
+#[derive(Clone)]
+pub struct VectorRegisters {
+    vlenb: usize,
+    data: Vec<u8>,
+    pub vl: usize,
+    pub vtype: u64,
+    pub vstart: usize,
+    /// Fixed-point rounding mode
+    pub vxrm: u64,
+    /// Fixed-point saturation flag
+    pub vxsat: bool,
+}
+
+impl VectorRegisters {
+    /// Creates a register file with `vlen` bits per register, which has to
+    /// be a power of two between ELEN and 65536
+    pub fn new(vlen: usize) -> Self {
+        assert!(vlen.is_power_of_two() && (ELEN..=65536).contains(&vlen),
+                "unsupported VLEN {}", vlen);
+        VectorRegisters {
+            vlenb: vlen / 8,
+            data: vec![0; 32 * vlen / 8],
+            vl: 0,
+            vtype: VTYPE_VILL,
+            vstart: 0,
+            vxrm: 0,
+            vxsat: false,
+        }
+    }
+
+    pub fn vlen(&self) -> usize {
+        self.vlenb * 8
+    }
+
+    pub fn vlenb(&self) -> usize {
+        self.vlenb
+    }
+
+    /// The current vtype. Instructions that depend on it are illegal while
+    /// vill is set.
+    pub fn config(&self) -> Result<VType, VmExit> {
+        VType::decode(self.vtype).ok_or(VmExit::IllegalInstruction)
+    }
+
+    /// Element `n` of `eew` bits in the register group starting at `reg`
+    pub fn get(&self, reg: VRegister, n: usize, eew: usize) -> u64 {
+        let off = reg as usize * self.vlenb + n * eew / 8;
+        let mut buf = [0; 8];
+        buf[..eew / 8].copy_from_slice(&self.data[off..off + eew / 8]);
+        u64::from_le_bytes(buf)
+    }
+
+    pub fn set(&mut self, reg: VRegister, n: usize, eew: usize, value: u64) {
+        let off = reg as usize * self.vlenb + n * eew / 8;
+        self.data[off..off + eew / 8].copy_from_slice(&value.to_le_bytes()[..eew / 8]);
+    }
+
+    /// Bit `n` of the mask held in `reg`
+    pub fn mask(&self, reg: VRegister, n: usize) -> bool {
+        (self.data[reg as usize * self.vlenb + n / 8] >> (n % 8)) & 1 != 0
+    }
+
+    pub fn set_mask(&mut self, reg: VRegister, n: usize, value: bool) {
+        let byte = &mut self.data[reg as usize * self.vlenb + n / 8];
+        *byte = (*byte & !(1 << (n % 8))) | ((value as u8) << (n % 8));
+    }
+
+    /// Completes an instruction, which always resets vstart
+    fn finish(&mut self, env: Env) {
+        self.vxsat |= env.sat;
+        self.vstart = 0;
+    }
+}
+
+/// Implements vsetvli, vsetivli and vsetvl. `avl` is the application vector
+/// length, None keeps the current vl.
+pub fn vsetvl(m: &mut Machine, rd: Register, avl: Option<u64>, vtype: u64) -> Result<(), VmExit> {
+    match VType::decode(vtype) {
+        Some(t) => {
+            let vlmax = t.vlmax(m.vregs.vlen()) as u64;
+            let avl = avl.unwrap_or(m.vregs.vl as u64);
+            m.vregs.vtype = vtype;
+            m.vregs.vl = avl.min(vlmax) as usize;
+        },
+        None => {
+            m.vregs.vtype = VTYPE_VILL;
+            m.vregs.vl = 0;
+        },
+    }
+    m.vregs.vstart = 0;
+    m.set_r(rd, m.vregs.vl as u64)
+}
+
+/// AVL of vsetvli and vsetvl, which asks for VLMAX when rs1 is x0, or keeps
+/// vl when rd is x0 as well
+fn avl(m: &Machine, rd: Register, rs1: Register) -> Option<u64> {
+    if rs1 != Register::Zero {
+        Some(m.get_r(rs1))
+    } else if rd != Register::Zero {
+        Some(u64::MAX)
+    } else {
+        None
+    }
+}
+
+/// All ones in the low `bits` bits
+fn ones(bits: usize) -> u64 {
+    if bits >= 64 { u64::MAX } else { (1 << bits) - 1 }
+}
+
+/// Sign-extends the low `bits` bits of `v`
+fn sext(v: u64, bits: usize) -> i64 {
+    ((v << (64 - bits)) as i64) >> (64 - bits)
+}
+
+/// Number of registers in a group with log2 multiplier `emul`
+fn group_len(emul: i32) -> usize {
+    1 << emul.max(0)
+}
+
+/// Checks that a group of 2^`emul` registers starting at `reg` is legal:
+/// the multiplier is supported and `reg` is a multiple of the group size
+fn check_group(reg: VRegister, emul: i32) -> Result<(), VmExit> {
+    if !(-3..=3).contains(&emul) || !(reg as usize).is_multiple_of(group_len(emul)) {
+        return Err(VmExit::IllegalInstruction);
+    }
+    Ok(())
+}
+
+/// Checks whether a destination group may overlap a source group. Groups
+/// with different element widths may only overlap in the lowest part of the
+/// source when narrowing, or in the highest part of the destination when
+/// widening.
+fn check_overlap(d: VRegister, d_eew: usize, d_emul: i32,
+                 s: VRegister, s_eew: usize, s_emul: i32) -> Result<(), VmExit> {
+    let (d, dn) = (d as usize, group_len(d_emul));
+    let (s, sn) = (s as usize, group_len(s_emul));
+    let allowed = d + dn <= s || s + sn <= d || d_eew == s_eew ||
+        (d_eew < s_eew && d == s) ||
+        (d_eew > s_eew && s_emul >= 0 && s + sn == d + dn);
+    if !allowed {
+        return Err(VmExit::IllegalInstruction);
+    }
+    Ok(())
+}
+
+/// Checks that two groups don't overlap at all
+fn check_disjoint(d: VRegister, d_emul: i32, s: VRegister, s_emul: i32) -> Result<(), VmExit> {
+    check_overlap(d, 0, d_emul, s, 1, s_emul.min(-1))
+}
+
+/// A masked instruction writing vector elements cannot overwrite the mask
+fn check_mask_dest(vm: bool, vd: VRegister) -> Result<(), VmExit> {
+    if !vm && vd == VRegister::V0 {
+        return Err(VmExit::IllegalInstruction);
+    }
+    Ok(())
+}
+
+/// Instructions that are only defined for vstart = 0
+fn check_vstart(m: &Machine) -> Result<(), VmExit> {
+    if m.vregs.vstart != 0 {
+        return Err(VmExit::IllegalInstruction);
+    }
+    Ok(())
+}
+
+/// Whether element `n` is active under the mask in v0
+fn active(m: &Machine, vm: bool, n: usize) -> bool {
+    vm || m.vregs.mask(VRegister::V0, n)
+}
+
+/// Where the first operand of an arithmetic instruction comes from
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Src {
+    /// Element of vs1
+    V,
+    /// Scalar register rs1
+    X,
+    /// Sign-extended immediate
+    I,
+    /// Unsigned immediate, for shift amounts and element indices
+    UI,
+}
+
+/// Element `n` of the first operand, truncated to `sew` bits
+fn op1(m: &Machine, i: VArith, src: Src, n: usize, sew: usize) -> u64 {
+    match src {
+        Src::V => m.vregs.get(VRegister::from(i.rs1), n, sew),
+        Src::X => m.get_r(Register::from(i.rs1)) & ones(sew),
+        Src::I => i.simm() as u64 & ones(sew),
+        Src::UI => i.rs1 as u64,
+    }
+}
+
+/// Checks the vs1 register group when the operand is a vector
+fn check_op1(i: VArith, src: Src, t: VType) -> Result<(), VmExit> {
+    if src == Src::V {
+        check_group(VRegister::from(i.rs1), t.lmul)?;
+    }
+    Ok(())
+}
+
+/// State shared by the elements of an instruction
+struct Env {
+    /// SEW in bits
+    sew: usize,
+    /// Fixed-point rounding mode
+    vxrm: u64,
+    /// Set when a fixed-point result saturated
+    sat: bool,
+}
+
+impl Env {
+    fn new(m: &Machine, t: VType) -> Self {
+        Env { sew: t.sew, vxrm: m.vregs.vxrm, sat: false }
+    }
+}
+
+/// An element operation, called with the elements of vd, vs2 and the first
+/// operand
+type ElemOp = fn(u64, u64, u64, &mut Env) -> u64;
+
+// Element widths of vd and vs2, as log2 multiples of SEW
+const SINGLE: (i32, i32) = (0, 0);
+const WIDEN: (i32, i32) = (1, 0);
+const WIDEN_W: (i32, i32) = (1, 1);
+const NARROW: (i32, i32) = (0, 1);
+
+/// Runs `f` on the active body elements and writes the result to vd. vd and
+/// vs2 are `widths` times wider than SEW, the first operand is always SEW.
+fn vop(m: &mut Machine, i: VArith, src: Src, widths: (i32, i32), f: ElemOp) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    let (dw, sw) = (t.sew << widths.0, t.sew << widths.1);
+    let (demul, semul) = (t.lmul + widths.0, t.lmul + widths.1);
+    if dw > ELEN || sw > ELEN {
+        return Err(VmExit::IllegalInstruction);
+    }
+    check_group(i.vd, demul)?;
+    check_group(i.vs2, semul)?;
+    check_overlap(i.vd, dw, demul, i.vs2, sw, semul)?;
+    check_op1(i, src, t)?;
+    if src == Src::V {
+        check_overlap(i.vd, dw, demul, VRegister::from(i.rs1), t.sew, t.lmul)?;
+    }
+    check_mask_dest(i.vm, i.vd)?;
+
+    let mut env = Env::new(m, t);
+    for n in m.vregs.vstart..m.vregs.vl {
+        if active(m, i.vm, n) {
+            let d = m.vregs.get(i.vd, n, dw);
+            let a = m.vregs.get(i.vs2, n, sw);
+            let b = op1(m, i, src, n, t.sew);
+            let r = f(d, a, b, &mut env);
+            m.vregs.set(i.vd, n, dw, r);
+        }
+    }
+    m.vregs.finish(env);
+    Ok(())
+}
+
+/// Integer compare writing a mask, `f` gets the elements of vs2 and the
+/// first operand and SEW
+fn vcmp(m: &mut Machine, i: VArith, src: Src, f: fn(u64, u64, usize) -> bool) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    check_group(i.vs2, t.lmul)?;
+    check_overlap(i.vd, 1, 0, i.vs2, t.sew, t.lmul)?;
+    check_op1(i, src, t)?;
+    if src == Src::V {
+        check_overlap(i.vd, 1, 0, VRegister::from(i.rs1), t.sew, t.lmul)?;
+    }
+
+    for n in m.vregs.vstart..m.vregs.vl {
+        if active(m, i.vm, n) {
+            let a = m.vregs.get(i.vs2, n, t.sew);
+            let b = op1(m, i, src, n, t.sew);
+            m.vregs.set_mask(i.vd, n, f(a, b, t.sew));
+        }
+    }
+    m.vregs.finish(Env::new(m, t));
+    Ok(())
+}
+
+/// Add or subtract with carry. The mask bit encodes whether v0 holds a
+/// carry in, `f` returns the result and the carry out for the elements of
+/// vs2, the first operand and the carry in. With `carry_out` the carries are
+/// written to vd as a mask, otherwise the results.
+fn vcarry(m: &mut Machine, i: VArith, src: Src, carry_out: bool,
+          f: fn(u64, u64, bool, usize) -> (u64, bool)) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    check_group(i.vs2, t.lmul)?;
+    check_op1(i, src, t)?;
+    if carry_out {
+        check_overlap(i.vd, 1, 0, i.vs2, t.sew, t.lmul)?;
+        if src == Src::V {
+            check_overlap(i.vd, 1, 0, VRegister::from(i.rs1), t.sew, t.lmul)?;
+        }
+    } else {
+        // vadc and vsbc always take the carry from v0
+        if i.vm {
+            return Err(VmExit::IllegalInstruction);
+        }
+        check_group(i.vd, t.lmul)?;
+        check_mask_dest(i.vm, i.vd)?;
+    }
+
+    for n in m.vregs.vstart..m.vregs.vl {
+        let carry = !i.vm && m.vregs.mask(VRegister::V0, n);
+        let a = m.vregs.get(i.vs2, n, t.sew);
+        let b = op1(m, i, src, n, t.sew);
+        let (r, c) = f(a, b, carry, t.sew);
+        if carry_out {
+            m.vregs.set_mask(i.vd, n, c);
+        } else {
+            m.vregs.set(i.vd, n, t.sew, r);
+        }
+    }
+    m.vregs.finish(Env::new(m, t));
+    Ok(())
+}
+
+/// vmerge, and vmv.v when unmasked: copies the first operand to the active
+/// elements and vs2 to the others
+fn vmerge(m: &mut Machine, i: VArith, src: Src) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    check_group(i.vd, t.lmul)?;
+    check_group(i.vs2, t.lmul)?;
+    check_op1(i, src, t)?;
+    check_mask_dest(i.vm, i.vd)?;
+
+    for n in m.vregs.vstart..m.vregs.vl {
+        let v = if active(m, i.vm, n) {
+            op1(m, i, src, n, t.sew)
+        } else {
+            m.vregs.get(i.vs2, n, t.sew)
+        };
+        m.vregs.set(i.vd, n, t.sew, v);
+    }
+    m.vregs.finish(Env::new(m, t));
+    Ok(())
+}
+
+/// Reduction of the active elements of vs2 into element 0 of vd, starting
+/// from element 0 of vs1. With `wide` the accumulator is 2*SEW.
+fn vred(m: &mut Machine, i: VArith, wide: bool, f: fn(u64, u64, usize) -> u64) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    check_vstart(m)?;
+    check_group(i.vs2, t.lmul)?;
+    let aw = if wide { 2 * t.sew } else { t.sew };
+    if aw > ELEN {
+        return Err(VmExit::IllegalInstruction);
+    }
+    if m.vregs.vl == 0 {
+        return Ok(());
+    }
+
+    let mut acc = m.vregs.get(VRegister::from(i.rs1), 0, aw);
+    for n in 0..m.vregs.vl {
+        if active(m, i.vm, n) {
+            acc = f(acc, m.vregs.get(i.vs2, n, t.sew), t.sew) & ones(aw);
+        }
+    }
+    m.vregs.set(i.vd, 0, aw, acc);
+    Ok(())
+}
+
+/// Mask-register logical instruction on vs2 and vs1
+fn vmask(m: &mut Machine, i: VArith, f: fn(bool, bool) -> bool) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    let vs1 = VRegister::from(i.rs1);
+    for n in m.vregs.vstart..m.vregs.vl {
+        let r = f(m.vregs.mask(i.vs2, n), m.vregs.mask(vs1, n));
+        m.vregs.set_mask(i.vd, n, r);
+    }
+    m.vregs.finish(Env::new(m, t));
+    Ok(())
+}
+
+/// Zero or sign extension from SEW / 2^`frac`
+fn vext(m: &mut Machine, i: VArith, frac: i32, signed: bool) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    let (eew, emul) = (t.sew >> frac, t.lmul - frac);
+    if eew < 8 {
+        return Err(VmExit::IllegalInstruction);
+    }
+    check_group(i.vd, t.lmul)?;
+    check_group(i.vs2, emul)?;
+    check_overlap(i.vd, t.sew, t.lmul, i.vs2, eew, emul)?;
+    check_mask_dest(i.vm, i.vd)?;
+
+    for n in m.vregs.vstart..m.vregs.vl {
+        if active(m, i.vm, n) {
+            let v = m.vregs.get(i.vs2, n, eew);
+            let v = if signed { sext(v, eew) as u64 } else { v };
+            m.vregs.set(i.vd, n, t.sew, v & ones(t.sew));
+        }
+    }
+    m.vregs.finish(Env::new(m, t));
+    Ok(())
+}
+
+/// vslideup and vslide1up, which shift the elements of vs2 up by `offset`.
+/// `fill` is written to element 0 by vslide1up.
+fn vslideup(m: &mut Machine, i: VArith, offset: u64, fill: Option<u64>) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    check_group(i.vd, t.lmul)?;
+    check_group(i.vs2, t.lmul)?;
+    check_disjoint(i.vd, t.lmul, i.vs2, t.lmul)?;
+    check_mask_dest(i.vm, i.vd)?;
+
+    // Elements below the offset are left alone, except element 0 of
+    // vslide1up
+    let start = match fill {
+        Some(_) => m.vregs.vstart,
+        None => (m.vregs.vstart as u64).max(offset.min(m.vregs.vl as u64)) as usize,
+    };
+    for n in start..m.vregs.vl {
+        if active(m, i.vm, n) {
+            let v = match fill {
+                Some(x) if n == 0 => x & ones(t.sew),
+                _ => m.vregs.get(i.vs2, n - offset as usize, t.sew),
+            };
+            m.vregs.set(i.vd, n, t.sew, v);
+        }
+    }
+    m.vregs.finish(Env::new(m, t));
+    Ok(())
+}
+
+/// vslidedown and vslide1down, which shift the elements of vs2 down by
+/// `offset`. Elements past VLMAX read as zero, or as `fill` past vl for
+/// vslide1down.
+fn vslidedown(m: &mut Machine, i: VArith, offset: u64, fill: Option<u64>) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    check_group(i.vd, t.lmul)?;
+    check_group(i.vs2, t.lmul)?;
+    check_mask_dest(i.vm, i.vd)?;
+
+    let vl = m.vregs.vl;
+    let vlmax = t.vlmax(m.vregs.vlen());
+    for n in m.vregs.vstart..vl {
+        if active(m, i.vm, n) {
+            let src = (n as u64).saturating_add(offset);
+            let v = match fill {
+                Some(x) if n + 1 == vl => x & ones(t.sew),
+                _ if src < vlmax as u64 => m.vregs.get(i.vs2, src as usize, t.sew),
+                _ => 0,
+            };
+            m.vregs.set(i.vd, n, t.sew, v);
+        }
+    }
+    m.vregs.finish(Env::new(m, t));
+    Ok(())
+}
+
+/// vrgather, gathering elements of vs2 by the index in the first operand.
+/// vrgatherei16 passes `ei16` to read 16-bit indices from vs1.
+fn vrgather(m: &mut Machine, i: VArith, src: Src, ei16: bool) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    let vs1 = VRegister::from(i.rs1);
+    check_group(i.vd, t.lmul)?;
+    check_group(i.vs2, t.lmul)?;
+    check_disjoint(i.vd, t.lmul, i.vs2, t.lmul)?;
+    let (ieew, iemul) = if ei16 {
+        (16, t.lmul + 4 - t.sew.trailing_zeros() as i32)
+    } else {
+        (t.sew, t.lmul)
+    };
+    if src == Src::V {
+        check_group(vs1, iemul)?;
+        check_disjoint(i.vd, t.lmul, vs1, iemul)?;
+    }
+    check_mask_dest(i.vm, i.vd)?;
+
+    let vlmax = t.vlmax(m.vregs.vlen()) as u64;
+    for n in m.vregs.vstart..m.vregs.vl {
+        if active(m, i.vm, n) {
+            let index = match src {
+                Src::V => m.vregs.get(vs1, n, ieew),
+                Src::X => m.get_r(Register::from(i.rs1)),
+                _ => i.rs1 as u64,
+            };
+            let v = if index < vlmax { m.vregs.get(i.vs2, index as usize, t.sew) } else { 0 };
+            m.vregs.set(i.vd, n, t.sew, v);
+        }
+    }
+    m.vregs.finish(Env::new(m, t));
+    Ok(())
+}
+
+/// vcompress, packing the elements of vs2 selected by the mask in vs1 into
+/// the lowest elements of vd
+fn vcompress(m: &mut Machine, i: VArith) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    let vs1 = VRegister::from(i.rs1);
+    check_vstart(m)?;
+    check_group(i.vd, t.lmul)?;
+    check_group(i.vs2, t.lmul)?;
+    check_disjoint(i.vd, t.lmul, i.vs2, t.lmul)?;
+    check_disjoint(i.vd, t.lmul, vs1, 0)?;
+
+    let mut k = 0;
+    for n in 0..m.vregs.vl {
+        if m.vregs.mask(vs1, n) {
+            let v = m.vregs.get(i.vs2, n, t.sew);
+            m.vregs.set(i.vd, k, t.sew, v);
+            k += 1;
+        }
+    }
+    Ok(())
+}
+
+/// vmsbf, vmsif and vmsof, which set the active mask bits before, up to and
+/// including, or only at the first set bit of vs2
+fn vmset_first(m: &mut Machine, i: VArith, before: bool, at: bool) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    check_vstart(m)?;
+    if i.vd == i.vs2 || (!i.vm && i.vd == VRegister::V0) {
+        return Err(VmExit::IllegalInstruction);
+    }
+
+    let mut found = false;
+    for n in 0..m.vregs.vl {
+        if active(m, i.vm, n) {
+            let set = m.vregs.mask(i.vs2, n);
+            let r = !found && ((before && !set) || (at && set));
+            found |= set;
+            m.vregs.set_mask(i.vd, n, r);
+        }
+    }
+    m.vregs.finish(Env::new(m, t));
+    Ok(())
+}
+
+/// viota, writing to every active element the number of active bits of vs2
+/// before it
+fn viota(m: &mut Machine, i: VArith) -> Result<(), VmExit> {
+    let t = m.vregs.config()?;
+    check_vstart(m)?;
+    check_group(i.vd, t.lmul)?;
+    check_disjoint(i.vd, t.lmul, i.vs2, 0)?;
+    check_mask_dest(i.vm, i.vd)?;
+
+    let mut count = 0;
+    for n in 0..m.vregs.vl {
+        if active(m, i.vm, n) {
+            m.vregs.set(i.vd, n, t.sew, count & ones(t.sew));
+            count += m.vregs.mask(i.vs2, n) as u64;
+        }
+    }
+    Ok(())
+}
+
+/// Whole register move of `nr` registers, which ignores vtype and vl
+fn vmvr(m: &mut Machine, i: VArith, nr: usize) -> Result<(), VmExit> {
+    if !(i.vd as usize).is_multiple_of(nr) || !(i.vs2 as usize).is_multiple_of(nr) {
+        return Err(VmExit::IllegalInstruction);
+    }
+    let bytes = m.vregs.vlenb * nr;
+    for n in m.vregs.vstart.min(bytes)..bytes {
+        let v = m.vregs.get(i.vs2, n, 8);
+        m.vregs.set(i.vd, n, 8, v);
+    }
+    m.vregs.vstart = 0;
+    Ok(())
+}
+
+/// Rounding increment when shifting `v` right by `d` bits in rounding mode
+/// `vxrm`
+fn round_bit(v: u128, d: u32, vxrm: u64) -> u128 {
+    if d == 0 {
+        return 0;
+    }
+    let bit = |n: u32| (v >> n) & 1;
+    let below = |n: u32| v & ((1 << n) - 1) != 0;
+    match vxrm {
+        // Round to nearest, ties up
+        0 => bit(d - 1),
+        // Round to nearest, ties to even
+        1 => bit(d - 1) & (below(d - 1) as u128 | bit(d)),
+        // Truncate
+        2 => 0,
+        // Round to odd
+        _ => (bit(d) == 0 && below(d)) as u128,
+    }
+}
+
+fn roundoff_unsigned(v: u128, d: u32, vxrm: u64) -> u128 {
+    (v >> d) + round_bit(v, d, vxrm)
+}
+
+fn roundoff_signed(v: i128, d: u32, vxrm: u64) -> i128 {
+    (v >> d) + round_bit(v as u128, d, vxrm) as i128
+}
+
+/// Saturates `v` to a signed value of `bits` bits
+fn clip_signed(v: i128, bits: usize, env: &mut Env) -> u64 {
+    let (lo, hi) = (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1);
+    let r = if v < lo {
+        env.sat = true;
+        lo
+    } else if v > hi {
+        env.sat = true;
+        hi
+    } else {
+        v
+    };
+    r as u64 & ones(bits)
+}
+
+/// Saturates `v` to an unsigned value of `bits` bits
+fn clip_unsigned(v: i128, bits: usize, env: &mut Env) -> u64 {
+    if v < 0 {
+        env.sat = true;
+        0
+    } else if v > ones(bits) as i128 {
+        env.sat = true;
+        ones(bits)
+    } else {
+        v as u64
+    }
+}
+
+// Element operations, taking the elements of vd, vs2 and the first operand
+
+fn add(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.wrapping_add(b) }
+fn sub(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.wrapping_sub(b) }
+fn rsub(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { b.wrapping_sub(a) }
+fn minu(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.min(b) }
+fn min(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { if sext(a, e.sew) < sext(b, e.sew) { a } else { b } }
+fn maxu(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.max(b) }
+fn max(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { if sext(a, e.sew) > sext(b, e.sew) { a } else { b } }
+fn and(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a & b }
+fn or(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a | b }
+fn xor(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a ^ b }
+fn sll(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { a << (b & (e.sew as u64 - 1)) }
+fn srl(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { a >> (b & (e.sew as u64 - 1)) }
+fn sra(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { (sext(a, e.sew) >> (b & (e.sew as u64 - 1))) as u64 }
+
+fn mul(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.wrapping_mul(b) }
+fn mulh(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    ((sext(a, e.sew) as i128 * sext(b, e.sew) as i128) >> e.sew) as u64
+}
+fn mulhu(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { ((a as u128 * b as u128) >> e.sew) as u64 }
+fn mulhsu(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { ((sext(a, e.sew) as i128 * b as i128) >> e.sew) as u64 }
+fn divu(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.checked_div(b).unwrap_or(u64::MAX) }
+fn div(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    if b == 0 { u64::MAX } else { sext(a, e.sew).wrapping_div(sext(b, e.sew)) as u64 }
+}
+fn remu(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.checked_rem(b).unwrap_or(a) }
+fn rem(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    if b == 0 { a } else { sext(a, e.sew).wrapping_rem(sext(b, e.sew)) as u64 }
+}
+fn macc(d: u64, a: u64, b: u64, _: &mut Env) -> u64 { d.wrapping_add(a.wrapping_mul(b)) }
+fn nmsac(d: u64, a: u64, b: u64, _: &mut Env) -> u64 { d.wrapping_sub(a.wrapping_mul(b)) }
+fn madd(d: u64, a: u64, b: u64, _: &mut Env) -> u64 { d.wrapping_mul(b).wrapping_add(a) }
+fn nmsub(d: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.wrapping_sub(d.wrapping_mul(b)) }
+
+// Widening, where vs2 is already 2*SEW for the .w forms
+fn waddu(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.wrapping_add(b) }
+fn wadd(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { sext(a, e.sew).wrapping_add(sext(b, e.sew)) as u64 }
+fn wsubu(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.wrapping_sub(b) }
+fn wsub(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { sext(a, e.sew).wrapping_sub(sext(b, e.sew)) as u64 }
+fn wadd_w(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { a.wrapping_add(sext(b, e.sew) as u64) }
+fn wsub_w(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { a.wrapping_sub(sext(b, e.sew) as u64) }
+fn wmulu(_: u64, a: u64, b: u64, _: &mut Env) -> u64 { a.wrapping_mul(b) }
+fn wmul(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { sext(a, e.sew).wrapping_mul(sext(b, e.sew)) as u64 }
+fn wmulsu(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { (sext(a, e.sew) as u64).wrapping_mul(b) }
+fn wmaccu(d: u64, a: u64, b: u64, _: &mut Env) -> u64 { d.wrapping_add(a.wrapping_mul(b)) }
+fn wmacc(d: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    d.wrapping_add(sext(a, e.sew).wrapping_mul(sext(b, e.sew)) as u64)
+}
+fn wmaccsu(d: u64, a: u64, b: u64, e: &mut Env) -> u64 { d.wrapping_add(a.wrapping_mul(sext(b, e.sew) as u64)) }
+fn wmaccus(d: u64, a: u64, b: u64, e: &mut Env) -> u64 { d.wrapping_add((sext(a, e.sew) as u64).wrapping_mul(b)) }
+
+// Narrowing, where vs2 is 2*SEW
+fn nsrl(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { a >> (b & (2 * e.sew as u64 - 1)) }
+fn nsra(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { (sext(a, 2 * e.sew) >> (b & (2 * e.sew as u64 - 1))) as u64 }
+fn nclipu(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    let v = roundoff_unsigned(a as u128, (b & (2 * e.sew as u64 - 1)) as u32, e.vxrm);
+    clip_unsigned(v as i128, e.sew, e)
+}
+fn nclip(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    let v = roundoff_signed(sext(a, 2 * e.sew) as i128, (b & (2 * e.sew as u64 - 1)) as u32, e.vxrm);
+    clip_signed(v, e.sew, e)
+}
+
+// Fixed point
+fn saddu(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { clip_unsigned(a as i128 + b as i128, e.sew, e) }
+fn sadd(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    clip_signed(sext(a, e.sew) as i128 + sext(b, e.sew) as i128, e.sew, e)
+}
+fn ssubu(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { clip_unsigned(a as i128 - b as i128, e.sew, e) }
+fn ssub(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    clip_signed(sext(a, e.sew) as i128 - sext(b, e.sew) as i128, e.sew, e)
+}
+fn aaddu(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { roundoff_unsigned(a as u128 + b as u128, 1, e.vxrm) as u64 }
+fn aadd(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    roundoff_signed(sext(a, e.sew) as i128 + sext(b, e.sew) as i128, 1, e.vxrm) as u64
+}
+fn asubu(_: u64, a: u64, b: u64, e: &mut Env) -> u64 { roundoff_signed(a as i128 - b as i128, 1, e.vxrm) as u64 }
+fn asub(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    roundoff_signed(sext(a, e.sew) as i128 - sext(b, e.sew) as i128, 1, e.vxrm) as u64
+}
+fn smul(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    let v = roundoff_signed(sext(a, e.sew) as i128 * sext(b, e.sew) as i128, e.sew as u32 - 1, e.vxrm);
+    clip_signed(v, e.sew, e)
+}
+fn ssrl(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    roundoff_unsigned(a as u128, (b & (e.sew as u64 - 1)) as u32, e.vxrm) as u64
+}
+fn ssra(_: u64, a: u64, b: u64, e: &mut Env) -> u64 {
+    roundoff_signed(sext(a, e.sew) as i128, (b & (e.sew as u64 - 1)) as u32, e.vxrm) as u64
+}
+
+// Add and subtract with carry in and out
+fn adc(a: u64, b: u64, c: bool, sew: usize) -> (u64, bool) {
+    let s = a as u128 + b as u128 + c as u128;
+    (s as u64 & ones(sew), s >> sew != 0)
+}
+fn sbc(a: u64, b: u64, c: bool, sew: usize) -> (u64, bool) {
+    (a.wrapping_sub(b).wrapping_sub(c as u64) & ones(sew), (a as u128) < b as u128 + c as u128)
+}
+
+// Reductions, taking the accumulator and an element of vs2
+fn redsum(acc: u64, x: u64, _: usize) -> u64 { acc.wrapping_add(x) }
+fn redand(acc: u64, x: u64, _: usize) -> u64 { acc & x }
+fn redor(acc: u64, x: u64, _: usize) -> u64 { acc | x }
+fn redxor(acc: u64, x: u64, _: usize) -> u64 { acc ^ x }
+fn redminu(acc: u64, x: u64, _: usize) -> u64 { acc.min(x) }
+fn redmin(acc: u64, x: u64, sew: usize) -> u64 { if sext(x, sew) < sext(acc, sew) { x } else { acc } }
+fn redmaxu(acc: u64, x: u64, _: usize) -> u64 { acc.max(x) }
+fn redmax(acc: u64, x: u64, sew: usize) -> u64 { if sext(x, sew) > sext(acc, sew) { x } else { acc } }
+fn wredsumu(acc: u64, x: u64, _: usize) -> u64 { acc.wrapping_add(x) }
+fn wredsum(acc: u64, x: u64, sew: usize) -> u64 { acc.wrapping_add(sext(x, sew) as u64) }
+
+/// Reads `eew` bits from guest memory
+fn load_elem(m: &mut Machine, addr: VirtAddr, eew: usize) -> Result<u64, VmExit> {
+    Ok(match eew {
+        8 => m.mmu.read_u8(addr)? as u64,
+        16 => m.mmu.read_u16(addr)? as u64,
+        32 => m.mmu.read_u32(addr)? as u64,
+        _ => m.mmu.read_u64(addr)?,
+    })
+}
+
+fn store_elem(m: &mut Machine, addr: VirtAddr, eew: usize, v: u64) -> Result<(), VmExit> {
+    m.invalidate_reservation(addr, eew / 8);
+    match eew {
+        8 => m.mmu.write_u8(addr, v as u8),
+        16 => m.mmu.write_u16(addr, v as u16),
+        32 => m.mmu.write_u32(addr, v as u32),
+        _ => m.mmu.write_u64(addr, v),
+    }
+}
+
+/// Moves elements `vstart..evl` of `eew` bits between consecutive memory at
+/// rs1 and the registers starting at vd, for the accesses that ignore the
+/// mask. A fault leaves vstart at the faulting element.
+fn contiguous(m: &mut Machine, i: VMem, eew: usize, evl: usize, store: bool) -> Result<(), VmExit> {
+    let base = m.get_r(i.rs1);
+    for n in m.vregs.vstart..evl {
+        let addr = m.vaddr(base.wrapping_add((n * eew / 8) as u64));
+        let r = if store {
+            let v = m.vregs.get(i.vd, n, eew);
+            store_elem(m, addr, eew, v)
+        } else {
+            load_elem(m, addr, eew).map(|v| m.vregs.set(i.vd, n, eew, v))
+        };
+        if let Err(e) = r {
+            m.vregs.vstart = n;
+            return Err(e);
+        }
+    }
+    m.vregs.vstart = 0;
+    Ok(())
+}
+
+/// Vector loads and stores: unit-stride, strided and indexed, each with up
+/// to 8 fields per segment, plus the mask, whole register and fault-only-
+/// first forms of the unit-stride accesses
+fn vmem(m: &mut Machine, i: VMem, store: bool) -> Result<(), VmExit> {
+    let eew = match i.width {
+        0b000 => 8,
+        0b101 => 16,
+        0b110 => 32,
+        _ => 64,
+    };
+    let nf = i.nf as usize + 1;
+
+    match (i.mop, i.rs2) {
+        // vl<nf>re<eew> and vs<nf>r, which don't depend on vtype
+        (0b00, 0b01000) => {
+            if !nf.is_power_of_two() || !(i.vd as usize).is_multiple_of(nf) {
+                return Err(VmExit::IllegalInstruction);
+            }
+            let evl = nf * m.vregs.vlen() / eew;
+            return contiguous(m, i, eew, evl, store);
+        },
+        // vlm and vsm, moving ceil(vl / 8) bytes
+        (0b00, 0b01011) => {
+            m.vregs.config()?;
+            let evl = m.vregs.vl.div_ceil(8);
+            return contiguous(m, i, 8, evl, store);
+        },
+        _ => {},
+    }
+
+    let t = m.vregs.config()?;
+    let indexed = i.mop & 1 != 0;
+    let fault_first = i.mop == 0b00 && i.rs2 == 0b10000;
+    // The data of indexed accesses is SEW wide, EEW is the width of the
+    // offsets
+    let eemul = t.lmul + eew.trailing_zeros() as i32 - t.sew.trailing_zeros() as i32;
+    let (dw, demul) = if indexed { (t.sew, t.lmul) } else { (eew, eemul) };
+    let regs = group_len(demul);
+    check_group(i.vd, demul)?;
+    if nf * regs > 8 || i.vd as usize + nf * regs > 32 {
+        return Err(VmExit::IllegalInstruction);
+    }
+    if !store {
+        check_mask_dest(i.vm, i.vd)?;
+    }
+    let vs2 = VRegister::from(i.rs2);
+    if indexed {
+        check_group(vs2, eemul)?;
+        if !store {
+            if nf > 1 {
+                check_disjoint(i.vd, (regs * nf).trailing_zeros() as i32, vs2, eemul)?;
+            } else {
+                check_overlap(i.vd, dw, demul, vs2, eew, eemul)?;
+            }
+        }
+    }
+
+    let base = m.get_r(i.rs1);
+    let bytes = dw / 8;
+    for n in m.vregs.vstart..m.vregs.vl {
+        if !active(m, i.vm, n) {
+            continue;
+        }
+        let offset = match i.mop {
+            0b00 => (n * nf * bytes) as u64,
+            0b10 => (n as u64).wrapping_mul(m.get_r(Register::from(i.rs2))),
+            _ => m.vregs.get(vs2, n, eew),
+        };
+        for f in 0..nf {
+            let addr = m.vaddr(base.wrapping_add(offset).wrapping_add((f * bytes) as u64));
+            let reg = VRegister::from((i.vd as usize + f * regs) as u32);
+            let r = if store {
+                let v = m.vregs.get(reg, n, dw);
+                store_elem(m, addr, dw, v)
+            } else {
+                load_elem(m, addr, dw).map(|v| m.vregs.set(reg, n, dw, v))
+            };
+            if let Err(e) = r {
+                // Fault-only-first loads only trap on element 0 and shorten
+                // vl for faults on later elements
+                if fault_first && n > 0 {
+                    m.vregs.vl = n;
+                    m.vregs.vstart = 0;
+                    return Ok(());
+                }
+                m.vregs.vstart = n;
+                return Err(e);
+            }
+        }
+    }
+    m.vregs.vstart = 0;
+    Ok(())
+}
+
+// Configuration
+instr!(VSETVLI,  VsetType, i, m, {
+    let rs1 = Register::from(i.rs1);
+    vsetvl(m, i.rd, avl(m, i.rd, rs1), i.zimm as u64)});
+instr!(VSETIVLI, VsetType, i, m, vsetvl(m, i.rd, Some(i.rs1 as u64), i.zimm as u64));
+instr!(VSETVL,   ItypeOp,  i, m, vsetvl(m, i.rd, avl(m, i.rd, i.rs1), m.get_r(i.rs2)));
+
+// Loads and stores
+instr!(VLE8_V,     VMem, i, m, vmem(m, i, false));
+instr!(VLE16_V,    VMem, i, m, vmem(m, i, false));
+instr!(VLE32_V,    VMem, i, m, vmem(m, i, false));
+instr!(VLE64_V,    VMem, i, m, vmem(m, i, false));
+instr!(VLE8FF_V,   VMem, i, m, vmem(m, i, false));
+instr!(VLE16FF_V,  VMem, i, m, vmem(m, i, false));
+instr!(VLE32FF_V,  VMem, i, m, vmem(m, i, false));
+instr!(VLE64FF_V,  VMem, i, m, vmem(m, i, false));
+instr!(VLRE8_V,    VMem, i, m, vmem(m, i, false));
+instr!(VLRE16_V,   VMem, i, m, vmem(m, i, false));
+instr!(VLRE32_V,   VMem, i, m, vmem(m, i, false));
+instr!(VLRE64_V,   VMem, i, m, vmem(m, i, false));
+instr!(VLM_V,      VMem, i, m, vmem(m, i, false));
+instr!(VLSE8_V,    VMem, i, m, vmem(m, i, false));
+instr!(VLSE16_V,   VMem, i, m, vmem(m, i, false));
+instr!(VLSE32_V,   VMem, i, m, vmem(m, i, false));
+instr!(VLSE64_V,   VMem, i, m, vmem(m, i, false));
+instr!(VLUXEI8_V,  VMem, i, m, vmem(m, i, false));
+instr!(VLUXEI16_V, VMem, i, m, vmem(m, i, false));
+instr!(VLUXEI32_V, VMem, i, m, vmem(m, i, false));
+instr!(VLUXEI64_V, VMem, i, m, vmem(m, i, false));
+instr!(VLOXEI8_V,  VMem, i, m, vmem(m, i, false));
+instr!(VLOXEI16_V, VMem, i, m, vmem(m, i, false));
+instr!(VLOXEI32_V, VMem, i, m, vmem(m, i, false));
+instr!(VLOXEI64_V, VMem, i, m, vmem(m, i, false));
+instr!(VSE8_V,     VMem, i, m, vmem(m, i, true));
+instr!(VSE16_V,    VMem, i, m, vmem(m, i, true));
+instr!(VSE32_V,    VMem, i, m, vmem(m, i, true));
+instr!(VSE64_V,    VMem, i, m, vmem(m, i, true));
+instr!(VSR_V,      VMem, i, m, vmem(m, i, true));
+instr!(VSM_V,      VMem, i, m, vmem(m, i, true));
+instr!(VSSE8_V,    VMem, i, m, vmem(m, i, true));
+instr!(VSSE16_V,   VMem, i, m, vmem(m, i, true));
+instr!(VSSE32_V,   VMem, i, m, vmem(m, i, true));
+instr!(VSSE64_V,   VMem, i, m, vmem(m, i, true));
+instr!(VSUXEI8_V,  VMem, i, m, vmem(m, i, true));
+instr!(VSUXEI16_V, VMem, i, m, vmem(m, i, true));
+instr!(VSUXEI32_V, VMem, i, m, vmem(m, i, true));
+instr!(VSUXEI64_V, VMem, i, m, vmem(m, i, true));
+instr!(VSOXEI8_V,  VMem, i, m, vmem(m, i, true));
+instr!(VSOXEI16_V, VMem, i, m, vmem(m, i, true));
+instr!(VSOXEI32_V, VMem, i, m, vmem(m, i, true));
+instr!(VSOXEI64_V, VMem, i, m, vmem(m, i, true));
+
+// Single-width arithmetic
+instr!(VADD_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, add));
+instr!(VADD_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, add));
+instr!(VADD_VI,     VArith, i, m, vop(m, i, Src::I, SINGLE, add));
+instr!(VSUB_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, sub));
+instr!(VSUB_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, sub));
+instr!(VRSUB_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, rsub));
+instr!(VRSUB_VI,    VArith, i, m, vop(m, i, Src::I, SINGLE, rsub));
+instr!(VMINU_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, minu));
+instr!(VMINU_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, minu));
+instr!(VMIN_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, min));
+instr!(VMIN_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, min));
+instr!(VMAXU_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, maxu));
+instr!(VMAXU_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, maxu));
+instr!(VMAX_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, max));
+instr!(VMAX_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, max));
+instr!(VAND_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, and));
+instr!(VAND_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, and));
+instr!(VAND_VI,     VArith, i, m, vop(m, i, Src::I, SINGLE, and));
+instr!(VOR_VV,      VArith, i, m, vop(m, i, Src::V, SINGLE, or));
+instr!(VOR_VX,      VArith, i, m, vop(m, i, Src::X, SINGLE, or));
+instr!(VOR_VI,      VArith, i, m, vop(m, i, Src::I, SINGLE, or));
+instr!(VXOR_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, xor));
+instr!(VXOR_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, xor));
+instr!(VXOR_VI,     VArith, i, m, vop(m, i, Src::I, SINGLE, xor));
+instr!(VSLL_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, sll));
+instr!(VSLL_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, sll));
+instr!(VSLL_VI,     VArith, i, m, vop(m, i, Src::UI, SINGLE, sll));
+instr!(VSRL_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, srl));
+instr!(VSRL_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, srl));
+instr!(VSRL_VI,     VArith, i, m, vop(m, i, Src::UI, SINGLE, srl));
+instr!(VSRA_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, sra));
+instr!(VSRA_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, sra));
+instr!(VSRA_VI,     VArith, i, m, vop(m, i, Src::UI, SINGLE, sra));
+instr!(VMUL_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, mul));
+instr!(VMUL_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, mul));
+instr!(VMULH_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, mulh));
+instr!(VMULH_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, mulh));
+instr!(VMULHU_VV,   VArith, i, m, vop(m, i, Src::V, SINGLE, mulhu));
+instr!(VMULHU_VX,   VArith, i, m, vop(m, i, Src::X, SINGLE, mulhu));
+instr!(VMULHSU_VV,  VArith, i, m, vop(m, i, Src::V, SINGLE, mulhsu));
+instr!(VMULHSU_VX,  VArith, i, m, vop(m, i, Src::X, SINGLE, mulhsu));
+instr!(VDIVU_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, divu));
+instr!(VDIVU_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, divu));
+instr!(VDIV_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, div));
+instr!(VDIV_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, div));
+instr!(VREMU_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, remu));
+instr!(VREMU_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, remu));
+instr!(VREM_VV,     VArith, i, m, vop(m, i, Src::V, SINGLE, rem));
+instr!(VREM_VX,     VArith, i, m, vop(m, i, Src::X, SINGLE, rem));
+instr!(VMACC_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, macc));
+instr!(VMACC_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, macc));
+instr!(VNMSAC_VV,   VArith, i, m, vop(m, i, Src::V, SINGLE, nmsac));
+instr!(VNMSAC_VX,   VArith, i, m, vop(m, i, Src::X, SINGLE, nmsac));
+instr!(VMADD_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, madd));
+instr!(VMADD_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, madd));
+instr!(VNMSUB_VV,   VArith, i, m, vop(m, i, Src::V, SINGLE, nmsub));
+instr!(VNMSUB_VX,   VArith, i, m, vop(m, i, Src::X, SINGLE, nmsub));
+
+// Widening arithmetic
+instr!(VWADDU_VV,   VArith, i, m, vop(m, i, Src::V, WIDEN, waddu));
+instr!(VWADDU_VX,   VArith, i, m, vop(m, i, Src::X, WIDEN, waddu));
+instr!(VWADD_VV,    VArith, i, m, vop(m, i, Src::V, WIDEN, wadd));
+instr!(VWADD_VX,    VArith, i, m, vop(m, i, Src::X, WIDEN, wadd));
+instr!(VWSUBU_VV,   VArith, i, m, vop(m, i, Src::V, WIDEN, wsubu));
+instr!(VWSUBU_VX,   VArith, i, m, vop(m, i, Src::X, WIDEN, wsubu));
+instr!(VWSUB_VV,    VArith, i, m, vop(m, i, Src::V, WIDEN, wsub));
+instr!(VWSUB_VX,    VArith, i, m, vop(m, i, Src::X, WIDEN, wsub));
+instr!(VWADDU_WV,   VArith, i, m, vop(m, i, Src::V, WIDEN_W, waddu));
+instr!(VWADDU_WX,   VArith, i, m, vop(m, i, Src::X, WIDEN_W, waddu));
+instr!(VWADD_WV,    VArith, i, m, vop(m, i, Src::V, WIDEN_W, wadd_w));
+instr!(VWADD_WX,    VArith, i, m, vop(m, i, Src::X, WIDEN_W, wadd_w));
+instr!(VWSUBU_WV,   VArith, i, m, vop(m, i, Src::V, WIDEN_W, wsubu));
+instr!(VWSUBU_WX,   VArith, i, m, vop(m, i, Src::X, WIDEN_W, wsubu));
+instr!(VWSUB_WV,    VArith, i, m, vop(m, i, Src::V, WIDEN_W, wsub_w));
+instr!(VWSUB_WX,    VArith, i, m, vop(m, i, Src::X, WIDEN_W, wsub_w));
+instr!(VWMULU_VV,   VArith, i, m, vop(m, i, Src::V, WIDEN, wmulu));
+instr!(VWMULU_VX,   VArith, i, m, vop(m, i, Src::X, WIDEN, wmulu));
+instr!(VWMUL_VV,    VArith, i, m, vop(m, i, Src::V, WIDEN, wmul));
+instr!(VWMUL_VX,    VArith, i, m, vop(m, i, Src::X, WIDEN, wmul));
+instr!(VWMULSU_VV,  VArith, i, m, vop(m, i, Src::V, WIDEN, wmulsu));
+instr!(VWMULSU_VX,  VArith, i, m, vop(m, i, Src::X, WIDEN, wmulsu));
+instr!(VWMACCU_VV,  VArith, i, m, vop(m, i, Src::V, WIDEN, wmaccu));
+instr!(VWMACCU_VX,  VArith, i, m, vop(m, i, Src::X, WIDEN, wmaccu));
+instr!(VWMACC_VV,   VArith, i, m, vop(m, i, Src::V, WIDEN, wmacc));
+instr!(VWMACC_VX,   VArith, i, m, vop(m, i, Src::X, WIDEN, wmacc));
+instr!(VWMACCSU_VV, VArith, i, m, vop(m, i, Src::V, WIDEN, wmaccsu));
+instr!(VWMACCSU_VX, VArith, i, m, vop(m, i, Src::X, WIDEN, wmaccsu));
+instr!(VWMACCUS_VX, VArith, i, m, vop(m, i, Src::X, WIDEN, wmaccus));
+
+// Narrowing shifts
+instr!(VNSRL_WV,    VArith, i, m, vop(m, i, Src::V, NARROW, nsrl));
+instr!(VNSRL_WX,    VArith, i, m, vop(m, i, Src::X, NARROW, nsrl));
+instr!(VNSRL_WI,    VArith, i, m, vop(m, i, Src::UI, NARROW, nsrl));
+instr!(VNSRA_WV,    VArith, i, m, vop(m, i, Src::V, NARROW, nsra));
+instr!(VNSRA_WX,    VArith, i, m, vop(m, i, Src::X, NARROW, nsra));
+instr!(VNSRA_WI,    VArith, i, m, vop(m, i, Src::UI, NARROW, nsra));
+
+// Fixed point
+instr!(VSADDU_VV,   VArith, i, m, vop(m, i, Src::V, SINGLE, saddu));
+instr!(VSADDU_VX,   VArith, i, m, vop(m, i, Src::X, SINGLE, saddu));
+instr!(VSADDU_VI,   VArith, i, m, vop(m, i, Src::I, SINGLE, saddu));
+instr!(VSADD_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, sadd));
+instr!(VSADD_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, sadd));
+instr!(VSADD_VI,    VArith, i, m, vop(m, i, Src::I, SINGLE, sadd));
+instr!(VSSUBU_VV,   VArith, i, m, vop(m, i, Src::V, SINGLE, ssubu));
+instr!(VSSUBU_VX,   VArith, i, m, vop(m, i, Src::X, SINGLE, ssubu));
+instr!(VSSUB_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, ssub));
+instr!(VSSUB_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, ssub));
+instr!(VAADDU_VV,   VArith, i, m, vop(m, i, Src::V, SINGLE, aaddu));
+instr!(VAADDU_VX,   VArith, i, m, vop(m, i, Src::X, SINGLE, aaddu));
+instr!(VAADD_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, aadd));
+instr!(VAADD_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, aadd));
+instr!(VASUBU_VV,   VArith, i, m, vop(m, i, Src::V, SINGLE, asubu));
+instr!(VASUBU_VX,   VArith, i, m, vop(m, i, Src::X, SINGLE, asubu));
+instr!(VASUB_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, asub));
+instr!(VASUB_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, asub));
+instr!(VSMUL_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, smul));
+instr!(VSMUL_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, smul));
+instr!(VSSRL_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, ssrl));
+instr!(VSSRL_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, ssrl));
+instr!(VSSRL_VI,    VArith, i, m, vop(m, i, Src::UI, SINGLE, ssrl));
+instr!(VSSRA_VV,    VArith, i, m, vop(m, i, Src::V, SINGLE, ssra));
+instr!(VSSRA_VX,    VArith, i, m, vop(m, i, Src::X, SINGLE, ssra));
+instr!(VSSRA_VI,    VArith, i, m, vop(m, i, Src::UI, SINGLE, ssra));
+instr!(VNCLIPU_WV,  VArith, i, m, vop(m, i, Src::V, NARROW, nclipu));
+instr!(VNCLIPU_WX,  VArith, i, m, vop(m, i, Src::X, NARROW, nclipu));
+instr!(VNCLIPU_WI,  VArith, i, m, vop(m, i, Src::UI, NARROW, nclipu));
+instr!(VNCLIP_WV,   VArith, i, m, vop(m, i, Src::V, NARROW, nclip));
+instr!(VNCLIP_WX,   VArith, i, m, vop(m, i, Src::X, NARROW, nclip));
+instr!(VNCLIP_WI,   VArith, i, m, vop(m, i, Src::UI, NARROW, nclip));
+
+// Add and subtract with carry
+instr!(VADC_VVM,    VArith, i, m, vcarry(m, i, Src::V, false, adc));
+instr!(VADC_VXM,    VArith, i, m, vcarry(m, i, Src::X, false, adc));
+instr!(VADC_VIM,    VArith, i, m, vcarry(m, i, Src::I, false, adc));
+instr!(VMADC_VVM,   VArith, i, m, vcarry(m, i, Src::V, true, adc));
+instr!(VMADC_VXM,   VArith, i, m, vcarry(m, i, Src::X, true, adc));
+instr!(VMADC_VIM,   VArith, i, m, vcarry(m, i, Src::I, true, adc));
+instr!(VMADC_VV,    VArith, i, m, vcarry(m, i, Src::V, true, adc));
+instr!(VMADC_VX,    VArith, i, m, vcarry(m, i, Src::X, true, adc));
+instr!(VMADC_VI,    VArith, i, m, vcarry(m, i, Src::I, true, adc));
+instr!(VSBC_VVM,    VArith, i, m, vcarry(m, i, Src::V, false, sbc));
+instr!(VSBC_VXM,    VArith, i, m, vcarry(m, i, Src::X, false, sbc));
+instr!(VMSBC_VVM,   VArith, i, m, vcarry(m, i, Src::V, true, sbc));
+instr!(VMSBC_VXM,   VArith, i, m, vcarry(m, i, Src::X, true, sbc));
+instr!(VMSBC_VV,    VArith, i, m, vcarry(m, i, Src::V, true, sbc));
+instr!(VMSBC_VX,    VArith, i, m, vcarry(m, i, Src::X, true, sbc));
+
+// Compares
+instr!(VMSEQ_VV,    VArith, i, m, vcmp(m, i, Src::V, |a, b, _| a == b));
+instr!(VMSEQ_VX,    VArith, i, m, vcmp(m, i, Src::X, |a, b, _| a == b));
+instr!(VMSEQ_VI,    VArith, i, m, vcmp(m, i, Src::I, |a, b, _| a == b));
+instr!(VMSNE_VV,    VArith, i, m, vcmp(m, i, Src::V, |a, b, _| a != b));
+instr!(VMSNE_VX,    VArith, i, m, vcmp(m, i, Src::X, |a, b, _| a != b));
+instr!(VMSNE_VI,    VArith, i, m, vcmp(m, i, Src::I, |a, b, _| a != b));
+instr!(VMSLTU_VV,   VArith, i, m, vcmp(m, i, Src::V, |a, b, _| a < b));
+instr!(VMSLTU_VX,   VArith, i, m, vcmp(m, i, Src::X, |a, b, _| a < b));
+instr!(VMSLT_VV,    VArith, i, m, vcmp(m, i, Src::V, |a, b, sew| sext(a, sew) < sext(b, sew)));
+instr!(VMSLT_VX,    VArith, i, m, vcmp(m, i, Src::X, |a, b, sew| sext(a, sew) < sext(b, sew)));
+instr!(VMSLEU_VV,   VArith, i, m, vcmp(m, i, Src::V, |a, b, _| a <= b));
+instr!(VMSLEU_VX,   VArith, i, m, vcmp(m, i, Src::X, |a, b, _| a <= b));
+instr!(VMSLEU_VI,   VArith, i, m, vcmp(m, i, Src::I, |a, b, _| a <= b));
+instr!(VMSLE_VV,    VArith, i, m, vcmp(m, i, Src::V, |a, b, sew| sext(a, sew) <= sext(b, sew)));
+instr!(VMSLE_VX,    VArith, i, m, vcmp(m, i, Src::X, |a, b, sew| sext(a, sew) <= sext(b, sew)));
+instr!(VMSLE_VI,    VArith, i, m, vcmp(m, i, Src::I, |a, b, sew| sext(a, sew) <= sext(b, sew)));
+instr!(VMSGTU_VX,   VArith, i, m, vcmp(m, i, Src::X, |a, b, _| a > b));
+instr!(VMSGTU_VI,   VArith, i, m, vcmp(m, i, Src::I, |a, b, _| a > b));
+instr!(VMSGT_VX,    VArith, i, m, vcmp(m, i, Src::X, |a, b, sew| sext(a, sew) > sext(b, sew)));
+instr!(VMSGT_VI,    VArith, i, m, vcmp(m, i, Src::I, |a, b, sew| sext(a, sew) > sext(b, sew)));
+
+// Merges and moves
+instr!(VMERGE_VVM,  VArith, i, m, vmerge(m, i, Src::V));
+instr!(VMERGE_VXM,  VArith, i, m, vmerge(m, i, Src::X));
+instr!(VMERGE_VIM,  VArith, i, m, vmerge(m, i, Src::I));
+instr!(VMV_V_V,     VArith, i, m, vmerge(m, i, Src::V));
+instr!(VMV_V_X,     VArith, i, m, vmerge(m, i, Src::X));
+instr!(VMV_V_I,     VArith, i, m, vmerge(m, i, Src::I));
+instr!(VMV1R_V,     VArith, i, m, vmvr(m, i, 1));
+instr!(VMV2R_V,     VArith, i, m, vmvr(m, i, 2));
+instr!(VMV4R_V,     VArith, i, m, vmvr(m, i, 4));
+instr!(VMV8R_V,     VArith, i, m, vmvr(m, i, 8));
+instr!(VMV_X_S,     VArith, i, m, {
+    let t = m.vregs.config()?;
+    let v = sext(m.vregs.get(i.vs2, 0, t.sew), t.sew);
+    m.set_r(Register::from(i.vd as u32), v as u64)});
+instr!(VMV_S_X,     VArith, i, m, {
+    let t = m.vregs.config()?;
+    if m.vregs.vstart < m.vregs.vl {
+        let v = m.get_r(Register::from(i.rs1)) & ones(t.sew);
+        m.vregs.set(i.vd, 0, t.sew, v);
+    }
+    m.vregs.vstart = 0;
+    Ok(())});
+instr!(VZEXT_VF2,   VArith, i, m, vext(m, i, 1, false));
+instr!(VZEXT_VF4,   VArith, i, m, vext(m, i, 2, false));
+instr!(VZEXT_VF8,   VArith, i, m, vext(m, i, 3, false));
+instr!(VSEXT_VF2,   VArith, i, m, vext(m, i, 1, true));
+instr!(VSEXT_VF4,   VArith, i, m, vext(m, i, 2, true));
+instr!(VSEXT_VF8,   VArith, i, m, vext(m, i, 3, true));
+
+// Permutations
+instr!(VSLIDEUP_VX,    VArith, i, m, vslideup(m, i, m.get_r(Register::from(i.rs1)), None));
+instr!(VSLIDEUP_VI,    VArith, i, m, vslideup(m, i, i.rs1 as u64, None));
+instr!(VSLIDEDOWN_VX,  VArith, i, m, vslidedown(m, i, m.get_r(Register::from(i.rs1)), None));
+instr!(VSLIDEDOWN_VI,  VArith, i, m, vslidedown(m, i, i.rs1 as u64, None));
+instr!(VSLIDE1UP_VX,   VArith, i, m, vslideup(m, i, 1, Some(m.get_r(Register::from(i.rs1)))));
+instr!(VSLIDE1DOWN_VX, VArith, i, m, vslidedown(m, i, 1, Some(m.get_r(Register::from(i.rs1)))));
+instr!(VRGATHER_VV,    VArith, i, m, vrgather(m, i, Src::V, false));
+instr!(VRGATHER_VX,    VArith, i, m, vrgather(m, i, Src::X, false));
+instr!(VRGATHER_VI,    VArith, i, m, vrgather(m, i, Src::UI, false));
+instr!(VRGATHEREI16_VV, VArith, i, m, vrgather(m, i, Src::V, true));
+instr!(VCOMPRESS_VM,   VArith, i, m, vcompress(m, i));
+
+// Reductions
+instr!(VREDSUM_VS,   VArith, i, m, vred(m, i, false, redsum));
+instr!(VREDAND_VS,   VArith, i, m, vred(m, i, false, redand));
+instr!(VREDOR_VS,    VArith, i, m, vred(m, i, false, redor));
+instr!(VREDXOR_VS,   VArith, i, m, vred(m, i, false, redxor));
+instr!(VREDMINU_VS,  VArith, i, m, vred(m, i, false, redminu));
+instr!(VREDMIN_VS,   VArith, i, m, vred(m, i, false, redmin));
+instr!(VREDMAXU_VS,  VArith, i, m, vred(m, i, false, redmaxu));
+instr!(VREDMAX_VS,   VArith, i, m, vred(m, i, false, redmax));
+instr!(VWREDSUMU_VS, VArith, i, m, vred(m, i, true, wredsumu));
+instr!(VWREDSUM_VS,  VArith, i, m, vred(m, i, true, wredsum));
+
+// Mask instructions
+instr!(VMANDN_MM,   VArith, i, m, vmask(m, i, |a, b| a & !b));
+instr!(VMAND_MM,    VArith, i, m, vmask(m, i, |a, b| a & b));
+instr!(VMOR_MM,     VArith, i, m, vmask(m, i, |a, b| a | b));
+instr!(VMXOR_MM,    VArith, i, m, vmask(m, i, |a, b| a ^ b));
+instr!(VMORN_MM,    VArith, i, m, vmask(m, i, |a, b| a | !b));
+instr!(VMNAND_MM,   VArith, i, m, vmask(m, i, |a, b| !(a & b)));
+instr!(VMNOR_MM,    VArith, i, m, vmask(m, i, |a, b| !(a | b)));
+instr!(VMXNOR_MM,   VArith, i, m, vmask(m, i, |a, b| !(a ^ b)));
+instr!(VCPOP_M,     VArith, i, m, {
+    m.vregs.config()?;
+    check_vstart(m)?;
+    let count = (0..m.vregs.vl).filter(|&n| active(m, i.vm, n) && m.vregs.mask(i.vs2, n)).count();
+    m.set_r(Register::from(i.vd as u32), count as u64)});
+instr!(VFIRST_M,    VArith, i, m, {
+    m.vregs.config()?;
+    check_vstart(m)?;
+    let first = (0..m.vregs.vl).find(|&n| active(m, i.vm, n) && m.vregs.mask(i.vs2, n));
+    m.set_r(Register::from(i.vd as u32), first.map_or(u64::MAX, |n| n as u64))});
+instr!(VMSBF_M,     VArith, i, m, vmset_first(m, i, true, false));
+instr!(VMSIF_M,     VArith, i, m, vmset_first(m, i, true, true));
+instr!(VMSOF_M,     VArith, i, m, vmset_first(m, i, false, true));
+instr!(VIOTA_M,     VArith, i, m, viota(m, i));
+instr!(VID_V,       VArith, i, m, {
+    let t = m.vregs.config()?;
+    check_group(i.vd, t.lmul)?;
+    check_mask_dest(i.vm, i.vd)?;
+    for n in m.vregs.vstart..m.vregs.vl {
+        if active(m, i.vm, n) {
+            m.vregs.set(i.vd, n, t.sew, n as u64 & ones(t.sew));
+        }
+    }
+    m.vregs.vstart = 0;
+    Ok(())});
+
+instr_enum!(VectorOp {
+    VSETVLI, VSETIVLI, VSETVL, VLE8_V, VLE16_V, VLE32_V, VLE64_V, VLE8FF_V,
+    VLE16FF_V, VLE32FF_V, VLE64FF_V, VLRE8_V, VLRE16_V, VLRE32_V, VLRE64_V,
+    VLM_V, VLSE8_V, VLSE16_V, VLSE32_V, VLSE64_V, VLUXEI8_V, VLUXEI16_V,
+    VLUXEI32_V, VLUXEI64_V, VLOXEI8_V, VLOXEI16_V, VLOXEI32_V, VLOXEI64_V,
+    VSE8_V, VSE16_V, VSE32_V, VSE64_V, VSR_V, VSM_V, VSSE8_V, VSSE16_V,
+    VSSE32_V, VSSE64_V, VSUXEI8_V, VSUXEI16_V, VSUXEI32_V, VSUXEI64_V,
+    VSOXEI8_V, VSOXEI16_V, VSOXEI32_V, VSOXEI64_V, VADD_VV, VADD_VX, VADD_VI,
+    VSUB_VV, VSUB_VX, VRSUB_VX, VRSUB_VI, VMINU_VV, VMINU_VX, VMIN_VV, VMIN_VX,
+    VMAXU_VV, VMAXU_VX, VMAX_VV, VMAX_VX, VAND_VV, VAND_VX, VAND_VI, VOR_VV,
+    VOR_VX, VOR_VI, VXOR_VV, VXOR_VX, VXOR_VI, VSLL_VV, VSLL_VX, VSLL_VI,
+    VSRL_VV, VSRL_VX, VSRL_VI, VSRA_VV, VSRA_VX, VSRA_VI, VMUL_VV, VMUL_VX,
+    VMULH_VV, VMULH_VX, VMULHU_VV, VMULHU_VX, VMULHSU_VV, VMULHSU_VX, VDIVU_VV,
+    VDIVU_VX, VDIV_VV, VDIV_VX, VREMU_VV, VREMU_VX, VREM_VV, VREM_VX, VMACC_VV,
+    VMACC_VX, VNMSAC_VV, VNMSAC_VX, VMADD_VV, VMADD_VX, VNMSUB_VV, VNMSUB_VX,
+    VWADDU_VV, VWADDU_VX, VWADD_VV, VWADD_VX, VWSUBU_VV, VWSUBU_VX, VWSUB_VV,
+    VWSUB_VX, VWADDU_WV, VWADDU_WX, VWADD_WV, VWADD_WX, VWSUBU_WV, VWSUBU_WX,
+    VWSUB_WV, VWSUB_WX, VWMULU_VV, VWMULU_VX, VWMUL_VV, VWMUL_VX, VWMULSU_VV,
+    VWMULSU_VX, VWMACCU_VV, VWMACCU_VX, VWMACC_VV, VWMACC_VX, VWMACCSU_VV,
+    VWMACCSU_VX, VWMACCUS_VX, VNSRL_WV, VNSRL_WX, VNSRL_WI, VNSRA_WV, VNSRA_WX,
+    VNSRA_WI, VSADDU_VV, VSADDU_VX, VSADDU_VI, VSADD_VV, VSADD_VX, VSADD_VI,
+    VSSUBU_VV, VSSUBU_VX, VSSUB_VV, VSSUB_VX, VAADDU_VV, VAADDU_VX, VAADD_VV,
+    VAADD_VX, VASUBU_VV, VASUBU_VX, VASUB_VV, VASUB_VX, VSMUL_VV, VSMUL_VX,
+    VSSRL_VV, VSSRL_VX, VSSRL_VI, VSSRA_VV, VSSRA_VX, VSSRA_VI, VNCLIPU_WV,
+    VNCLIPU_WX, VNCLIPU_WI, VNCLIP_WV, VNCLIP_WX, VNCLIP_WI, VADC_VVM, VADC_VXM,
+    VADC_VIM, VMADC_VVM, VMADC_VXM, VMADC_VIM, VMADC_VV, VMADC_VX, VMADC_VI,
+    VSBC_VVM, VSBC_VXM, VMSBC_VVM, VMSBC_VXM, VMSBC_VV, VMSBC_VX, VMSEQ_VV,
+    VMSEQ_VX, VMSEQ_VI, VMSNE_VV, VMSNE_VX, VMSNE_VI, VMSLTU_VV, VMSLTU_VX,
+    VMSLT_VV, VMSLT_VX, VMSLEU_VV, VMSLEU_VX, VMSLEU_VI, VMSLE_VV, VMSLE_VX,
+    VMSLE_VI, VMSGTU_VX, VMSGTU_VI, VMSGT_VX, VMSGT_VI, VMERGE_VVM, VMERGE_VXM,
+    VMERGE_VIM, VMV_V_V, VMV_V_X, VMV_V_I, VMV1R_V, VMV2R_V, VMV4R_V, VMV8R_V,
+    VMV_X_S, VMV_S_X, VZEXT_VF2, VZEXT_VF4, VZEXT_VF8, VSEXT_VF2, VSEXT_VF4,
+    VSEXT_VF8, VSLIDEUP_VX, VSLIDEUP_VI, VSLIDEDOWN_VX, VSLIDEDOWN_VI,
+    VSLIDE1UP_VX, VSLIDE1DOWN_VX, VRGATHER_VV, VRGATHER_VX, VRGATHER_VI,
+    VRGATHEREI16_VV, VCOMPRESS_VM, VREDSUM_VS, VREDAND_VS, VREDOR_VS,
+    VREDXOR_VS, VREDMINU_VS, VREDMIN_VS, VREDMAXU_VS, VREDMAX_VS, VWREDSUMU_VS,
+    VWREDSUM_VS, VMANDN_MM, VMAND_MM, VMOR_MM, VMXOR_MM, VMORN_MM, VMNAND_MM,
+    VMNOR_MM, VMXNOR_MM, VCPOP_M, VFIRST_M, VMSBF_M, VMSIF_M, VMSOF_M, VIOTA_M,
+    VID_V,
+});
+
+/// A vector instruction. These are illegal while mstatus.VS is Off, and
+/// set it to Dirty as they change the vector state.
+#[derive(Clone, Copy)]
+pub struct VectorInst(VectorOp);
+
+impl Disassemble for VectorInst {
+    fn disassemble(&self) -> String {
+        self.0.disassemble()
+    }
+}
+
+impl Emulate for VectorInst {
+    fn emulate(&self, m: &mut Machine) -> Result<(), VmExit> {
+        m.check_vector_enabled()?;
+        self.0.emulate(m)?;
+        m.mark_vector_dirty();
+        Ok(())
+    }
+}
+
+/// Decodes the vector instructions in the OP-V opcode and the vector loads
+/// and stores in LOAD-FP and STORE-FP
+pub fn parse_vector(i: u32) -> Result<VectorInst, VmExit> {
+    parse_op(i).map(VectorInst)
+}
+
+fn parse_op(i: u32) -> Result<VectorOp, VmExit> {
+    let opcode = i & 0b1111111;
+    match opcode {
+        0b0000111 => {
+            let inst = VMem::from(i);
+            if inst.mew != 0 {
+                return Err(VmExit::InvalidOpcode(i));
+            }
+            match (inst.mop, inst.rs2, inst.width) {
+                (0b00, 0b00000, 0b000) => {Ok(VLE8_V::new(inst).into())},
+                (0b00, 0b00000, 0b101) => {Ok(VLE16_V::new(inst).into())},
+                (0b00, 0b00000, 0b110) => {Ok(VLE32_V::new(inst).into())},
+                (0b00, 0b00000, 0b111) => {Ok(VLE64_V::new(inst).into())},
+                (0b00, 0b10000, 0b000) => {Ok(VLE8FF_V::new(inst).into())},
+                (0b00, 0b10000, 0b101) => {Ok(VLE16FF_V::new(inst).into())},
+                (0b00, 0b10000, 0b110) => {Ok(VLE32FF_V::new(inst).into())},
+                (0b00, 0b10000, 0b111) => {Ok(VLE64FF_V::new(inst).into())},
+                (0b00, 0b01000, _) if !inst.vm => Err(VmExit::InvalidOpcode(i)),
+                (0b00, 0b01000, 0b000) => {Ok(VLRE8_V::new(inst).into())},
+                (0b00, 0b01000, 0b101) => {Ok(VLRE16_V::new(inst).into())},
+                (0b00, 0b01000, 0b110) => {Ok(VLRE32_V::new(inst).into())},
+                (0b00, 0b01000, 0b111) => {Ok(VLRE64_V::new(inst).into())},
+                (0b00, 0b01011, 0b000) if inst.vm && inst.nf == 0 => {Ok(VLM_V::new(inst).into())},
+                (0b10, _, 0b000) => {Ok(VLSE8_V::new(inst).into())},
+                (0b10, _, 0b101) => {Ok(VLSE16_V::new(inst).into())},
+                (0b10, _, 0b110) => {Ok(VLSE32_V::new(inst).into())},
+                (0b10, _, 0b111) => {Ok(VLSE64_V::new(inst).into())},
+                (0b01, _, 0b000) => {Ok(VLUXEI8_V::new(inst).into())},
+                (0b01, _, 0b101) => {Ok(VLUXEI16_V::new(inst).into())},
+                (0b01, _, 0b110) => {Ok(VLUXEI32_V::new(inst).into())},
+                (0b01, _, 0b111) => {Ok(VLUXEI64_V::new(inst).into())},
+                (0b11, _, 0b000) => {Ok(VLOXEI8_V::new(inst).into())},
+                (0b11, _, 0b101) => {Ok(VLOXEI16_V::new(inst).into())},
+                (0b11, _, 0b110) => {Ok(VLOXEI32_V::new(inst).into())},
+                (0b11, _, 0b111) => {Ok(VLOXEI64_V::new(inst).into())},
+                _ => Err(VmExit::InvalidOpcode(i)),
+            }
+        },
+        0b0100111 => {
+            let inst = VMem::from(i);
+            if inst.mew != 0 {
+                return Err(VmExit::InvalidOpcode(i));
+            }
+            match (inst.mop, inst.rs2, inst.width) {
+                (0b00, 0b00000, 0b000) => {Ok(VSE8_V::new(inst).into())},
+                (0b00, 0b00000, 0b101) => {Ok(VSE16_V::new(inst).into())},
+                (0b00, 0b00000, 0b110) => {Ok(VSE32_V::new(inst).into())},
+                (0b00, 0b00000, 0b111) => {Ok(VSE64_V::new(inst).into())},
+                (0b00, 0b01000, 0b000) if inst.vm => {Ok(VSR_V::new(inst).into())},
+                (0b00, 0b01011, 0b000) if inst.vm && inst.nf == 0 => {Ok(VSM_V::new(inst).into())},
+                (0b10, _, 0b000) => {Ok(VSSE8_V::new(inst).into())},
+                (0b10, _, 0b101) => {Ok(VSSE16_V::new(inst).into())},
+                (0b10, _, 0b110) => {Ok(VSSE32_V::new(inst).into())},
+                (0b10, _, 0b111) => {Ok(VSSE64_V::new(inst).into())},
+                (0b01, _, 0b000) => {Ok(VSUXEI8_V::new(inst).into())},
+                (0b01, _, 0b101) => {Ok(VSUXEI16_V::new(inst).into())},
+                (0b01, _, 0b110) => {Ok(VSUXEI32_V::new(inst).into())},
+                (0b01, _, 0b111) => {Ok(VSUXEI64_V::new(inst).into())},
+                (0b11, _, 0b000) => {Ok(VSOXEI8_V::new(inst).into())},
+                (0b11, _, 0b101) => {Ok(VSOXEI16_V::new(inst).into())},
+                (0b11, _, 0b110) => {Ok(VSOXEI32_V::new(inst).into())},
+                (0b11, _, 0b111) => {Ok(VSOXEI64_V::new(inst).into())},
+                _ => Err(VmExit::InvalidOpcode(i)),
+            }
+        },
+        0b1010111 => {
+            let inst = VArith::from(i);
+            let vs2 = inst.vs2 as u32;
+            match (inst.funct3, inst.funct6) {
+                // vsetvli, vsetivli and vsetvl
+                (0b111, _) if i >> 31 == 0 => {Ok(VSETVLI::new(VsetType::from(i)).into())},
+                (0b111, _) if i >> 30 == 0b11 => {Ok(VSETIVLI::new(VsetType::from(i)).into())},
+                (0b111, 0b100000) if (i >> 25) & 1 == 0 => {Ok(VSETVL::new(ItypeOp::from(i)).into())},
+
+                // OPIVV
+                (0b000, 0b000000) => {Ok(VADD_VV::new(inst).into())},
+                (0b000, 0b000010) => {Ok(VSUB_VV::new(inst).into())},
+                (0b000, 0b000100) => {Ok(VMINU_VV::new(inst).into())},
+                (0b000, 0b000101) => {Ok(VMIN_VV::new(inst).into())},
+                (0b000, 0b000110) => {Ok(VMAXU_VV::new(inst).into())},
+                (0b000, 0b000111) => {Ok(VMAX_VV::new(inst).into())},
+                (0b000, 0b001001) => {Ok(VAND_VV::new(inst).into())},
+                (0b000, 0b001010) => {Ok(VOR_VV::new(inst).into())},
+                (0b000, 0b001011) => {Ok(VXOR_VV::new(inst).into())},
+                (0b000, 0b001100) => {Ok(VRGATHER_VV::new(inst).into())},
+                (0b000, 0b001110) => {Ok(VRGATHEREI16_VV::new(inst).into())},
+                (0b000, 0b010000) => {Ok(VADC_VVM::new(inst).into())},
+                (0b000, 0b010001) if inst.vm => {Ok(VMADC_VV::new(inst).into())},
+                (0b000, 0b010001) => {Ok(VMADC_VVM::new(inst).into())},
+                (0b000, 0b010010) => {Ok(VSBC_VVM::new(inst).into())},
+                (0b000, 0b010011) if inst.vm => {Ok(VMSBC_VV::new(inst).into())},
+                (0b000, 0b010011) => {Ok(VMSBC_VVM::new(inst).into())},
+                (0b000, 0b010111) if !inst.vm => {Ok(VMERGE_VVM::new(inst).into())},
+                (0b000, 0b010111) if vs2 == 0 => {Ok(VMV_V_V::new(inst).into())},
+                (0b000, 0b011000) => {Ok(VMSEQ_VV::new(inst).into())},
+                (0b000, 0b011001) => {Ok(VMSNE_VV::new(inst).into())},
+                (0b000, 0b011010) => {Ok(VMSLTU_VV::new(inst).into())},
+                (0b000, 0b011011) => {Ok(VMSLT_VV::new(inst).into())},
+                (0b000, 0b011100) => {Ok(VMSLEU_VV::new(inst).into())},
+                (0b000, 0b011101) => {Ok(VMSLE_VV::new(inst).into())},
+                (0b000, 0b100000) => {Ok(VSADDU_VV::new(inst).into())},
+                (0b000, 0b100001) => {Ok(VSADD_VV::new(inst).into())},
+                (0b000, 0b100010) => {Ok(VSSUBU_VV::new(inst).into())},
+                (0b000, 0b100011) => {Ok(VSSUB_VV::new(inst).into())},
+                (0b000, 0b100101) => {Ok(VSLL_VV::new(inst).into())},
+                (0b000, 0b100111) => {Ok(VSMUL_VV::new(inst).into())},
+                (0b000, 0b101000) => {Ok(VSRL_VV::new(inst).into())},
+                (0b000, 0b101001) => {Ok(VSRA_VV::new(inst).into())},
+                (0b000, 0b101010) => {Ok(VSSRL_VV::new(inst).into())},
+                (0b000, 0b101011) => {Ok(VSSRA_VV::new(inst).into())},
+                (0b000, 0b101100) => {Ok(VNSRL_WV::new(inst).into())},
+                (0b000, 0b101101) => {Ok(VNSRA_WV::new(inst).into())},
+                (0b000, 0b101110) => {Ok(VNCLIPU_WV::new(inst).into())},
+                (0b000, 0b101111) => {Ok(VNCLIP_WV::new(inst).into())},
+                (0b000, 0b110000) => {Ok(VWREDSUMU_VS::new(inst).into())},
+                (0b000, 0b110001) => {Ok(VWREDSUM_VS::new(inst).into())},
+
+                // OPIVX
+                (0b100, 0b000000) => {Ok(VADD_VX::new(inst).into())},
+                (0b100, 0b000010) => {Ok(VSUB_VX::new(inst).into())},
+                (0b100, 0b000011) => {Ok(VRSUB_VX::new(inst).into())},
+                (0b100, 0b000100) => {Ok(VMINU_VX::new(inst).into())},
+                (0b100, 0b000101) => {Ok(VMIN_VX::new(inst).into())},
+                (0b100, 0b000110) => {Ok(VMAXU_VX::new(inst).into())},
+                (0b100, 0b000111) => {Ok(VMAX_VX::new(inst).into())},
+                (0b100, 0b001001) => {Ok(VAND_VX::new(inst).into())},
+                (0b100, 0b001010) => {Ok(VOR_VX::new(inst).into())},
+                (0b100, 0b001011) => {Ok(VXOR_VX::new(inst).into())},
+                (0b100, 0b001100) => {Ok(VRGATHER_VX::new(inst).into())},
+                (0b100, 0b001110) => {Ok(VSLIDEUP_VX::new(inst).into())},
+                (0b100, 0b001111) => {Ok(VSLIDEDOWN_VX::new(inst).into())},
+                (0b100, 0b010000) => {Ok(VADC_VXM::new(inst).into())},
+                (0b100, 0b010001) if inst.vm => {Ok(VMADC_VX::new(inst).into())},
+                (0b100, 0b010001) => {Ok(VMADC_VXM::new(inst).into())},
+                (0b100, 0b010010) => {Ok(VSBC_VXM::new(inst).into())},
+                (0b100, 0b010011) if inst.vm => {Ok(VMSBC_VX::new(inst).into())},
+                (0b100, 0b010011) => {Ok(VMSBC_VXM::new(inst).into())},
+                (0b100, 0b010111) if !inst.vm => {Ok(VMERGE_VXM::new(inst).into())},
+                (0b100, 0b010111) if vs2 == 0 => {Ok(VMV_V_X::new(inst).into())},
+                (0b100, 0b011000) => {Ok(VMSEQ_VX::new(inst).into())},
+                (0b100, 0b011001) => {Ok(VMSNE_VX::new(inst).into())},
+                (0b100, 0b011010) => {Ok(VMSLTU_VX::new(inst).into())},
+                (0b100, 0b011011) => {Ok(VMSLT_VX::new(inst).into())},
+                (0b100, 0b011100) => {Ok(VMSLEU_VX::new(inst).into())},
+                (0b100, 0b011101) => {Ok(VMSLE_VX::new(inst).into())},
+                (0b100, 0b011110) => {Ok(VMSGTU_VX::new(inst).into())},
+                (0b100, 0b011111) => {Ok(VMSGT_VX::new(inst).into())},
+                (0b100, 0b100000) => {Ok(VSADDU_VX::new(inst).into())},
+                (0b100, 0b100001) => {Ok(VSADD_VX::new(inst).into())},
+                (0b100, 0b100010) => {Ok(VSSUBU_VX::new(inst).into())},
+                (0b100, 0b100011) => {Ok(VSSUB_VX::new(inst).into())},
+                (0b100, 0b100101) => {Ok(VSLL_VX::new(inst).into())},
+                (0b100, 0b100111) => {Ok(VSMUL_VX::new(inst).into())},
+                (0b100, 0b101000) => {Ok(VSRL_VX::new(inst).into())},
+                (0b100, 0b101001) => {Ok(VSRA_VX::new(inst).into())},
+                (0b100, 0b101010) => {Ok(VSSRL_VX::new(inst).into())},
+                (0b100, 0b101011) => {Ok(VSSRA_VX::new(inst).into())},
+                (0b100, 0b101100) => {Ok(VNSRL_WX::new(inst).into())},
+                (0b100, 0b101101) => {Ok(VNSRA_WX::new(inst).into())},
+                (0b100, 0b101110) => {Ok(VNCLIPU_WX::new(inst).into())},
+                (0b100, 0b101111) => {Ok(VNCLIP_WX::new(inst).into())},
+
+                // OPIVI
+                (0b011, 0b000000) => {Ok(VADD_VI::new(inst).into())},
+                (0b011, 0b000011) => {Ok(VRSUB_VI::new(inst).into())},
+                (0b011, 0b001001) => {Ok(VAND_VI::new(inst).into())},
+                (0b011, 0b001010) => {Ok(VOR_VI::new(inst).into())},
+                (0b011, 0b001011) => {Ok(VXOR_VI::new(inst).into())},
+                (0b011, 0b001100) => {Ok(VRGATHER_VI::new(inst).into())},
+                (0b011, 0b001110) => {Ok(VSLIDEUP_VI::new(inst).into())},
+                (0b011, 0b001111) => {Ok(VSLIDEDOWN_VI::new(inst).into())},
+                (0b011, 0b010000) => {Ok(VADC_VIM::new(inst).into())},
+                (0b011, 0b010001) if inst.vm => {Ok(VMADC_VI::new(inst).into())},
+                (0b011, 0b010001) => {Ok(VMADC_VIM::new(inst).into())},
+                (0b011, 0b010111) if !inst.vm => {Ok(VMERGE_VIM::new(inst).into())},
+                (0b011, 0b010111) if vs2 == 0 => {Ok(VMV_V_I::new(inst).into())},
+                (0b011, 0b011000) => {Ok(VMSEQ_VI::new(inst).into())},
+                (0b011, 0b011001) => {Ok(VMSNE_VI::new(inst).into())},
+                (0b011, 0b011100) => {Ok(VMSLEU_VI::new(inst).into())},
+                (0b011, 0b011101) => {Ok(VMSLE_VI::new(inst).into())},
+                (0b011, 0b011110) => {Ok(VMSGTU_VI::new(inst).into())},
+                (0b011, 0b011111) => {Ok(VMSGT_VI::new(inst).into())},
+                (0b011, 0b100000) => {Ok(VSADDU_VI::new(inst).into())},
+                (0b011, 0b100001) => {Ok(VSADD_VI::new(inst).into())},
+                (0b011, 0b100101) => {Ok(VSLL_VI::new(inst).into())},
+                (0b011, 0b100111) if inst.vm && inst.rs1 == 0 => {Ok(VMV1R_V::new(inst).into())},
+                (0b011, 0b100111) if inst.vm && inst.rs1 == 1 => {Ok(VMV2R_V::new(inst).into())},
+                (0b011, 0b100111) if inst.vm && inst.rs1 == 3 => {Ok(VMV4R_V::new(inst).into())},
+                (0b011, 0b100111) if inst.vm && inst.rs1 == 7 => {Ok(VMV8R_V::new(inst).into())},
+                (0b011, 0b101000) => {Ok(VSRL_VI::new(inst).into())},
+                (0b011, 0b101001) => {Ok(VSRA_VI::new(inst).into())},
+                (0b011, 0b101010) => {Ok(VSSRL_VI::new(inst).into())},
+                (0b011, 0b101011) => {Ok(VSSRA_VI::new(inst).into())},
+                (0b011, 0b101100) => {Ok(VNSRL_WI::new(inst).into())},
+                (0b011, 0b101101) => {Ok(VNSRA_WI::new(inst).into())},
+                (0b011, 0b101110) => {Ok(VNCLIPU_WI::new(inst).into())},
+                (0b011, 0b101111) => {Ok(VNCLIP_WI::new(inst).into())},
+
+                // OPMVV
+                (0b010, 0b000000) => {Ok(VREDSUM_VS::new(inst).into())},
+                (0b010, 0b000001) => {Ok(VREDAND_VS::new(inst).into())},
+                (0b010, 0b000010) => {Ok(VREDOR_VS::new(inst).into())},
+                (0b010, 0b000011) => {Ok(VREDXOR_VS::new(inst).into())},
+                (0b010, 0b000100) => {Ok(VREDMINU_VS::new(inst).into())},
+                (0b010, 0b000101) => {Ok(VREDMIN_VS::new(inst).into())},
+                (0b010, 0b000110) => {Ok(VREDMAXU_VS::new(inst).into())},
+                (0b010, 0b000111) => {Ok(VREDMAX_VS::new(inst).into())},
+                (0b010, 0b001000) => {Ok(VAADDU_VV::new(inst).into())},
+                (0b010, 0b001001) => {Ok(VAADD_VV::new(inst).into())},
+                (0b010, 0b001010) => {Ok(VASUBU_VV::new(inst).into())},
+                (0b010, 0b001011) => {Ok(VASUB_VV::new(inst).into())},
+                (0b010, 0b010000) => {
+                    match inst.rs1 {
+                        0b00000 if inst.vm => {Ok(VMV_X_S::new(inst).into())},
+                        0b10000 => {Ok(VCPOP_M::new(inst).into())},
+                        0b10001 => {Ok(VFIRST_M::new(inst).into())},
+                        _ => Err(VmExit::InvalidOpcode(i)),
+                    }
+                },
+                (0b010, 0b010010) => {
+                    match inst.rs1 {
+                        0b00010 => {Ok(VZEXT_VF8::new(inst).into())},
+                        0b00011 => {Ok(VSEXT_VF8::new(inst).into())},
+                        0b00100 => {Ok(VZEXT_VF4::new(inst).into())},
+                        0b00101 => {Ok(VSEXT_VF4::new(inst).into())},
+                        0b00110 => {Ok(VZEXT_VF2::new(inst).into())},
+                        0b00111 => {Ok(VSEXT_VF2::new(inst).into())},
+                        _ => Err(VmExit::InvalidOpcode(i)),
+                    }
+                },
+                (0b010, 0b010100) => {
+                    match inst.rs1 {
+                        0b00001 => {Ok(VMSBF_M::new(inst).into())},
+                        0b00010 => {Ok(VMSOF_M::new(inst).into())},
+                        0b00011 => {Ok(VMSIF_M::new(inst).into())},
+                        0b10000 => {Ok(VIOTA_M::new(inst).into())},
+                        0b10001 if vs2 == 0 => {Ok(VID_V::new(inst).into())},
+                        _ => Err(VmExit::InvalidOpcode(i)),
+                    }
+                },
+                (0b010, 0b010111) if inst.vm => {Ok(VCOMPRESS_VM::new(inst).into())},
+                (0b010, 0b011000) if inst.vm => {Ok(VMANDN_MM::new(inst).into())},
+                (0b010, 0b011001) if inst.vm => {Ok(VMAND_MM::new(inst).into())},
+                (0b010, 0b011010) if inst.vm => {Ok(VMOR_MM::new(inst).into())},
+                (0b010, 0b011011) if inst.vm => {Ok(VMXOR_MM::new(inst).into())},
+                (0b010, 0b011100) if inst.vm => {Ok(VMORN_MM::new(inst).into())},
+                (0b010, 0b011101) if inst.vm => {Ok(VMNAND_MM::new(inst).into())},
+                (0b010, 0b011110) if inst.vm => {Ok(VMNOR_MM::new(inst).into())},
+                (0b010, 0b011111) if inst.vm => {Ok(VMXNOR_MM::new(inst).into())},
+                (0b010, 0b100000) => {Ok(VDIVU_VV::new(inst).into())},
+                (0b010, 0b100001) => {Ok(VDIV_VV::new(inst).into())},
+                (0b010, 0b100010) => {Ok(VREMU_VV::new(inst).into())},
+                (0b010, 0b100011) => {Ok(VREM_VV::new(inst).into())},
+                (0b010, 0b100100) => {Ok(VMULHU_VV::new(inst).into())},
+                (0b010, 0b100101) => {Ok(VMUL_VV::new(inst).into())},
+                (0b010, 0b100110) => {Ok(VMULHSU_VV::new(inst).into())},
+                (0b010, 0b100111) => {Ok(VMULH_VV::new(inst).into())},
+                (0b010, 0b101001) => {Ok(VMADD_VV::new(inst).into())},
+                (0b010, 0b101011) => {Ok(VNMSUB_VV::new(inst).into())},
+                (0b010, 0b101101) => {Ok(VMACC_VV::new(inst).into())},
+                (0b010, 0b101111) => {Ok(VNMSAC_VV::new(inst).into())},
+                (0b010, 0b110000) => {Ok(VWADDU_VV::new(inst).into())},
+                (0b010, 0b110001) => {Ok(VWADD_VV::new(inst).into())},
+                (0b010, 0b110010) => {Ok(VWSUBU_VV::new(inst).into())},
+                (0b010, 0b110011) => {Ok(VWSUB_VV::new(inst).into())},
+                (0b010, 0b110100) => {Ok(VWADDU_WV::new(inst).into())},
+                (0b010, 0b110101) => {Ok(VWADD_WV::new(inst).into())},
+                (0b010, 0b110110) => {Ok(VWSUBU_WV::new(inst).into())},
+                (0b010, 0b110111) => {Ok(VWSUB_WV::new(inst).into())},
+                (0b010, 0b111000) => {Ok(VWMULU_VV::new(inst).into())},
+                (0b010, 0b111010) => {Ok(VWMULSU_VV::new(inst).into())},
+                (0b010, 0b111011) => {Ok(VWMUL_VV::new(inst).into())},
+                (0b010, 0b111100) => {Ok(VWMACCU_VV::new(inst).into())},
+                (0b010, 0b111101) => {Ok(VWMACC_VV::new(inst).into())},
+                (0b010, 0b111111) => {Ok(VWMACCSU_VV::new(inst).into())},
+
+                // OPMVX
+                (0b110, 0b001000) => {Ok(VAADDU_VX::new(inst).into())},
+                (0b110, 0b001001) => {Ok(VAADD_VX::new(inst).into())},
+                (0b110, 0b001010) => {Ok(VASUBU_VX::new(inst).into())},
+                (0b110, 0b001011) => {Ok(VASUB_VX::new(inst).into())},
+                (0b110, 0b001110) => {Ok(VSLIDE1UP_VX::new(inst).into())},
+                (0b110, 0b001111) => {Ok(VSLIDE1DOWN_VX::new(inst).into())},
+                (0b110, 0b010000) if inst.vm && vs2 == 0 => {Ok(VMV_S_X::new(inst).into())},
+                (0b110, 0b100000) => {Ok(VDIVU_VX::new(inst).into())},
+                (0b110, 0b100001) => {Ok(VDIV_VX::new(inst).into())},
+                (0b110, 0b100010) => {Ok(VREMU_VX::new(inst).into())},
+                (0b110, 0b100011) => {Ok(VREM_VX::new(inst).into())},
+                (0b110, 0b100100) => {Ok(VMULHU_VX::new(inst).into())},
+                (0b110, 0b100101) => {Ok(VMUL_VX::new(inst).into())},
+                (0b110, 0b100110) => {Ok(VMULHSU_VX::new(inst).into())},
+                (0b110, 0b100111) => {Ok(VMULH_VX::new(inst).into())},
+                (0b110, 0b101001) => {Ok(VMADD_VX::new(inst).into())},
+                (0b110, 0b101011) => {Ok(VNMSUB_VX::new(inst).into())},
+                (0b110, 0b101101) => {Ok(VMACC_VX::new(inst).into())},
+                (0b110, 0b101111) => {Ok(VNMSAC_VX::new(inst).into())},
+                (0b110, 0b110000) => {Ok(VWADDU_VX::new(inst).into())},
+                (0b110, 0b110001) => {Ok(VWADD_VX::new(inst).into())},
+                (0b110, 0b110010) => {Ok(VWSUBU_VX::new(inst).into())},
+                (0b110, 0b110011) => {Ok(VWSUB_VX::new(inst).into())},
+                (0b110, 0b110100) => {Ok(VWADDU_WX::new(inst).into())},
+                (0b110, 0b110101) => {Ok(VWADD_WX::new(inst).into())},
+                (0b110, 0b110110) => {Ok(VWSUBU_WX::new(inst).into())},
+                (0b110, 0b110111) => {Ok(VWSUB_WX::new(inst).into())},
+                (0b110, 0b111000) => {Ok(VWMULU_VX::new(inst).into())},
+                (0b110, 0b111010) => {Ok(VWMULSU_VX::new(inst).into())},
+                (0b110, 0b111011) => {Ok(VWMUL_VX::new(inst).into())},
+                (0b110, 0b111100) => {Ok(VWMACCU_VX::new(inst).into())},
+                (0b110, 0b111101) => {Ok(VWMACC_VX::new(inst).into())},
+                (0b110, 0b111110) => {Ok(VWMACCUS_VX::new(inst).into())},
+                (0b110, 0b111111) => {Ok(VWMACCSU_VX::new(inst).into())},
+                _ => Err(VmExit::InvalidOpcode(i)),
+            }
+        },
+        _ => Err(VmExit::InvalidOpcode(i)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vtype() {
+        let vtype = |vsew: u64, vlmul: u64| (vsew << 3) | vlmul;
+        assert_eq!(VType::decode(vtype(3, 0)), Some(VType { sew: 64, lmul: 0 }));
+        assert_eq!(VType::decode(vtype(1, 7)), Some(VType { sew: 16, lmul: -1 }));
+        assert_eq!(VType::decode(vtype(2, 4)), None);
+        assert_eq!(VType::decode(vtype(4, 0)), None);
+        assert_eq!(VType::decode(VTYPE_VILL), None);
+        // SEW = 32 only fits in LMUL = 1/2 and up
+        assert_eq!(VType::decode(vtype(2, 6)), None);
+        assert_eq!(VType::decode(vtype(2, 7)).unwrap().vlmax(128), 2);
+        assert_eq!(VType::decode(vtype(0, 3)).unwrap().vlmax(128), 128);
+    }
+
+    #[test]
+    fn test_fixed_point_rounding() {
+        // 0b1011 >> 2 is 2.75, 0b1010 >> 2 is the tie 2.5
+        assert_eq!(roundoff_unsigned(0b1011, 2, 0), 3);
+        assert_eq!(roundoff_unsigned(0b1010, 2, 0), 3);
+        assert_eq!(roundoff_unsigned(0b1010, 2, 1), 2);
+        assert_eq!(roundoff_unsigned(0b1011, 2, 2), 2);
+        assert_eq!(roundoff_unsigned(0b1001, 2, 3), 3);
+        assert_eq!(roundoff_signed(-5, 1, 1), -2);
+
+        let mut env = Env { sew: 8, vxrm: 0, sat: false };
+        assert_eq!(clip_signed(127, 8, &mut env), 127);
+        assert!(!env.sat);
+        assert_eq!(clip_signed(-129, 8, &mut env), 0x80);
+        assert!(env.sat);
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::Write;
+
+use crate::common::{Machine, VmExit};
+use crate::mmu::PAGE_SIZE;
+use crate::riscv::register::Register::{*};
+
+// Linux/newlib syscall numbers as used by riscv64-unknown-elf
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ:  u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT:  u64 = 93;
+const SYS_BRK:   u64 = 214;
+
+const EIO: i64 = 5;
+const ENOSYS: i64 = 38;
+
+/// Writes `len` bytes from `addr` in the guest to `out` one page at a time,
+/// so the length the guest passes does not size a host allocation. Errors
+/// of the host are returned to the guest as EIO.
+fn write_guest(m: &mut Machine, out: &mut dyn Write, addr: u64, len: u64) -> Result<u64, VmExit> {
+    let mut buf = [0; PAGE_SIZE];
+    let mut done = 0;
+    while done < len {
+        let n = std::cmp::min(len - done, PAGE_SIZE as u64) as usize;
+        m.mmu.read_into(m.vaddr(addr.wrapping_add(done)), &mut buf[..n])?;
+        if out.write_all(&buf[..n]).is_err() {
+            return Ok(-EIO as u64);
+        }
+        done += n as u64;
+    }
+    Ok(len)
+}
+
+/// Emulates the minimal set of host syscalls newlib needs to run a program.
+/// Arguments are passed in a0-a5, the syscall number in a7 and the result is
+/// returned in a0.
+pub fn handle_syscall(m: &mut Machine) -> Result<(), VmExit> {
+    let a0 = m.get_r(A0);
+    let a1 = m.get_r(A1);
+    let a2 = m.get_ru(A2);
+
+    let ret = match m.get_r(A7) {
+        SYS_EXIT => return Err(VmExit::Exit(a0 as i64)),
+        SYS_WRITE => {
+            match a0 {
+                1 => write_guest(m, &mut std::io::stdout(), a1, a2)?,
+                2 => write_guest(m, &mut std::io::stderr(), a1, a2)?,
+                _ => -1i64 as u64,
+            }
+        },
+        SYS_BRK => {
+            // The break stays where it is if the new one does not fit
+            let a0 = m.get_ru(A0) as usize;
+            let cur = m.mmu.alloc_next();
+            if a0 > cur.0 {
+                m.mmu.alloc(a0 - cur.0);
+            }
+            m.mmu.alloc_next().0 as u64
+        },
+        SYS_CLOSE => 0,
+        SYS_FSTAT | SYS_LSEEK | SYS_READ => -1i64 as u64,
+        _ => -ENOSYS as u64,
+    };
+
+    m.set_r(A0, ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::VirtAddr;
+    use crate::test_util::machine;
+
+    #[test]
+    fn test_write() {
+        // Nothing is allocated for the length, the write faults at the
+        // first byte outside memory
+        let mut m = machine(&[]);
+        m.set_r(A7, SYS_WRITE).unwrap();
+        m.set_r(A0, 1).unwrap();
+        m.set_r(A1, 0x10000).unwrap();
+        m.set_r(A2, u64::MAX).unwrap();
+        assert_eq!(handle_syscall(&mut m), Err(VmExit::ReadFault(VirtAddr(0x10000))));
+
+        // Host errors are returned to the guest
+        assert_eq!(write_guest(&mut m, &mut Closed, 0, 16), Ok(-EIO as u64));
+    }
+
+    /// Output whose reader went away
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
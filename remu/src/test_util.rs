@@ -0,0 +1,25 @@
+// Fixtures shared by the tests of the engines
+
+use crate::block::Engine;
+use crate::common::Machine;
+use crate::mmu::{Mmu, VirtAddr};
+
+/// Every engine that is built
+pub const ENGINES: &[Engine] = &[
+    Engine::Step,
+    Engine::Block,
+    #[cfg(feature = "x86-jit")]
+    Engine::Jit,
+    #[cfg(feature = "cranelift")]
+    Engine::Cranelift,
+];
+
+/// Machine running `prog` from address 0, in 8K of memory
+pub fn machine(prog: &[u32]) -> Machine {
+    let mut mmu = Mmu::new(8192);
+    for (n, inst) in prog.iter().enumerate() {
+        mmu.write_u32(VirtAddr(n * 4), *inst).unwrap();
+    }
+    mmu.entry_point = Some(VirtAddr(0));
+    Machine::new(mmu)
+}